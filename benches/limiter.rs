@@ -0,0 +1,204 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use ngx_http_rate_limiter::cidr_trie::CidrTrie;
+use ngx_http_rate_limiter::denylist::Network;
+use ngx_http_rate_limiter::rules::{PathMatch, Rule, RuleSet};
+use ngx_http_rate_limiter::storage::{MemoryStorage, StorageBackend};
+use ngx_http_rate_limiter::{request_key, RateLimiter};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+/// Distinct clients cycled through, so the map is not a single hot key.
+const CLIENTS: usize = 1024;
+
+fn clients() -> Vec<String> {
+    (0..CLIENTS)
+        .map(|i| request_key(Ipv4Addr::new(10, 0, (i >> 8) as u8, i as u8)).to_string())
+        .collect()
+}
+
+fn key_extraction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request_key");
+    let v4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 146));
+    let v6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0x85a3, 0, 0, 0x8a2e, 0x370, 0x7334));
+    for (name, addr) in [("ipv4", v4), ("ipv6", v6)] {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &addr, |b, addr| {
+            b.iter(|| request_key(black_box(addr)))
+        });
+    }
+    group.finish();
+}
+
+/// 100 rules, half prefixes and half regexes, matched against a path that
+/// hits the last one, one that hits nothing and one that hits a prefix.
+/// Should stay under a microsecond.
+fn rule_matching(c: &mut Criterion) {
+    let rules = (0..100)
+        .map(|i| Rule {
+            path: if i % 2 == 0 {
+                PathMatch::Prefix(format!("/service-{}/", i))
+            } else {
+                PathMatch::Regex(format!(r"^/api/v\d+/resource-{}/\d+$", i))
+            },
+            requests: 100,
+            window: 60,
+            rejection: None,
+            body: None,
+            counter: None,
+            scope: None,
+            priority: None,
+            id: None,
+        })
+        .collect();
+    let set = RuleSet::compile(1, rules).unwrap();
+
+    let mut group = c.benchmark_group("rules/100");
+    for (name, path) in [
+        ("regex", "/api/v2/resource-99/12345"),
+        ("miss", "/static/js/app.3f9c2a.js"),
+        ("prefix", "/service-42/orders/7"),
+    ] {
+        group.bench_with_input(BenchmarkId::from_parameter(name), path, |b, path| {
+            b.iter(|| set.find(black_box(path)).is_some())
+        });
+    }
+    group.finish();
+}
+
+/// Longest-prefix lookups among a million IPv4 prefixes of /16 to /32,
+/// for an address under one of them and one under none.
+fn cidr_trie(c: &mut Criterion) {
+    let mut trie = CidrTrie::new();
+    // A xorshift sequence, so the prefixes are spread but reproducible
+    let mut state = 0x9e37_79b9_u32;
+    while trie.len() < 1_000_000 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        // Nothing under 0.0.0.0/8, so the miss stays a miss
+        let address = Ipv4Addr::from(state | 0x0100_0000);
+        let prefix = 16 + (state % 17) as u8;
+        trie.insert(Network { address: IpAddr::V4(address), prefix }, ());
+    }
+    let listed = trie.iter().next().map(|(network, _)| network.address).unwrap();
+
+    let mut group = c.benchmark_group("cidr_trie/1m");
+    for (name, address) in [("hit", listed), ("miss", IpAddr::V4(Ipv4Addr::new(0, 1, 2, 3)))] {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &address, |b, address| {
+            b.iter(|| trie.longest_match(black_box(*address)).is_some())
+        });
+    }
+    group.finish();
+}
+
+fn memory_storage(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let storage = MemoryStorage::new();
+    let clients = clients();
+
+    let mut i = 0;
+    c.bench_function("memory/increment", |b| {
+        b.to_async(&runtime).iter(|| {
+            i = (i + 1) % CLIENTS;
+            let key = &clients[i];
+            let storage = &storage;
+            async move { storage.increment(key, 60).await.unwrap() }
+        })
+    });
+}
+
+fn decision(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let clients = clients();
+    let redis_url = format!("redis://{}", runtime.block_on(mock_redis()));
+
+    let mut group = c.benchmark_group("decision");
+    for (backend, config) in [("memory", ""), ("redis", redis_url.as_str())] {
+        // Allow path: the limit is never reached; deny path: it always is
+        for (path, limit) in [("allow", u32::MAX), ("deny", 0)] {
+            let limiter = runtime
+                .block_on(RateLimiter::with_backend(backend, config, limit, 60))
+                .unwrap();
+            let mut i = 0;
+            group.bench_function(BenchmarkId::new(backend, path), |b| {
+                b.to_async(&runtime).iter(|| {
+                    i = (i + 1) % CLIENTS;
+                    let key = &clients[i];
+                    let limiter = &limiter;
+                    async move { limiter.is_rate_limited(key).await }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Just enough of the Redis protocol for `RedisStorage`, so the benchmark
+/// measures the client side without a server: GET returns the count,
+/// EVALSHA/EVAL bump it, anything else is `+OK`.
+async fn mock_redis() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let counts = Arc::new(Mutex::new(HashMap::new()));
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(serve(socket, Arc::clone(&counts)));
+        }
+    });
+    addr
+}
+
+async fn serve(socket: TcpStream, counts: Arc<Mutex<HashMap<Vec<u8>, u64>>>) {
+    let (read, mut write) = socket.into_split();
+    let mut reader = BufReader::new(read);
+    let mut line = String::new();
+
+    loop {
+        // *<argc>\r\n followed by argc × $<len>\r\n<bytes>\r\n
+        line.clear();
+        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let argc: usize = line.trim_end().trim_start_matches('*').parse().unwrap_or(0);
+        let mut args = Vec::with_capacity(argc);
+        for _ in 0..argc {
+            line.clear();
+            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let len: usize = line.trim_end().trim_start_matches('$').parse().unwrap_or(0);
+            let mut arg = vec![0; len + 2];
+            if reader.read_exact(&mut arg).await.is_err() {
+                return;
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+
+        let command = args.first().map(|command| command.to_ascii_uppercase());
+        let reply = match command.as_deref() {
+            Some(b"GET") if args.len() > 1 => match counts.lock().unwrap().get(&args[1]) {
+                Some(count) => format!("${}\r\n{}\r\n", count.to_string().len(), count),
+                None => "$-1\r\n".to_string(),
+            },
+            // EVALSHA <sha> <numkeys> <key> ...
+            Some(b"EVALSHA" | b"EVAL") if args.len() > 3 => {
+                let mut counts = counts.lock().unwrap();
+                let count = counts.entry(args[3].clone()).or_insert(0);
+                *count += 1;
+                format!(":{}\r\n", count)
+            }
+            _ => "+OK\r\n".to_string(),
+        };
+        if write.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+criterion_group!(benches, key_extraction, rule_matching, cidr_trie, memory_storage, decision);
+criterion_main!(benches);
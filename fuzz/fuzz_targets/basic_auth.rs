@@ -0,0 +1,52 @@
+//! `Authorization: Basic` headers come straight from clients. Decoding
+//! them must never panic, and credentials that were encoded properly must
+//! key the request by exactly their username.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use ngx_http_rate_limiter::basic_auth::username_key;
+use ngx_http_rate_limiter::tenant::RequestInfo;
+
+#[derive(Debug, Arbitrary)]
+struct Input<'a> {
+    header: &'a str,
+    username: &'a str,
+    password: &'a str,
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().fold(0u32, |group, byte| (group << 8) | u32::from(*byte)) << (8 * (3 - chunk.len()));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn key_for(authorization: &str) -> Option<String> {
+    let headers = [("authorization", authorization)];
+    username_key(&RequestInfo { headers: &headers, ..RequestInfo::default() }).map(|key| key.as_str().to_string())
+}
+
+fuzz_target!(|input: Input| {
+    if let Some(key) = key_for(input.header) {
+        let username = key.strip_prefix("user:").expect("keys start with user:");
+        assert!(!username.is_empty());
+        assert!(!username.contains(':'));
+    }
+
+    // The username ends at the first colon, wherever that is
+    let credentials = format!("{}:{}", input.username, input.password);
+    let username = input.username.split(':').next().unwrap_or_default();
+    let expected = (!username.is_empty()).then(|| format!("user:{}", username));
+    assert_eq!(key_for(&format!("Basic {}", encode_base64(credentials.as_bytes()))), expected);
+});
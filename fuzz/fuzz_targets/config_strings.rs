@@ -0,0 +1,13 @@
+//! Backend config strings from `rate_limit_storage` must be rejected with
+//! an error, never a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ngx_http_rate_limiter::storage::{MemoryOptions, SentinelConfig};
+
+fuzz_target!(|config: &str| {
+    let _ = MemoryOptions::parse(config);
+    let _ = SentinelConfig::parse(config);
+    let _ = SentinelConfig::parse(&format!("sentinel://{}", config));
+});
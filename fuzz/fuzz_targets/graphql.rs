@@ -0,0 +1,27 @@
+//! GraphQL request bodies are scanned for their depth and field count
+//! before anything else reads them. No body may panic the scan, and what
+//! it reports must hang together.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ngx_http_rate_limiter::graphql;
+
+fuzz_target!(|body: &[u8]| {
+    let Some(operation) = graphql::parse(body) else {
+        return;
+    };
+
+    // Fields are only counted inside a selection set
+    assert!(operation.fields == 0 || operation.depth > 0);
+    assert_eq!(operation.cost(), u64::from(operation.fields.max(1)));
+
+    // A bare document is scanned as it is, so every level of nesting and
+    // every field is in the body itself
+    if let Ok(document) = std::str::from_utf8(body) {
+        if !document.trim_start().starts_with('{') {
+            assert!(operation.depth as usize <= document.matches('{').count());
+            assert!(operation.fields as usize <= document.len());
+        }
+    }
+});
@@ -0,0 +1,46 @@
+//! Compiling arbitrary path rules and matching arbitrary request paths
+//! against them. Bad patterns must come back as `RuleError`, and a lookup
+//! must only ever return one of the rules it was given.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use ngx_http_rate_limiter::rules::{PathMatch, Rule, RuleSet};
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    rules: Vec<(bool, String, u32)>,
+    paths: Vec<String>,
+}
+
+fuzz_target!(|input: Input| {
+    let rules: Vec<Rule> = input
+        .rules
+        .into_iter()
+        .take(64)
+        .map(|(regex, pattern, requests)| Rule {
+            path: if regex { PathMatch::Regex(pattern) } else { PathMatch::Prefix(pattern) },
+            requests,
+            window: 60,
+            rejection: None,
+            body: None,
+            counter: None,
+            scope: None,
+            priority: None,
+            id: None,
+        })
+        .collect();
+    let Ok(set) = RuleSet::compile(1, rules.clone()) else {
+        return;
+    };
+
+    for path in &input.paths {
+        if let Some(rule) = set.find(path) {
+            assert!(rules.contains(rule));
+            if let PathMatch::Prefix(prefix) = &rule.path {
+                assert!(path.starts_with(prefix.as_str()));
+            }
+        }
+    }
+});
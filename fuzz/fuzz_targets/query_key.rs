@@ -0,0 +1,38 @@
+//! Query parameter keys are decoded from the request line. Any query must
+//! decode without panicking, and an accepted value must respect the limits
+//! it was checked against.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use ngx_http_rate_limiter::query_key::QueryKey;
+use ngx_http_rate_limiter::tenant::RequestInfo;
+
+#[derive(Debug, Arbitrary)]
+struct Input<'a> {
+    param: &'a str,
+    path: &'a str,
+    fold_case: bool,
+    max_len: u8,
+}
+
+fuzz_target!(|input: Input| {
+    let mut query_key = QueryKey::new(input.param).with_max_len(usize::from(input.max_len));
+    if input.fold_case {
+        query_key = query_key.with_case_folding();
+    }
+
+    let request = RequestInfo { path: input.path, ..RequestInfo::default() };
+    let Some(key) = query_key.key(&request) else {
+        return;
+    };
+    let value = key
+        .as_str()
+        .strip_prefix(input.param)
+        .and_then(|rest| rest.strip_prefix(':'))
+        .expect("keys start with the parameter name");
+    assert!(!value.is_empty());
+    assert!(value.len() <= usize::from(input.max_len));
+    assert!(request.query_param(input.param).is_some());
+});
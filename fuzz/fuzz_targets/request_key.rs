@@ -0,0 +1,22 @@
+//! Request keys are built on the stack from client-supplied text; whatever
+//! goes in must come out unchanged, inline or spilled.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ngx_http_rate_limiter::request_key;
+use ngx_http_rate_limiter::storage::{KeyBuf, INLINE_KEY_LEN};
+
+fuzz_target!(|parts: Vec<&str>| {
+    let mut key = KeyBuf::new();
+    let mut expected = String::new();
+    for part in &parts {
+        key.push_str(part);
+        expected.push_str(part);
+    }
+    assert_eq!(key.as_str(), expected);
+    assert_eq!(key.spilled(), expected.len() > INLINE_KEY_LEN);
+
+    let joined = parts.concat();
+    assert_eq!(request_key(&joined).as_str(), joined);
+});
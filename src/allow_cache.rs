@@ -0,0 +1,154 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+
+/// Thresholds of the `AllowCache`.
+#[derive(Debug, Clone)]
+pub struct AllowCacheOptions {
+    /// Share of the limit a key must have left for its allowance to be
+    /// cached
+    pub headroom: f64,
+    /// Share of what is left that one worker may allow locally
+    pub share: f64,
+    /// How long an allowance is served locally, capped at the window
+    pub ttl: Duration,
+    /// Keys remembered at once; further allowances are not cached
+    pub max_entries: usize,
+}
+
+impl Default for AllowCacheOptions {
+    fn default() -> Self {
+        Self {
+            headroom: 0.5,
+            share: 0.1,
+            ttl: Duration::from_secs(1),
+            max_entries: 100_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Allowance {
+    /// The limit it was granted under, so a reload invalidates it
+    limit: u32,
+    /// The key's count when it was granted
+    count: u64,
+    /// Requests still allowed locally
+    left: u32,
+    /// Requests allowed locally and not yet counted in the backend
+    used: u32,
+    /// Clock time it runs out
+    expires: Duration,
+}
+
+/// The counterpart of the `DenyCache`: remembers keys nowhere near their
+/// limit, so their requests are allowed without a backend round trip.
+///
+/// A key allowed with at least `headroom` of its limit left is granted
+/// `share` of what is left as a local allowance for `ttl`. Requests taken
+/// from it are counted in the backend in one batch when it runs out, so an
+/// allowance abandoned before that goes uncounted. Each worker holds its
+/// own allowance, so with N workers a key can overshoot by up to `N ×
+/// share` of what it had left; keep `share` below `1 / N`.
+#[derive(Debug)]
+pub struct AllowCache {
+    allowances: DashMap<String, Allowance>,
+    options: AllowCacheOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl AllowCache {
+    pub fn new(options: AllowCacheOptions) -> Self {
+        Self {
+            allowances: DashMap::new(),
+            options,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Allow a request for `key` under `limit` from its allowance, and
+    /// return the key's count as far as this worker knows.
+    pub fn take(&self, key: &str, limit: u32) -> Option<u64> {
+        let now = self.clock.now();
+        let mut allowance = self.allowances.get_mut(key)?;
+        if allowance.limit != limit || allowance.left == 0 || allowance.expires <= now {
+            return None;
+        }
+        allowance.left -= 1;
+        allowance.used += 1;
+        metrics::counter!("rate_limiter_allow_cache_hits_total").increment(1);
+        Some(allowance.count + u64::from(allowance.used))
+    }
+
+    /// Drop the allowance of `key`, returning the requests taken from it
+    /// that the backend has yet to count.
+    pub fn settle(&self, key: &str) -> u32 {
+        self.allowances.remove(key).map_or(0, |(_, allowance)| allowance.used)
+    }
+
+    /// Grant `key` an allowance if, at `count` in a window of `window`
+    /// seconds, it is far enough from `limit`.
+    pub fn grant(&self, key: &str, count: u64, limit: u32, window: u32) {
+        let remaining = u64::from(limit).saturating_sub(count);
+        if (remaining as f64) < f64::from(limit) * self.options.headroom {
+            return;
+        }
+        let left = (remaining as f64 * self.options.share) as u32;
+        if left == 0 {
+            return;
+        }
+        let now = self.clock.now();
+        if self.allowances.len() >= self.options.max_entries {
+            self.allowances.retain(|_, allowance| allowance.expires > now);
+            if self.allowances.len() >= self.options.max_entries {
+                return;
+            }
+        }
+        let ttl = self.options.ttl.min(Duration::from_secs(window.into()));
+        let allowance = Allowance { limit, count, left, used: 0, expires: now + ttl };
+        self.allowances.insert(key.to_string(), allowance);
+    }
+
+    pub fn len(&self) -> usize {
+        self.allowances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allowances.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_allowances_are_granted_far_from_the_limit() {
+        let clock = Arc::new(ManualClock::default());
+        let cache = AllowCache::new(AllowCacheOptions::default()).with_clock(clock.clone());
+
+        cache.grant("busy", 60, 100, 60);
+        assert_eq!(cache.take("busy", 100), None);
+
+        cache.grant("quiet", 10, 100, 60);
+        assert_eq!(cache.take("quiet", 100), Some(11));
+        assert_eq!(cache.take("quiet", 50), None);
+        for _ in 0..8 {
+            assert!(cache.take("quiet", 100).is_some());
+        }
+        assert_eq!(cache.take("quiet", 100), None);
+        assert_eq!(cache.settle("quiet"), 9);
+        assert_eq!(cache.settle("quiet"), 0);
+
+        cache.grant("quiet", 20, 100, 60);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.take("quiet", 100), None);
+    }
+}
@@ -0,0 +1,211 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+
+/// Most empty buckets folded into a baseline after a quiet spell; more
+/// would only push the mean further towards zero.
+const MAX_IDLE_BUCKETS: u64 = 16;
+
+/// Sensitivity of `AnomalyDetector`.
+#[derive(Debug, Clone)]
+pub struct AnomalyOptions {
+    /// Length of the buckets request rates are measured in
+    pub bucket: Duration,
+    /// Weight of the newest bucket in the moving averages
+    pub alpha: f64,
+    /// Standard deviations above its mean at which a key is flagged
+    pub threshold: f64,
+    /// Buckets a key must have been seen over before it can be flagged
+    pub warm_up: u32,
+    /// Fraction of the limit flagged keys get, e.g. 0.5; limits are left
+    /// alone when unset
+    pub tighten: Option<f64>,
+    /// How long a key stays flagged
+    pub flag_for: Duration,
+    /// Keys tracked per worker; keys idle the longest are dropped first
+    pub max_keys: usize,
+}
+
+impl Default for AnomalyOptions {
+    fn default() -> Self {
+        Self {
+            bucket: Duration::from_secs(10),
+            alpha: 0.1,
+            threshold: 4.0,
+            warm_up: 30,
+            tighten: None,
+            flag_for: Duration::from_secs(300),
+            max_keys: 100_000,
+        }
+    }
+}
+
+/// One key's request rate and how it usually behaves.
+#[derive(Debug)]
+struct Baseline {
+    bucket: u64,
+    count: u64,
+    /// Exponentially weighted mean and variance of completed buckets
+    mean: f64,
+    variance: f64,
+    buckets: u32,
+    flagged_until: Option<Duration>,
+}
+
+impl Baseline {
+    fn new(bucket: u64) -> Self {
+        Self {
+            bucket,
+            count: 0,
+            mean: 0.0,
+            variance: 0.0,
+            buckets: 0,
+            flagged_until: None,
+        }
+    }
+
+    fn fold(&mut self, count: u64, alpha: f64) {
+        let diff = count as f64 - self.mean;
+        let increment = alpha * diff;
+        self.mean += increment;
+        self.variance = (1.0 - alpha) * (self.variance + diff * increment);
+        self.buckets = self.buckets.saturating_add(1);
+    }
+
+    /// Move to `bucket`, folding the finished one and any empty ones since.
+    fn advance(&mut self, bucket: u64, alpha: f64) {
+        if bucket <= self.bucket {
+            return;
+        }
+        self.fold(self.count, alpha);
+        for _ in 1..(bucket - self.bucket).min(MAX_IDLE_BUCKETS) {
+            self.fold(0, alpha);
+        }
+        self.bucket = bucket;
+        self.count = 0;
+    }
+
+    fn z_score(&self) -> f64 {
+        // A floor of one request keeps perfectly steady keys from being
+        // flagged for a single extra request
+        (self.count as f64 - self.mean) / self.variance.sqrt().max(1.0)
+    }
+}
+
+/// Flags keys whose request rate jumps far above their own baseline, even
+/// while they are under the hard limit.
+///
+/// Each key's rate is measured per `bucket`. The mean and variance of past
+/// buckets are exponentially weighted moving averages, and the current
+/// bucket's z-score against them decides. State is per worker and costs
+/// about a hundred bytes per key.
+pub struct AnomalyDetector {
+    keys: DashMap<String, Baseline>,
+    options: AnomalyOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for AnomalyDetector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AnomalyDetector").field("keys", &self.keys.len()).finish()
+    }
+}
+
+impl AnomalyDetector {
+    pub fn new(options: AnomalyOptions) -> Self {
+        Self {
+            keys: DashMap::new(),
+            options,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn bucket(&self, now: Duration) -> u64 {
+        (now.as_millis() / self.options.bucket.as_millis().max(1)) as u64
+    }
+
+    /// Count one request for `key`. Returns the z-score when this request
+    /// gets the key flagged.
+    pub fn observe(&self, key: &str) -> Option<f64> {
+        let now = self.clock.now();
+        let bucket = self.bucket(now);
+        if !self.keys.contains_key(key) && self.keys.len() >= self.options.max_keys {
+            self.evict(bucket);
+        }
+        let mut baseline = self.keys.entry(key.to_string()).or_insert_with(|| Baseline::new(bucket));
+        baseline.advance(bucket, self.options.alpha);
+        baseline.count += 1;
+
+        if baseline.flagged_until.is_some_and(|until| until > now) || baseline.buckets < self.options.warm_up {
+            return None;
+        }
+        let z = baseline.z_score();
+        if z < self.options.threshold {
+            return None;
+        }
+        baseline.flagged_until = Some(now + self.options.flag_for);
+        Some(z)
+    }
+
+    /// Whether `key` is flagged now.
+    pub fn is_flagged(&self, key: &str) -> bool {
+        let now = self.clock.now();
+        self.keys
+            .get(key)
+            .is_some_and(|baseline| baseline.flagged_until.is_some_and(|until| until > now))
+    }
+
+    /// The limit for `key`: tightened while it is flagged, if configured.
+    pub fn limit_for(&self, key: &str, limit: u32) -> u32 {
+        match self.options.tighten {
+            Some(fraction) if self.is_flagged(key) => {
+                ((f64::from(limit) * fraction).ceil() as u32).clamp(1, limit.max(1))
+            }
+            _ => limit,
+        }
+    }
+
+    /// Drop the keys idle the longest to make room.
+    fn evict(&self, bucket: u64) {
+        let idle = MAX_IDLE_BUCKETS.min(bucket);
+        self.keys.retain(|_, baseline| baseline.bucket + idle >= bucket);
+        if self.keys.len() >= self.options.max_keys {
+            self.keys.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_flags_keys_far_above_their_baseline() {
+        let clock = Arc::new(ManualClock::default());
+        let options = AnomalyOptions { warm_up: 10, tighten: Some(0.5), ..AnomalyOptions::default() };
+        let detector = AnomalyDetector::new(options).with_clock(clock.clone());
+
+        // A steady 20 to 22 requests per bucket
+        for i in 0..40 {
+            for _ in 0..20 + i % 3 {
+                assert_eq!(detector.observe("client"), None);
+            }
+            clock.advance(Duration::from_secs(10));
+        }
+        let flagged: Vec<f64> = (0..60).filter_map(|_| detector.observe("client")).collect();
+        assert_eq!(flagged.len(), 1);
+        assert!(flagged[0] >= 4.0);
+        assert_eq!(detector.limit_for("client", 100), 50);
+        assert_eq!(detector.limit_for("other", 100), 100);
+
+        clock.advance(Duration::from_secs(301));
+        assert!(!detector.is_flagged("client"));
+    }
+}
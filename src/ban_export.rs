@@ -0,0 +1,249 @@
+use dashmap::DashMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+
+/// Line format for file and socket sinks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanFormat {
+    /// `<unix time> rate_limiter ban <ip> <seconds>`, for a fail2ban
+    /// `failregex` of `^\d+ rate_limiter ban <HOST>`
+    Fail2ban,
+    /// `add <set> <ip> timeout <seconds> -exist`, for `ipset restore`
+    Ipset(String),
+}
+
+/// Where bans go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanSink {
+    /// Appended to a file
+    File { path: PathBuf, format: BanFormat },
+    /// Sent as one datagram per ban to a UNIX datagram socket
+    UnixSocket { path: PathBuf, format: BanFormat },
+    /// Added to an ipset with `ipset add`
+    Ipset { set: String },
+    /// Added to an nftables set with `nft add element`
+    Nft { family: String, table: String, set: String },
+}
+
+/// When a client counts as a persistent abuser, and where its ban goes.
+#[derive(Debug, Clone)]
+pub struct BanExportOptions {
+    /// Denials within `period` that get a client banned
+    pub threshold: u32,
+    pub period: Duration,
+    /// Ban duration passed to the sink
+    pub ban_for: Duration,
+    /// Clients tracked at once; further clients are not tracked
+    pub max_entries: usize,
+    pub sink: BanSink,
+}
+
+impl BanExportOptions {
+    pub fn new(sink: BanSink) -> Self {
+        Self {
+            threshold: 100,
+            period: Duration::from_secs(60),
+            ban_for: Duration::from_secs(3600),
+            max_entries: 100_000,
+            sink,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Strikes {
+    denials: u32,
+    /// Clock time the current period started
+    since: Duration,
+    /// Clock time the last exported ban ends, if any
+    banned_until: Duration,
+}
+
+/// Hands clients that keep getting denied to the firewall, so they are
+/// dropped at the packet level instead of occupying nginx workers.
+///
+/// Only keys that are a bare IP address, or a stream key such as
+/// `stream:tcp:192.0.2.7`, can be banned; other keys are ignored. A client
+/// is exported once per ban, not on every denial.
+#[derive(Debug)]
+pub struct BanExporter {
+    strikes: DashMap<IpAddr, Strikes>,
+    options: BanExportOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl BanExporter {
+    pub fn new(options: BanExportOptions) -> Self {
+        Self {
+            strikes: DashMap::new(),
+            options,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Count a denial for `key`. Returns whether it got the client banned.
+    pub fn record_denial(&self, key: &str) -> bool {
+        let Some(ip) = key_ip(key) else {
+            return false;
+        };
+        let now = self.clock.now();
+        if !self.strikes.contains_key(&ip) && self.strikes.len() >= self.options.max_entries {
+            let period = self.options.period;
+            self.strikes
+                .retain(|_, strikes| now < strikes.since + period || now < strikes.banned_until);
+            if self.strikes.len() >= self.options.max_entries {
+                return false;
+            }
+        }
+
+        let banned = {
+            let mut strikes = self.strikes.entry(ip).or_insert(Strikes {
+                denials: 0,
+                since: now,
+                banned_until: Duration::ZERO,
+            });
+            if now >= strikes.since + self.options.period {
+                strikes.denials = 0;
+                strikes.since = now;
+            }
+            strikes.denials += 1;
+            if strikes.denials >= self.options.threshold && now >= strikes.banned_until {
+                strikes.banned_until = now + self.options.ban_for;
+                true
+            } else {
+                false
+            }
+        };
+
+        if banned {
+            self.export(ip, self.options.ban_for);
+        }
+        banned
+    }
+
+    /// Ban the client of `key` for `ban_for` at once, whatever its
+    /// denials. Returns whether it was banned, i.e. it is an address not
+    /// already banned for at least that long.
+    pub fn ban(&self, key: &str, ban_for: Duration) -> bool {
+        let Some(ip) = key_ip(key) else {
+            return false;
+        };
+        let now = self.clock.now();
+        {
+            let mut strikes = self.strikes.entry(ip).or_insert(Strikes {
+                denials: 0,
+                since: now,
+                banned_until: Duration::ZERO,
+            });
+            if strikes.banned_until >= now + ban_for {
+                return false;
+            }
+            strikes.banned_until = now + ban_for;
+        }
+        self.export(ip, ban_for);
+        true
+    }
+
+    fn export(&self, ip: IpAddr, ban_for: Duration) {
+        metrics::counter!("rate_limiter_bans_exported_total").increment(1);
+        if let Err(e) = self.write_ban(ip, ban_for.as_secs()) {
+            log::warn!("Exporting rate limit ban of {} failed: {}", ip, e);
+        }
+    }
+
+    fn write_ban(&self, ip: IpAddr, seconds: u64) -> std::io::Result<()> {
+        match &self.options.sink {
+            BanSink::File { path, format } => {
+                let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+                file.write_all(self.line(format, ip, seconds).as_bytes())
+            }
+            BanSink::UnixSocket { path, format } => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.send_to(self.line(format, ip, seconds).as_bytes(), path).map(|_| ())
+            }
+            BanSink::Ipset { set } => run(
+                "ipset",
+                &["add", set, &ip.to_string(), "timeout", &seconds.to_string(), "-exist"],
+            ),
+            BanSink::Nft { family, table, set } => {
+                let element = format!("{{ {} timeout {}s }}", ip, seconds);
+                run("nft", &["add", "element", family, table, set, &element])
+            }
+        }
+    }
+
+    fn line(&self, format: &BanFormat, ip: IpAddr, seconds: u64) -> String {
+        match format {
+            BanFormat::Fail2ban => format!("{} rate_limiter ban {} {}\n", self.clock.unix_secs(), ip, seconds),
+            BanFormat::Ipset(set) => format!("add {} {} timeout {} -exist\n", set, ip, seconds),
+        }
+    }
+}
+
+/// Start `program` without waiting for it; the event loop must not block
+/// on the firewall. The child is reaped on a thread of its own.
+fn run(program: &str, args: &[&str]) -> std::io::Result<()> {
+    let mut child = std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .spawn()?;
+    let program = program.to_string();
+    std::thread::spawn(move || match child.wait() {
+        Ok(status) if !status.success() => log::warn!("{} exited with {}", program, status),
+        Ok(_) => {}
+        Err(e) => log::warn!("Waiting for {} failed: {}", program, e),
+    });
+    Ok(())
+}
+
+/// The client address in a request or stream key.
+fn key_ip(key: &str) -> Option<IpAddr> {
+    if let Ok(ip) = key.parse() {
+        return Some(ip);
+    }
+    let (_, ip) = key.strip_prefix("stream:")?.split_once(':')?;
+    ip.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_bans_after_threshold_once_per_ban() {
+        let path = std::env::temp_dir().join(format!("rate-limiter-bans-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = Arc::new(ManualClock::default());
+        let exporter = BanExporter::new(BanExportOptions {
+            threshold: 3,
+            ban_for: Duration::from_secs(600),
+            ..BanExportOptions::new(BanSink::File { path: path.clone(), format: BanFormat::Ipset("abusers".into()) })
+        })
+        .with_clock(clock.clone());
+
+        let banned: Vec<bool> = (0..5).map(|_| exporter.record_denial("192.0.2.7")).collect();
+        assert_eq!(banned, vec![false, false, true, false, false]);
+        assert!(!exporter.record_denial("not an address"));
+        assert!(!exporter.record_denial("stream:tcp:2001:db8::1"));
+
+        // Denials spread over several periods never add up to a ban
+        clock.advance(Duration::from_secs(61));
+        assert!(!exporter.record_denial("stream:tcp:2001:db8::1"));
+        assert!(!exporter.record_denial("stream:tcp:2001:db8::1"));
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(lines, "add abusers 192.0.2.7 timeout 600 -exist\n");
+        let _ = std::fs::remove_file(&path);
+    }
+}
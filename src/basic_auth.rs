@@ -0,0 +1,87 @@
+use std::fmt::Write;
+use crate::storage::KeyBuf;
+use crate::tenant::RequestInfo;
+
+/// `user:{username}` for a request with an `Authorization: Basic` header.
+///
+/// Only the username is kept; the decoded password is wiped before
+/// returning and never logged. The password is not checked either, so
+/// requests with a wrong one still count against the user, as an attacker
+/// guessing passwords should.
+pub fn username_key(request: &RequestInfo<'_>) -> Option<KeyBuf> {
+    let (scheme, credentials) = request.header("authorization")?.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let mut decoded = decode_base64(credentials.trim())?;
+    let username = decoded
+        .iter()
+        .position(|b| *b == b':')
+        .and_then(|colon| std::str::from_utf8(&decoded[..colon]).ok())
+        .filter(|username| !username.is_empty())
+        .map(|username| {
+            let mut key = KeyBuf::new();
+            let _ = write!(key, "user:{}", username);
+            key
+        });
+    decoded.fill(0);
+    username
+}
+
+/// Standard base64 with padding, as RFC 7617 requires.
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        Some(u32::from(value))
+    }
+
+    let bytes = encoded.as_bytes();
+    if bytes.is_empty() || !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(bytes.len() / 4 * 3);
+    let last = bytes.len() / 4 - 1;
+    for (index, chunk) in bytes.chunks(4).enumerate() {
+        let padding = if index == last { chunk.iter().rev().take_while(|c| **c == b'=').count() } else { 0 };
+        if padding > 2 {
+            return None;
+        }
+        let mut group = 0;
+        for c in &chunk[..4 - padding] {
+            group = (group << 6) | value(*c)?;
+        }
+        group <<= 6 * padding as u32;
+        decoded.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usernames_are_decoded_from_basic_credentials() {
+        let key = |value: &'static str| {
+            let headers = [("Authorization", value)];
+            let request = RequestInfo { headers: &headers, ..RequestInfo::default() };
+            username_key(&request).map(|key| key.as_str().to_string())
+        };
+        // alice:open sesame
+        assert_eq!(key("Basic YWxpY2U6b3BlbiBzZXNhbWU=").as_deref(), Some("user:alice"));
+        // bob:pw
+        assert_eq!(key("basic  Ym9iOnB3").as_deref(), Some("user:bob"));
+        // :pw, no colon, not base64, another scheme
+        assert_eq!(key("Basic OnB3"), None);
+        assert_eq!(key("Basic Ym9i"), None);
+        assert_eq!(key("Basic Ym9iOnB3!"), None);
+        assert_eq!(key("Bearer Ym9iOnB3"), None);
+    }
+}
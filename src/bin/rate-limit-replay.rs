@@ -0,0 +1,143 @@
+//! Replay an nginx access log through the limiter and report throughput,
+//! decision latency and how many requests would have been rejected.
+//!
+//! ```text
+//! rate-limit-replay <access.log> [--backend memory] [--config ""]
+//!     [--requests 100] [--window 60] [--concurrency 64]
+//! ```
+//!
+//! The client address is the first field of each line, as in the default
+//! `combined` format. Lines are replayed as fast as the backend allows.
+
+use ngx_http_rate_limiter::RateLimiter;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+struct Args {
+    log: String,
+    backend: String,
+    config: String,
+    requests: u32,
+    window: u32,
+    concurrency: usize,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        log: String::new(),
+        backend: "memory".to_string(),
+        config: String::new(),
+        requests: 100,
+        window: 60,
+        concurrency: 64,
+    };
+
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--backend" => args.backend = value()?,
+            "--config" => args.config = value()?,
+            "--requests" => args.requests = value()?.parse().map_err(|e| format!("--requests: {}", e))?,
+            "--window" => args.window = value()?.parse().map_err(|e| format!("--window: {}", e))?,
+            "--concurrency" => args.concurrency = value()?.parse().map_err(|e| format!("--concurrency: {}", e))?,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => args.log = arg,
+        }
+    }
+
+    if args.log.is_empty() {
+        return Err("usage: rate-limit-replay <access.log> [--backend memory] [--config \"\"] \
+                    [--requests 100] [--window 60] [--concurrency 64]"
+            .to_string());
+    }
+    Ok(args)
+}
+
+/// The latency below which `per_mille` thousandths of `sorted` fall.
+fn percentile(sorted: &[Duration], per_mille: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(sorted.len() - 1) * per_mille / 1000]
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let log = match std::fs::read_to_string(&args.log) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("cannot read {}: {}", args.log, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let clients: Arc<Vec<String>> = Arc::new(
+        log.lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(str::to_string)
+            .collect(),
+    );
+
+    let limiter = match RateLimiter::with_backend(&args.backend, &args.config, args.requests, args.window).await {
+        Ok(limiter) => Arc::new(limiter),
+        Err(e) => {
+            eprintln!("cannot open {} backend: {}", args.backend, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let next = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let workers: Vec<_> = (0..args.concurrency.max(1))
+        .map(|_| {
+            let (clients, limiter, next) = (Arc::clone(&clients), Arc::clone(&limiter), Arc::clone(&next));
+            tokio::spawn(async move {
+                let mut latencies = Vec::new();
+                let mut limited = 0usize;
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(client) = clients.get(i) else { break };
+                    let decided = Instant::now();
+                    if limiter.is_rate_limited(client).await {
+                        limited += 1;
+                    }
+                    latencies.push(decided.elapsed());
+                }
+                (latencies, limited)
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(clients.len());
+    let mut limited = 0;
+    for worker in workers {
+        let (worker_latencies, worker_limited) = worker.await.expect("replay worker panicked");
+        latencies.extend(worker_latencies);
+        limited += worker_limited;
+    }
+    let elapsed = started.elapsed();
+    latencies.sort_unstable();
+
+    println!("requests:   {}", latencies.len());
+    println!("limited:    {}", limited);
+    println!("elapsed:    {:?}", elapsed);
+    println!("throughput: {:.0} decisions/s", latencies.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON));
+    println!(
+        "latency:    p50 {:?}  p99 {:?}  p99.9 {:?}  max {:?}",
+        percentile(&latencies, 500),
+        percentile(&latencies, 990),
+        percentile(&latencies, 999),
+        percentile(&latencies, 1000),
+    );
+    ExitCode::SUCCESS
+}
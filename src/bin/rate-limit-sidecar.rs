@@ -0,0 +1,92 @@
+//! Serve limit decisions over HTTP and gRPC, for services that are not
+//! behind nginx and for nginx workers that delegate to a local sidecar.
+//!
+//! ```text
+//! rate-limit-sidecar [--listen 127.0.0.1:8089] [--backend memory]
+//!     [--config ""] [--requests 100] [--window 60]
+//! ```
+//!
+//! `--requests` and `--window` are the limits for gRPC callers; HTTP
+//! callers send their own.
+
+use ngx_http_rate_limiter::config::Limits;
+use ngx_http_rate_limiter::sidecar::Sidecar;
+use ngx_http_rate_limiter::storage::create_backend;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+struct Args {
+    listen: String,
+    backend: String,
+    config: String,
+    requests: u32,
+    window: u32,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        listen: "127.0.0.1:8089".to_string(),
+        backend: "memory".to_string(),
+        config: String::new(),
+        requests: 100,
+        window: 60,
+    };
+
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--listen" => args.listen = value()?,
+            "--backend" => args.backend = value()?,
+            "--config" => args.config = value()?,
+            "--requests" => args.requests = value()?.parse().map_err(|e| format!("--requests: {}", e))?,
+            "--window" => args.window = value()?.parse().map_err(|e| format!("--window: {}", e))?,
+            _ => {
+                return Err(format!(
+                    "unknown option {}\nusage: rate-limit-sidecar [--listen 127.0.0.1:8089] [--backend memory] \
+                     [--config \"\"] [--requests 100] [--window 60]",
+                    arg
+                ))
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let storage = match create_backend(&args.backend, &args.config).await {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("cannot open {} backend: {}", args.backend, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let listener = match TcpListener::bind(&args.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("cannot listen on {}: {}", args.listen, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    log::info!("Serving rate limit decisions on {}", args.listen);
+    let sidecar = Arc::new(Sidecar::new(Arc::from(storage), Limits::new(args.requests, args.window)));
+    match sidecar.serve(listener).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("sidecar stopped: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! Replay an nginx access log, or a synthetic traffic profile, through the
+//! limiter on simulated time and report which keys and endpoints would have
+//! been rejected. Meant for choosing limits before turning enforcement on.
+//!
+//! ```text
+//! ratelimit-sim <access.log> [--requests 100] [--window 60] [--key ip]
+//!     [--top 10]
+//! ratelimit-sim --profile steady|zipf|burst [--clients 1000] [--rate 500]
+//!     [--duration 600] [--seed 1] [--requests 100] [--window 60] ...
+//! ```
+//!
+//! Log lines are read in the `combined` format: the client address, the
+//! `[10/Oct/2000:13:55:36 -0700]` timestamp and the request line. Time is
+//! taken from the log, not the wall clock, so a day of traffic replays in
+//! seconds with the windows it actually had. `--key ip+path` counts each
+//! client and endpoint separately instead of per client.
+
+use ngx_http_rate_limiter::clock::ManualClock;
+use ngx_http_rate_limiter::storage::MemoryStorage;
+use ngx_http_rate_limiter::RateLimiter;
+use std::collections::HashMap;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone, Copy, PartialEq)]
+enum KeyBy {
+    Ip,
+    IpPath,
+}
+
+#[derive(Clone, Copy)]
+enum Profile {
+    /// Every client sends at the same rate
+    Steady,
+    /// Client popularity follows a Zipf distribution, as with real users
+    Zipf,
+    /// Steady, plus one client in twenty sending twenty times as much
+    Burst,
+}
+
+struct Args {
+    log: Option<String>,
+    profile: Option<Profile>,
+    clients: usize,
+    rate: f64,
+    duration: u64,
+    seed: u64,
+    requests: u32,
+    window: u32,
+    key: KeyBy,
+    top: usize,
+}
+
+const USAGE: &str = "usage: ratelimit-sim (<access.log> | --profile steady|zipf|burst [--clients 1000] \
+                     [--rate 500] [--duration 600] [--seed 1]) [--requests 100] [--window 60] \
+                     [--key ip|ip+path] [--top 10]";
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        log: None,
+        profile: None,
+        clients: 1000,
+        rate: 500.0,
+        duration: 600,
+        seed: 1,
+        requests: 100,
+        window: 60,
+        key: KeyBy::Ip,
+        top: 10,
+    };
+
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--profile" => {
+                args.profile = Some(match value()?.as_str() {
+                    "steady" => Profile::Steady,
+                    "zipf" => Profile::Zipf,
+                    "burst" => Profile::Burst,
+                    other => return Err(format!("--profile: unknown profile {}", other)),
+                })
+            }
+            "--clients" => args.clients = value()?.parse().map_err(|e| format!("--clients: {}", e))?,
+            "--rate" => args.rate = value()?.parse().map_err(|e| format!("--rate: {}", e))?,
+            "--duration" => args.duration = value()?.parse().map_err(|e| format!("--duration: {}", e))?,
+            "--seed" => args.seed = value()?.parse().map_err(|e| format!("--seed: {}", e))?,
+            "--requests" => args.requests = value()?.parse().map_err(|e| format!("--requests: {}", e))?,
+            "--window" => args.window = value()?.parse().map_err(|e| format!("--window: {}", e))?,
+            "--top" => args.top = value()?.parse().map_err(|e| format!("--top: {}", e))?,
+            "--key" => {
+                args.key = match value()?.as_str() {
+                    "ip" => KeyBy::Ip,
+                    "ip+path" => KeyBy::IpPath,
+                    other => return Err(format!("--key: expected ip or ip+path, got {}", other)),
+                }
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => args.log = Some(arg),
+        }
+    }
+
+    if args.log.is_some() == args.profile.is_some() {
+        return Err(USAGE.to_string());
+    }
+    Ok(args)
+}
+
+/// One request to replay.
+struct Request {
+    /// Seconds since the Unix epoch
+    at: f64,
+    client: String,
+    path: String,
+}
+
+/// Parse a `combined` log line; lines that do not fit are skipped.
+fn parse_line(line: &str) -> Option<Request> {
+    let client = line.split_whitespace().next()?;
+    let open = line.find('[')? + 1;
+    let stamp = &line[open..open + line[open..].find(']')?];
+    let request = line.split('"').nth(1)?;
+    let target = request.split_whitespace().nth(1)?;
+    let path = target.split('?').next().unwrap_or(target);
+
+    Some(Request {
+        at: parse_timestamp(stamp)? as f64,
+        client: client.to_string(),
+        path: path.to_string(),
+    })
+}
+
+/// `10/Oct/2000:13:55:36 -0700` as seconds since the Unix epoch.
+fn parse_timestamp(stamp: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (local, zone) = stamp.split_once(' ')?;
+    let mut parts = local.splitn(3, '/');
+    let day: i64 = parts.next()?.parse().ok()?;
+    let name = parts.next()?;
+    let month = MONTHS.iter().position(|month| *month == name)? as i64 + 1;
+    let mut rest = parts.next()?.split(':');
+    let year: i64 = rest.next()?.parse().ok()?;
+    let (hour, minute, second): (i64, i64, i64) =
+        (rest.next()?.parse().ok()?, rest.next()?.parse().ok()?, rest.next()?.parse().ok()?);
+
+    let offset: i64 = zone.get(1..)?.parse().ok()?;
+    let offset = (offset / 100 * 3600 + offset % 100 * 60) * if zone.starts_with('-') { -1 } else { 1 };
+
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+/// Days from 1970-01-01 to the given proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// xorshift64*, so profiles are reproducible from `--seed`.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in [0, 1).
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn synthesize(profile: Profile, args: &Args) -> Vec<Request> {
+    const PATHS: [&str; 5] = ["/", "/api/search", "/api/items", "/api/login", "/static/app.js"];
+    let clients = args.clients.max(1);
+    let mut rng = Rng(args.seed.max(1));
+
+    // Cumulative client weights, sampled by binary search
+    let weights: Vec<f64> = (0..clients)
+        .map(|i| match profile {
+            Profile::Steady => 1.0,
+            Profile::Zipf => 1.0 / (i + 1) as f64,
+            Profile::Burst if i % 20 == 0 => 20.0,
+            Profile::Burst => 1.0,
+        })
+        .scan(0.0, |total, weight| {
+            *total += weight;
+            Some(*total)
+        })
+        .collect();
+    let total = weights[clients - 1];
+
+    let count = (args.rate * args.duration as f64) as usize;
+    let start = 1_700_000_000.0;
+    (0..count)
+        .map(|i| {
+            let target = rng.unit() * total;
+            let client = weights.partition_point(|&weight| weight <= target).min(clients - 1);
+            Request {
+                at: start + i as f64 / args.rate.max(f64::EPSILON),
+                client: format!("10.{}.{}.{}", (client >> 16) & 0xff, (client >> 8) & 0xff, client & 0xff),
+                path: PATHS[rng.next() as usize % PATHS.len()].to_string(),
+            }
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Tally {
+    requests: u64,
+    rejected: u64,
+}
+
+impl Tally {
+    fn add(&mut self, rejected: bool) {
+        self.requests += 1;
+        self.rejected += rejected as u64;
+    }
+}
+
+fn report(title: &str, tallies: HashMap<String, Tally>, top: usize) {
+    let affected = tallies.values().filter(|tally| tally.rejected > 0).count();
+    let mut worst: Vec<_> = tallies.into_iter().filter(|(_, tally)| tally.rejected > 0).collect();
+    worst.sort_unstable_by(|a, b| b.1.rejected.cmp(&a.1.rejected).then_with(|| a.0.cmp(&b.0)));
+
+    println!();
+    println!("{} with rejections: {}", title, affected);
+    for (name, tally) in worst.iter().take(top) {
+        println!(
+            "  {:<40} {:>8} of {:>8} rejected ({:.1}%)",
+            name,
+            tally.rejected,
+            tally.requests,
+            tally.rejected as f64 * 100.0 / tally.requests as f64
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let requests = match (&args.log, args.profile) {
+        (Some(log), _) => match std::fs::read_to_string(log) {
+            Ok(log) => log.lines().filter_map(parse_line).collect(),
+            Err(e) => {
+                eprintln!("cannot read {}: {}", log, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        (None, Some(profile)) => synthesize(profile, &args),
+        (None, None) => unreachable!("checked by parse_args"),
+    };
+    if requests.is_empty() {
+        eprintln!("no requests to replay");
+        return ExitCode::FAILURE;
+    }
+
+    let clock = Arc::new(ManualClock::new(Duration::ZERO));
+    let storage = MemoryStorage::new().with_clock(clock.clone());
+    let limiter = RateLimiter::with_storage(Arc::new(storage), args.requests, args.window);
+
+    let mut keys: HashMap<String, Tally> = HashMap::new();
+    let mut endpoints: HashMap<String, Tally> = HashMap::new();
+    let mut rejected = 0u64;
+    for request in &requests {
+        clock.set(Duration::from_secs_f64(request.at.max(0.0)));
+        let key = match args.key {
+            KeyBy::Ip => request.client.clone(),
+            KeyBy::IpPath => format!("{} {}", request.client, request.path),
+        };
+
+        let limited = limiter.is_rate_limited(&key).await;
+        rejected += limited as u64;
+        keys.entry(key).or_default().add(limited);
+        endpoints.entry(request.path.clone()).or_default().add(limited);
+    }
+
+    let span = requests.last().map_or(0.0, |last| last.at) - requests[0].at;
+    println!("limit:      {} requests per {}s", args.requests, args.window);
+    println!("requests:   {} over {:.0}s", requests.len(), span);
+    println!("rejected:   {} ({:.2}%)", rejected, rejected as f64 * 100.0 / requests.len() as f64);
+    println!("keys:       {}", keys.len());
+    report("Keys", keys, args.top);
+    report("Endpoints", endpoints, args.top);
+    ExitCode::SUCCESS
+}
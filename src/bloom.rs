@@ -0,0 +1,79 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A Bloom filter whose bits are set atomically, so inserts and lookups
+/// need no lock.
+///
+/// It answers "certainly not present" or "possibly present": a lookup of
+/// an item that was inserted is always true, and one of an item that was
+/// not is true with about the false positive rate it was sized for while
+/// it holds at most `capacity` items. Items cannot be removed; rebuild the
+/// filter instead.
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Box<[AtomicU64]>,
+    /// Bits set per item
+    hashes: u32,
+    capacity: usize,
+    hasher: RandomState,
+}
+
+impl BloomFilter {
+    /// A filter for `capacity` items with false positives at about
+    /// `false_positive_rate`.
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(1);
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(capacity as f64) * rate.ln() / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let hashes = ((bits as f64 / capacity as f64) * ln2).round().clamp(1.0, 16.0) as u32;
+        Self {
+            bits: (0..bits.div_ceil(64)).map(|_| AtomicU64::new(0)).collect(),
+            hashes,
+            capacity,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Bit positions of `item`, by double hashing one 64-bit hash.
+    fn positions<T: Hash + ?Sized>(&self, item: &T) -> impl Iterator<Item = usize> + '_ {
+        let hash = self.hasher.hash_one(item);
+        let (first, step) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let len = (self.bits.len() * 64) as u64;
+        (0..u64::from(self.hashes)).map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&self, item: &T) {
+        for bit in self.positions(item) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// False if `item` was certainly never inserted.
+    pub fn might_contain<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        self.positions(item)
+            .all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    /// Items the filter was sized for.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives_and_few_false_positives() {
+        let filter = BloomFilter::new(10_000, 0.01);
+        for i in 0..10_000u32 {
+            filter.insert(&i);
+        }
+        assert!((0..10_000u32).all(|i| filter.might_contain(&i)));
+        let false_positives = (10_000..110_000u32).filter(|i| filter.might_contain(i)).count();
+        assert!(false_positives < 2_000, "{} false positives in 100000", false_positives);
+    }
+}
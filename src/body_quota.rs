@@ -0,0 +1,73 @@
+use std::fmt::Write;
+use crate::storage::{KeyBuf, StorageBackend};
+use crate::tenant::RequestInfo;
+
+/// Request body bytes a key may send per window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyQuota {
+    pub bytes: u64,
+    pub window: u32,
+}
+
+impl BodyQuota {
+    pub fn new(bytes: u64, window: u32) -> Self {
+        Self { bytes, window }
+    }
+
+    /// Charge `bytes` to `key` and return whether it is now over quota.
+    ///
+    /// Bytes are added with `increment_by` under `bytes:{key}`, apart from
+    /// the key's request count. Bytes of uploads turned away still count,
+    /// so a client retrying a large upload stays over. A backend error
+    /// lets the body through, and so does every charge on backends that
+    /// cannot add amounts, such as the HTTP decision service.
+    pub async fn charge(&self, storage: &dyn StorageBackend, key: &str, bytes: u64) -> bool {
+        if bytes == 0 {
+            return false;
+        }
+        match storage.increment_by(&body_key(key), bytes, self.window).await {
+            Ok(charged) if charged.count > self.bytes => {
+                metrics::counter!("rate_limiter_body_quota_rejections_total").increment(1);
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                log::warn!("Charging body bytes for {} failed: {}", key, e);
+                false
+            }
+        }
+    }
+}
+
+/// `bytes:{key}`, the counter of a key's body bytes.
+pub fn body_key(key: &str) -> KeyBuf {
+    let mut buf = KeyBuf::new();
+    let _ = write!(buf, "bytes:{}", key);
+    buf
+}
+
+/// The body size a request declares in `Content-Length`.
+pub fn content_length(request: &RequestInfo<'_>) -> Option<u64> {
+    request.header("content-length")?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_bytes_are_charged_against_the_quota() {
+        let storage = MemoryStorage::new();
+        let quota = BodyQuota::new(1000, 60);
+
+        assert!(!quota.charge(&storage, "client", 600).await);
+        assert!(!quota.charge(&storage, "client", 400).await);
+        assert!(quota.charge(&storage, "client", 1).await);
+        assert!(!quota.charge(&storage, "other", 0).await);
+        assert_eq!(storage.get("bytes:client").await.unwrap(), 1001);
+
+        let headers = [("Content-Length", " 42")];
+        assert_eq!(content_length(&RequestInfo { headers: &headers, ..RequestInfo::default() }), Some(42));
+    }
+}
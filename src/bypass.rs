@@ -0,0 +1,224 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+use crate::storage::KeySecret;
+use crate::tenant::RequestInfo;
+
+/// Where tokens are looked for, and how long they may be used.
+#[derive(Debug, Clone)]
+pub struct BypassOptions {
+    pub header: Option<String>,
+    /// Query parameter, for clients that cannot set headers
+    pub query_param: Option<String>,
+    /// Longest a token may be valid from when it was issued, however far
+    /// off its expiry. Tokens are not single-use: a leaked one can be
+    /// replayed until it expires, so this bounds for how long
+    pub max_lifetime: Duration,
+    /// Clock difference tolerated between signer and limiter
+    pub max_skew: Duration,
+}
+
+impl Default for BypassOptions {
+    fn default() -> Self {
+        Self {
+            header: Some("X-RateLimit-Bypass".to_string()),
+            query_param: None,
+            max_lifetime: Duration::from_secs(3600),
+            max_skew: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Why a token was not accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BypassError {
+    Malformed,
+    UnknownKey,
+    BadSignature,
+    NotYetValid,
+    Expired,
+    /// Valid for longer than the maximum lifetime
+    TooLong,
+}
+
+impl BypassError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BypassError::Malformed => "malformed",
+            BypassError::UnknownKey => "unknown_key",
+            BypassError::BadSignature => "bad_signature",
+            BypassError::NotYetValid => "not_yet_valid",
+            BypassError::Expired => "expired",
+            BypassError::TooLong => "too_long",
+        }
+    }
+}
+
+/// Signed, expiring tokens that skip rate limiting, for load tests and
+/// health checkers on ephemeral infrastructure that no address allowlist
+/// can keep up with.
+///
+/// A token is `{key id}.{issued}.{expires}.{signature}`, times in Unix
+/// seconds, the signature the hex HMAC-SHA256 of everything before it
+/// under the secret named by the key id. To rotate, add the new secret,
+/// sign with it, and drop the old one once its tokens have expired.
+pub struct BypassTokens {
+    secrets: Vec<KeySecret>,
+    options: BypassOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for BypassTokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BypassTokens")
+            .field("secrets", &self.secrets)
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+fn mac(secret: &KeySecret, signed: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret.secret).expect("HMAC accepts keys of any length");
+    mac.update(signed.as_bytes());
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl BypassTokens {
+    pub fn new(secrets: Vec<KeySecret>, options: BypassOptions) -> Self {
+        Self {
+            secrets,
+            options,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// A token signed with the secret `key_id`, valid from now for
+    /// `valid_for`; `None` for an unknown key id, or a `valid_for` that
+    /// takes the expiry past `u64::MAX` seconds.
+    pub fn sign(&self, key_id: &str, valid_for: Duration) -> Option<String> {
+        let secret = self.secrets.iter().find(|secret| secret.id == key_id)?;
+        let issued = self.clock.unix_secs();
+        let expires = issued.checked_add(valid_for.as_secs())?;
+        let mut token = format!("{}.{}.{}", key_id, issued, expires);
+        let signature = mac(secret, &token).finalize().into_bytes();
+        token.push('.');
+        for byte in signature {
+            let _ = write!(token, "{:02x}", byte);
+        }
+        Some(token)
+    }
+
+    /// Check a token; returns the id of the key that signed it.
+    pub fn verify<'a>(&self, token: &'a str) -> Result<&'a str, BypassError> {
+        let (signed, signature) = token.rsplit_once('.').ok_or(BypassError::Malformed)?;
+        let mut fields = signed.split('.');
+        let (Some(key_id), Some(issued), Some(expires), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(BypassError::Malformed);
+        };
+        let issued: u64 = issued.parse().map_err(|_| BypassError::Malformed)?;
+        let expires: u64 = expires.parse().map_err(|_| BypassError::Malformed)?;
+        let signature = decode_hex(signature).ok_or(BypassError::Malformed)?;
+
+        let secret = self
+            .secrets
+            .iter()
+            .find(|secret| secret.id == key_id)
+            .ok_or(BypassError::UnknownKey)?;
+        mac(secret, signed)
+            .verify_slice(&signature)
+            .map_err(|_| BypassError::BadSignature)?;
+
+        let now = self.clock.unix_secs();
+        if issued > now + self.options.max_skew.as_secs() {
+            return Err(BypassError::NotYetValid);
+        }
+        if now >= expires {
+            return Err(BypassError::Expired);
+        }
+        if expires.saturating_sub(issued) > self.options.max_lifetime.as_secs() {
+            return Err(BypassError::TooLong);
+        }
+        Ok(key_id)
+    }
+
+    /// The token a request carries, in the header or the query parameter.
+    pub fn token<'a>(&self, request: &RequestInfo<'a>) -> Option<&'a str> {
+        let header = self.options.header.as_deref().and_then(|header| request.header(header));
+        header.or_else(|| request.query_param(self.options.query_param.as_deref()?))
+    }
+
+    /// Whether `request` carries a valid token. Invalid tokens are logged
+    /// and counted, and the request is limited as usual.
+    pub fn allows(&self, request: &RequestInfo<'_>) -> bool {
+        let Some(token) = self.token(request) else {
+            return false;
+        };
+        match self.verify(token) {
+            Ok(key_id) => {
+                metrics::counter!("rate_limiter_bypassed_total", "key_id" => key_id.to_string()).increment(1);
+                true
+            }
+            Err(e) => {
+                metrics::counter!("rate_limiter_bypass_rejections_total", "reason" => e.as_str()).increment(1);
+                log::debug!("Ignoring rate limit bypass token: {}", e.as_str());
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_tokens_are_signed_expire_and_rotate() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1_700_000_000)));
+        let secrets = vec![KeySecret::new("k2", "new secret"), KeySecret::new("k1", "old secret")];
+        let options = BypassOptions { query_param: Some("bypass".into()), ..BypassOptions::default() };
+        let tokens = BypassTokens::new(secrets, options.clone()).with_clock(clock.clone());
+
+        let old = tokens.sign("k1", Duration::from_secs(600)).unwrap();
+        let new = tokens.sign("k2", Duration::from_secs(600)).unwrap();
+        assert_eq!(tokens.verify(&old), Ok("k1"));
+        assert_eq!(tokens.verify(&new), Ok("k2"));
+        assert_eq!(tokens.verify(&new.replace("k2.", "k1.")), Err(BypassError::BadSignature));
+        assert_eq!(tokens.verify("k3.1.2.00"), Err(BypassError::UnknownKey));
+        assert_eq!(tokens.verify("garbage"), Err(BypassError::Malformed));
+        let long = tokens.sign("k2", Duration::from_secs(7200)).unwrap();
+        assert_eq!(tokens.verify(&long), Err(BypassError::TooLong));
+        assert_eq!(tokens.sign("k2", Duration::MAX), None);
+
+        // Only the new secret is left after rotation
+        let rotated = BypassTokens::new(vec![KeySecret::new("k2", "new secret")], options).with_clock(clock.clone());
+        assert_eq!(rotated.verify(&old), Err(BypassError::UnknownKey));
+        let path = format!("/health?x=1&bypass={}", new);
+        assert!(rotated.allows(&RequestInfo { path: &path, ..RequestInfo::default() }));
+        let headers = [("x-ratelimit-bypass", new.as_str())];
+        assert!(rotated.allows(&RequestInfo { headers: &headers, ..RequestInfo::default() }));
+
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(rotated.verify(&new), Err(BypassError::Expired));
+    }
+}
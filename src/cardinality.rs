@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, PoisonError};
+
+/// Label value standing in for everything outside the top values.
+pub const OTHER: &str = "other";
+
+/// Values seen between recomputations of the top values.
+const RECOMPUTE_EVERY: u64 = 1024;
+
+#[derive(Debug, Default)]
+struct Tally {
+    /// Approximate frequency of the values tracked
+    counts: HashMap<String, u64>,
+    /// Values currently reported as themselves
+    top: HashSet<String>,
+    seen: u64,
+}
+
+/// Keeps a metric label to its `max` most frequent values, reporting the
+/// rest as `other`, so thousands of tenants cannot explode the number of
+/// series an exporter holds.
+///
+/// Frequencies are tracked with the Space-Saving algorithm over `4 × max`
+/// values: a new value takes over the least frequent one's count, plus
+/// one. The top values are recomputed every 1024 values seen, so a tenant
+/// that grows busy gets its own series shortly after and a quiet one
+/// falls back into `other`.
+#[derive(Debug)]
+pub struct CardinalityGuard {
+    max: usize,
+    tally: Mutex<Tally>,
+}
+
+impl CardinalityGuard {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            tally: Mutex::new(Tally::default()),
+        }
+    }
+
+    /// Count `value` and return the label to report it under.
+    pub fn label(&self, value: &str) -> String {
+        let mut tally = self.tally.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = tally.counts.get_mut(value) {
+            *count += 1;
+        } else {
+            let mut count = 1;
+            if tally.counts.len() >= self.max.saturating_mul(4).max(1) {
+                let least = tally.counts.iter().min_by_key(|(_, count)| **count);
+                if let Some((least, least_count)) = least.map(|(value, count)| (value.clone(), *count)) {
+                    tally.counts.remove(&least);
+                    count += least_count;
+                }
+            }
+            tally.counts.insert(value.to_string(), count);
+        }
+        tally.seen += 1;
+
+        if tally.seen % RECOMPUTE_EVERY == 0 {
+            let mut ranked: Vec<(&String, &u64)> = tally.counts.iter().collect();
+            ranked.sort_unstable_by(|a, b| b.1.cmp(a.1));
+            let top = ranked.into_iter().take(self.max).map(|(value, _)| value.clone()).collect();
+            tally.top = top;
+        } else if tally.top.len() < self.max && !tally.top.contains(value) {
+            tally.top.insert(value.to_string());
+        }
+        if tally.top.contains(value) {
+            value.to_string()
+        } else {
+            OTHER.to_string()
+        }
+    }
+}
+
+/// Labels for `rate_limiter_decisions_total`.
+#[derive(Debug)]
+pub struct MetricLabels {
+    /// Names the limiter, e.g. the nginx zone it serves
+    pub zone: String,
+    tenants: CardinalityGuard,
+}
+
+impl MetricLabels {
+    /// Label decisions with `zone`, and with the `max_tenants` busiest
+    /// tenants by name.
+    pub fn new(zone: &str, max_tenants: usize) -> Self {
+        Self {
+            zone: zone.to_string(),
+            tenants: CardinalityGuard::new(max_tenants),
+        }
+    }
+
+    /// Count one decision under the rule that matched it, `default` when
+    /// none did, and its tenant, `none` without one.
+    pub fn record(&self, limited: bool, rule: &str, tenant: Option<&str>) {
+        let decision = if limited { "limited" } else { "allowed" };
+        let tenant = tenant.map_or_else(|| "none".to_string(), |tenant| self.tenants.label(tenant));
+        metrics::counter!(
+            "rate_limiter_decisions_total",
+            "decision" => decision,
+            "rule" => rule.to_string(),
+            "zone" => self.zone.clone(),
+            "tenant" => tenant
+        )
+        .increment(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_the_busiest_values_keep_their_label() {
+        let guard = CardinalityGuard::new(2);
+        assert_eq!(guard.label("a"), "a");
+        assert_eq!(guard.label("b"), "b");
+        assert_eq!(guard.label("c"), OTHER);
+        guard.label("a");
+
+        // "c" becomes the busiest and takes a place at the next recount
+        for _ in 0..RECOMPUTE_EVERY {
+            guard.label("c");
+        }
+        assert_eq!(guard.label("c"), "c");
+        assert_eq!(guard.label("a"), "a");
+        assert_eq!(guard.label("b"), OTHER);
+
+        // Never more values tracked than four times the labels kept
+        for i in 0..100 {
+            guard.label(&format!("tenant-{}", i));
+        }
+        assert!(guard.tally.lock().unwrap().counts.len() <= 8);
+    }
+}
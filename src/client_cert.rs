@@ -0,0 +1,111 @@
+use std::fmt::Write;
+use crate::storage::KeyBuf;
+use crate::tenant::RequestInfo;
+
+/// What identifies a client by its certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertIdentity {
+    /// The certificate's SHA-1 fingerprint (`$ssl_client_fingerprint`);
+    /// changes when the certificate is renewed
+    Fingerprint,
+    /// The first subject alternative name, e.g. a SPIFFE ID; stays the
+    /// same across renewals
+    San,
+    /// The subject DN (`$ssl_client_s_dn`)
+    Subject,
+}
+
+/// Where the client certificate of a request is read from.
+///
+/// `HTTPContext` does not expose nginx variables, so they reach `check` as
+/// pseudo-headers the embedder fills in from `$ssl_client_verify` and
+/// friends, or that nginx sets with `proxy_set_header` in front of a
+/// sidecar. They must never be taken from the client.
+#[derive(Debug, Clone)]
+pub struct ClientCertOptions {
+    pub identity: CertIdentity,
+    /// `SUCCESS` when nginx verified the certificate (`$ssl_client_verify`)
+    pub verify_header: String,
+    pub fingerprint_header: String,
+    /// Names as OpenSSL prints them, e.g. `URI:spiffe://prod/billing, DNS:billing.internal`
+    pub san_header: String,
+    pub subject_header: String,
+}
+
+impl Default for ClientCertOptions {
+    fn default() -> Self {
+        Self {
+            identity: CertIdentity::Fingerprint,
+            verify_header: "X-SSL-Client-Verify".to_string(),
+            fingerprint_header: "X-SSL-Client-Fingerprint".to_string(),
+            san_header: "X-SSL-Client-SAN".to_string(),
+            subject_header: "X-SSL-Client-S-DN".to_string(),
+        }
+    }
+}
+
+impl ClientCertOptions {
+    pub fn new(identity: CertIdentity) -> Self {
+        Self { identity, ..Self::default() }
+    }
+
+    /// `cert:{identity}` for a request with a verified client certificate.
+    /// Requests without one, or whose certificate failed verification, keep
+    /// their own key.
+    pub fn key(&self, request: &RequestInfo<'_>) -> Option<KeyBuf> {
+        if request.header(&self.verify_header)? != "SUCCESS" {
+            return None;
+        }
+        let mut key = KeyBuf::new();
+        match self.identity {
+            CertIdentity::Fingerprint => {
+                let fingerprint = request.header(&self.fingerprint_header)?.trim();
+                if fingerprint.is_empty() {
+                    return None;
+                }
+                key.push_str("cert:");
+                // Fingerprints are hex, in either case depending on the source
+                for c in fingerprint.chars().filter(|c| *c != ':') {
+                    let _ = key.write_char(c.to_ascii_lowercase());
+                }
+            }
+            CertIdentity::San => {
+                let san = request.header(&self.san_header)?.split(',').map(str::trim).find(|san| !san.is_empty())?;
+                let _ = write!(key, "cert:{}", san);
+            }
+            CertIdentity::Subject => {
+                let subject = request.header(&self.subject_header)?.trim();
+                if subject.is_empty() {
+                    return None;
+                }
+                let _ = write!(key, "cert:{}", subject);
+            }
+        }
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verified_certificates_give_identity_keys() {
+        let headers = [
+            ("x-ssl-client-verify", "SUCCESS"),
+            ("x-ssl-client-fingerprint", "AB:CD:EF:01"),
+            ("x-ssl-client-san", "URI:spiffe://prod/billing, DNS:billing.internal"),
+            ("x-ssl-client-s-dn", "CN=billing,O=Example"),
+        ];
+        let request = RequestInfo { headers: &headers, ..RequestInfo::default() };
+        let key = |identity| ClientCertOptions::new(identity).key(&request).map(|key| key.as_str().to_string());
+
+        assert_eq!(key(CertIdentity::Fingerprint).as_deref(), Some("cert:abcdef01"));
+        assert_eq!(key(CertIdentity::San).as_deref(), Some("cert:URI:spiffe://prod/billing"));
+        assert_eq!(key(CertIdentity::Subject).as_deref(), Some("cert:CN=billing,O=Example"));
+
+        let failed = [("x-ssl-client-verify", "FAILED:certificate has expired"), headers[1]];
+        let request = RequestInfo { headers: &failed, ..RequestInfo::default() };
+        assert!(ClientCertOptions::default().key(&request).is_none());
+    }
+}
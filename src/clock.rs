@@ -0,0 +1,133 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time for windows, expiry and local caches.
+///
+/// Everything that reads the time takes an `Arc<dyn Clock>`, defaulting to
+/// `SystemClock`, so tests can swap in a `ManualClock` or a `TokioClock`
+/// instead of sleeping.
+pub trait Clock: Debug + Send + Sync {
+    /// Time since the Unix epoch.
+    fn now(&self) -> Duration;
+
+    fn unix_secs(&self) -> u64 {
+        self.now().as_secs()
+    }
+}
+
+/// The system wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+    }
+}
+
+/// Wall time at creation, advanced by tokio's clock, so it follows
+/// `tokio::time::pause()` and `advance()` in tests.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub struct TokioClock {
+    origin: Duration,
+    started: tokio::time::Instant,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl TokioClock {
+    pub fn new() -> Self {
+        Self {
+            origin: SystemClock.now(),
+            started: tokio::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Clock for TokioClock {
+    fn now(&self) -> Duration {
+        self.origin + self.started.elapsed()
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug)]
+pub struct ManualClock {
+    nanos: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(start: Duration) -> Self {
+        Self {
+            nanos: AtomicU64::new(start.as_nanos() as u64),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.nanos.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, to: Duration) {
+        self.nanos.store(to.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Default for ManualClock {
+    /// A fixed point in time (2023-11-14), so runs are reproducible.
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1_700_000_000))
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+/// The default clock.
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// Proleptic Gregorian date of a day count since 1970-01-01.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tokio_clock_follows_paused_time() {
+        let clock = TokioClock::new();
+        let start = clock.now();
+        tokio::time::sleep(Duration::from_secs(90)).await;
+        assert_eq!(clock.now() - start, Duration::from_secs(90));
+
+        let manual = ManualClock::default();
+        manual.advance(Duration::from_millis(1500));
+        assert_eq!(manual.unix_secs(), 1_700_000_001);
+    }
+}
@@ -0,0 +1,178 @@
+use arc_swap::{ArcSwap, Guard};
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use crate::rejection::RejectionPage;
+use crate::rules::{Rule, RuleError, RuleSet};
+
+/// Limits in force for one configuration generation.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub requests_per_second: u32,
+    pub window_size: u32,
+    /// Per-path overrides, compiled for this generation
+    pub rules: Option<Arc<RuleSet>>,
+}
+
+impl Limits {
+    pub fn new(requests_per_second: u32, window_size: u32) -> Self {
+        Self {
+            requests_per_second,
+            window_size,
+            rules: None,
+        }
+    }
+
+    /// Requests and window for `path`: the first matching rule, or the
+    /// defaults.
+    pub fn for_path(&self, path: &str) -> (u32, u32) {
+        match self.rules.as_ref().and_then(|rules| rules.find(path)) {
+            Some(rule) => (rule.requests, rule.window),
+            None => (self.requests_per_second, self.window_size),
+        }
+    }
+
+    /// The 429 body for `path`: the first matching rule's, or the standard
+    /// page.
+    pub fn rejection_for(&self, path: &str) -> &RejectionPage {
+        self.rules
+            .as_ref()
+            .and_then(|rules| rules.find(path))
+            .and_then(|rule| rule.rejection.as_ref())
+            .unwrap_or_else(RejectionPage::standard)
+    }
+}
+
+/// Limits as written in a JSON configuration file, the format shared by
+/// the Proxy-Wasm filter and other embedders:
+///
+/// ```json
+/// {"requests": 100, "window": 60, "rules": [{"path": {"prefix": "/api/"}, "requests": 10, "window": 60,
+///  "rejection": "{\"error\": \"rate limited\", \"retry_after\": {retry_after}}"}]}
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct LimitsConfig {
+    pub requests: u32,
+    pub window: u32,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+impl LimitsConfig {
+    /// Compile the rules for `generation`.
+    pub fn compile(self, generation: u64) -> Result<Limits, RuleError> {
+        let rules = if self.rules.is_empty() {
+            None
+        } else {
+            Some(Arc::new(RuleSet::compile(generation, self.rules)?))
+        };
+        Ok(Limits {
+            requests_per_second: self.requests,
+            window_size: self.window,
+            rules,
+        })
+    }
+}
+
+/// A published `Limits` with its generation number.
+#[derive(Debug)]
+pub struct Generation {
+    pub number: u64,
+    pub limits: Limits,
+    /// Version of the source the limits were loaded from, e.g. a Consul
+    /// index, for status reports
+    pub version: Option<String>,
+}
+
+impl Drop for Generation {
+    fn drop(&mut self) {
+        log::debug!("Retired rate limit configuration generation {}", self.number);
+    }
+}
+
+/// The current configuration, swapped atomically on reload.
+///
+/// Requests take a snapshot with `load` and use it until they finish,
+/// without locking. `publish` swaps in a new generation for the requests
+/// that start after it, and does not wait for requests still in flight.
+/// Those keep the generation they started with. A generation is retired,
+/// meaning dropped, once the last request holding it completes.
+#[derive(Debug)]
+pub struct ConfigStore {
+    current: ArcSwap<Generation>,
+    next: AtomicU64,
+}
+
+impl ConfigStore {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(Generation { number: 1, limits, version: None }),
+            next: AtomicU64::new(2),
+        }
+    }
+
+    /// Snapshot for the current request; cheap enough to take per request.
+    pub fn load(&self) -> Guard<Arc<Generation>> {
+        self.current.load()
+    }
+
+    /// Snapshot that may be kept across awaits or moved to another task.
+    pub fn load_full(&self) -> Arc<Generation> {
+        self.current.load_full()
+    }
+
+    /// Make `limits` current and return its generation number.
+    pub fn publish(&self, limits: Limits) -> u64 {
+        self.store(limits, None)
+    }
+
+    /// Make `limits` current, recording the source `version` they came
+    /// from, and return its generation number.
+    pub fn publish_version(&self, limits: Limits, version: String) -> u64 {
+        self.store(limits, Some(version))
+    }
+
+    fn store(&self, limits: Limits, version: Option<String>) -> u64 {
+        let number = self.next.fetch_add(1, Ordering::Relaxed);
+        self.current.store(Arc::new(Generation { number, limits, version }));
+        metrics::gauge!("rate_limiter_config_generation").set(number as f64);
+        number
+    }
+
+    /// Publish a copy of the current limits changed by `update`, e.g. to
+    /// adjust one value without racing another reload.
+    pub fn update(&self, update: impl Fn(&Limits) -> Limits) -> u64 {
+        let number = self.next.fetch_add(1, Ordering::Relaxed);
+        self.current.rcu(|current| Generation {
+            number,
+            limits: update(&current.limits),
+            version: current.version.clone(),
+        });
+        metrics::gauge!("rate_limiter_config_generation").set(number as f64);
+        number
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight_requests_keep_their_generation() {
+        let store = ConfigStore::new(Limits::new(100, 60));
+        let in_flight = store.load_full();
+
+        assert_eq!(store.publish(Limits::new(10, 60)), 2);
+        assert_eq!(in_flight.limits.requests_per_second, 100);
+        assert_eq!(store.load().limits.requests_per_second, 10);
+
+        // The old generation lives exactly as long as its last reader
+        let retired = Arc::downgrade(&in_flight);
+        drop(in_flight);
+        assert!(retired.upgrade().is_none());
+
+        store.update(|limits| Limits { window_size: 1, ..limits.clone() });
+        let current = store.load();
+        assert_eq!((current.number, current.limits.window_size), (3, 1));
+    }
+}
@@ -0,0 +1,122 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+
+/// Bounds for the `DenyCache`.
+#[derive(Debug, Clone)]
+pub struct DenyCacheOptions {
+    /// Keys remembered at once; further denials are not cached
+    pub max_entries: usize,
+    /// How long a denial is served locally, capped at the window
+    pub ttl: Duration,
+}
+
+impl Default for DenyCacheOptions {
+    fn default() -> Self {
+        Self {
+            max_entries: 100_000,
+            ttl: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Remembers keys that were over their limit, so repeated requests from an
+/// abusive client are rejected without a backend round trip.
+///
+/// The backend does not report when a key's window ends, so a denial is
+/// cached for `ttl` and a client may stay blocked up to `ttl` past the end
+/// of its window. A short `ttl` still turns thousands of requests per
+/// second from one client into one backend call per `ttl`.
+#[derive(Debug)]
+pub struct DenyCache {
+    /// Key to the clock time its denial ends
+    denied: DashMap<String, Duration>,
+    options: DenyCacheOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl DenyCache {
+    pub fn new(options: DenyCacheOptions) -> Self {
+        Self {
+            denied: DashMap::new(),
+            options,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Whether `key` was denied within the last `ttl`.
+    pub fn is_denied(&self, key: &str) -> bool {
+        let until = match self.denied.get(key) {
+            Some(until) => *until,
+            None => return false,
+        };
+        let now = self.clock.now();
+        if until > now {
+            metrics::counter!("rate_limiter_deny_cache_hits_total").increment(1);
+            return true;
+        }
+        self.denied.remove_if(key, |_, until| *until <= now);
+        false
+    }
+
+    /// Remember that `key` is over its limit in a window of `window` seconds.
+    pub fn deny(&self, key: &str, window: u32) {
+        if self.denied.len() >= self.options.max_entries {
+            let now = self.clock.now();
+            self.denied.retain(|_, until| *until > now);
+            if self.denied.len() >= self.options.max_entries {
+                return;
+            }
+        }
+
+        let ttl = self.options.ttl.min(Duration::from_secs(window.into()));
+        self.denied.insert(key.to_string(), self.clock.now() + ttl);
+    }
+
+    /// Forget `key`, e.g. after its counter was reset.
+    pub fn forget(&self, key: &str) {
+        self.denied.remove(key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.denied.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.denied.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_denials_expire_and_are_bounded() {
+        let clock = Arc::new(ManualClock::default());
+        let cache = DenyCache::new(DenyCacheOptions {
+            max_entries: 2,
+            ttl: Duration::from_millis(20),
+        })
+        .with_clock(clock.clone());
+        cache.deny("a", 60);
+        cache.deny("b", 60);
+        cache.deny("c", 60);
+        assert!(cache.is_denied("a") && cache.is_denied("b"));
+        assert!(!cache.is_denied("c"));
+
+        clock.advance(Duration::from_millis(30));
+        assert!(!cache.is_denied("a"));
+        cache.deny("c", 60);
+        assert!(cache.is_denied("c"));
+        assert!(!cache.is_denied("b"));
+        assert_eq!(cache.len(), 1);
+    }
+}
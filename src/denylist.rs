@@ -0,0 +1,274 @@
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use crate::bloom::BloomFilter;
+use crate::cidr_trie::CidrTrie;
+use crate::clock::{self, Clock};
+
+/// Hosts the filter of a new `Denylist` is sized for; it grows as needed.
+const INITIAL_BLOOM_CAPACITY: usize = 1024;
+
+/// False positive rate the host filter is sized for.
+const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// A block of addresses, e.g. `203.0.113.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    pub address: IpAddr,
+    pub prefix: u8,
+}
+
+impl Network {
+    /// Parse `203.0.113.7` or `203.0.113.0/24`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (address, prefix) = match s.trim().split_once('/') {
+            Some((address, prefix)) => (address.parse::<IpAddr>().ok()?, prefix.parse::<u8>().ok()?),
+            None => {
+                let address = s.trim().parse::<IpAddr>().ok()?;
+                (address, if address.is_ipv4() { 32 } else { 128 })
+            }
+        };
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        (prefix <= bits).then_some(Self { address, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn is_host(&self) -> bool {
+        self.prefix == if self.address.is_ipv4() { 32 } else { 128 }
+    }
+}
+
+/// The addresses and networks of a list with one per line. Anything after
+/// a `#` or `,` is ignored, as are lines that do not parse.
+pub fn parse_list(body: &str) -> impl Iterator<Item = Network> + '_ {
+    body.lines()
+        .filter_map(|line| line.split(['#', ',']).next())
+        .filter_map(Network::parse)
+}
+
+/// Addresses and networks rejected before any counter is consulted, each
+/// until its own expiry.
+///
+/// Single addresses are a hash lookup behind a Bloom filter, so the common
+/// case of an address that is not listed costs a few hashes and no lock,
+/// even with hundreds of thousands of hosts from threat feeds. The filter
+/// takes new hosts as they are inserted. It is rebuilt when it fills up,
+/// or when half the hosts it holds were removed or expired. Networks are
+/// kept in a `CidrTrie`, so checking an address against millions of them
+/// only visits the prefixes on its path.
+#[derive(Debug)]
+pub struct Denylist {
+    /// Address to the clock time its entry expires
+    hosts: DashMap<IpAddr, Duration>,
+    /// Every host in `hosts`, and some removed since it was built
+    bloom: ArcSwap<BloomFilter>,
+    /// Hosts inserted into `bloom`, and removed since it was built
+    bloomed: AtomicUsize,
+    stale: AtomicUsize,
+    /// Held while hosts are added or `bloom` is rebuilt, so no host misses
+    /// the new filter
+    rebuild: Mutex<()>,
+    networks: RwLock<CidrTrie<Duration>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Denylist {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Denylist {
+    pub fn new() -> Self {
+        Self {
+            hosts: DashMap::new(),
+            bloom: ArcSwap::from_pointee(BloomFilter::new(INITIAL_BLOOM_CAPACITY, BLOOM_FALSE_POSITIVE_RATE)),
+            bloomed: AtomicUsize::new(0),
+            stale: AtomicUsize::new(0),
+            rebuild: Mutex::new(()),
+            networks: RwLock::new(CidrTrie::new()),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Deny `network` for `ttl`, extending an existing entry.
+    pub fn insert(&self, network: Network, ttl: Duration) {
+        let until = self.clock.now() + ttl;
+        if network.is_host() {
+            let _guard = self.rebuild.lock().unwrap_or_else(|e| e.into_inner());
+            let mut added = false;
+            let mut entry = self.hosts.entry(network.address).or_insert_with(|| {
+                added = true;
+                until
+            });
+            *entry = (*entry).max(until);
+            drop(entry);
+            if added {
+                let bloom = self.bloom.load();
+                bloom.insert(&network.address);
+                if self.bloomed.fetch_add(1, Ordering::Relaxed) + 1 > bloom.capacity() {
+                    self.rebuild_bloom();
+                }
+            }
+            return;
+        }
+        let mut networks = self.networks.write().unwrap_or_else(|e| e.into_inner());
+        let until = networks.get(network).map_or(until, |existing| (*existing).max(until));
+        networks.insert(network, until);
+    }
+
+    /// Deny every address and network listed in the file at `path` for
+    /// `ttl`, e.g. a reputation list kept on disk; returns how many were
+    /// read. The format is that of `parse_list`.
+    pub fn load_file(&self, path: &Path, ttl: Duration) -> std::io::Result<usize> {
+        let body = std::fs::read_to_string(path)?;
+        let mut loaded = 0;
+        for network in parse_list(&body) {
+            self.insert(network, ttl);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    pub fn remove(&self, network: Network) {
+        if network.is_host() {
+            if self.hosts.remove(&network.address).is_some() {
+                self.removed(1);
+            }
+        } else {
+            self.networks.write().unwrap_or_else(|e| e.into_inner()).remove(network);
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let now = self.clock.now();
+        if self.bloom.load().might_contain(&ip) && self.hosts.get(&ip).is_some_and(|until| *until > now) {
+            return true;
+        }
+        self.networks
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .matches(ip)
+            .any(|(_, until)| *until > now)
+    }
+
+    /// Whether the address in a request key is denied. Keys that are not
+    /// an address never are.
+    pub fn contains_key(&self, key: &str) -> bool {
+        key.parse().is_ok_and(|ip| self.contains(ip))
+    }
+
+    /// Drop expired entries; returns how many were removed.
+    pub fn purge_expired(&self) -> usize {
+        let now = self.clock.now();
+        let before = self.len();
+        let hosts = self.hosts.len();
+        self.hosts.retain(|_, until| *until > now);
+        self.removed(hosts - self.hosts.len());
+        self.networks
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, until| *until > now);
+        before - self.len()
+    }
+
+    /// Note `count` hosts gone from the filter, rebuilding it once half
+    /// of what it holds is gone.
+    fn removed(&self, count: usize) {
+        let stale = self.stale.fetch_add(count, Ordering::Relaxed) + count;
+        if stale > INITIAL_BLOOM_CAPACITY && stale * 2 > self.bloomed.load(Ordering::Relaxed) {
+            let _guard = self.rebuild.lock().unwrap_or_else(|e| e.into_inner());
+            self.rebuild_bloom();
+        }
+    }
+
+    /// Build a filter of the current hosts, with room for twice as many.
+    /// Callers hold `rebuild`.
+    fn rebuild_bloom(&self) {
+        let hosts = self.hosts.len();
+        let bloom = BloomFilter::new((hosts * 2).max(INITIAL_BLOOM_CAPACITY), BLOOM_FALSE_POSITIVE_RATE);
+        for entry in self.hosts.iter() {
+            bloom.insert(entry.key());
+        }
+        self.bloom.store(Arc::new(bloom));
+        self.bloomed.store(hosts, Ordering::Relaxed);
+        self.stale.store(0, Ordering::Relaxed);
+        metrics::counter!("rate_limiter_denylist_bloom_rebuilds_total").increment(1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.hosts.len() + self.networks.read().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_hosts_and_networks_expire() {
+        let clock = Arc::new(ManualClock::default());
+        let denylist = Denylist::new().with_clock(clock.clone());
+        denylist.insert(Network::parse("192.0.2.7").unwrap(), Duration::from_secs(60));
+        denylist.insert(Network::parse("198.51.100.0/24").unwrap(), Duration::from_secs(120));
+        denylist.insert(Network::parse("2001:db8::/32").unwrap(), Duration::from_secs(120));
+
+        assert!(denylist.contains_key("192.0.2.7"));
+        assert!(denylist.contains_key("198.51.100.200"));
+        assert!(denylist.contains_key("2001:db8::1"));
+        assert!(!denylist.contains_key("198.51.101.1"));
+        assert!(!denylist.contains_key("not an address"));
+        assert!(Network::parse("10.0.0.0/33").is_none());
+
+        clock.advance(Duration::from_secs(61));
+        assert!(!denylist.contains_key("192.0.2.7"));
+        assert_eq!(denylist.purge_expired(), 1);
+        assert_eq!(denylist.len(), 2);
+    }
+
+    #[test]
+    fn test_host_filter_follows_the_list() {
+        let denylist = Denylist::new();
+        let host = |i: u32| Network { address: IpAddr::from((0x0a00_0000 + i).to_be_bytes()), prefix: 32 };
+        for i in 0..5_000 {
+            denylist.insert(host(i), Duration::from_secs(60));
+        }
+        assert!(denylist.bloom.load().capacity() >= 5_000);
+        assert!((0..5_000).all(|i| denylist.contains(host(i).address)));
+        assert!(!denylist.contains(host(5_000).address));
+
+        for i in 0..4_000 {
+            denylist.remove(host(i));
+        }
+        assert!(denylist.stale.load(Ordering::Relaxed) < 4_000);
+        assert!(!denylist.contains(host(0).address));
+        assert!((4_000..5_000).all(|i| denylist.contains(host(i).address)));
+    }
+}
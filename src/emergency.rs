@@ -0,0 +1,153 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use crate::storage::{StorageBackend, StorageError};
+
+/// Backend key whose presence engages the brake on every worker watching
+/// it.
+pub const EMERGENCY_KEY: &str = "emergency";
+
+/// The limit applied while the brake is engaged.
+#[derive(Debug, Clone)]
+pub struct EmergencyOptions {
+    pub requests: u32,
+    pub window: u32,
+    /// Brake only requests counted by their address, leaving clients known
+    /// by a key source or token alone
+    pub anonymous_only: bool,
+    /// How often workers read `EMERGENCY_KEY`
+    pub poll_interval: Duration,
+}
+
+impl Default for EmergencyOptions {
+    fn default() -> Self {
+        Self {
+            requests: 10,
+            window: 60,
+            anonymous_only: false,
+            poll_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A switch that holds all traffic, or anonymous traffic only, to an
+/// aggressive limit during an incident, without touching the nginx
+/// configuration.
+///
+/// It is engaged on one worker with `engage`, or on every worker by
+/// setting `EMERGENCY_KEY` in the backend with `engage_everywhere` (or the
+/// sidecar's `PUT /emergency`), which workers pick up within
+/// `poll_interval`. The brake only ever tightens: a key keeps its own
+/// limit when that is lower.
+#[derive(Debug)]
+pub struct EmergencyBrake {
+    options: EmergencyOptions,
+    engaged: AtomicBool,
+    /// Whether `EMERGENCY_KEY` was set at the last poll
+    flagged: AtomicBool,
+}
+
+impl EmergencyBrake {
+    pub fn new(options: EmergencyOptions) -> Self {
+        Self {
+            options,
+            engaged: AtomicBool::new(false),
+            flagged: AtomicBool::new(false),
+        }
+    }
+
+    pub fn engage(&self) {
+        if !self.engaged.swap(true, Ordering::Relaxed) {
+            log::warn!("Emergency brake engaged on this worker");
+        }
+    }
+
+    pub fn release(&self) {
+        if self.engaged.swap(false, Ordering::Relaxed) {
+            log::warn!("Emergency brake released on this worker");
+        }
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged.load(Ordering::Relaxed) || self.flagged.load(Ordering::Relaxed)
+    }
+
+    /// The limit for a request under `limit` and `window`: the brake's
+    /// while it is engaged and applies, theirs otherwise.
+    pub fn limit(&self, anonymous: bool, limit: u32, window: u32) -> (u32, u32) {
+        if !self.is_engaged() || (self.options.anonymous_only && !anonymous) {
+            return (limit, window);
+        }
+        // Compare rates, so a brake over a longer window never loosens
+        let brake = f64::from(self.options.requests) / f64::from(self.options.window.max(1));
+        if brake < f64::from(limit) / f64::from(window.max(1)) {
+            (self.options.requests, self.options.window)
+        } else {
+            (limit, window)
+        }
+    }
+
+    /// Read `EMERGENCY_KEY` from `storage`. An unreadable backend leaves
+    /// the brake as it was.
+    pub async fn refresh(&self, storage: &dyn StorageBackend) -> Result<bool, StorageError> {
+        let flagged = storage.get(EMERGENCY_KEY).await? > 0;
+        if self.flagged.swap(flagged, Ordering::Relaxed) != flagged {
+            log::warn!("Emergency brake {} through the backend", if flagged { "engaged" } else { "released" });
+        }
+        metrics::gauge!("rate_limiter_emergency_engaged").set(if self.is_engaged() { 1.0 } else { 0.0 });
+        Ok(flagged)
+    }
+
+    /// Poll the backend every `poll_interval` until the returned handle is
+    /// aborted.
+    pub fn spawn(self: &Arc<Self>, storage: Arc<dyn StorageBackend>) -> JoinHandle<()> {
+        let brake = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(brake.options.poll_interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = brake.refresh(storage.as_ref()).await {
+                    log::warn!("Reading the emergency flag failed: {}", e);
+                }
+            }
+        })
+    }
+}
+
+/// Engage the brake on every worker watching `storage`, for at most `ttl`
+/// seconds, so a forgotten brake releases itself.
+pub async fn engage_everywhere(storage: &dyn StorageBackend, ttl: u32) -> Result<(), StorageError> {
+    storage.increment(EMERGENCY_KEY, ttl).await.map(|_| ())
+}
+
+/// Release a brake engaged with `engage_everywhere`.
+pub async fn release_everywhere(storage: &dyn StorageBackend) -> Result<(), StorageError> {
+    storage.delete(EMERGENCY_KEY).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_brake_tightens_limits_while_engaged() {
+        let storage = MemoryStorage::new();
+        let brake = EmergencyBrake::new(EmergencyOptions { anonymous_only: true, ..EmergencyOptions::default() });
+        assert_eq!(brake.limit(true, 100, 1), (100, 1));
+
+        engage_everywhere(&storage, 3600).await.unwrap();
+        assert!(brake.refresh(&storage).await.unwrap());
+        assert_eq!(brake.limit(true, 100, 1), (10, 60));
+        assert_eq!(brake.limit(false, 100, 1), (100, 1));
+        // Already tighter than the brake
+        assert_eq!(brake.limit(true, 5, 60), (5, 60));
+
+        release_everywhere(&storage).await.unwrap();
+        assert!(!brake.refresh(&storage).await.unwrap());
+        assert!(!brake.is_engaged());
+        brake.engage();
+        assert_eq!(brake.limit(true, 100, 1), (10, 60));
+    }
+}
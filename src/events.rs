@@ -0,0 +1,369 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::clock::{self, Clock};
+
+/// What happened to a request or client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Allow,
+    Deny,
+    /// The client was handed to the firewall by `BanExporter`
+    Ban,
+    /// An API key crossed a soft threshold of its plan's quota; the
+    /// event's limit is the quota
+    QuotaWarning,
+    /// A key's request rate jumped far above its baseline; the event's
+    /// count is the z-score, rounded down
+    Anomaly,
+    /// A key requested a honeypot path and was banned; the event's window
+    /// is the ban in seconds
+    Honeypot,
+}
+
+impl EventKind {
+    /// Position in the Avro enum's symbols.
+    fn avro_index(self) -> i64 {
+        match self {
+            EventKind::Allow => 0,
+            EventKind::Deny => 1,
+            EventKind::Ban => 2,
+            EventKind::QuotaWarning => 3,
+            EventKind::Anomaly => 4,
+            EventKind::Honeypot => 5,
+        }
+    }
+}
+
+/// One rate limit event, as published.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RateLimitEvent {
+    pub kind: EventKind,
+    pub key: String,
+    /// Count in the window after the decision
+    pub count: u64,
+    pub limit: u32,
+    pub window: u32,
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+}
+
+/// Avro schema of events encoded with `EventFormat::Avro`.
+pub const AVRO_SCHEMA: &str = r#"{"type":"record","name":"RateLimitEvent","namespace":"nginx.ratelimiter","fields":[{"name":"kind","type":{"type":"enum","name":"EventKind","symbols":["allow","deny","ban","quota_warning","anomaly","honeypot"]}},{"name":"key","type":"string"},{"name":"count","type":"long"},{"name":"limit","type":"int"},{"name":"window","type":"int"},{"name":"timestamp","type":{"type":"long","logicalType":"timestamp-millis"}}]}"#;
+
+/// Wire format of published events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFormat {
+    Json,
+    /// Avro binary with `AVRO_SCHEMA`. With a schema registry id, each
+    /// payload gets the Confluent framing: a zero byte and the id.
+    Avro { schema_id: Option<u32> },
+    /// RFC 5424 syslog messages, for `syslog::SyslogSink`
+    #[cfg(feature = "syslog")]
+    Syslog(crate::syslog::Facility),
+}
+
+impl RateLimitEvent {
+    pub fn encode(&self, format: EventFormat) -> Vec<u8> {
+        match format {
+            // The event only holds plain values, so this cannot fail
+            EventFormat::Json => serde_json::to_vec(self).unwrap_or_default(),
+            EventFormat::Avro { schema_id } => {
+                let mut out = Vec::with_capacity(32 + self.key.len());
+                if let Some(id) = schema_id {
+                    out.push(0);
+                    out.extend_from_slice(&id.to_be_bytes());
+                }
+                avro_long(&mut out, self.kind.avro_index());
+                avro_long(&mut out, self.key.len() as i64);
+                out.extend_from_slice(self.key.as_bytes());
+                avro_long(&mut out, self.count.min(i64::MAX as u64) as i64);
+                avro_long(&mut out, i64::from(self.limit.min(i32::MAX as u32)));
+                avro_long(&mut out, i64::from(self.window.min(i32::MAX as u32)));
+                avro_long(&mut out, self.timestamp.min(i64::MAX as u64) as i64);
+                out
+            }
+            #[cfg(feature = "syslog")]
+            EventFormat::Syslog(facility) => crate::syslog::format_message(self, facility).into_bytes(),
+        }
+    }
+}
+
+/// Avro `int` and `long`: zig-zag, then a base-128 varint.
+fn avro_long(out: &mut Vec<u8>, value: i64) {
+    let mut n = ((value << 1) ^ (value >> 63)) as u64;
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// Where encoded events go.
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    /// Publish `(key, payload)` pairs; the key is the rate limit key, for
+    /// partitioning.
+    async fn publish(&self, events: &[(String, Vec<u8>)]) -> Result<(), String>;
+}
+
+/// Queue and batching settings for `EventEmitter`.
+#[derive(Debug, Clone)]
+pub struct EventOptions {
+    pub format: EventFormat,
+    /// Events queued before new ones are dropped
+    pub queue_depth: usize,
+    /// Most events handed to the sink at once
+    pub batch_size: usize,
+    /// Whether allowed requests are published, not only denials and bans
+    pub include_allowed: bool,
+}
+
+impl Default for EventOptions {
+    fn default() -> Self {
+        Self {
+            format: EventFormat::Json,
+            queue_depth: 10_000,
+            batch_size: 500,
+            include_allowed: false,
+        }
+    }
+}
+
+/// Publishes rate limit activity to an event stream for analytics and
+/// billing, off the request path.
+///
+/// `emit` never waits: events go into a bounded queue drained by a
+/// background task, and are dropped, and counted in
+/// `rate_limiter_events_dropped_total`, when the queue is full or the sink
+/// fails.
+pub struct EventEmitter {
+    sender: mpsc::Sender<RateLimitEvent>,
+    include_allowed: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for EventEmitter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventEmitter").field("include_allowed", &self.include_allowed).finish()
+    }
+}
+
+impl EventEmitter {
+    /// Start the publishing task on the current runtime; call it in the
+    /// worker, not before the fork.
+    pub fn spawn(sink: Arc<dyn EventSink>, options: EventOptions) -> (Self, JoinHandle<()>) {
+        let (sender, mut receiver) = mpsc::channel::<RateLimitEvent>(options.queue_depth.max(1));
+        let batch_size = options.batch_size.max(1);
+        let format = options.format;
+        let task = tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(batch_size);
+            while receiver.recv_many(&mut batch, batch_size).await > 0 {
+                let encoded: Vec<(String, Vec<u8>)> =
+                    batch.drain(..).map(|event| (event.key.clone(), event.encode(format))).collect();
+                if let Err(e) = sink.publish(&encoded).await {
+                    metrics::counter!("rate_limiter_events_dropped_total").increment(encoded.len() as u64);
+                    log::warn!("Publishing {} rate limit events failed: {}", encoded.len(), e);
+                }
+            }
+        });
+        let emitter = Self {
+            sender,
+            include_allowed: options.include_allowed,
+            clock: clock::system(),
+        };
+        (emitter, task)
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn emit(&self, kind: EventKind, key: &str, count: u64, limit: u32, window: u32) {
+        if kind == EventKind::Allow && !self.include_allowed {
+            return;
+        }
+        let event = RateLimitEvent {
+            kind,
+            key: key.to_string(),
+            count,
+            limit,
+            window,
+            timestamp: self.clock.now().as_millis() as u64,
+        };
+        if self.sender.try_send(event).is_err() {
+            metrics::counter!("rate_limiter_events_dropped_total").increment(1);
+        }
+    }
+}
+
+/// POSTs each batch as a JSON array to a webhook, e.g. one that alerts
+/// API consumers nearing their quota. Use it with `EventFormat::Json`.
+#[cfg(feature = "webhook")]
+pub struct WebhookSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[cfg(feature = "webhook")]
+impl WebhookSink {
+    pub fn new(url: &str) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(5))
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self { client, url: url.to_string() })
+    }
+}
+
+#[cfg(feature = "webhook")]
+#[async_trait]
+impl EventSink for WebhookSink {
+    async fn publish(&self, events: &[(String, Vec<u8>)]) -> Result<(), String> {
+        let mut body = b"[".to_vec();
+        for (i, (_, payload)) in events.iter().enumerate() {
+            if i > 0 {
+                body.push(b',');
+            }
+            body.extend_from_slice(payload);
+        }
+        body.push(b']');
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("webhook returned {}", status)),
+        }
+    }
+}
+
+/// Publishes to a Kafka topic, keyed by the rate limit key.
+#[cfg(feature = "kafka")]
+pub struct KafkaSink {
+    producer: rdkafka::producer::FutureProducer,
+    topic: String,
+    timeout: std::time::Duration,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaSink {
+    /// `brokers` is a `bootstrap.servers` list such as `kafka-1:9092,kafka-2:9092`.
+    pub fn new(brokers: &str, topic: &str) -> Result<Self, String> {
+        let producer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("linger.ms", "50")
+            .create()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            producer,
+            topic: topic.to_string(),
+            timeout: std::time::Duration::from_secs(5),
+        })
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl EventSink for KafkaSink {
+    async fn publish(&self, events: &[(String, Vec<u8>)]) -> Result<(), String> {
+        let deliveries = events.iter().map(|(key, payload)| {
+            let record = rdkafka::producer::FutureRecord::to(&self.topic).key(key).payload(payload);
+            self.producer.send(record, self.timeout)
+        });
+        for delivery in futures_util::future::join_all(deliveries).await {
+            delivery.map_err(|(e, _)| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Publishes to a NATS subject.
+#[cfg(feature = "nats")]
+pub struct NatsSink {
+    client: async_nats::Client,
+    subject: String,
+}
+
+#[cfg(feature = "nats")]
+impl NatsSink {
+    pub async fn connect(url: &str, subject: &str) -> Result<Self, String> {
+        let client = async_nats::connect(url).await.map_err(|e| e.to_string())?;
+        Ok(Self {
+            client,
+            subject: subject.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl EventSink for NatsSink {
+    async fn publish(&self, events: &[(String, Vec<u8>)]) -> Result<(), String> {
+        for (_, payload) in events {
+            self.client
+                .publish(self.subject.clone(), bytes::Bytes::copy_from_slice(payload))
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        self.client.flush().await.map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<(String, Vec<u8>)>>);
+
+    #[async_trait]
+    impl EventSink for Collect {
+        async fn publish(&self, events: &[(String, Vec<u8>)]) -> Result<(), String> {
+            self.0.lock().unwrap().extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_avro_encoding() {
+        let event = RateLimitEvent {
+            kind: EventKind::Deny,
+            key: "ab".to_string(),
+            count: 64,
+            limit: 1,
+            window: 60,
+            timestamp: 0,
+        };
+        assert_eq!(
+            event.encode(EventFormat::Avro { schema_id: Some(7) }),
+            vec![0, 0, 0, 0, 7, 2, 4, b'a', b'b', 0x80, 0x01, 2, 120, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_emitted_events_reach_the_sink() {
+        let sink = Arc::new(Collect::default());
+        let (emitter, task) = EventEmitter::spawn(sink.clone(), EventOptions::default());
+        emitter.emit(EventKind::Allow, "client", 1, 2, 60);
+        emitter.emit(EventKind::Deny, "client", 2, 2, 60);
+        drop(emitter);
+        task.await.unwrap();
+
+        let events = sink.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let json: serde_json::Value = serde_json::from_slice(&events[0].1).unwrap();
+        assert_eq!(json["kind"], "deny");
+        assert_eq!(events[0].0, "client");
+    }
+}
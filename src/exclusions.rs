@@ -0,0 +1,114 @@
+use crate::tenant::RequestInfo;
+
+/// Health checks recognized by path and `User-Agent` together, so a client
+/// that only copies a probe's user agent is still limited elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// Paths probed, ignoring the query; a trailing `*` matches any path
+    /// starting with the rest
+    pub paths: Vec<String>,
+    /// Substrings of the probe's `User-Agent`, ignoring case; checked only
+    /// for requests to `paths`
+    pub user_agents: Vec<String>,
+}
+
+impl HealthCheck {
+    pub fn new(paths: &[&str], user_agents: &[&str]) -> Self {
+        Self {
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+            user_agents: user_agents.iter().map(|agent| agent.to_string()).collect(),
+        }
+    }
+
+    pub fn matches(&self, request: &RequestInfo<'_>) -> bool {
+        let path = request.path.split_once('?').map_or(request.path, |(path, _)| path);
+        let path_matches = self.paths.iter().any(|probed| match probed.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == probed,
+        });
+        path_matches
+            && request.header("user-agent").is_some_and(|agent| {
+                let agent = agent.to_ascii_lowercase();
+                self.user_agents.iter().any(|probe| agent.contains(&probe.to_ascii_lowercase()))
+            })
+    }
+}
+
+/// Requests `check` lets through without counting them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exclusions {
+    /// Skip subrequests and internal redirects, e.g. `error_page`
+    /// handling, which would charge a client twice for one request
+    pub internal: bool,
+    pub health_checks: Vec<HealthCheck>,
+}
+
+impl Default for Exclusions {
+    /// Internal requests, and the probes of Kubernetes, AWS and Google
+    /// Cloud load balancers, Consul and common uptime monitors on the usual
+    /// health paths.
+    fn default() -> Self {
+        Self {
+            internal: true,
+            health_checks: vec![HealthCheck::new(
+                &["/health", "/health/*", "/healthz", "/livez", "/readyz", "/ping", "/status"],
+                &[
+                    "kube-probe/",
+                    "ELB-HealthChecker/",
+                    "GoogleHC/",
+                    "Consul Health Check",
+                    "UptimeRobot/",
+                    "Pingdom.com_bot",
+                ],
+            )],
+        }
+    }
+}
+
+impl Exclusions {
+    /// Exclude nothing.
+    pub fn none() -> Self {
+        Self {
+            internal: false,
+            health_checks: Vec::new(),
+        }
+    }
+
+    /// Why `request` is not limited, if it is excluded.
+    pub fn reason(&self, request: &RequestInfo<'_>) -> Option<&'static str> {
+        if self.internal && request.internal {
+            Some("internal")
+        } else if self.health_checks.iter().any(|check| check.matches(request)) {
+            Some("health_check")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probes_and_internal_requests_are_excluded() {
+        let exclusions = Exclusions::default();
+        let probe: &'static [(&str, &str)] = &[("User-Agent", "kube-probe/1.30")];
+        let browser: &'static [(&str, &str)] = &[("User-Agent", "Mozilla/5.0")];
+        let request = |path: &'static str, headers: &'static [(&'static str, &'static str)]| RequestInfo {
+            path,
+            headers,
+            ..RequestInfo::default()
+        };
+
+        assert_eq!(exclusions.reason(&request("/healthz?verbose", probe)), Some("health_check"));
+        assert_eq!(exclusions.reason(&request("/health/db", probe)), Some("health_check"));
+        // Copying the user agent is not enough off the health paths
+        assert_eq!(exclusions.reason(&request("/api/users", probe)), None);
+        assert_eq!(exclusions.reason(&request("/healthz", browser)), None);
+
+        let internal = RequestInfo { internal: true, ..RequestInfo::default() };
+        assert_eq!(exclusions.reason(&internal), Some("internal"));
+        assert_eq!(Exclusions::none().reason(&internal), None);
+    }
+}
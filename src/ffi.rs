@@ -0,0 +1,197 @@
+//! A C API over named limiters, so OpenResty Lua (through LuaJIT's FFI)
+//! and other scripted request flows consult the same counters as the
+//! module.
+//!
+//! ```lua
+//! local ffi = require "ffi"
+//! ffi.cdef[[
+//! int ngx_rate_limiter_check(const char *key, size_t key_len, const char *zone, size_t zone_len);
+//! ]]
+//! local lib = ffi.load("ngx_http_rate_limiter")
+//! if lib.ngx_rate_limiter_check(key, #key, "api", 3) == 1 then
+//!     return ngx.exit(429)
+//! end
+//! ```
+
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::os::raw::{c_char, c_int};
+use std::sync::{Arc, OnceLock, RwLock};
+use crate::RateLimiter;
+
+/// The request is allowed and was counted.
+pub const RATE_LIMITER_ALLOWED: c_int = 0;
+/// The key is over its limit.
+pub const RATE_LIMITER_LIMITED: c_int = 1;
+/// The zone is unknown, or the arguments are not valid UTF-8.
+pub const RATE_LIMITER_ERROR: c_int = -1;
+
+fn zones() -> &'static RwLock<HashMap<String, Arc<RateLimiter>>> {
+    static ZONES: OnceLock<RwLock<HashMap<String, Arc<RateLimiter>>>> = OnceLock::new();
+    ZONES.get_or_init(Default::default)
+}
+
+/// Make `limiter` reachable from the C API as `zone`, replacing any
+/// limiter registered under that name.
+pub fn register_zone(zone: &str, limiter: Arc<RateLimiter>) {
+    zones().write().unwrap_or_else(|e| e.into_inner()).insert(zone.to_string(), limiter);
+}
+
+/// The limiter registered as `zone`.
+pub fn zone(zone: &str) -> Option<Arc<RateLimiter>> {
+    zones().read().unwrap_or_else(|e| e.into_inner()).get(zone).cloned()
+}
+
+/// Decide one request for `key` in `zone`, counting it when it is allowed.
+///
+/// Called from the worker's event loop, so the decision blocks it: on the
+/// zone's worker runtime when `rate_limit_runtime` is on, otherwise on a
+/// runtime owned by the calling thread. Returns `RATE_LIMITER_ALLOWED`,
+/// `RATE_LIMITER_LIMITED` or `RATE_LIMITER_ERROR`.
+///
+/// # Safety
+///
+/// `key` and `zone_name` must point to `key_len` and `zone_len` readable
+/// bytes. Neither needs to be NUL terminated.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_rate_limiter_check(
+    key: *const c_char,
+    key_len: usize,
+    zone_name: *const c_char,
+    zone_len: usize,
+) -> c_int {
+    let (Some(key), Some(zone_name)) = (str_from_raw(key, key_len), str_from_raw(zone_name, zone_len)) else {
+        return RATE_LIMITER_ERROR;
+    };
+    let Some(limiter) = zone(zone_name) else {
+        log::warn!("Rate limit check for unknown zone {}", zone_name);
+        return RATE_LIMITER_ERROR;
+    };
+
+    let limited = match limiter.runtime.get() {
+        Some(runtime) => runtime.handle().block_on(limiter.is_rate_limited(key)),
+        None => match with_local_runtime(|runtime| runtime.block_on(limiter.is_rate_limited(key))) {
+            Ok(limited) => limited,
+            Err(e) => {
+                log::error!("Cannot start a runtime for rate limit checks: {}", e);
+                return RATE_LIMITER_ERROR;
+            }
+        },
+    };
+    if limited {
+        RATE_LIMITER_LIMITED
+    } else {
+        RATE_LIMITER_ALLOWED
+    }
+}
+
+/// Render the 429 body for a request to `path` in `zone` into `buf`,
+/// without allocating, and point `content_type` at its content type.
+///
+/// Returns the body's length. When that is more than `buf_len`, nothing
+/// was written and the call can be repeated with a larger buffer. Returns
+/// `RATE_LIMITER_ERROR` for an unknown zone or invalid arguments.
+///
+/// # Safety
+///
+/// `zone_name` and `path` must point to `zone_len` and `path_len` readable
+/// bytes, `buf` to `buf_len` writable bytes, and `content_type` and
+/// `content_type_len` to writable pointers or be null. The content type
+/// is static and not NUL terminated.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn ngx_rate_limiter_rejection(
+    zone_name: *const c_char,
+    zone_len: usize,
+    path: *const c_char,
+    path_len: usize,
+    retry_after: u64,
+    reset: u64,
+    buf: *mut u8,
+    buf_len: usize,
+    content_type: *mut *const c_char,
+    content_type_len: *mut usize,
+) -> isize {
+    let (Some(zone_name), Some(path)) = (str_from_raw(zone_name, zone_len), str_from_raw(path, path_len)) else {
+        return RATE_LIMITER_ERROR as isize;
+    };
+    if buf.is_null() && buf_len > 0 {
+        return RATE_LIMITER_ERROR as isize;
+    }
+    let Some(limiter) = zone(zone_name) else {
+        return RATE_LIMITER_ERROR as isize;
+    };
+
+    let config = limiter.config.load();
+    let page = config.limits.rejection_for(path);
+    if !content_type.is_null() && !content_type_len.is_null() {
+        *content_type = page.content_type().as_ptr().cast();
+        *content_type_len = page.content_type().len();
+    }
+    let out: &mut [u8] = if buf_len == 0 { &mut [] } else { std::slice::from_raw_parts_mut(buf, buf_len) };
+    let len = page
+        .render(retry_after, reset, out)
+        .unwrap_or_else(|| page.rendered_len(retry_after, reset));
+    len.min(isize::MAX as usize) as isize
+}
+
+unsafe fn str_from_raw<'a>(ptr: *const c_char, len: usize) -> Option<&'a str> {
+    if ptr.is_null() {
+        return None;
+    }
+    std::str::from_utf8(std::slice::from_raw_parts(ptr.cast::<u8>(), len)).ok()
+}
+
+/// Run `f` on a current-thread runtime kept per thread. Built on first use,
+/// after the fork from the master process.
+fn with_local_runtime<T>(f: impl FnOnce(&tokio::runtime::Runtime) -> T) -> std::io::Result<T> {
+    thread_local! {
+        static RUNTIME: OnceCell<tokio::runtime::Runtime> = const { OnceCell::new() };
+    }
+    RUNTIME.with(|cell| {
+        if cell.get().is_none() {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            let _ = cell.set(runtime);
+        }
+        Ok(f(cell.get().expect("runtime was just set")))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_check() {
+        let limiter = RateLimiter::with_storage(Arc::new(MemoryStorage::new()), 1, 60);
+        register_zone("ffi-test", Arc::new(limiter));
+
+        let check = |key: &str, zone: &str| unsafe {
+            ngx_rate_limiter_check(key.as_ptr().cast(), key.len(), zone.as_ptr().cast(), zone.len())
+        };
+        assert_eq!(check("client", "ffi-test"), RATE_LIMITER_ALLOWED);
+        assert_eq!(check("client", "ffi-test"), RATE_LIMITER_LIMITED);
+        assert_eq!(check("client", "missing"), RATE_LIMITER_ERROR);
+        assert_eq!(unsafe { ngx_rate_limiter_check(std::ptr::null(), 0, std::ptr::null(), 0) }, RATE_LIMITER_ERROR);
+
+        let mut buf = [0u8; 64];
+        let (mut content_type, mut content_type_len) = (std::ptr::null(), 0);
+        let len = unsafe {
+            ngx_rate_limiter_rejection(
+                "ffi-test".as_ptr().cast(),
+                8,
+                "/".as_ptr().cast(),
+                1,
+                7,
+                0,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut content_type,
+                &mut content_type_len,
+            )
+        };
+        assert_eq!(&buf[..len as usize], b"Too many requests. Retry in 7 seconds.\n");
+        assert_eq!(content_type_len, "text/plain; charset=utf-8".len());
+    }
+}
@@ -0,0 +1,257 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use crate::storage::KeyBuf;
+
+/// Where an address is, as far as limits care.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 code, upper case
+    pub country: Option<String>,
+    /// Autonomous system number
+    pub asn: Option<u32>,
+}
+
+/// Looks up the country and network of an address.
+pub trait GeoLookup: Send + Sync {
+    fn lookup(&self, address: IpAddr) -> GeoInfo;
+}
+
+/// Address ranges as published by iptoasn.com (`ip2asn-combined.tsv`):
+/// tab-separated start, end, AS number, country code and description.
+/// Unrouted ranges (AS 0) and countries written `None` are left out.
+#[derive(Debug, Default)]
+pub struct Ip2AsnTable {
+    /// Sorted by start; IPv4 addresses are stored IPv6-mapped
+    ranges: Vec<(u128, u128, u32, Option<[u8; 2]>)>,
+}
+
+fn address_bits(address: IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+impl Ip2AsnTable {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let tsv = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Self::parse(&tsv))
+    }
+
+    /// Parse the table, skipping malformed lines.
+    pub fn parse(tsv: &str) -> Self {
+        let mut ranges: Vec<_> = tsv
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let start: IpAddr = fields.next()?.trim().parse().ok()?;
+                let end: IpAddr = fields.next()?.trim().parse().ok()?;
+                let asn: u32 = fields.next()?.trim().parse().ok()?;
+                let country = match fields.next()?.trim().as_bytes() {
+                    [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+                        Some([a.to_ascii_uppercase(), b.to_ascii_uppercase()])
+                    }
+                    _ => None,
+                };
+                (asn != 0 || country.is_some()).then(|| (address_bits(start), address_bits(end), asn, country))
+            })
+            .collect();
+        ranges.sort_unstable_by_key(|range| range.0);
+        Self { ranges }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+impl GeoLookup for Ip2AsnTable {
+    fn lookup(&self, address: IpAddr) -> GeoInfo {
+        let bits = address_bits(address);
+        let index = self.ranges.partition_point(|range| range.0 <= bits);
+        match index.checked_sub(1).map(|index| self.ranges[index]) {
+            Some((_, end, asn, country)) if bits <= end => GeoInfo {
+                country: country.map(|code| String::from_utf8_lossy(&code).into_owned()),
+                asn: (asn != 0).then_some(asn),
+            },
+            _ => GeoInfo::default(),
+        }
+    }
+}
+
+/// MaxMind GeoIP2 or GeoLite2 databases: a Country (or City) database and
+/// an ASN database, either optional.
+#[cfg(feature = "geoip")]
+pub struct MaxMindLookup {
+    country: Option<maxminddb::Reader<Vec<u8>>>,
+    asn: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+#[cfg(feature = "geoip")]
+impl MaxMindLookup {
+    pub fn open(country: Option<&Path>, asn: Option<&Path>) -> Result<Self, String> {
+        let open = |path: &Path| {
+            maxminddb::Reader::open_readfile(path).map_err(|e| format!("{}: {}", path.display(), e))
+        };
+        Ok(Self {
+            country: country.map(open).transpose()?,
+            asn: asn.map(open).transpose()?,
+        })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl GeoLookup for MaxMindLookup {
+    fn lookup(&self, address: IpAddr) -> GeoInfo {
+        let country = self.country.as_ref().and_then(|reader| {
+            let record: maxminddb::geoip2::Country = reader.lookup(address).ok()?;
+            record.country?.iso_code.map(str::to_string)
+        });
+        let asn = self.asn.as_ref().and_then(|reader| {
+            let record: maxminddb::geoip2::Asn = reader.lookup(address).ok()?;
+            record.autonomous_system_number
+        });
+        GeoInfo { country, asn }
+    }
+}
+
+/// A collective ceiling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Cap {
+    pub requests: u32,
+    pub window: u32,
+}
+
+/// Ceilings shared by every client in a country or autonomous system:
+///
+/// ```json
+/// {"countries": {"XX": {"requests": 5000, "window": 60}},
+///  "asns": {"64500": {"requests": 2000, "window": 60}},
+///  "every_asn": {"requests": 20000, "window": 60}}
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AggregateConfig {
+    #[serde(default)]
+    pub countries: HashMap<String, Cap>,
+    #[serde(default)]
+    pub asns: HashMap<u32, Cap>,
+    /// Ceiling for each country not listed
+    #[serde(default)]
+    pub every_country: Option<Cap>,
+    /// Ceiling for each autonomous system not listed
+    #[serde(default)]
+    pub every_asn: Option<Cap>,
+}
+
+/// Which collective ceiling a counter is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Country,
+    Asn,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Country => "country",
+            Scope::Asn => "asn",
+        }
+    }
+}
+
+/// One aggregate counter a request counts against.
+#[derive(Debug, Clone)]
+pub struct AggregateKey {
+    pub scope: Scope,
+    /// `geo:cc:{country}` or `geo:as:{asn}`
+    pub key: KeyBuf,
+    pub cap: Cap,
+}
+
+/// Collective ceilings per country and per autonomous system, so a
+/// botnet spread over thousands of addresses in one network still hits a
+/// cap. A request is allowed only while its client, country and network
+/// are all under their limits, and then counts against each.
+pub struct AggregateCaps {
+    lookup: Arc<dyn GeoLookup>,
+    config: AggregateConfig,
+}
+
+impl std::fmt::Debug for AggregateCaps {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AggregateCaps").field("config", &self.config).finish()
+    }
+}
+
+impl AggregateCaps {
+    pub fn new(lookup: Arc<dyn GeoLookup>, mut config: AggregateConfig) -> Self {
+        config.countries = config
+            .countries
+            .into_iter()
+            .map(|(country, cap)| (country.to_ascii_uppercase(), cap))
+            .collect();
+        Self { lookup, config }
+    }
+
+    /// The aggregate counters for a request key; none for keys that are
+    /// not an address, or addresses without a matching ceiling.
+    pub fn keys(&self, key: &str) -> Vec<AggregateKey> {
+        let Ok(address) = key.parse::<IpAddr>() else {
+            return Vec::new();
+        };
+        let info = self.lookup.lookup(address);
+        let mut keys = Vec::with_capacity(2);
+        if let Some(country) = info.country {
+            if let Some(cap) = self.config.countries.get(&country).or(self.config.every_country.as_ref()) {
+                let mut key = KeyBuf::new();
+                let _ = write!(key, "geo:cc:{}", country);
+                keys.push(AggregateKey { scope: Scope::Country, key, cap: *cap });
+            }
+        }
+        if let Some(asn) = info.asn {
+            if let Some(cap) = self.config.asns.get(&asn).or(self.config.every_asn.as_ref()) {
+                let mut key = KeyBuf::new();
+                let _ = write!(key, "geo:as:{}", asn);
+                keys.push(AggregateKey { scope: Scope::Asn, key, cap: *cap });
+            }
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_and_aggregate_keys() {
+        let table = Ip2AsnTable::parse(
+            "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+             1.0.1.0\t1.0.3.255\t0\tNone\tNot routed\n\
+             2001:db8::\t2001:db8::ffff\t64500\tjp\tEXAMPLE\n\
+             garbage\n",
+        );
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.lookup("1.0.0.7".parse().unwrap()).asn, Some(13335));
+        assert_eq!(table.lookup("1.0.2.1".parse().unwrap()), GeoInfo::default());
+        assert_eq!(table.lookup("2001:db8::1".parse().unwrap()).country.as_deref(), Some("JP"));
+
+        let config: AggregateConfig = serde_json::from_str(
+            r#"{"countries": {"us": {"requests": 10, "window": 60}}, "every_asn": {"requests": 5, "window": 60}}"#,
+        )
+        .unwrap();
+        let caps = AggregateCaps::new(Arc::new(table), config);
+        let keys: Vec<String> = caps.keys("1.0.0.7").iter().map(|key| key.key.to_string()).collect();
+        assert_eq!(keys, vec!["geo:cc:US", "geo:as:13335"]);
+        assert_eq!(caps.keys("2001:db8::1").len(), 1);
+        assert!(caps.keys("api-key").is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+use serde::Deserialize;
+use std::fmt::Write;
+use crate::storage::{KeyBuf, StorageBackend};
+
+/// What an operation does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationKind {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+/// The operation a GraphQL request runs, and how expensive it looks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operation {
+    pub kind: OperationKind,
+    pub name: Option<String>,
+    /// Deepest nesting of selection sets in the document
+    pub depth: u32,
+    /// Fields selected in the document, fragments included
+    pub fields: u32,
+}
+
+impl Operation {
+    /// Estimated cost: one per field selected.
+    pub fn cost(&self) -> u64 {
+        u64::from(self.fields.max(1))
+    }
+}
+
+#[derive(Deserialize)]
+struct Request<'a> {
+    #[serde(borrow)]
+    query: std::borrow::Cow<'a, str>,
+    #[serde(default, rename = "operationName")]
+    operation_name: Option<String>,
+}
+
+/// Parse a request body: JSON with `query` and `operationName`, or a bare
+/// document as sent with `application/graphql`. Returns `None` for bodies
+/// that are neither.
+pub fn parse(body: &[u8]) -> Option<Operation> {
+    if body.iter().find(|c| !c.is_ascii_whitespace()) == Some(&b'{') {
+        if let Ok(request) = serde_json::from_slice::<Request>(body) {
+            return analyze(&request.query, request.operation_name);
+        }
+    }
+    analyze(std::str::from_utf8(body).ok()?, None)
+}
+
+/// Walk the document's tokens once, skipping strings, comments and
+/// arguments, to find the operation and count fields and depth.
+fn analyze(document: &str, operation_name: Option<String>) -> Option<Operation> {
+    let bytes = document.as_bytes();
+    let (mut depth, mut max_depth, mut fields, mut parens) = (0u32, 0u32, 0u32, 0u32);
+    // Top-level definitions seen so far: (keyword, name)
+    let mut operations: Vec<(&str, Option<&str>)> = Vec::new();
+    let mut pending: Option<(&str, Option<&str>)> = None;
+    let mut directive = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'"' => {
+                let block = bytes[i..].starts_with(b"\"\"\"");
+                i += if block { 3 } else { 1 };
+                while i < bytes.len() {
+                    if block && bytes[i..].starts_with(b"\"\"\"") {
+                        i += 2;
+                        break;
+                    }
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    } else if !block && bytes[i] == b'"' {
+                        break;
+                    }
+                    i += 1;
+                }
+            }
+            b'(' => parens += 1,
+            b')' => parens = parens.saturating_sub(1),
+            b'{' if parens == 0 => {
+                if depth == 0 {
+                    operations.push(pending.take().unwrap_or(("query", None)));
+                }
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            b'}' if parens == 0 => depth = depth.saturating_sub(1),
+            b'@' => directive = true,
+            c if c == b'_' || c.is_ascii_alphabetic() => {
+                let start = i;
+                while i + 1 < bytes.len() && (bytes[i + 1] == b'_' || bytes[i + 1].is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                let word = &document[start..=i];
+                if std::mem::take(&mut directive) || parens > 0 {
+                    // Directive names and arguments select no fields
+                } else if depth == 0 {
+                    match pending {
+                        Some((_, None)) if word != "on" => pending = pending.map(|(kind, _)| (kind, Some(word))),
+                        None => pending = Some((word, None)),
+                        _ => {}
+                    }
+                } else {
+                    let alias = bytes[i + 1..].iter().find(|c| !c.is_ascii_whitespace()) == Some(&b':');
+                    if !alias && word != "on" {
+                        fields += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    let (keyword, name) = match &operation_name {
+        Some(wanted) => *operations.iter().find(|(_, name)| *name == Some(wanted.as_str()))?,
+        None => *operations.iter().find(|(keyword, _)| *keyword != "fragment")?,
+    };
+    let kind = match keyword {
+        "query" => OperationKind::Query,
+        "mutation" => OperationKind::Mutation,
+        "subscription" => OperationKind::Subscription,
+        _ => return None,
+    };
+    Some(Operation {
+        kind,
+        name: name.map(str::to_string),
+        depth: max_depth,
+        fields,
+    })
+}
+
+/// Limits for GraphQL requests.
+#[derive(Debug, Clone)]
+pub struct GraphQlOptions {
+    /// Path the GraphQL endpoint is served on
+    pub path: String,
+    /// Cost a key may spend per `window` seconds
+    pub budget: u64,
+    pub window: u32,
+    /// Deeper documents are refused outright
+    pub max_depth: Option<u32>,
+}
+
+impl Default for GraphQlOptions {
+    fn default() -> Self {
+        Self {
+            path: "/graphql".to_string(),
+            budget: 1000,
+            window: 60,
+            max_depth: Some(15),
+        }
+    }
+}
+
+impl GraphQlOptions {
+    /// Whether `path` is the GraphQL endpoint, ignoring the query string.
+    pub fn serves(&self, path: &str) -> bool {
+        path.split_once('?').map_or(path, |(path, _)| path) == self.path
+    }
+
+    /// Charge `operation` to `key` and return whether it is refused: too
+    /// deep, or over the key's budget. Costs are added with `increment_by`
+    /// under `graphql:{key}`; a backend error lets the request through.
+    pub async fn charge(&self, storage: &dyn StorageBackend, key: &str, operation: &Operation) -> bool {
+        if self.max_depth.is_some_and(|max| operation.depth > max) {
+            metrics::counter!("rate_limiter_graphql_rejections_total", "reason" => "depth").increment(1);
+            return true;
+        }
+        let mut cost_key = KeyBuf::new();
+        let _ = write!(cost_key, "graphql:{}", key);
+        match storage.increment_by(&cost_key, operation.cost(), self.window).await {
+            Ok(spent) if spent.count > self.budget => {
+                metrics::counter!("rate_limiter_graphql_rejections_total", "reason" => "cost").increment(1);
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                log::warn!("Charging GraphQL cost for {} failed: {}", key, e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operations_are_named_and_measured() {
+        let body = br#"{"query": "query Feed { me { name friends(first: 10) { name posts { title } } } } mutation Like($id: ID!) { like(id: $id) { ok } }", "operationName": "Like"}"#;
+        let operation = parse(body).unwrap();
+        assert_eq!((operation.kind, operation.name.as_deref()), (OperationKind::Mutation, Some("Like")));
+        assert_eq!((operation.depth, operation.fields), (4, 8));
+
+        let shorthand = parse(b"{ user(id: \"}{\") { n: name @include(if: true) } } # }").unwrap();
+        assert_eq!((shorthand.kind, shorthand.name), (OperationKind::Query, None));
+        assert_eq!((shorthand.depth, shorthand.fields), (2, 2));
+
+        let fragments = parse(b"fragment F on User { name } query Q { me { ...F } }").unwrap();
+        assert_eq!(fragments.name.as_deref(), Some("Q"));
+        assert!(parse(b"\xff").is_none());
+    }
+}
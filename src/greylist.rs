@@ -0,0 +1,149 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+use crate::storage::{hash_tag, StorageBackend};
+
+/// What happens to a key's first request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GreylistAction {
+    /// Answer 429 with `Retry-After`; retries that wait that long pass
+    Reject,
+    /// Hold the request this long, then let it through
+    Delay(Duration),
+}
+
+/// Settings for `Greylist`.
+#[derive(Debug, Clone)]
+pub struct GreylistOptions {
+    pub action: GreylistAction,
+    /// How long a rejected client must wait before retrying
+    pub retry_after: Duration,
+    /// How long a key counts as seen after its last request
+    pub remember: Duration,
+}
+
+impl Default for GreylistOptions {
+    fn default() -> Self {
+        Self {
+            action: GreylistAction::Reject,
+            retry_after: Duration::from_secs(5),
+            remember: Duration::from_secs(86400),
+        }
+    }
+}
+
+/// Outcome of `Greylist::check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GreylistVerdict {
+    Pass,
+    Reject { retry_after: Duration },
+    Delay(Duration),
+}
+
+/// Turns away the first request of keys never seen before, once.
+///
+/// Real clients honour `Retry-After` and get through on their retry;
+/// simple bots retry at once and keep being rejected, or never retry.
+/// Keys are remembered in the backend, so every worker and node agrees on
+/// who has been seen: `grey:{key}` for `remember`, and `grey:{key}:wait`
+/// while a rejected client must wait. The key is their hash tag, so on
+/// Redis Cluster both live in one slot. Keys that passed are also cached in
+/// the worker so they cost no backend calls. A backend error lets the
+/// request through.
+pub struct Greylist {
+    storage: Arc<dyn StorageBackend>,
+    options: GreylistOptions,
+    /// Key to the clock time it stops being known in this worker
+    passed: DashMap<String, Duration>,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for Greylist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Greylist").field("options", &self.options).finish()
+    }
+}
+
+impl Greylist {
+    pub fn new(storage: Arc<dyn StorageBackend>, options: GreylistOptions) -> Self {
+        Self {
+            storage,
+            options,
+            passed: DashMap::new(),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn pass(&self, key: &str, now: Duration) -> GreylistVerdict {
+        self.passed.insert(key.to_string(), now + self.options.remember);
+        GreylistVerdict::Pass
+    }
+
+    pub async fn check(&self, key: &str) -> GreylistVerdict {
+        let now = self.clock.now();
+        if self.passed.get(key).is_some_and(|until| *until > now) {
+            return GreylistVerdict::Pass;
+        }
+
+        let seen_key = format!("grey:{}", hash_tag(key));
+        let wait_key = format!("{}:wait", seen_key);
+        let remember = self.options.remember.as_secs().clamp(1, u64::from(u32::MAX)) as u32;
+        let seen = match self.storage.increment(&seen_key, remember).await {
+            Ok(seen) => seen.count,
+            Err(e) => {
+                log::debug!("Greylist lookup for {} failed, letting it through: {}", key, e);
+                return GreylistVerdict::Pass;
+            }
+        };
+
+        let retry_after = self.options.retry_after;
+        if seen == 1 {
+            metrics::counter!("rate_limiter_greylisted_total").increment(1);
+            return match self.options.action {
+                GreylistAction::Reject => {
+                    let wait = retry_after.as_secs().clamp(1, u64::from(u32::MAX)) as u32;
+                    let _ = self.storage.increment(&wait_key, wait).await;
+                    GreylistVerdict::Reject { retry_after }
+                }
+                GreylistAction::Delay(delay) => {
+                    self.pass(key, now);
+                    GreylistVerdict::Delay(delay)
+                }
+            };
+        }
+        match self.storage.get(&wait_key).await {
+            // Retried before `Retry-After` was up
+            Ok(waiting) if waiting > 0 => GreylistVerdict::Reject { retry_after },
+            _ => self.pass(key, now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_first_request_is_turned_away_once() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1_700_000_000)));
+        let storage = Arc::new(MemoryStorage::new().with_clock(clock.clone()));
+        let greylist = Greylist::new(storage, GreylistOptions::default()).with_clock(clock.clone());
+        let rejected = GreylistVerdict::Reject { retry_after: Duration::from_secs(5) };
+
+        assert_eq!(greylist.check("client").await, rejected);
+        // An impatient retry is turned away again
+        assert_eq!(greylist.check("client").await, rejected);
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(greylist.check("client").await, GreylistVerdict::Pass);
+        assert_eq!(greylist.check("client").await, GreylistVerdict::Pass);
+        assert_eq!(greylist.check("other").await, rejected);
+    }
+}
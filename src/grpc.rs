@@ -0,0 +1,82 @@
+use std::fmt::Write;
+use std::time::Duration;
+use crate::storage::KeyBuf;
+use crate::tenant::RequestInfo;
+
+/// gRPC status code for a request refused for want of quota.
+pub const RESOURCE_EXHAUSTED: u32 = 8;
+
+/// Trailer telling gRPC clients how long to wait before retrying; clients
+/// with a retry policy honour it instead of their own backoff.
+pub const PUSHBACK_TRAILER: &str = "grpc-retry-pushback-ms";
+
+/// The service and method of a gRPC call, from its `:path` of
+/// `/{package}.{Service}/{Method}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrpcMethod<'a> {
+    pub service: &'a str,
+    pub method: &'a str,
+}
+
+impl<'a> GrpcMethod<'a> {
+    pub fn parse(path: &'a str) -> Option<Self> {
+        let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+        if service.is_empty() || method.is_empty() || method.contains(['/', '?']) {
+            return None;
+        }
+        Some(Self { service, method })
+    }
+}
+
+/// Whether `request` is a gRPC call, by its content type.
+pub fn is_grpc(request: &RequestInfo<'_>) -> bool {
+    request
+        .header("content-type")
+        .is_some_and(|content_type| content_type.starts_with("application/grpc"))
+}
+
+/// `grpc:{service}/{method}:{key}`, so each method of a client is counted
+/// on its own.
+pub fn method_key(key: &str, method: &GrpcMethod<'_>) -> KeyBuf {
+    let mut buf = KeyBuf::new();
+    let _ = write!(buf, "grpc:{}/{}:{}", method.service, method.method, key);
+    buf
+}
+
+/// Headers of a trailers-only gRPC response refusing a call with
+/// `RESOURCE_EXHAUSTED`, sent with HTTP status 200 as gRPC requires in
+/// place of a 429. Values are formatted on the stack.
+pub fn rejection_trailers(retry_after: Duration) -> [(&'static str, KeyBuf); 4] {
+    let mut status = KeyBuf::new();
+    let _ = write!(status, "{}", RESOURCE_EXHAUSTED);
+    let mut pushback = KeyBuf::new();
+    let _ = write!(pushback, "{}", retry_after.as_millis());
+    [
+        ("content-type", KeyBuf::from("application/grpc")),
+        ("grpc-status", status),
+        ("grpc-message", KeyBuf::from("rate limit exceeded")),
+        (PUSHBACK_TRAILER, pushback),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_methods_keys_and_trailers() {
+        let method = GrpcMethod::parse("/search.v1.Search/Query").unwrap();
+        assert_eq!((method.service, method.method), ("search.v1.Search", "Query"));
+        assert!(GrpcMethod::parse("/index.html").is_none());
+        assert!(GrpcMethod::parse("/a/b/c").is_none());
+        assert_eq!(method_key("192.0.2.7", &method).as_str(), "grpc:search.v1.Search/Query:192.0.2.7");
+
+        let headers = [("content-type", "application/grpc+proto")];
+        assert!(is_grpc(&RequestInfo { headers: &headers, ..RequestInfo::default() }));
+        assert!(!is_grpc(&RequestInfo::default()));
+
+        let trailers = rejection_trailers(Duration::from_secs(3));
+        assert_eq!(trailers[1].1.as_str(), "8");
+        assert_eq!((trailers[3].0, trailers[3].1.as_str()), ("grpc-retry-pushback-ms", "3000"));
+    }
+}
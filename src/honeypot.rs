@@ -0,0 +1,111 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+
+/// Paths no real client requests, and what requesting one costs.
+#[derive(Debug, Clone)]
+pub struct HoneypotOptions {
+    /// Exact paths such as `/wp-login.php`, or prefixes ending in `*` such
+    /// as `/phpmyadmin/*`; matched ignoring case and the query string
+    pub paths: Vec<String>,
+    pub ban_for: Duration,
+}
+
+impl HoneypotOptions {
+    pub fn new(paths: Vec<String>) -> Self {
+        Self { paths, ban_for: Duration::from_secs(3600) }
+    }
+}
+
+/// Bans keys that request a honeypot path, e.g. `/wp-login.php` on a site
+/// that is not WordPress, for `ban_for` on their first such request.
+///
+/// Bans are kept in the worker, so they need no backend calls; the
+/// limiter also hands them to its `BanExporter`, when it has one, to be
+/// shared through the firewall.
+pub struct Honeypot {
+    options: HoneypotOptions,
+    /// Key to the clock time its ban ends
+    banned: DashMap<String, Duration>,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for Honeypot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Honeypot")
+            .field("options", &self.options)
+            .field("banned", &self.banned.len())
+            .finish()
+    }
+}
+
+impl Honeypot {
+    pub fn new(options: HoneypotOptions) -> Self {
+        Self {
+            options,
+            banned: DashMap::new(),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn ban_for(&self) -> Duration {
+        self.options.ban_for
+    }
+
+    /// Whether `path` is a honeypot.
+    pub fn is_trap(&self, path: &str) -> bool {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        self.options.paths.iter().any(|trap| match trap.strip_suffix('*') {
+            Some(prefix) => path.get(..prefix.len()).is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+            None => path.eq_ignore_ascii_case(trap),
+        })
+    }
+
+    /// Ban `key`. Returns false when it was banned already.
+    pub fn ban(&self, key: &str) -> bool {
+        let now = self.clock.now();
+        if self.is_banned(key) {
+            return false;
+        }
+        // Ended bans are dropped as new ones come in
+        self.banned.retain(|_, until| *until > now);
+        self.banned.insert(key.to_string(), now + self.options.ban_for);
+        true
+    }
+
+    pub fn is_banned(&self, key: &str) -> bool {
+        let now = self.clock.now();
+        self.banned.get(key).is_some_and(|until| *until > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_trap_paths_ban_for_a_while() {
+        let clock = Arc::new(ManualClock::default());
+        let honeypot = Honeypot::new(HoneypotOptions::new(vec!["/wp-login.php".into(), "/phpmyadmin/*".into()]))
+            .with_clock(clock.clone());
+
+        assert!(honeypot.is_trap("/WP-Login.php?redirect_to=/"));
+        assert!(honeypot.is_trap("/phpMyAdmin/index.php"));
+        assert!(!honeypot.is_trap("/wp-login.php.html"));
+        assert!(!honeypot.is_trap("/"));
+
+        assert!(honeypot.ban("192.0.2.7"));
+        assert!(!honeypot.ban("192.0.2.7"));
+        assert!(honeypot.is_banned("192.0.2.7"));
+        assert!(!honeypot.is_banned("192.0.2.8"));
+        clock.advance(Duration::from_secs(3601));
+        assert!(!honeypot.is_banned("192.0.2.7"));
+    }
+}
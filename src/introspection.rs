@@ -0,0 +1,278 @@
+use async_trait::async_trait;
+use dashmap::DashMap;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt::{Debug, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+use crate::storage::KeyBuf;
+use crate::tenant::RequestInfo;
+
+/// An introspection response (RFC 7662), of which only the fields the
+/// limiter uses are read.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Space-separated scopes granted to the token
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// Expiry of the token, in seconds since the epoch
+    #[serde(default)]
+    pub exp: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IntrospectionError {
+    #[error("Introspection endpoint unavailable: {0}")]
+    Unavailable(String),
+    #[error("Invalid introspection response: {0}")]
+    InvalidResponse(String),
+}
+
+/// Resolves bearer tokens to what they were issued for.
+#[async_trait]
+pub trait Introspector: Debug + Send + Sync {
+    async fn introspect(&self, token: &str) -> Result<Introspection, IntrospectionError>;
+}
+
+/// Asks an OAuth2 authorization server's introspection endpoint.
+///
+/// Every lookup POSTs `token={token}&token_type_hint=access_token` to the
+/// endpoint, authenticated as the limiter's own client with HTTP Basic.
+#[cfg(feature = "oauth2")]
+#[derive(Debug)]
+pub struct HttpIntrospector {
+    client: reqwest::Client,
+    url: String,
+    client_id: String,
+    client_secret: String,
+}
+
+#[cfg(feature = "oauth2")]
+impl HttpIntrospector {
+    /// An introspector that gives the endpoint `timeout` to answer,
+    /// including connecting.
+    pub fn new(url: &str, client_id: &str, client_secret: &str, timeout: Duration) -> Result<Self, IntrospectionError> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .connect_timeout(timeout)
+            .build()
+            .map_err(|e| IntrospectionError::Unavailable(e.to_string()))?;
+        Ok(Self {
+            client,
+            url: url.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "oauth2")]
+#[async_trait]
+impl Introspector for HttpIntrospector {
+    async fn introspect(&self, token: &str) -> Result<Introspection, IntrospectionError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("token", token), ("token_type_hint", "access_token")])
+            .send()
+            .await
+            .map_err(|e| IntrospectionError::Unavailable(e.to_string()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(IntrospectionError::Unavailable(format!("Introspection returned {}", status)));
+        }
+        response
+            .json()
+            .await
+            .map_err(|e| IntrospectionError::InvalidResponse(e.to_string()))
+    }
+}
+
+/// How long introspection results are kept.
+#[derive(Debug, Clone)]
+pub struct IntrospectionOptions {
+    /// How long an active token's client is kept, at most until the token
+    /// expires
+    pub cache_ttl: Duration,
+    /// How long an inactive token is remembered as such, so clients
+    /// replaying a revoked token do not reach the endpoint every time
+    pub inactive_ttl: Duration,
+}
+
+impl Default for IntrospectionOptions {
+    fn default() -> Self {
+        Self {
+            cache_ttl: Duration::from_secs(300),
+            inactive_ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The client a bearer token was issued to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenClient {
+    pub client_id: String,
+    /// Space-separated scopes granted to the token
+    pub scope: String,
+}
+
+impl TokenClient {
+    /// `client:{client_id}`, the key a client's requests are counted under
+    /// whichever address they come from.
+    pub fn key(&self) -> KeyBuf {
+        let mut buf = KeyBuf::new();
+        let _ = write!(buf, "client:{}", self.client_id);
+        buf
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split_ascii_whitespace().any(|granted| granted == scope)
+    }
+}
+
+/// Identifies clients by their bearer tokens through an `Introspector`.
+///
+/// Results are cached per token for `cache_ttl`, so the endpoint sees one
+/// call per token and worker rather than one per request. The cache is
+/// keyed by SHA-256 of the token, so tokens themselves are not kept in
+/// memory. A failed introspection is not cached, and the request is
+/// limited by its own key as if it carried no token.
+pub struct TokenClients {
+    introspector: Arc<dyn Introspector>,
+    /// Token digest to the client, none for inactive tokens, and the clock
+    /// time the entry expires
+    cache: DashMap<[u8; 32], (Option<Arc<TokenClient>>, Duration)>,
+    options: IntrospectionOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for TokenClients {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenClients")
+            .field("introspector", &self.introspector)
+            .field("cached", &self.cache.len())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl TokenClients {
+    pub fn new(introspector: Arc<dyn Introspector>, options: IntrospectionOptions) -> Self {
+        Self {
+            introspector,
+            cache: DashMap::new(),
+            options,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The client of the bearer token in `request`'s `Authorization`
+    /// header, if it has an active one.
+    pub async fn identify(&self, request: &RequestInfo<'_>) -> Option<Arc<TokenClient>> {
+        let token = bearer_token(request)?;
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        let now = self.clock.now();
+        if let Some(cached) = self.cache.get(&digest).filter(|cached| cached.1 > now) {
+            return cached.0.clone();
+        }
+
+        let introspection = match self.introspector.introspect(token).await {
+            Ok(introspection) => introspection,
+            Err(e) => {
+                metrics::counter!("rate_limiter_introspection_errors_total").increment(1);
+                log::warn!("Token introspection failed: {}", e);
+                return None;
+            }
+        };
+        let client = match introspection.client_id {
+            Some(client_id) if introspection.active => Some(Arc::new(TokenClient {
+                client_id,
+                scope: introspection.scope.unwrap_or_default(),
+            })),
+            _ => None,
+        };
+        let ttl = match (&client, introspection.exp) {
+            (None, _) => self.options.inactive_ttl,
+            (Some(_), Some(exp)) => {
+                let left = exp.saturating_sub(clock::system().unix_secs());
+                self.options.cache_ttl.min(Duration::from_secs(left))
+            }
+            (Some(_), None) => self.options.cache_ttl,
+        };
+        self.cache.insert(digest, (client.clone(), now + ttl));
+        client
+    }
+
+    /// Forget results that have run out.
+    pub fn purge(&self) {
+        let now = self.clock.now();
+        self.cache.retain(|_, cached| cached.1 > now);
+    }
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub fn bearer_token<'a>(request: &RequestInfo<'a>) -> Option<&'a str> {
+    let (scheme, token) = request.header("authorization")?.trim().split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Default)]
+    struct Endpoint {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Introspector for Endpoint {
+        async fn introspect(&self, token: &str) -> Result<Introspection, IntrospectionError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(Introspection {
+                active: token != "revoked",
+                client_id: Some("reporting".to_string()),
+                scope: Some("read admin".to_string()),
+                exp: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tokens_resolve_to_cached_clients() {
+        let clock = Arc::new(ManualClock::default());
+        let endpoint = Arc::new(Endpoint::default());
+        let clients = TokenClients::new(endpoint.clone(), IntrospectionOptions::default()).with_clock(clock.clone());
+        let request = |headers: &'static [(&'static str, &'static str)]| RequestInfo {
+            headers,
+            ..RequestInfo::default()
+        };
+
+        let client = clients.identify(&request(&[("Authorization", "Bearer abc")])).await.unwrap();
+        assert_eq!((client.key().as_str(), client.has_scope("admin")), ("client:reporting", true));
+        assert!(!client.has_scope("adm"));
+        assert!(clients.identify(&request(&[("authorization", "bearer abc")])).await.is_some());
+        assert!(clients.identify(&request(&[("Authorization", "Bearer revoked")])).await.is_none());
+        assert!(clients.identify(&request(&[("Authorization", "Basic abc")])).await.is_none());
+        assert_eq!(endpoint.calls.load(Ordering::SeqCst), 2);
+
+        clock.advance(Duration::from_secs(301));
+        clients.purge();
+        assert!(clients.identify(&request(&[("Authorization", "Bearer abc")])).await.is_some());
+        assert_eq!(endpoint.calls.load(Ordering::SeqCst), 3);
+    }
+}
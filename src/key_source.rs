@@ -0,0 +1,133 @@
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use crate::basic_auth;
+use crate::client_cert::ClientCertOptions;
+use crate::query_key::QueryKey;
+use crate::storage::KeyBuf;
+use crate::tenant::RequestInfo;
+
+/// Where a request's key can come from. A limiter tries its sources in
+/// order and falls back to the key it was given, usually the address.
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    /// A verified client certificate, `cert:{identity}`
+    ClientCert(ClientCertOptions),
+    /// The username of `Authorization: Basic` credentials, `user:{username}`
+    BasicAuth,
+    /// A query parameter, `{param}:{value}`
+    Query(QueryKey),
+    /// A header such as `X-API-Key`, `{header}:{value}` with the name
+    /// lowercased
+    Header(String),
+    /// A cookie such as a session id, `cookie:{name}:{hash}`
+    Cookie(String),
+    /// A hash of the values of the headers, e.g. `User-Agent` and
+    /// `Accept-Language`, `fp:{hash}`; requests without any of them have
+    /// no fingerprint
+    Fingerprint(Vec<String>),
+}
+
+impl KeySource {
+    /// Name of the source, for logs and the `source` label of
+    /// `rate_limiter_key_source_total`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeySource::ClientCert(_) => "client_cert",
+            KeySource::BasicAuth => "basic_auth",
+            KeySource::Query(_) => "query",
+            KeySource::Header(_) => "header",
+            KeySource::Cookie(_) => "cookie",
+            KeySource::Fingerprint(_) => "fingerprint",
+        }
+    }
+
+    /// The key this source gives `request`, if it has one.
+    ///
+    /// Cookies and fingerprints are hashed rather than kept, so session
+    /// ids never reach the backend, and the same session or client maps to
+    /// the same key on every worker.
+    pub fn key(&self, request: &RequestInfo<'_>) -> Option<KeyBuf> {
+        match self {
+            KeySource::ClientCert(options) => options.key(request),
+            KeySource::BasicAuth => basic_auth::username_key(request),
+            KeySource::Query(query_key) => query_key.key(request),
+            KeySource::Header(name) => {
+                let value = request.header(name)?.trim();
+                if value.is_empty() {
+                    return None;
+                }
+                let mut key = KeyBuf::new();
+                let _ = write!(key, "{}:{}", name.to_ascii_lowercase(), value);
+                Some(key)
+            }
+            KeySource::Cookie(name) => {
+                let value = request
+                    .header("cookie")?
+                    .split(';')
+                    .filter_map(|cookie| cookie.trim().split_once('='))
+                    .find(|(cookie, value)| *cookie == name.as_str() && !value.is_empty())?
+                    .1;
+                let mut key = KeyBuf::new();
+                let _ = write!(key, "cookie:{}:", name);
+                Some(hashed(key, &[value]))
+            }
+            KeySource::Fingerprint(headers) => {
+                let values: Vec<&str> = headers.iter().map(|header| request.header(header).unwrap_or("")).collect();
+                if values.iter().all(|value| value.is_empty()) {
+                    return None;
+                }
+                Some(hashed(KeyBuf::from("fp:"), &values))
+            }
+        }
+    }
+}
+
+/// `key` followed by the first 16 bytes of the SHA-256 of `values`, in
+/// hex.
+fn hashed(mut key: KeyBuf, values: &[&str]) -> KeyBuf {
+    let mut hasher = Sha256::new();
+    for value in values {
+        hasher.update(value.as_bytes());
+        hasher.update(b"\n");
+    }
+    for byte in &hasher.finalize()[..16] {
+        let _ = write!(key, "{:02x}", byte);
+    }
+    key
+}
+
+/// The key of `request` from the first of `sources` that gives one, and
+/// the name of that source; `fallback` and `address` when none does.
+pub fn resolve(sources: &[KeySource], request: &RequestInfo<'_>, fallback: &str) -> (KeyBuf, &'static str) {
+    sources
+        .iter()
+        .find_map(|source| Some((source.key(request)?, source.name())))
+        .unwrap_or_else(|| (KeyBuf::from(fallback), "address"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sources_fall_back_in_order() {
+        let sources = [
+            KeySource::Header("X-API-Key".to_string()),
+            KeySource::Cookie("session".to_string()),
+            KeySource::Fingerprint(vec!["User-Agent".to_string(), "Accept-Language".to_string()]),
+        ];
+        let resolved = |headers: &'static [(&'static str, &'static str)]| {
+            let (key, source) = resolve(&sources, &RequestInfo { headers, ..RequestInfo::default() }, "192.0.2.1");
+            (key.as_str().to_string(), source)
+        };
+
+        let api_key = resolved(&[("X-API-Key", "k-123"), ("Cookie", "session=s1")]);
+        assert_eq!(api_key, ("x-api-key:k-123".to_string(), "header"));
+        let (session, source) = resolved(&[("Cookie", "theme=dark; session=s1")]);
+        assert_eq!((session.len(), source), ("cookie:session:".len() + 32, "cookie"));
+        assert_eq!(resolved(&[("Cookie", "session=s1")]).0, session);
+        assert_eq!(resolved(&[("User-Agent", "curl/8.0")]).1, "fingerprint");
+        assert_ne!(resolved(&[("User-Agent", "curl/8.0")]).0, resolved(&[("User-Agent", "curl/8.1")]).0);
+        assert_eq!(resolved(&[("Cookie", "session=")]), ("192.0.2.1".to_string(), "address"));
+    }
+}
@@ -0,0 +1,115 @@
+use futures_util::TryStreamExt;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::{ApiResource, DynamicObject, GroupVersionKind};
+use kube::runtime::{watcher, WatchStreamExt};
+use kube::{Api, Client};
+use serde::Deserialize;
+use crate::config::{Limits, LimitsConfig};
+
+/// API group of the `RateLimitPolicy` custom resource.
+pub const POLICY_GROUP: &str = "ratelimiter.nginx.org";
+pub const POLICY_VERSION: &str = "v1";
+pub const POLICY_KIND: &str = "RateLimitPolicy";
+pub const POLICY_PLURAL: &str = "ratelimitpolicies";
+
+/// The object limits are read from, in the pod's namespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KubeSource {
+    /// A ConfigMap entry holding `LimitsConfig` JSON
+    ConfigMap { name: String, key: String },
+    /// A `RateLimitPolicy` whose `spec` is a `LimitsConfig`
+    Policy { name: String },
+}
+
+/// Watch `source` and publish its limits each time it changes, until the
+/// stream ends.
+///
+/// Every nginx pod runs its own watch, so a change applied with GitOps
+/// reaches the fleet as fast as the API server announces it. An object
+/// that does not parse is logged and the current limits stay in force.
+/// Watch errors are retried with backoff.
+pub async fn watch(
+    client: Client,
+    namespace: &str,
+    source: KubeSource,
+    publish: impl Fn(Limits) -> u64 + Send + Sync,
+) -> Result<(), watcher::Error> {
+    match source {
+        KubeSource::ConfigMap { name, key } => {
+            let api: Api<ConfigMap> = Api::namespaced(client, namespace);
+            let config = watcher::Config::default().fields(&format!("metadata.name={}", name));
+            let mut objects = Box::pin(watcher(api, config).default_backoff().applied_objects());
+            while let Some(config_map) = objects.try_next().await? {
+                apply(&name, configmap_limits(&config_map, &key), &publish);
+            }
+        }
+        KubeSource::Policy { name } => {
+            let gvk = GroupVersionKind::gvk(POLICY_GROUP, POLICY_VERSION, POLICY_KIND);
+            let resource = ApiResource::from_gvk_with_plural(&gvk, POLICY_PLURAL);
+            let api: Api<DynamicObject> = Api::namespaced_with(client, namespace, &resource);
+            let config = watcher::Config::default().fields(&format!("metadata.name={}", name));
+            let mut objects = Box::pin(watcher(api, config).default_backoff().applied_objects());
+            while let Some(policy) = objects.try_next().await? {
+                apply(&name, policy_limits(&policy), &publish);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply(name: &str, limits: Result<Limits, String>, publish: &impl Fn(Limits) -> u64) {
+    match limits {
+        Ok(limits) => {
+            let generation = publish(limits);
+            log::info!("Applied rate limits from {} as generation {}", name, generation);
+        }
+        Err(e) => {
+            metrics::counter!("rate_limiter_config_rejected_total", "source" => "kubernetes").increment(1);
+            log::error!("Ignoring rate limits from {}: {}", name, e);
+        }
+    }
+}
+
+fn configmap_limits(config_map: &ConfigMap, key: &str) -> Result<Limits, String> {
+    let json = config_map
+        .data
+        .as_ref()
+        .and_then(|data| data.get(key))
+        .ok_or_else(|| format!("no {} entry", key))?;
+    let config: LimitsConfig = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    config.compile(resource_version(config_map.metadata.resource_version.as_deref())).map_err(|e| e.to_string())
+}
+
+fn policy_limits(policy: &DynamicObject) -> Result<Limits, String> {
+    let spec = policy.data.get("spec").ok_or_else(|| "no spec".to_string())?;
+    let config = LimitsConfig::deserialize(spec).map_err(|e| e.to_string())?;
+    config.compile(resource_version(policy.metadata.resource_version.as_deref())).map_err(|e| e.to_string())
+}
+
+/// Resource versions are opaque, but etcd-backed ones are increasing
+/// integers, which makes a usable rule-set generation.
+fn resource_version(version: Option<&str>) -> u64 {
+    version.and_then(|version| version.parse().ok()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_spec_is_limits_config() {
+        let policy: DynamicObject = serde_json::from_value(serde_json::json!({
+            "apiVersion": "ratelimiter.nginx.org/v1",
+            "kind": "RateLimitPolicy",
+            "metadata": {"name": "edge", "resourceVersion": "42"},
+            "spec": {"requests": 100, "window": 60, "rules": [{"path": {"prefix": "/api/"}, "requests": 5, "window": 1}]}
+        }))
+        .unwrap();
+        let limits = policy_limits(&policy).unwrap();
+        assert_eq!(limits.for_path("/api/x"), (5, 1));
+        assert_eq!(limits.for_path("/"), (100, 60));
+
+        let config_map = ConfigMap::default();
+        assert_eq!(configmap_limits(&config_map, "limits.json").unwrap_err(), "no limits.json entry");
+    }
+}
@@ -1,63 +1,1210 @@
+#[cfg(not(target_arch = "wasm32"))]
 use nginx_module::{
     http::{HTTPModule, HTTPContext, Status},
     bindings,
 };
+#[cfg(not(target_arch = "wasm32"))]
 use async_trait::async_trait;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::fmt::Write;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::{Arc, OnceLock};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
 
-mod storage;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod allow_cache;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod anomaly;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod ban_export;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod basic_auth;
+pub mod bloom;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod body_quota;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod bypass;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cardinality;
+pub mod cidr_trie;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod client_cert;
+pub mod clock;
+pub mod config;
+pub mod deny_cache;
+pub mod denylist;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod emergency;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod events;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod exclusions;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod ffi;
+pub mod geo;
+pub mod graphql;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod greylist;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod honeypot;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod key_source;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod grpc;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod introspection;
+#[cfg(feature = "kubernetes")]
+pub mod kubernetes;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod maintenance;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod memo;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod multiplex;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod plans;
+pub mod policy;
+pub mod prefilter;
+pub mod priority;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod query_key;
+pub mod rate;
+pub mod rejection;
+#[cfg(any(feature = "consul", feature = "etcd"))]
+pub mod remote_config;
+pub mod rules;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod runtime;
+#[cfg(feature = "sidecar")]
+pub mod sidecar;
+pub mod storage;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod slow_requests;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod smoothing;
+#[cfg(feature = "syslog")]
+pub mod syslog;
+#[cfg(feature = "threat-feed")]
+pub mod threat_feed;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod stream;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tenant;
+#[cfg(all(any(test, feature = "testing"), not(target_arch = "wasm32")))]
+pub mod testing;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod usage;
+#[cfg(feature = "proxy-wasm")]
+pub mod wasm_filter;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod websocket;
+use storage::KeyBuf;
+#[cfg(not(target_arch = "wasm32"))]
+use allow_cache::{AllowCache, AllowCacheOptions};
+#[cfg(not(target_arch = "wasm32"))]
+use anomaly::{AnomalyDetector, AnomalyOptions};
+#[cfg(not(target_arch = "wasm32"))]
+use ban_export::{BanExportOptions, BanExporter};
+#[cfg(not(target_arch = "wasm32"))]
+use body_quota::BodyQuota;
+#[cfg(not(target_arch = "wasm32"))]
+use bypass::BypassTokens;
+#[cfg(not(target_arch = "wasm32"))]
+use cardinality::MetricLabels;
+#[cfg(not(target_arch = "wasm32"))]
+use client_cert::ClientCertOptions;
+#[cfg(not(target_arch = "wasm32"))]
+use config::{ConfigStore, Limits};
+#[cfg(not(target_arch = "wasm32"))]
+use deny_cache::{DenyCache, DenyCacheOptions};
+#[cfg(not(target_arch = "wasm32"))]
+use denylist::Denylist;
+#[cfg(not(target_arch = "wasm32"))]
+use emergency::EmergencyBrake;
+#[cfg(not(target_arch = "wasm32"))]
+use events::{EventEmitter, EventKind};
+#[cfg(not(target_arch = "wasm32"))]
+use exclusions::Exclusions;
+#[cfg(not(target_arch = "wasm32"))]
+use geo::AggregateCaps;
+#[cfg(not(target_arch = "wasm32"))]
+use graphql::GraphQlOptions;
+#[cfg(not(target_arch = "wasm32"))]
+use greylist::{Greylist, GreylistOptions, GreylistVerdict};
+#[cfg(not(target_arch = "wasm32"))]
+use honeypot::{Honeypot, HoneypotOptions};
+#[cfg(not(target_arch = "wasm32"))]
+use introspection::TokenClients;
+#[cfg(not(target_arch = "wasm32"))]
+use key_source::KeySource;
+#[cfg(not(target_arch = "wasm32"))]
+use maintenance::Maintenance;
+#[cfg(not(target_arch = "wasm32"))]
+use memo::{DecisionMemo, MemoOptions};
+#[cfg(not(target_arch = "wasm32"))]
+use multiplex::StreamTracker;
+#[cfg(not(target_arch = "wasm32"))]
+use policy::{Policy, PolicyInput};
+#[cfg(not(target_arch = "wasm32"))]
+use prefilter::{Prefilter, PrefilterOptions};
+#[cfg(not(target_arch = "wasm32"))]
+use priority::{Priority, PriorityAdmission};
+#[cfg(not(target_arch = "wasm32"))]
+use query_key::QueryKey;
+#[cfg(not(target_arch = "wasm32"))]
+use smoothing::Smoothing;
+#[cfg(not(target_arch = "wasm32"))]
+use rate::Rate;
+#[cfg(not(target_arch = "wasm32"))]
+use runtime::{RuntimeOptions, WorkerRuntime};
+#[cfg(not(target_arch = "wasm32"))]
+use tenant::{RequestInfo, TenantResolver};
+#[cfg(not(target_arch = "wasm32"))]
+use usage::UsageRecorder;
+#[cfg(not(target_arch = "wasm32"))]
+use websocket::WebSocketOptions;
+#[cfg(not(target_arch = "wasm32"))]
 use storage::{
+    Increment,
     StorageBackend,
-    MemcachedStorage,
+    StorageError,
     RedisStorage,
-    MySQLStorage,
-    PostgresStorage,
 };
 
+#[cfg(not(target_arch = "wasm32"))]
 #[derive(Debug)]
 pub struct RateLimiter {
-    storage: Arc<Mutex<Box<dyn StorageBackend>>>,
-    requests_per_second: u32,
-    window_size: u32,
+    /// Shared by concurrent requests without a lock
+    storage: Arc<dyn StorageBackend>,
+    /// Limits, swapped on reload without blocking requests
+    config: ConfigStore,
+    fail_closed: bool,
+    snapshot_path: Option<PathBuf>,
+    runtime_options: Option<RuntimeOptions>,
+    /// Built per worker by `init_worker`
+    runtime: OnceLock<WorkerRuntime>,
+    local: Local,
+    tenants: Option<Arc<TenantResolver>>,
+    /// Header whose value identifies an operation, counted once however
+    /// often it is retried
+    idempotency_header: Option<String>,
+    bypass: Option<Arc<BypassTokens>>,
+    /// Count gRPC calls per method in `check`
+    grpc_method_keys: bool,
+    websocket: Option<WebSocketOptions>,
+    body_quota: Option<BodyQuota>,
+    graphql: Option<GraphQlOptions>,
+    token_clients: Option<Arc<TokenClients>>,
+    /// Where `check` looks for a key before the one it is given, in order
+    key_sources: Vec<KeySource>,
+    /// Pseudo-header carrying a per-request rate, e.g. from an nginx `map`
+    rate_variable: Option<String>,
+    memo: Option<Arc<DecisionMemo>>,
+    /// Requests `check` lets through uncounted
+    exclusions: Exclusions,
+    priority: Option<PriorityAdmission>,
+    emergency: Option<Arc<EmergencyBrake>>,
+    maintenance: Option<Maintenance>,
+    metric_labels: Option<MetricLabels>,
 }
 
+/// Per-worker state consulted before the backend.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Default)]
+struct Local {
+    denylist: Option<Arc<Denylist>>,
+    deny_cache: Option<Arc<DenyCache>>,
+    prefilter: Option<Arc<Prefilter>>,
+    policy: Option<Arc<dyn Policy>>,
+    bans: Option<Arc<BanExporter>>,
+    events: Option<Arc<EventEmitter>>,
+    usage: Option<Arc<UsageRecorder>>,
+    anomaly: Option<Arc<AnomalyDetector>>,
+    greylist: Option<Arc<Greylist>>,
+    aggregates: Option<Arc<AggregateCaps>>,
+    honeypot: Option<Arc<Honeypot>>,
+    streams: Option<Arc<StreamTracker>>,
+    smoothing: Option<Smoothing>,
+    allow_cache: Option<Arc<AllowCache>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
 impl RateLimiter {
-    pub fn new(backend_type: &str, requests_per_second: u32, window_size: u32) -> Self {
-        let storage: Box<dyn StorageBackend> = match backend_type {
-            "memcached" => Box::new(MemcachedStorage::new()),
-            "redis" => Box::new(RedisStorage::new()),
-            "mysql" => Box::new(MySQLStorage::new()),
-            "postgresql" => Box::new(PostgresStorage::new()),
-            _ => Box::new(RedisStorage::new()), // デフォルトはRedis
-        };
+    /// Build a limiter on any registered backend, built-in or added with
+    /// `storage::register_backend`.
+    pub async fn with_backend(
+        backend_name: &str,
+        backend_config: &str,
+        requests_per_second: u32,
+        window_size: u32,
+    ) -> Result<Self, storage::StorageError> {
+        let storage = storage::create_backend(backend_name, backend_config).await?;
+        Ok(Self::with_storage(Arc::from(storage), requests_per_second, window_size))
+    }
 
+    /// Build a limiter on a backend constructed by the caller, e.g. one
+    /// wrapped in other backends or driven by a test clock.
+    pub fn with_storage(storage: Arc<dyn StorageBackend>, requests_per_second: u32, window_size: u32) -> Self {
         RateLimiter {
-            storage: Arc::new(Mutex::new(storage)),
-            requests_per_second,
-            window_size,
+            storage,
+            config: ConfigStore::new(Limits::new(requests_per_second, window_size)),
+            fail_closed: false,
+            snapshot_path: None,
+            runtime_options: None,
+            runtime: OnceLock::new(),
+            local: Local::default(),
+            tenants: None,
+            idempotency_header: None,
+            bypass: None,
+            grpc_method_keys: false,
+            websocket: None,
+            body_quota: None,
+            graphql: None,
+            token_clients: None,
+            key_sources: Vec::new(),
+            rate_variable: None,
+            memo: None,
+            exclusions: Exclusions::default(),
+            priority: None,
+            emergency: None,
+            maintenance: None,
+            metric_labels: None,
         }
     }
 
-    async fn is_rate_limited(&self, key: &str) -> bool {
-        let mut storage = self.storage.lock().await;
-        let current_count = storage.get(key).await.unwrap_or(0);
+    /// Refuse to start a worker whose backend cannot be warmed up
+    /// (`rate_limit_fail_closed on`), instead of starting and failing
+    /// requests later.
+    pub fn with_fail_closed(mut self, fail_closed: bool) -> Self {
+        self.fail_closed = fail_closed;
+        self
+    }
+
+    /// Restore counters from `path` when the worker starts and save them
+    /// there with `save_snapshot` (`rate_limit_snapshot`), so limits
+    /// survive a restart of in-memory backends.
+    pub fn with_snapshot(mut self, path: impl Into<PathBuf>) -> Self {
+        self.snapshot_path = Some(path.into());
+        self
+    }
 
-        if current_count >= self.requests_per_second {
-            true
+    /// Run backend calls on a dedicated runtime per worker, with at most
+    /// `queue_depth` decisions queued; requests beyond that get
+    /// `overload_status` (`rate_limit_runtime`). Without it decisions run
+    /// on whatever executor drives `handle`.
+    pub fn with_runtime(mut self, options: RuntimeOptions) -> Self {
+        self.runtime_options = Some(options);
+        self
+    }
+
+    /// Replace the limits for requests that start from now on; requests in
+    /// flight finish under the generation they started with. Returns the
+    /// new generation number.
+    pub fn reload(&self, limits: Limits) -> u64 {
+        let generation = self.config.publish(limits);
+        log::info!("Rate limit configuration generation {} published", generation);
+        generation
+    }
+
+    pub fn config(&self) -> &ConfigStore {
+        &self.config
+    }
+
+    /// Reject clients in `denylist` before any counter is read, e.g. one
+    /// kept current by `threat_feed::ThreatFeed` (`rate_limit_threat_feed`).
+    pub fn with_denylist(mut self, denylist: Arc<Denylist>) -> Self {
+        self.local.denylist = Some(denylist);
+        self
+    }
+
+    /// Turn away the first request of keys never seen before, once, or
+    /// hold it for a moment (`rate_limit_greylist`). Seen keys are kept in
+    /// this limiter's backend.
+    pub fn with_greylist(mut self, options: GreylistOptions) -> Self {
+        self.local.greylist = Some(Arc::new(Greylist::new(Arc::clone(&self.storage), options)));
+        self
+    }
+
+    /// Reject keys that were recently over their limit without asking the
+    /// backend (`rate_limit_deny_cache`).
+    pub fn with_deny_cache(mut self, options: DenyCacheOptions) -> Self {
+        self.local.deny_cache = Some(Arc::new(DenyCache::new(options)));
+        self
+    }
+
+    /// Allow clients far from their limit from a local allowance, without
+    /// asking the backend for every request (`rate_limit_allow_cache`).
+    /// Keys checked with a policy or country and network caps always ask
+    /// the backend.
+    pub fn with_allow_cache(mut self, options: AllowCacheOptions) -> Self {
+        self.local.allow_cache = Some(Arc::new(AllowCache::new(options)));
+        self
+    }
+
+    /// Allow clients that have used less than a fraction of their limit
+    /// from a local count-min sketch, without asking the backend
+    /// (`rate_limit_prefilter`).
+    pub fn with_prefilter(mut self, options: PrefilterOptions) -> Self {
+        self.local.prefilter = Some(Arc::new(Prefilter::new(options)));
+        self
+    }
+
+    /// Let `policy` make the final decision for requests that reach the
+    /// backend (`rate_limit_opa_url` for `policy::OpaPolicy`). Keys served
+    /// from the deny cache or the prefilter skip it.
+    pub fn with_policy(mut self, policy: Arc<dyn Policy>) -> Self {
+        self.local.policy = Some(policy);
+        self
+    }
+
+    /// Ban clients that keep getting denied at the firewall
+    /// (`rate_limit_ban_export`).
+    pub fn with_ban_export(mut self, options: BanExportOptions) -> Self {
+        self.local.bans = Some(Arc::new(BanExporter::new(options)));
+        self
+    }
+
+    /// Publish allow, deny and ban events to an event stream
+    /// (`rate_limit_events`). The emitter's task must run in the worker.
+    pub fn with_events(mut self, emitter: Arc<EventEmitter>) -> Self {
+        self.local.events = Some(emitter);
+        self
+    }
+
+    /// Count allowed requests per key into hourly and daily buckets for
+    /// billing (`rate_limit_usage`). The recorder's flush task must run in
+    /// the worker.
+    pub fn with_usage(mut self, recorder: Arc<UsageRecorder>) -> Self {
+        self.local.usage = Some(recorder);
+        self
+    }
+
+    /// Tighten the limit of clients whose HTTP/2 or HTTP/3 connections
+    /// open or reset streams excessively (`rate_limit_streams`). The
+    /// embedder reports streams to `tracker`.
+    pub fn with_stream_tracker(mut self, tracker: Arc<StreamTracker>) -> Self {
+        self.local.streams = Some(tracker);
+        self
+    }
+
+    /// Flag keys whose request rate jumps far above their own baseline,
+    /// publishing an anomaly event and optionally tightening their limit
+    /// (`rate_limit_anomaly`).
+    pub fn with_anomaly_detection(mut self, options: AnomalyOptions) -> Self {
+        self.local.anomaly = Some(Arc::new(AnomalyDetector::new(options)));
+        self
+    }
+
+    /// Cap the requests of every client in a country or autonomous system
+    /// together, on top of each client's own limit
+    /// (`rate_limit_aggregate_caps`).
+    pub fn with_aggregate_caps(mut self, caps: AggregateCaps) -> Self {
+        self.local.aggregates = Some(Arc::new(caps));
+        self
+    }
+
+    /// Ban keys that request one of the honeypot paths
+    /// (`rate_limit_honeypot`).
+    pub fn with_honeypot(mut self, options: HoneypotOptions) -> Self {
+        self.local.honeypot = Some(Arc::new(Honeypot::new(options)));
+        self
+    }
+
+    /// Reject a growing fraction of a key's requests around its limit
+    /// instead of all of them at it (`rate_limit_smoothing`).
+    pub fn with_smoothing(mut self, smoothing: Smoothing) -> Self {
+        self.local.smoothing = Some(smoothing);
+        self
+    }
+
+    /// Give each tenant its own limits, and optionally its own key
+    /// namespace or backend (`rate_limit_tenants`).
+    pub fn with_tenants(mut self, tenants: TenantResolver) -> Self {
+        self.tenants = Some(Arc::new(tenants));
+        self
+    }
+
+    /// Count requests carrying the same `header` value, e.g.
+    /// `Idempotency-Key`, once per window in `check`, so retries of one
+    /// operation do not use up the limit (`rate_limit_idempotency_key`).
+    /// Requests without the header are counted as usual.
+    pub fn with_idempotency_key(mut self, header: &str) -> Self {
+        self.idempotency_header = Some(header.to_string());
+        self
+    }
+
+    /// Count each method of a gRPC client on its own in `check`, under
+    /// `grpc:{service}/{method}:{key}` (`rate_limit_grpc`). Rules for a
+    /// method's `:path` give it its own limit.
+    pub fn with_grpc_method_keys(mut self) -> Self {
+        self.grpc_method_keys = true;
+        self
+    }
+
+    /// Count WebSocket upgrades in `check` against their own limit,
+    /// under `ws:{key}` (`rate_limit_websocket`). Proxies apply the
+    /// message budget with `websocket::MessageBudget`.
+    pub fn with_websocket_limits(mut self, options: WebSocketOptions) -> Self {
+        self.websocket = Some(options);
+        self
+    }
+
+    /// Limit the request body bytes each key sends per window, on top of
+    /// its request count (`rate_limit_body_quota`). `check` charges the
+    /// `Content-Length`; bodies without one are charged with `charge_body`
+    /// as they are read.
+    pub fn with_body_quota(mut self, quota: BodyQuota) -> Self {
+        self.body_quota = Some(quota);
+        self
+    }
+
+    /// Charge GraphQL requests their estimated cost, one per field
+    /// selected, against a per-key budget, and refuse documents nested too
+    /// deeply (`rate_limit_graphql`). Costs are counted under
+    /// `graphql:{key}`; rules pick operations with `graphql` body
+    /// conditions.
+    pub fn with_graphql(mut self, options: GraphQlOptions) -> Self {
+        self.graphql = Some(options);
+        self
+    }
+
+    /// Look for each request's key in `sources`, in order, in `check`,
+    /// falling back to the key it is given (`rate_limit_key_sources`).
+    /// Replaces the sources added so far.
+    pub fn with_key_sources(mut self, sources: Vec<KeySource>) -> Self {
+        self.key_sources = sources;
+        self
+    }
+
+    /// Count requests with a verified client certificate under its
+    /// identity, `cert:{identity}`, instead of their own key
+    /// (`rate_limit_client_cert_key`). Added after the key sources so far.
+    pub fn with_client_cert_keys(mut self, options: ClientCertOptions) -> Self {
+        self.key_sources.push(KeySource::ClientCert(options));
+        self
+    }
+
+    /// Count requests with `Authorization: Basic` credentials under their
+    /// username, `user:{username}`, instead of their own key
+    /// (`rate_limit_basic_auth_key`), for legacy APIs where that is the
+    /// only identity clients have. The password is neither checked nor
+    /// logged. Added after the key sources so far.
+    pub fn with_basic_auth_keys(mut self) -> Self {
+        self.key_sources.push(KeySource::BasicAuth);
+        self
+    }
+
+    /// Count requests carrying an acceptable value of a query parameter,
+    /// e.g. `?api_key=`, under `{param}:{value}` instead of their own key
+    /// (`rate_limit_query_key`). Added after the key sources so far.
+    pub fn with_query_key(mut self, query_key: QueryKey) -> Self {
+        self.key_sources.push(KeySource::Query(query_key));
+        self
+    }
+
+    /// Take each request's rate from the pseudo-header `name`, filled in
+    /// from an nginx variable such as one set by a `map`
+    /// (`rate_limit_rate $limit_for_plan`), in place of the configured
+    /// requests and window. Path rules still apply to their paths; empty
+    /// or invalid values leave the configured rate.
+    pub fn with_rate_variable(mut self, name: &str) -> Self {
+        self.rate_variable = Some(name.to_string());
+        self
+    }
+
+    /// Decide each client request once in `check`, by its id, however
+    /// many phases, subrequests or internal redirects evaluate it, and give
+    /// the same answer every time (`rate_limit_memoize`).
+    pub fn with_memoized_decisions(mut self, options: MemoOptions) -> Self {
+        self.memo = Some(Arc::new(DecisionMemo::new(options)));
+        self
+    }
+
+    /// Replace the requests `check` lets through uncounted: by default
+    /// internal requests and known health probes on health paths
+    /// (`rate_limit_skip_internal`, `rate_limit_health_check`).
+    pub fn with_exclusions(mut self, exclusions: Exclusions) -> Self {
+        self.exclusions = exclusions;
+        self
+    }
+
+    /// Share a global limit between all keys in `check`, admitting
+    /// requests of lower priority, as set by path rules, only while more of
+    /// it is left (`rate_limit_priority_capacity`).
+    pub fn with_priority_admission(mut self, admission: PriorityAdmission) -> Self {
+        self.priority = Some(admission);
+        self
+    }
+
+    /// Hold requests in `check` to the brake's limit while it is engaged,
+    /// e.g. through the sidecar's `PUT /emergency` with `brake` spawned on
+    /// the backend (`rate_limit_emergency`). Requests are anonymous when
+    /// counted by their address rather than a key source or token client.
+    pub fn with_emergency_brake(mut self, brake: Arc<EmergencyBrake>) -> Self {
+        self.emergency = Some(brake);
+        self
+    }
+
+    /// Relax or lift limits in `check` for the keys and networks of
+    /// `maintenance`'s windows while they are open
+    /// (`rate_limit_maintenance`). An engaged emergency brake still
+    /// applies.
+    pub fn with_maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+
+    /// Count the decisions `check` reaches the counters with in
+    /// `rate_limiter_decisions_total`, labelled by rule id, zone and
+    /// tenant (`rate_limit_metric_labels`).
+    pub fn with_metric_labels(mut self, labels: MetricLabels) -> Self {
+        self.metric_labels = Some(labels);
+        self
+    }
+
+    /// Count requests with an active OAuth2 bearer token under the token's
+    /// client, `client:{client_id}`, instead of their own key, and select
+    /// rules by the token's scopes (`rate_limit_oauth2_introspection`).
+    pub fn with_token_introspection(mut self, clients: TokenClients) -> Self {
+        self.token_clients = Some(Arc::new(clients));
+        self
+    }
+
+    /// Let requests carrying a valid signed bypass token skip limiting in
+    /// `check` (`rate_limit_bypass_tokens`).
+    pub fn with_bypass_tokens(mut self, tokens: BypassTokens) -> Self {
+        self.bypass = Some(Arc::new(tokens));
+        self
+    }
+
+    /// Init-worker hook: start the worker runtime, connect to the backend,
+    /// load scripts and prepare statements before the worker accepts
+    /// traffic. Errors are only returned in fail-closed mode; otherwise
+    /// they are logged and the backend connects lazily as before.
+    pub async fn init_worker(&self) -> Result<(), storage::StorageError> {
+        // Started here rather than at configuration time: threads do not
+        // survive the fork from the master process
+        if let Some(options) = &self.runtime_options {
+            if self.runtime.get().is_none() {
+                let runtime = WorkerRuntime::new(options.clone())
+                    .map_err(|e| storage::StorageError::ConnectionError(e.to_string()))?;
+                let _ = self.runtime.set(runtime);
+            }
+        }
+
+        match self.storage.warm_up().await {
+            Ok(()) => {}
+            Err(e) if self.fail_closed => {
+                log::error!("Rate limit storage warm-up failed: {}", e);
+                return Err(e);
+            }
+            Err(e) => log::warn!("Rate limit storage warm-up failed, continuing: {}", e),
+        }
+
+        // A lost snapshot only means limits start over, as they did before
+        if let Some(path) = &self.snapshot_path {
+            match storage::load_snapshot(self.storage.as_ref(), path).await {
+                Ok(restored) => log::info!("Restored {} rate limit counters from {}", restored, path.display()),
+                Err(e) => log::warn!("Restoring rate limit snapshot from {} failed: {}", path.display(), e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Save every live counter to the snapshot path, e.g. from an admin
+    /// endpoint or before a deploy. Returns how many were saved.
+    pub async fn save_snapshot(&self) -> Result<usize, storage::StorageError> {
+        let path = self.snapshot_path.as_ref().ok_or_else(|| {
+            storage::StorageError::Unsupported("no snapshot path configured".to_string())
+        })?;
+        storage::save_snapshot(self.storage.as_ref(), path).await
+    }
+
+    /// Save a snapshot whenever the worker receives SIGUSR2.
+    pub fn spawn_snapshot_trigger(&self) -> Result<tokio::task::JoinHandle<()>, storage::StorageError> {
+        let path = self.snapshot_path.clone().ok_or_else(|| {
+            storage::StorageError::Unsupported("no snapshot path configured".to_string())
+        })?;
+        storage::spawn_snapshot_on_signal(
+            Arc::clone(&self.storage),
+            path,
+            tokio::signal::unix::SignalKind::user_defined2(),
+        )
+    }
+
+    /// Admin hook: copy the live counters of another backend (e.g. the
+    /// SQLite file being retired) into this limiter's storage, with their
+    /// remaining TTLs. Counters are added to what is already stored, so
+    /// call it once, not from every worker.
+    pub async fn migrate_from(&self, backend_name: &str, backend_config: &str) -> Result<u64, storage::StorageError> {
+        let source = storage::create_backend(backend_name, backend_config).await?;
+        let copied = storage::migrate(source.as_ref(), self.storage.as_ref()).await?;
+        log::info!("Copied {} rate limit counters from {}", copied, backend_name);
+        Ok(copied)
+    }
+
+    /// Start sweeping expired keys from the backend in the background.
+    pub fn spawn_cleanup(&self, options: storage::CleanupOptions) -> tokio::task::JoinHandle<()> {
+        storage::spawn_cleanup(Arc::clone(&self.storage), options)
+    }
+
+    /// Requests and window of the current generation. The snapshot is
+    /// released before any backend call.
+    fn limits(&self) -> (u32, u32) {
+        let config = self.config.load();
+        (config.limits.requests_per_second, config.limits.window_size)
+    }
+
+    /// Decide one request for `key`, counting it when it is allowed.
+    pub async fn is_rate_limited(&self, key: &str) -> bool {
+        let (limit, window) = self.limits();
+        decide(self.storage.as_ref(), &self.local, key, limit, window).await
+    }
+
+    /// Decide one request for `key` like `is_rate_limited`, and report
+    /// how much of the limit is left and when the window resets, e.g. for
+    /// `RateLimit` headers. When the backend decides and the request did
+    /// not start the window, the reset costs one more backend call, a
+    /// `ttl`; decisions taken in the worker leave it unknown.
+    pub async fn check_and_increment(&self, key: &str) -> Verdict {
+        let (limit, window) = self.limits();
+        decide_verdict(self.storage.as_ref(), &self.local, key, limit, window, true).await
+    }
+
+    /// Decide one request for `key` under the limits of the tenant
+    /// `request` belongs to, for callers that see its host and headers.
+    pub async fn check(&self, request: &RequestInfo<'_>, key: &str) -> bool {
+        if let Some(reason) = self.exclusions.reason(request) {
+            metrics::counter!("rate_limiter_excluded_requests_total", "reason" => reason).increment(1);
+            return false;
+        }
+        let Some((memo, memo_key)) = self.memo.as_ref().and_then(|memo| Some((memo, memo.key(request)?))) else {
+            return self.check_uncached(request, key).await;
+        };
+        if let Some(limited) = memo.get(&memo_key) {
+            return limited;
+        }
+        let limited = self.check_uncached(request, key).await;
+        memo.insert(&memo_key, limited);
+        limited
+    }
+
+    async fn check_uncached(&self, request: &RequestInfo<'_>, key: &str) -> bool {
+        if self.bypass.as_ref().is_some_and(|bypass| bypass.allows(request)) {
+            return false;
+        }
+        if trapped(&self.local, request.path, key) {
+            return true;
+        }
+        let address = key;
+        let (resolved, source) = self.key_for(request, key);
+        if !self.key_sources.is_empty() {
+            metrics::counter!("rate_limiter_key_source_total", "source" => source).increment(1);
+            log::debug!("Counting request under {} from its {}", resolved, source);
+        }
+        let key = resolved.as_str();
+        let client = match &self.token_clients {
+            Some(clients) => clients.identify(request).await,
+            None => None,
+        };
+        let client_key = client.as_ref().map(|client| client.key());
+        let key = client_key.as_deref().unwrap_or(key);
+        let identity = key;
+        let scoped = RequestInfo {
+            scope: client.as_ref().map(|client| client.scope.as_str()),
+            ..*request
+        };
+        let request = &scoped;
+        let anonymous = source == "address" && client.is_none();
+        if let Some(websocket) = self.websocket.as_ref().filter(|_| websocket::is_upgrade(request)) {
+            let (storage, key, _, _) = self.route(request, &websocket::upgrade_key(key));
+            let Some((limit, window)) = self.adjust(address, identity, anonymous, websocket.upgrades, websocket.upgrade_window)
+            else {
+                return false;
+            };
+            return decide(storage.as_ref(), &self.local, &key, limit, window).await;
+        }
+        let method_key = match grpc::GrpcMethod::parse(request.path) {
+            Some(method) if self.grpc_method_keys && grpc::is_grpc(request) => Some(grpc::method_key(key, &method)),
+            _ => None,
+        };
+        let (storage, counted, limit, window) = self.route(request, method_key.as_deref().unwrap_or(key));
+        let Some((limit, window)) = self.adjust(address, identity, anonymous, limit, window) else {
+            return false;
+        };
+        if let Some(length) = body_quota::content_length(request) {
+            if self.charge_body(request, key, length).await {
+                return true;
+            }
+        }
+        if let Some(graphql) = self.graphql.as_ref().filter(|graphql| graphql.serves(request.path)) {
+            if let Some(operation) = request.body.and_then(graphql::parse) {
+                let (storage, key, _, _) = self.route(request, key);
+                if graphql.charge(storage.as_ref(), &key, &operation).await {
+                    return true;
+                }
+            }
+        }
+        let key = counted;
+        let priority = self.priority.as_ref().map(|admission| (admission, self.priority_for(request)));
+        if let Some((admission, priority)) = priority {
+            if !admission.admits(self.storage.as_ref(), priority).await {
+                return true;
+            }
+        }
+        let operation = self.idempotency_header.as_deref().and_then(|header| request.header(header));
+        let limited = match operation {
+            Some(operation) => decide_operation(storage.as_ref(), &self.local, &key, operation, limit, window).await,
+            None => decide(storage.as_ref(), &self.local, &key, limit, window).await,
+        };
+        if let Some((admission, priority)) = priority.filter(|_| !limited) {
+            admission.charge(self.storage.as_ref(), priority).await;
+        }
+        if let Some(labels) = &self.metric_labels {
+            self.label_decision(labels, request, limited);
+        }
+        limited
+    }
+
+    /// `limit` and `window` as relaxed by the open maintenance windows and
+    /// tightened by the emergency brake, or `None` when a maintenance
+    /// window lifts limiting and the brake is released.
+    fn adjust(&self, address: &str, identity: &str, anonymous: bool, limit: u32, window: u32) -> Option<(u32, u32)> {
+        let relaxed = match &self.maintenance {
+            Some(maintenance) => maintenance.relax(address, identity, limit, window),
+            None => Some((limit, window)),
+        };
+        let (limit, window) = match relaxed {
+            Some(relaxed) => relaxed,
+            None if self.emergency.as_ref().is_some_and(|brake| brake.is_engaged()) => (limit, window),
+            None => return None,
+        };
+        Some(match &self.emergency {
+            Some(brake) => brake.limit(anonymous, limit, window),
+            None => (limit, window),
+        })
+    }
+
+    /// Count a decision for `request` under its rule and tenant.
+    fn label_decision(&self, labels: &MetricLabels, request: &RequestInfo<'_>, limited: bool) {
+        let tenant = self.tenants.as_ref().and_then(|tenants| tenants.resolve(request));
+        let config = tenant.map_or(&self.config, |tenant| &tenant.config).load();
+        let rules = config.limits.rules.as_ref();
+        let rule = match rules.and_then(|rules| rules.find_scoped(request.path, request.body, request.scope)) {
+            Some(rule) => rule.id.as_deref().unwrap_or("unnamed"),
+            None => "default",
+        };
+        labels.record(limited, rule, tenant.map(|tenant| tenant.name.as_str()));
+    }
+
+    /// The priority the rule for `request` gives it.
+    fn priority_for(&self, request: &RequestInfo<'_>) -> Priority {
+        let tenant = self.tenants.as_ref().and_then(|tenants| tenants.resolve(request));
+        let config = tenant.map_or(&self.config, |tenant| &tenant.config).load();
+        let rules = config.limits.rules.as_ref();
+        rules
+            .and_then(|rules| rules.find_scoped(request.path, request.body, request.scope))
+            .and_then(|rule| rule.priority)
+            .unwrap_or_default()
+    }
+
+    /// The key `check` counts `request` under, from the first key source
+    /// that has one, and the name of that source, e.g. for the embedder to
+    /// log as `$rate_limit_key_source`. `key` and `address` when no source
+    /// has one.
+    pub fn key_for(&self, request: &RequestInfo<'_>, key: &str) -> (KeyBuf, &'static str) {
+        key_source::resolve(&self.key_sources, request, key)
+    }
+
+    /// Charge `bytes` of the body of `request` to `key` as they are read,
+    /// for bodies without a `Content-Length`. Returns whether the key is
+    /// over its body quota, in which case the upload should be cut off.
+    pub async fn charge_body(&self, request: &RequestInfo<'_>, key: &str, bytes: u64) -> bool {
+        let Some(quota) = &self.body_quota else {
+            return false;
+        };
+        let (storage, key, _, _) = self.route(request, key);
+        quota.charge(storage.as_ref(), &key, bytes).await
+    }
+
+    /// The requests and window in the rate variable of `request`, if set.
+    fn variable_rate(&self, request: &RequestInfo<'_>) -> Option<(u32, u32)> {
+        let value = request.header(self.rate_variable.as_deref()?)?.trim();
+        if value.is_empty() {
+            return None;
+        }
+        match value.parse::<Rate>() {
+            Ok(rate) => Some((rate.requests, rate.window)),
+            Err(e) => {
+                metrics::counter!("rate_limiter_invalid_rate_variables_total").increment(1);
+                log::warn!("{}; using the configured rate", e);
+                None
+            }
+        }
+    }
+
+    /// Backend, key, requests and window for a request: its tenant's, or
+    /// the limiter's own when it has none, under the rule for its path.
+    fn route(&self, request: &RequestInfo<'_>, key: &str) -> (Arc<dyn StorageBackend>, KeyBuf, u32, u32) {
+        let tenant = self.tenants.as_ref().and_then(|tenants| tenants.resolve(request));
+        let config = tenant.map_or(&self.config, |tenant| &tenant.config).load();
+        let rule = config
+            .limits
+            .rules
+            .as_ref()
+            .and_then(|rules| rules.find_scoped(request.path, request.body, request.scope));
+        let (limit, window) = match rule {
+            Some(rule) => (rule.requests, rule.window),
+            None => self
+                .variable_rate(request)
+                .unwrap_or((config.limits.requests_per_second, config.limits.window_size)),
+        };
+
+        let mut counted = KeyBuf::new();
+        if let Some(counter) = rule.and_then(|rule| rule.counter.as_deref()) {
+            let _ = write!(counted, "{}:", counter);
+        }
+        counted.push_str(key);
+        match tenant {
+            Some(tenant) => {
+                let storage = tenant.storage.as_ref().unwrap_or(&self.storage);
+                (Arc::clone(storage), tenant.key(&counted), limit, window)
+            }
+            None => (Arc::clone(&self.storage), counted, limit, window),
+        }
+    }
+}
+
+/// Whether a key with `count` requests counted is over `limit`.
+#[cfg(not(target_arch = "wasm32"))]
+fn over(local: &Local, count: u64, limit: u32) -> bool {
+    match &local.smoothing {
+        Some(smoothing) => smoothing.rejects(count, limit),
+        None => count >= u64::from(limit),
+    }
+}
+
+/// The outcome of deciding one request.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verdict {
+    pub limited: bool,
+    /// Requests still allowed in the window, as far as this worker knows
+    pub remaining: u64,
+    /// Unix time the window ends, when known
+    pub reset_at: Option<u64>,
+}
+
+/// Unix time `ttl` from now, rounded up to the second.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn reset_at(ttl: Duration) -> u64 {
+    let at = clock::system().now() + ttl;
+    at.as_secs() + u64::from(at.subsec_nanos() > 0)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Verdict {
+    fn limited() -> Self {
+        Self { limited: true, remaining: 0, reset_at: None }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn decide(storage: &dyn StorageBackend, local: &Local, key: &str, limit: u32, window: u32) -> bool {
+    decide_verdict(storage, local, key, limit, window, false).await.limited
+}
+
+/// Count `hits` requests for `key` and return the last count. Taken one
+/// at a time in a batch rather than with `increment_by`, which only adds
+/// more than one on some backends; allowances are small enough for that.
+#[cfg(not(target_arch = "wasm32"))]
+async fn count_hits(storage: &dyn StorageBackend, key: &str, hits: u64, window: u32) -> Result<Increment, StorageError> {
+    if hits == 1 {
+        return storage.increment(key, window).await;
+    }
+    let keys = vec![(key, window); hits as usize];
+    let mut increments = storage.increment_many(&keys).await?;
+    increments
+        .pop()
+        .ok_or_else(|| StorageError::InvalidValueType("empty increment batch".to_string()))
+}
+
+/// When the window of `key` ends, if the backend can tell.
+#[cfg(not(target_arch = "wasm32"))]
+async fn window_end(storage: &dyn StorageBackend, key: &str) -> Option<u64> {
+    storage.ttl(key).await.ok().flatten().map(reset_at)
+}
+
+/// Decide one request for `key`. With `with_reset`, a decision the backend
+/// took also reports when the window ends, looking it up if need be.
+#[cfg(not(target_arch = "wasm32"))]
+async fn decide_verdict(
+    storage: &dyn StorageBackend,
+    local: &Local,
+    key: &str,
+    limit: u32,
+    window: u32,
+    with_reset: bool,
+) -> Verdict {
+    if local.denylist.as_ref().is_some_and(|denylist| denylist.contains_key(key)) {
+        metrics::counter!("rate_limiter_denylist_rejections_total").increment(1);
+        return Verdict::limited();
+    }
+    if let Some(greylist) = &local.greylist {
+        match greylist.check(key).await {
+            GreylistVerdict::Pass => {}
+            GreylistVerdict::Reject { .. } => return Verdict::limited(),
+            GreylistVerdict::Delay(delay) => tokio::time::sleep(delay).await,
+        }
+    }
+    let limit = match &local.anomaly {
+        Some(detector) => {
+            if let Some(z) = detector.observe(key) {
+                flag_anomaly(local, key, z, limit, window);
+            }
+            detector.limit_for(key, limit)
+        }
+        None => limit,
+    };
+    let limit = match &local.streams {
+        Some(streams) => streams.limit_for(key, limit),
+        None => limit,
+    };
+    if local.deny_cache.as_ref().is_some_and(|cache| cache.is_denied(key)) {
+        // The count is not read on this path; it is at least the limit
+        record_denial(local, key, u64::from(limit), limit, window);
+        return Verdict::limited();
+    }
+    if local.prefilter.as_ref().is_some_and(|prefilter| prefilter.allows(key, limit, window)) {
+        return Verdict { limited: false, remaining: u64::from(limit), reset_at: None };
+    }
+    // Allowances skip the backend, so only for keys it has nothing more to
+    // say about
+    let allow_cache = local.allow_cache.as_ref().filter(|_| local.policy.is_none() && local.aggregates.is_none());
+    let mut uncounted = 0;
+    if let Some(cache) = allow_cache {
+        if let Some(count) = cache.take(key, limit) {
+            record_allow(local, key, count, limit, window);
+            return Verdict { limited: false, remaining: u64::from(limit).saturating_sub(count), reset_at: None };
+        }
+        uncounted = u64::from(cache.settle(key));
+    }
+
+    // The client's counter, then its country's and network's, in one batch
+    let aggregates = local.aggregates.as_ref().map(|caps| caps.keys(key)).unwrap_or_default();
+    let (current_count, over_limit) = if aggregates.is_empty() {
+        let count = storage.get(key).await.unwrap_or(0) + uncounted;
+        (count, over(local, count, limit))
+    } else {
+        let mut keys = Vec::with_capacity(1 + aggregates.len());
+        keys.push(key);
+        keys.extend(aggregates.iter().map(|aggregate| aggregate.key.as_str()));
+        let counts = storage.get_many(&keys).await.unwrap_or_else(|_| vec![0; keys.len()]);
+        let capped = aggregates
+            .iter()
+            .zip(&counts[1..])
+            .find(|(aggregate, count)| **count >= u64::from(aggregate.cap.requests));
+        if let Some((aggregate, _)) = capped {
+            metrics::counter!("rate_limiter_aggregate_rejections_total", "scope" => aggregate.scope.as_str())
+                .increment(1);
+        }
+        (counts[0], over(local, counts[0], limit) || capped.is_some())
+    };
+    let limited = match &local.policy {
+        Some(policy) => {
+            let input = PolicyInput { key, count: current_count, limit, window, over_limit };
+            match policy.allow(&input).await {
+                Ok(allowed) => !allowed,
+                Err(e) => {
+                    log::warn!("Rate limit policy failed for {}, using counters: {}", key, e);
+                    over_limit
+                }
+            }
+        }
+        None => over_limit,
+    };
+
+    if limited {
+        // Only counter denials last for the rest of the window, and with
+        // smoothing only once every request would be rejected
+        let certain = local.smoothing.is_none_or(|smoothing| smoothing.probability(current_count, limit) >= 1.0);
+        if over_limit && certain {
+            if let Some(cache) = &local.deny_cache {
+                cache.deny(key, window);
+            }
+        }
+        if uncounted > 0 {
+            if let Err(e) = count_hits(storage, key, uncounted, window).await {
+                log::warn!("Failed to count {} allowed requests of {}: {}", uncounted, key, e);
+            }
+        }
+        record_denial(local, key, current_count, limit, window);
+        let reset_at = if with_reset { window_end(storage, key).await } else { None };
+        Verdict { reset_at, ..Verdict::limited() }
+    } else {
+        let mut count = current_count + 1;
+        let mut reset_at = None;
+        if aggregates.is_empty() {
+            if let Ok(counted) = count_hits(storage, key, 1 + uncounted, window).await {
+                count = counted.count;
+                // The read can miss hits: those of requests racing this one,
+                // or a first hit the memory doorkeeper held back. The count
+                // the increment returns has them
+                if local.policy.is_none() && count > u64::from(limit) {
+                    record_denial(local, key, count, limit, window);
+                    let reset_at = if with_reset { window_end(storage, key).await } else { None };
+                    return Verdict { reset_at, ..Verdict::limited() };
+                }
+                // Counters expire a window after the request that creates them
+                if counted.count == 1 + uncounted {
+                    reset_at = Some(clock::system().unix_secs() + u64::from(window));
+                }
+            }
         } else {
-            storage.increment(key, self.window_size).await.unwrap_or(());
+            let mut keys = Vec::with_capacity(1 + aggregates.len());
+            keys.push((key, window));
+            keys.extend(aggregates.iter().map(|aggregate| (aggregate.key.as_str(), aggregate.cap.window)));
+            let _ = storage.increment_many(&keys).await;
+        }
+        if with_reset && reset_at.is_none() {
+            reset_at = window_end(storage, key).await;
+        }
+        if let Some(cache) = allow_cache {
+            cache.grant(key, count, limit, window);
+        }
+        record_allow(local, key, count, limit, window);
+        Verdict { limited: false, remaining: u64::from(limit).saturating_sub(count), reset_at }
+    }
+}
+
+/// Decide one request that is part of `operation`, counting each
+/// operation once: retries of one already counted in the window add
+/// nothing. Over the limit, retries are denied like new operations.
+#[cfg(not(target_arch = "wasm32"))]
+async fn decide_operation(
+    storage: &dyn StorageBackend,
+    local: &Local,
+    key: &str,
+    operation: &str,
+    limit: u32,
+    window: u32,
+) -> bool {
+    if local.denylist.as_ref().is_some_and(|denylist| denylist.contains_key(key)) {
+        metrics::counter!("rate_limiter_denylist_rejections_total").increment(1);
+        return true;
+    }
+    match storage.add_distinct(key, operation, window).await {
+        Ok(counted) if counted.count > u64::from(limit) => {
+            record_denial(local, key, counted.count, limit, window);
+            true
+        }
+        Ok(counted) => {
+            record_allow(local, key, counted.count, limit, window);
+            false
+        }
+        // As in `decide`, a backend that cannot be read lets requests through
+        Err(e) => {
+            log::warn!("Counting operation for {} failed: {}", key, e);
             false
         }
     }
 }
 
+/// Whether `key` is banned by the honeypot, banning it first if `path` is
+/// a honeypot path.
+#[cfg(not(target_arch = "wasm32"))]
+fn trapped(local: &Local, path: &str, key: &str) -> bool {
+    let Some(honeypot) = &local.honeypot else {
+        return false;
+    };
+    if !honeypot.is_trap(path) {
+        return honeypot.is_banned(key);
+    }
+    if honeypot.ban(key) {
+        metrics::counter!("rate_limiter_honeypot_bans_total").increment(1);
+        let ban_for = honeypot.ban_for();
+        let exported = local.bans.as_ref().is_some_and(|bans| bans.ban(key, ban_for));
+        if let Some(events) = &local.events {
+            let seconds = ban_for.as_secs().min(u64::from(u32::MAX)) as u32;
+            events.emit(EventKind::Honeypot, key, 0, 0, seconds);
+            if exported {
+                events.emit(EventKind::Ban, key, 0, 0, seconds);
+            }
+        }
+    }
+    true
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn flag_anomaly(local: &Local, key: &str, z: f64, limit: u32, window: u32) {
+    log::warn!("Rate limit key {} is {:.1} standard deviations above its baseline", key, z);
+    metrics::counter!("rate_limiter_anomalies_total").increment(1);
+    if let Some(events) = &local.events {
+        events.emit(EventKind::Anomaly, key, z.max(0.0) as u64, limit, window);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn record_allow(local: &Local, key: &str, count: u64, limit: u32, window: u32) {
+    if let Some(usage) = &local.usage {
+        usage.record(key);
+    }
+    if let Some(events) = &local.events {
+        events.emit(EventKind::Allow, key, count, limit, window);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn record_denial(local: &Local, key: &str, count: u64, limit: u32, window: u32) {
+    let banned = local.bans.as_ref().is_some_and(|bans| bans.record_denial(key));
+    if let Some(events) = &local.events {
+        events.emit(EventKind::Deny, key, count, limit, window);
+        if banned {
+            events.emit(EventKind::Ban, key, count, limit, window);
+        }
+    }
+}
+
+/// The storage key for a request from `remote_addr`. Formatted on the
+/// stack; the allow path must not touch the heap.
+pub fn request_key(remote_addr: impl std::fmt::Display) -> KeyBuf {
+    let mut key = KeyBuf::new();
+    let _ = write!(key, "{}", remote_addr);
+    key
+}
+
+#[cfg(not(target_arch = "wasm32"))]
 #[async_trait]
 impl HTTPModule for RateLimiter {
     async fn handle(&self, ctx: &mut HTTPContext) -> Status {
-        let ip = ctx.remote_addr().to_string();
+        // HTTPContext exposes no headers, so only a default tenant applies
+        let client = request_key(ctx.remote_addr());
+        // Nor the path, so only bans from `check` apply
+        if trapped(&self.local, "", &client) {
+            ctx.set_status(429);
+            return Status::Declined;
+        }
+        let (storage, key, limit, window) = self.route(&RequestInfo::default(), &client);
+
+        let limited = match self.runtime.get() {
+            Some(runtime) => {
+                let local = self.local.clone();
+                let decision = async move { decide(storage.as_ref(), &local, &key, limit, window).await };
+                match runtime.submit(decision) {
+                    // A decision lost with the runtime lets the request through
+                    Ok(decision) => decision.await.unwrap_or(false),
+                    Err(_) => {
+                        ctx.set_status(runtime.overload_status());
+                        return Status::Declined;
+                    }
+                }
+            }
+            None => decide(storage.as_ref(), &self.local, &key, limit, window).await,
+        };
 
-        if self.is_rate_limited(&ip).await {
+        if limited {
             ctx.set_status(429);
             Status::Declined
         } else {
@@ -66,8 +1213,526 @@ impl HTTPModule for RateLimiter {
     }
 }
 
+/// Backend of the module until the configuration names another
+#[cfg(not(target_arch = "wasm32"))]
+const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379";
+
+#[cfg(not(target_arch = "wasm32"))]
 #[no_mangle]
 pub extern "C" fn ngx_http_rate_limiter_module() -> *mut bindings::ngx_module_t {
-    let rate_limiter = RateLimiter::new("redis", 100, 60);
+    // Opening a client does not connect, so only a bad URL could fail
+    let storage = RedisStorage::new(DEFAULT_REDIS_URL).expect("the default Redis URL is valid");
+    let rate_limiter = RateLimiter::with_storage(Arc::new(storage), 100, 60);
     nginx_module::create_http_module!(rate_limiter)
 }
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::ManualClock;
+    use proptest::prelude::*;
+    use std::time::Duration;
+    use testing::{MockStorage, Operation};
+
+    /// Allows everything except one key.
+    #[derive(Debug)]
+    struct Blocklist(&'static str);
+
+    #[async_trait]
+    impl Policy for Blocklist {
+        async fn allow(&self, input: &PolicyInput<'_>) -> Result<bool, policy::PolicyError> {
+            Ok(input.key != self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_overrides_counters() {
+        let storage = MockStorage::new();
+        let local = Local { policy: Some(Arc::new(Blocklist("banned"))), ..Local::default() };
+
+        assert!(decide(&storage, &local, "banned", 10, 60).await);
+        // Counted even past the limit, since the policy let them through
+        for _ in 0..3 {
+            assert!(!decide(&storage, &local, "partner", 1, 60).await);
+        }
+        assert_eq!(storage.get("partner").await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_tenants_have_their_own_limits_and_keys() {
+        let storage = Arc::new(MockStorage::new());
+        let tenants = tenant::TenantResolver::new(tenant::TenantSource::Host)
+            .with_tenant(tenant::Tenant::new("acme", Limits::new(2, 60)), &["acme.example.com"]);
+        let limiter = RateLimiter::with_storage(storage.clone(), 1, 60).with_tenants(tenants);
+        let acme = RequestInfo { host: Some("acme.example.com"), ..RequestInfo::default() };
+
+        assert!(!limiter.check(&acme, "192.0.2.7").await);
+        assert!(!limiter.check(&acme, "192.0.2.7").await);
+        assert!(limiter.check(&acme, "192.0.2.7").await);
+        // Unknown hosts get the limiter's own limit, in their own counter
+        assert!(!limiter.check(&RequestInfo::default(), "192.0.2.7").await);
+        assert_eq!(storage.get("acme:192.0.2.7").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retries_of_an_operation_count_once() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage, 2, 60).with_idempotency_key("Idempotency-Key");
+        let request = |headers: &'static [(&'static str, &'static str)]| RequestInfo {
+            headers,
+            ..RequestInfo::default()
+        };
+
+        for _ in 0..3 {
+            assert!(!limiter.check(&request(&[("idempotency-key", "op-1")]), "client").await);
+        }
+        assert!(!limiter.check(&request(&[("idempotency-key", "op-2")]), "client").await);
+        assert!(limiter.check(&request(&[("idempotency-key", "op-3")]), "client").await);
+        assert!(limiter.check(&request(&[]), "client").await);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_methods_are_limited_separately() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage.clone(), 5, 60).with_grpc_method_keys();
+        let limits: config::LimitsConfig = serde_json::from_str(
+            r#"{"requests": 5, "window": 60, "rules": [{"path": {"prefix": "/search.Search/Query"}, "requests": 1, "window": 60}]}"#,
+        )
+        .unwrap();
+        limiter.reload(limits.compile(2).unwrap());
+        let headers = [("content-type", "application/grpc")];
+        let call = |path: &'static str| RequestInfo { path, headers: &headers, ..RequestInfo::default() };
+
+        assert!(!limiter.check(&call("/search.Search/Query"), "client").await);
+        assert!(limiter.check(&call("/search.Search/Query"), "client").await);
+        assert!(!limiter.check(&call("/grpc.health.v1.Health/Check"), "client").await);
+        assert_eq!(storage.get("grpc:grpc.health.v1.Health/Check:client").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrades_have_their_own_limit() {
+        let storage = Arc::new(MockStorage::new());
+        let options = WebSocketOptions { upgrades: 1, ..WebSocketOptions::default() };
+        let limiter = RateLimiter::with_storage(storage.clone(), 5, 60).with_websocket_limits(options);
+        let headers = [("upgrade", "websocket")];
+        let upgrade = RequestInfo { headers: &headers, ..RequestInfo::default() };
+
+        assert!(!limiter.check(&upgrade, "client").await);
+        assert!(limiter.check(&upgrade, "client").await);
+        assert!(!limiter.check(&RequestInfo::default(), "client").await);
+        assert_eq!(storage.get("ws:client").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_uploads_are_limited_by_bytes() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage.clone(), 100, 60).with_body_quota(BodyQuota::new(1 << 20, 60));
+        let headers = [("content-length", "700000")];
+        let upload = RequestInfo { headers: &headers, ..RequestInfo::default() };
+
+        assert!(!limiter.check(&upload, "client").await);
+        assert!(limiter.check(&upload, "client").await);
+        // Chunked bodies are charged as they are read
+        assert!(!limiter.charge_body(&RequestInfo::default(), "other", 1 << 19).await);
+        assert!(limiter.charge_body(&RequestInfo::default(), "other", 1 << 20).await);
+        assert_eq!(storage.get("client").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_graphql_requests_are_charged_their_cost() {
+        let storage = Arc::new(MockStorage::new());
+        let options = GraphQlOptions { budget: 4, max_depth: Some(3), ..GraphQlOptions::default() };
+        let limiter = RateLimiter::with_storage(storage.clone(), 100, 60).with_graphql(options);
+        let query = |body: &'static [u8]| RequestInfo { path: "/graphql", body: Some(body), ..RequestInfo::default() };
+
+        assert!(!limiter.check(&query(br#"{"query": "{ me { name email } }"}"#), "client").await);
+        assert!(limiter.check(&query(b"{ a { b { c { d } } } }"), "other").await);
+        assert!(limiter.check(&query(b"{ feed { title } }"), "client").await);
+        assert_eq!(storage.get("graphql:client").await.unwrap(), 5);
+    }
+
+    #[derive(Debug)]
+    struct StaticIntrospector;
+
+    #[async_trait::async_trait]
+    impl introspection::Introspector for StaticIntrospector {
+        async fn introspect(
+            &self,
+            token: &str,
+        ) -> Result<introspection::Introspection, introspection::IntrospectionError> {
+            Ok(introspection::Introspection {
+                active: true,
+                client_id: Some(token.to_string()),
+                scope: Some(if token == "ops" { "admin" } else { "read" }.to_string()),
+                exp: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_clients_are_limited_by_client_id_and_scope() {
+        let storage = Arc::new(MockStorage::new());
+        let clients = TokenClients::new(Arc::new(StaticIntrospector), Default::default());
+        let limiter = RateLimiter::with_storage(storage.clone(), 1, 60).with_token_introspection(clients);
+        let limits: config::LimitsConfig = serde_json::from_str(
+            r#"{"requests": 1, "window": 60, "rules": [{"path": {"prefix": "/"}, "requests": 3, "window": 60, "scope": "admin"}]}"#,
+        )
+        .unwrap();
+        limiter.reload(limits.compile(2).unwrap());
+        let bearer = |headers: &'static [(&'static str, &'static str)]| RequestInfo { path: "/", headers, ..RequestInfo::default() };
+
+        // Two addresses, one client
+        assert!(!limiter.check(&bearer(&[("authorization", "Bearer app")]), "192.0.2.1").await);
+        assert!(limiter.check(&bearer(&[("authorization", "Bearer app")]), "192.0.2.2").await);
+        for _ in 0..3 {
+            assert!(!limiter.check(&bearer(&[("authorization", "Bearer ops")]), "192.0.2.1").await);
+        }
+        assert_eq!(storage.get("client:ops").await.unwrap(), 3);
+        assert!(!limiter.check(&RequestInfo::default(), "192.0.2.1").await);
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates_are_limited_by_identity() {
+        let storage = Arc::new(MockStorage::new());
+        let options = client_cert::ClientCertOptions::new(client_cert::CertIdentity::San);
+        let limiter = RateLimiter::with_storage(storage.clone(), 1, 60).with_client_cert_keys(options);
+        let headers = [("X-SSL-Client-Verify", "SUCCESS"), ("X-SSL-Client-SAN", "URI:spiffe://prod/billing")];
+        let machine = RequestInfo { headers: &headers, ..RequestInfo::default() };
+
+        // One machine behind NAT from two addresses
+        assert!(!limiter.check(&machine, "192.0.2.1").await);
+        assert!(limiter.check(&machine, "192.0.2.2").await);
+        assert!(!limiter.check(&RequestInfo::default(), "192.0.2.1").await);
+        assert_eq!(storage.get("cert:URI:spiffe://prod/billing").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth_users_are_limited_by_username() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage.clone(), 2, 60).with_basic_auth_keys();
+        let login = |credentials: &'static str| [("Authorization", credentials)];
+
+        // alice:right, then alice:wrong from elsewhere
+        let right = login("Basic YWxpY2U6cmlnaHQ=");
+        let wrong = login("Basic YWxpY2U6d3Jvbmc=");
+        assert!(!limiter.check(&RequestInfo { headers: &right, ..RequestInfo::default() }, "192.0.2.1").await);
+        assert!(!limiter.check(&RequestInfo { headers: &wrong, ..RequestInfo::default() }, "192.0.2.2").await);
+        assert!(limiter.check(&RequestInfo { headers: &right, ..RequestInfo::default() }, "192.0.2.1").await);
+        assert_eq!(storage.get("user:alice").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_query_keys_count_across_spellings() {
+        let storage = Arc::new(MockStorage::new());
+        let query_key = QueryKey::new("api_key").with_case_folding().with_pattern("^[0-9a-f]{8}$").unwrap();
+        let limiter = RateLimiter::with_storage(storage.clone(), 2, 60).with_query_key(query_key);
+        let get = |path: &'static str| RequestInfo { path, ..RequestInfo::default() };
+
+        assert!(!limiter.check(&get("/items?api_key=DEADBEEF"), "192.0.2.1").await);
+        assert!(!limiter.check(&get("/items?api_key=dead%62eef"), "192.0.2.2").await);
+        assert!(limiter.check(&get("/items?api_key=deadbeef"), "192.0.2.3").await);
+        // Not a key: counted by address
+        assert!(!limiter.check(&get("/items?api_key=guess"), "192.0.2.3").await);
+        assert_eq!(storage.get("api_key:deadbeef").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_key_sources_fall_back_in_order() {
+        let storage = Arc::new(MockStorage::new());
+        let sources = vec![KeySource::Header("X-API-Key".to_string()), KeySource::Cookie("sid".to_string())];
+        let limiter = RateLimiter::with_storage(storage.clone(), 1, 60).with_key_sources(sources);
+        let request = |headers: &'static [(&'static str, &'static str)]| RequestInfo { headers, ..RequestInfo::default() };
+
+        assert_eq!(limiter.key_for(&request(&[("x-api-key", "k1")]), "192.0.2.1").1, "header");
+        assert_eq!(limiter.key_for(&request(&[("cookie", "sid=abc")]), "192.0.2.1").1, "cookie");
+        assert_eq!(limiter.key_for(&request(&[]), "192.0.2.1").1, "address");
+
+        assert!(!limiter.check(&request(&[("x-api-key", "k1")]), "192.0.2.1").await);
+        assert!(limiter.check(&request(&[("x-api-key", "k1"), ("cookie", "sid=abc")]), "192.0.2.2").await);
+        assert!(!limiter.check(&request(&[("cookie", "sid=abc")]), "192.0.2.1").await);
+        assert_eq!(storage.get("x-api-key:k1").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rates_come_from_the_rate_variable() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage, 1, 60).with_rate_variable("X-Limit-For-Plan");
+        let plan = |rate: &'static str| [("X-Limit-For-Plan", rate)];
+
+        let premium = plan("3r/m");
+        for _ in 0..3 {
+            assert!(!limiter.check(&RequestInfo { headers: &premium, ..RequestInfo::default() }, "premium").await);
+        }
+        assert!(limiter.check(&RequestInfo { headers: &premium, ..RequestInfo::default() }, "premium").await);
+        // Empty and invalid values leave the configured rate
+        let unset = plan("");
+        let invalid = plan("lots");
+        assert!(!limiter.check(&RequestInfo { headers: &unset, ..RequestInfo::default() }, "free").await);
+        assert!(limiter.check(&RequestInfo { headers: &invalid, ..RequestInfo::default() }, "free").await);
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_charged_once() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage.clone(), 2, 60).with_memoized_decisions(MemoOptions::default());
+        let first = [("X-Request-Id", "r1")];
+        let second = [("X-Request-Id", "r2")];
+
+        // auth_request, then the main request after an internal redirect
+        for path in ["/auth", "/app", "/@fallback"] {
+            assert!(!limiter.check(&RequestInfo { path, headers: &first, ..RequestInfo::default() }, "client").await);
+        }
+        assert_eq!(storage.get("client").await.unwrap(), 1);
+        assert!(!limiter.check(&RequestInfo { headers: &second, ..RequestInfo::default() }, "client").await);
+        assert!(limiter.check(&RequestInfo::default(), "client").await);
+    }
+
+    #[tokio::test]
+    async fn test_internal_requests_and_probes_are_not_counted() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage.clone(), 1, 60);
+        let headers = [("User-Agent", "ELB-HealthChecker/2.0")];
+        let probe = RequestInfo { path: "/healthz", headers: &headers, ..RequestInfo::default() };
+        let error_page = RequestInfo { path: "/50x.html", internal: true, ..RequestInfo::default() };
+
+        for _ in 0..3 {
+            assert!(!limiter.check(&probe, "10.0.0.2").await);
+            assert!(!limiter.check(&error_page, "client").await);
+        }
+        assert_eq!(storage.get("client").await.unwrap(), 0);
+
+        let limiter = RateLimiter::with_storage(storage, 1, 60).with_exclusions(Exclusions::none());
+        assert!(!limiter.check(&probe, "10.0.0.2").await);
+        assert!(limiter.check(&probe, "10.0.0.2").await);
+    }
+
+    #[tokio::test]
+    async fn test_smoothing_rejects_some_requests_before_the_limit() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage.clone(), 100, 60).with_smoothing(Smoothing::new(0.5, 1.0));
+
+        let mut rejected = 0;
+        for _ in 0..200 {
+            if limiter.is_rate_limited("client").await {
+                rejected += 1;
+            }
+        }
+        let counted = storage.get("client").await.unwrap();
+        assert!((50..=100).contains(&counted), "{} counted", counted);
+        assert_eq!(rejected, 200 - counted);
+    }
+
+    #[tokio::test]
+    async fn test_low_priority_requests_yield_under_contention() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage.clone(), 100, 60)
+            .with_priority_admission(PriorityAdmission::new(4, 60).with_share(Priority::Low, 0.5));
+        let limits: config::LimitsConfig = serde_json::from_str(
+            r#"{"requests": 100, "window": 60, "rules": [
+                {"path": {"prefix": "/paid/"}, "requests": 100, "window": 60, "priority": "high"},
+                {"path": {"prefix": "/"}, "requests": 100, "window": 60, "priority": "low"}]}"#,
+        )
+        .unwrap();
+        limiter.reload(limits.compile(2).unwrap());
+        let get = |path: &'static str| RequestInfo { path, ..RequestInfo::default() };
+
+        assert!(!limiter.check(&get("/free"), "anonymous").await);
+        assert!(!limiter.check(&get("/free"), "anonymous").await);
+        assert!(limiter.check(&get("/free"), "anonymous").await);
+        assert!(!limiter.check(&get("/paid/report"), "customer").await);
+        assert!(!limiter.check(&get("/paid/report"), "customer").await);
+        assert!(limiter.check(&get("/paid/report"), "customer").await);
+        assert_eq!(storage.get("priority:low").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_emergency_brake_holds_anonymous_traffic() {
+        let storage = Arc::new(MockStorage::new());
+        let options = emergency::EmergencyOptions {
+            requests: 1,
+            window: 60,
+            anonymous_only: true,
+            ..emergency::EmergencyOptions::default()
+        };
+        let brake = Arc::new(EmergencyBrake::new(options));
+        let limiter = RateLimiter::with_storage(storage.clone(), 100, 60)
+            .with_key_sources(vec![KeySource::Header("X-Api-Key".to_string())])
+            .with_emergency_brake(brake.clone());
+        let keyed: &'static [(&str, &str)] = &[("X-Api-Key", "k1")];
+        let customer = RequestInfo { headers: keyed, ..RequestInfo::default() };
+        let anonymous = RequestInfo::default();
+
+        assert!(!limiter.check(&anonymous, "10.0.0.1").await);
+        assert!(!limiter.check(&anonymous, "10.0.0.1").await);
+        emergency::engage_everywhere(storage.as_ref(), 3600).await.unwrap();
+        brake.refresh(storage.as_ref()).await.unwrap();
+        assert!(limiter.check(&anonymous, "10.0.0.1").await);
+        assert!(!limiter.check(&customer, "10.0.0.1").await);
+        assert!(!limiter.check(&customer, "10.0.0.1").await);
+
+        emergency::release_everywhere(storage.as_ref()).await.unwrap();
+        brake.refresh(storage.as_ref()).await.unwrap();
+        assert!(!limiter.check(&anonymous, "10.0.0.1").await);
+    }
+
+    #[tokio::test]
+    async fn test_doorkeeper_first_hit_counts_against_the_limit() {
+        let storage = Arc::new(storage::MemoryStorage::with_options(storage::MemoryOptions {
+            doorkeeper_bits: Some(1 << 16),
+            ..storage::MemoryOptions::default()
+        }));
+        let limiter = RateLimiter::with_storage(storage, 1, 60);
+
+        assert!(!limiter.check(&RequestInfo::default(), "client").await);
+        assert!(limiter.check(&RequestInfo::default(), "client").await);
+        assert!(limiter.check(&RequestInfo::default(), "client").await);
+    }
+
+    #[tokio::test]
+    async fn test_emergency_brake_holds_websocket_upgrades() {
+        let storage = Arc::new(MockStorage::new());
+        let options = emergency::EmergencyOptions {
+            requests: 1,
+            window: 60,
+            ..emergency::EmergencyOptions::default()
+        };
+        let brake = Arc::new(EmergencyBrake::new(options));
+        let limiter = RateLimiter::with_storage(storage.clone(), 100, 60)
+            .with_websocket_limits(WebSocketOptions { upgrades: 100, ..WebSocketOptions::default() })
+            .with_emergency_brake(brake.clone());
+        let headers = [("upgrade", "websocket")];
+        let upgrade = RequestInfo { headers: &headers, ..RequestInfo::default() };
+
+        assert!(!limiter.check(&upgrade, "client").await);
+        assert!(!limiter.check(&upgrade, "client").await);
+        brake.engage();
+        assert!(limiter.check(&upgrade, "client").await);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_windows_lift_limits_until_they_close() {
+        let storage = Arc::new(MockStorage::new());
+        let clock = Arc::new(ManualClock::default());
+        // Tuesdays from 22:00 UTC; the clock starts at 22:13
+        let load_test = maintenance::MaintenanceWindow::new("load-test", "0 22 * * 2", Duration::from_secs(3600))
+            .unwrap()
+            .with_networks(vec![denylist::Network::parse("10.0.0.0/8").unwrap()]);
+        let limiter = RateLimiter::with_storage(storage.clone(), 1, 60)
+            .with_maintenance(Maintenance::new(vec![load_test]).with_clock(clock.clone()));
+        let request = RequestInfo::default();
+
+        for _ in 0..3 {
+            assert!(!limiter.check(&request, "10.1.2.3").await);
+        }
+        assert_eq!(storage.get("10.1.2.3").await.unwrap(), 0);
+        assert!(!limiter.check(&request, "192.0.2.1").await);
+        assert!(limiter.check(&request, "192.0.2.1").await);
+
+        clock.advance(Duration::from_secs(3600));
+        assert!(!limiter.check(&request, "10.1.2.3").await);
+        assert!(limiter.check(&request, "10.1.2.3").await);
+    }
+
+    #[tokio::test]
+    async fn test_allow_cache_skips_the_backend_far_from_the_limit() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage.clone(), 100, 60).with_allow_cache(AllowCacheOptions::default());
+
+        let first = limiter.check_and_increment("client").await;
+        assert!(!first.limited);
+        assert_eq!(first.remaining, 99);
+        assert!(first.reset_at.is_some());
+        for _ in 0..9 {
+            assert!(!limiter.is_rate_limited("client").await);
+        }
+        assert_eq!(storage.get("client").await.unwrap(), 1);
+
+        let settled = limiter.check_and_increment("client").await;
+        assert_eq!(storage.get("client").await.unwrap(), 11);
+        assert_eq!(settled.remaining, 89);
+        assert_eq!(settled.reset_at, first.reset_at);
+        assert_eq!(storage.call_count(Operation::Ttl), 1);
+
+        // Taken from the allowance: no backend call, so no reset either
+        let cached = limiter.check_and_increment("client").await;
+        assert_eq!((cached.limited, cached.reset_at), (false, None));
+        assert_eq!(storage.call_count(Operation::Ttl), 1);
+    }
+
+    #[tokio::test]
+    async fn test_body_rules_count_apart() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage.clone(), 100, 60);
+        let limits: config::LimitsConfig = serde_json::from_str(
+            r#"{"requests": 100, "window": 60, "rules": [{"path": {"prefix": "/graphql"}, "requests": 1, "window": 60,
+                "body": {"contains": "mutation"}, "counter": "mutations"}]}"#,
+        )
+        .unwrap();
+        limiter.reload(limits.compile(2).unwrap());
+        let request = |body: &'static [u8]| RequestInfo { path: "/graphql", body: Some(body), ..RequestInfo::default() };
+
+        assert!(!limiter.check(&request(b"mutation { a }"), "client").await);
+        assert!(limiter.check(&request(b"mutation { b }"), "client").await);
+        assert!(!limiter.check(&request(b"query { a }"), "client").await);
+        assert_eq!(storage.get("mutations:client").await.unwrap(), 1);
+        assert_eq!(storage.get("client").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_honeypot_paths_ban_the_client() {
+        let storage = Arc::new(MockStorage::new());
+        let limiter = RateLimiter::with_storage(storage, 100, 60)
+            .with_honeypot(HoneypotOptions::new(vec!["/wp-login.php".into()]));
+        let request = |path: &'static str| RequestInfo { path, ..RequestInfo::default() };
+
+        assert!(!limiter.check(&request("/"), "192.0.2.7").await);
+        assert!(limiter.check(&request("/wp-login.php"), "192.0.2.7").await);
+        assert!(limiter.check(&request("/"), "192.0.2.7").await);
+        assert!(!limiter.check(&request("/"), "192.0.2.8").await);
+    }
+
+    proptest! {
+        /// One client never gets more than `limit` requests through in a
+        /// window, with or without a deny cache, and gets `limit` again
+        /// once the window has passed. Requests are sequential here;
+        /// `decide` reads then increments, so concurrent requests can
+        /// overshoot.
+        #[test]
+        fn prop_at_most_limit_per_window(limit in 0..50u32, requests in 0..120usize, cached in any::<bool>()) {
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            let clock = Arc::new(ManualClock::default());
+            let storage = MockStorage::new().with_clock(clock.clone());
+            let local = Local {
+                denylist: None,
+                deny_cache: cached.then(|| {
+                    Arc::new(DenyCache::new(DenyCacheOptions::default()).with_clock(clock.clone()))
+                }),
+                prefilter: None,
+                policy: None,
+                bans: None,
+                events: None,
+                usage: None,
+                anomaly: None,
+                greylist: None,
+                aggregates: None,
+                honeypot: None,
+                streams: None,
+                smoothing: None,
+                allow_cache: None,
+            };
+
+            for _ in 0..2 {
+                let allowed = runtime.block_on(async {
+                    let mut allowed = 0;
+                    for _ in 0..requests {
+                        allowed += !decide(&storage, &local, "client", limit, 60).await as usize;
+                    }
+                    allowed
+                });
+                prop_assert_eq!(allowed, requests.min(limit as usize));
+                clock.advance(Duration::from_secs(61));
+            }
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use crate::clock::{self, civil_from_days, Clock};
+use crate::denylist::Network;
+
+/// Longest window scanned for its start, so a typo in a duration cannot
+/// make every decision walk years of minutes.
+const MAX_WINDOW_MINUTES: u64 = 31 * 24 * 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CronError {
+    #[error("Invalid cron expression {0:?}, expected minute, hour, day of month, month and day of week")]
+    Invalid(String),
+}
+
+/// A five-field cron expression, `minute hour day-of-month month
+/// day-of-week`, in UTC. Fields take `*`, numbers, ranges, lists and
+/// steps, e.g. `0 2 * * 6` or `*/15 9-17 * * 1-5`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day fields start with `*`; as in cron, when both are
+    /// restricted a day matching either one matches
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || CronError::Invalid(value.to_string());
+        let fields: Vec<&str> = value.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(invalid());
+        };
+        let weekdays = field(weekday, 0, 7).ok_or_else(invalid)?;
+        Ok(Self {
+            minutes: field(minute, 0, 59).ok_or_else(invalid)?,
+            hours: field(hour, 0, 23).ok_or_else(invalid)?,
+            days: field(day, 1, 31).ok_or_else(invalid)?,
+            months: field(month, 1, 12).ok_or_else(invalid)?,
+            // Sunday is both 0 and 7
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+}
+
+/// The values a cron field lists between `min` and `max`, as bits.
+fn field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut bits = 0u64;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>().ok().filter(|step| *step > 0)?),
+            None => (item, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            // `5/15` steps from 5 to the end of the field
+            None => {
+                let start = range.parse().ok()?;
+                (start, if step > 1 { max } else { start })
+            }
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Some(bits)
+}
+
+impl Cron {
+    /// Whether the expression fires in the minute `unix_minute` minutes
+    /// after the epoch.
+    pub fn matches(&self, unix_minute: u64) -> bool {
+        let days = unix_minute / 1440;
+        let (_, month, day) = civil_from_days(days as i64);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4) % 7;
+        let (day, weekday) = (self.days >> day & 1 == 1, self.weekdays >> weekday & 1 == 1);
+        let day_matches = if self.any_day || self.any_weekday { day && weekday } else { day || weekday };
+        self.minutes >> (unix_minute % 60) & 1 == 1
+            && self.hours >> (unix_minute / 60 % 24) & 1 == 1
+            && self.months >> month & 1 == 1
+            && day_matches
+    }
+}
+
+/// What a maintenance window does to the limits of the traffic it covers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Relaxation {
+    /// Let requests through uncounted
+    Disable,
+    /// Multiply limits by the factor
+    Scale(f64),
+}
+
+/// A recurring period during which limits are relaxed for some keys or
+/// networks, e.g. for a planned load test.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceWindow {
+    /// Used in logs and in the `window` label of metrics
+    pub name: String,
+    /// When the window opens
+    pub cron: Cron,
+    /// How long it stays open, at most 31 days
+    pub duration: Duration,
+    /// Keys relaxed, as `check` counts them
+    pub keys: Vec<String>,
+    /// Client addresses relaxed
+    pub networks: Vec<Network>,
+    pub relaxation: Relaxation,
+}
+
+impl MaintenanceWindow {
+    /// A window opening at `cron` for `duration` that disables limiting
+    /// for all traffic until narrowed with `with_keys` or `with_networks`.
+    pub fn new(name: &str, cron: &str, duration: Duration) -> Result<Self, CronError> {
+        Ok(Self {
+            name: name.to_string(),
+            cron: cron.parse()?,
+            duration,
+            keys: Vec::new(),
+            networks: Vec::new(),
+            relaxation: Relaxation::Disable,
+        })
+    }
+
+    pub fn with_keys(mut self, keys: &[&str]) -> Self {
+        self.keys = keys.iter().map(|key| key.to_string()).collect();
+        self
+    }
+
+    pub fn with_networks(mut self, networks: Vec<Network>) -> Self {
+        self.networks = networks;
+        self
+    }
+
+    pub fn with_relaxation(mut self, relaxation: Relaxation) -> Self {
+        self.relaxation = relaxation;
+        self
+    }
+
+    /// Whether the window applies to a request from `address` counted
+    /// under `key`. A window naming no keys or networks covers everything.
+    pub fn covers(&self, address: &str, key: &str) -> bool {
+        if self.keys.is_empty() && self.networks.is_empty() {
+            return true;
+        }
+        self.keys.iter().any(|covered| covered == key)
+            || address
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| self.networks.iter().any(|network| network.contains(ip)))
+    }
+
+    /// The minute the window last opened, if it is still open at
+    /// `unix_minute`.
+    pub fn opened_at(&self, unix_minute: u64) -> Option<u64> {
+        let span = (self.duration.as_secs() / 60).clamp(1, MAX_WINDOW_MINUTES);
+        (0..span.min(unix_minute + 1))
+            .map(|back| unix_minute - back)
+            .find(|minute| self.cron.matches(*minute))
+    }
+}
+
+#[derive(Debug)]
+struct OpenWindows {
+    /// Minute the windows were last evaluated at
+    minute: u64,
+    /// Minute each window opened, while it is open
+    opened: Vec<Option<u64>>,
+}
+
+/// Scheduled windows relaxing limits, reverting on their own when they
+/// close.
+///
+/// The windows are evaluated once a minute. Each opening and closing is
+/// logged at warn level under the `rate_limiter::audit` target, so there
+/// is a record of when limits were off and for whom.
+#[derive(Debug)]
+pub struct Maintenance {
+    windows: Vec<MaintenanceWindow>,
+    open: RwLock<OpenWindows>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Maintenance {
+    pub fn new(windows: Vec<MaintenanceWindow>) -> Self {
+        let opened = vec![None; windows.len()];
+        Self {
+            windows,
+            open: RwLock::new(OpenWindows { minute: u64::MAX, opened }),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The limit for a request from `address` counted under `key`, relaxed
+    /// by the open windows covering it, or `None` when one disables
+    /// limiting for it.
+    pub fn relax(&self, address: &str, key: &str, limit: u32, window: u32) -> Option<(u32, u32)> {
+        let minute = self.clock.unix_secs() / 60;
+        {
+            let open = self.open.read().unwrap_or_else(PoisonError::into_inner);
+            if open.minute == minute {
+                return self.apply(&open.opened, address, key, limit, window);
+            }
+        }
+        let mut open = self.open.write().unwrap_or_else(PoisonError::into_inner);
+        if open.minute != minute {
+            self.update(&mut open, minute);
+        }
+        self.apply(&open.opened, address, key, limit, window)
+    }
+
+    fn apply(&self, opened: &[Option<u64>], address: &str, key: &str, limit: u32, window: u32) -> Option<(u32, u32)> {
+        let mut relaxed = limit;
+        for (maintenance, _) in self.windows.iter().zip(opened).filter(|(_, opened)| opened.is_some()) {
+            if !maintenance.covers(address, key) {
+                continue;
+            }
+            metrics::counter!("rate_limiter_maintenance_relaxed_total", "window" => maintenance.name.clone())
+                .increment(1);
+            match maintenance.relaxation {
+                Relaxation::Disable => return None,
+                Relaxation::Scale(factor) => {
+                    relaxed = relaxed.max((f64::from(limit) * factor).min(f64::from(u32::MAX)) as u32);
+                }
+            }
+        }
+        Some((relaxed, window))
+    }
+
+    fn update(&self, open: &mut OpenWindows, minute: u64) {
+        for (maintenance, opened) in self.windows.iter().zip(open.opened.iter_mut()) {
+            let now = maintenance.opened_at(minute);
+            match (*opened, now) {
+                (None, Some(start)) => log::warn!(
+                    target: "rate_limiter::audit",
+                    "Maintenance window {} opened, relaxing limits ({:?}) for {} key(s) and {} network(s) until {}",
+                    maintenance.name,
+                    maintenance.relaxation,
+                    maintenance.keys.len(),
+                    maintenance.networks.len(),
+                    start * 60 + maintenance.duration.as_secs()
+                ),
+                (Some(_), None) => log::warn!(
+                    target: "rate_limiter::audit",
+                    "Maintenance window {} closed, limits restored",
+                    maintenance.name
+                ),
+                _ => {}
+            }
+            *opened = now;
+        }
+        open.minute = minute;
+        let count = open.opened.iter().filter(|opened| opened.is_some()).count();
+        metrics::gauge!("rate_limiter_maintenance_windows_open").set(count as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_windows_open_on_schedule_and_revert() {
+        // Saturday 2023-11-18 02:00 UTC
+        let saturday = 1_700_272_800;
+        let cron: Cron = "0 2 * * 6".parse().unwrap();
+        assert!(cron.matches(saturday / 60));
+        assert!(!cron.matches(saturday / 60 + 1));
+        assert!(!cron.matches(saturday / 60 - 1440));
+        assert!("*/15 9-17 1,15 * 1-5".parse::<Cron>().is_ok());
+        assert!("0 24 * * *".parse::<Cron>().is_err());
+        assert!("0 2 * *".parse::<Cron>().is_err());
+
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(saturday - 60)));
+        let load_test = MaintenanceWindow::new("load-test", "0 2 * * 6", Duration::from_secs(7200))
+            .unwrap()
+            .with_networks(vec![Network::parse("10.0.0.0/8").unwrap()]);
+        let relaxed = MaintenanceWindow::new("partners", "0 2 * * 6", Duration::from_secs(3600))
+            .unwrap()
+            .with_keys(&["partner"])
+            .with_relaxation(Relaxation::Scale(10.0));
+        let maintenance = Maintenance::new(vec![load_test, relaxed]).with_clock(clock.clone());
+
+        assert_eq!(maintenance.relax("10.1.2.3", "10.1.2.3", 100, 60), Some((100, 60)));
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(maintenance.relax("10.1.2.3", "10.1.2.3", 100, 60), None);
+        assert_eq!(maintenance.relax("192.0.2.1", "partner", 100, 60), Some((1000, 60)));
+        assert_eq!(maintenance.relax("192.0.2.1", "192.0.2.1", 100, 60), Some((100, 60)));
+
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(maintenance.relax("192.0.2.1", "partner", 100, 60), Some((100, 60)));
+        assert_eq!(maintenance.relax("10.1.2.3", "10.1.2.3", 100, 60), None);
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(maintenance.relax("10.1.2.3", "10.1.2.3", 100, 60), Some((100, 60)));
+    }
+}
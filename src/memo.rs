@@ -0,0 +1,133 @@
+use dashmap::DashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+use crate::storage::KeyBuf;
+use crate::tenant::RequestInfo;
+
+/// Options of `DecisionMemo`.
+#[derive(Debug, Clone)]
+pub struct MemoOptions {
+    /// Pseudo-header carrying the id of the client request, filled in from
+    /// `$request_id`, which internal redirects and subrequests share
+    pub header: String,
+    /// Charge each subrequest URI of a request once, rather than the
+    /// request as a whole once (`rate_limit_charge_subrequests`)
+    pub charge_subrequests: bool,
+    /// How long a decision is remembered; longer than any request lasts
+    pub ttl: Duration,
+    /// Decisions kept before expired ones are purged on insert
+    pub max_entries: usize,
+}
+
+impl Default for MemoOptions {
+    fn default() -> Self {
+        Self {
+            header: "X-Request-Id".to_string(),
+            charge_subrequests: false,
+            ttl: Duration::from_secs(60),
+            max_entries: 100_000,
+        }
+    }
+}
+
+/// Remembers the decision for each client request, so one evaluated in
+/// several phases, through `auth_request` or after internal redirects is
+/// charged once and gets the same answer every time.
+pub struct DecisionMemo {
+    /// Request id to the decision and the clock time it is forgotten
+    decisions: DashMap<String, (bool, Duration)>,
+    options: MemoOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for DecisionMemo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecisionMemo")
+            .field("decisions", &self.decisions.len())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl DecisionMemo {
+    pub fn new(options: MemoOptions) -> Self {
+        Self {
+            decisions: DashMap::new(),
+            options,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// What `request` is remembered under: its id, and with
+    /// `charge_subrequests` its path. `None` for requests without an id,
+    /// which are decided every time.
+    pub fn key(&self, request: &RequestInfo<'_>) -> Option<KeyBuf> {
+        let id = request.header(&self.options.header)?.trim();
+        if id.is_empty() {
+            return None;
+        }
+        let mut key = KeyBuf::from(id);
+        if self.options.charge_subrequests {
+            let _ = write!(key, " {}", request.path);
+        }
+        Some(key)
+    }
+
+    /// The decision remembered under `key`.
+    pub fn get(&self, key: &str) -> Option<bool> {
+        let now = self.clock.now();
+        let decision = self.decisions.get(key).filter(|entry| entry.1 > now).map(|entry| entry.0);
+        if decision.is_some() {
+            metrics::counter!("rate_limiter_memoized_decisions_total").increment(1);
+        }
+        decision
+    }
+
+    pub fn insert(&self, key: &str, limited: bool) {
+        let now = self.clock.now();
+        if self.decisions.len() >= self.options.max_entries {
+            self.decisions.retain(|_, entry| entry.1 > now);
+        }
+        self.decisions.insert(key.to_string(), (limited, now + self.options.ttl));
+    }
+
+    /// Forget decisions that have run out.
+    pub fn purge(&self) {
+        let now = self.clock.now();
+        self.decisions.retain(|_, entry| entry.1 > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_decisions_are_remembered_per_request() {
+        let clock = Arc::new(ManualClock::default());
+        let memo = DecisionMemo::new(MemoOptions::default()).with_clock(clock.clone());
+        let headers = [("X-Request-Id", "f3a1")];
+        let request = RequestInfo { path: "/auth", headers: &headers, ..RequestInfo::default() };
+
+        let key = memo.key(&request).unwrap();
+        assert_eq!(memo.get(&key), None);
+        memo.insert(&key, true);
+        assert_eq!(memo.get(&key), Some(true));
+        assert!(memo.key(&RequestInfo::default()).is_none());
+
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(memo.get(&key), None);
+        memo.purge();
+
+        let per_uri = DecisionMemo::new(MemoOptions { charge_subrequests: true, ..MemoOptions::default() });
+        assert_eq!(per_uri.key(&request).unwrap().as_str(), "f3a1 /auth");
+    }
+}
@@ -0,0 +1,249 @@
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+
+/// Limits of `StreamTracker`.
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    /// Streams one connection may have open at once
+    pub max_concurrent: u32,
+    /// Streams one connection may open per `window` before its client
+    /// counts as excessive
+    pub max_opened: u32,
+    /// Streams one connection may reset per `window`, against rapid-reset
+    /// floods that open and cancel streams without ever hitting
+    /// `max_concurrent`
+    pub max_resets: u32,
+    pub window: Duration,
+    /// Fraction of its limit an excessive client gets, e.g. 0.5
+    pub tighten: f64,
+    /// How long a client stays excessive
+    pub penalty: Duration,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 100,
+            max_opened: 1000,
+            max_resets: 100,
+            window: Duration::from_secs(10),
+            tighten: 0.5,
+            penalty: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Why a stream was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamRejection {
+    /// The connection has `max_concurrent` streams open
+    TooManyConcurrent,
+}
+
+#[derive(Debug)]
+struct Connection {
+    address: IpAddr,
+    open: u32,
+    window: u64,
+    opened: u32,
+    resets: u32,
+}
+
+/// Tracks the streams of multiplexed HTTP/2 and HTTP/3 connections.
+///
+/// Per-connection heuristics from HTTP/1.1 do not hold when one connection
+/// carries hundreds of requests: every stream is a request and is counted
+/// against the client's key as such, while this caps the streams a
+/// connection has open at once. Clients whose connections open or reset
+/// streams faster than `max_opened` or `max_resets` per `window` are
+/// excessive for `penalty`, and get `tighten` of their limit from the
+/// limiter they are attached to.
+///
+/// Call `start` for each new stream with the connection's id and keep the
+/// returned `Stream` until the stream ends; call `Stream::reset` instead
+/// when the client cancels it, and `close` when the connection goes away.
+pub struct StreamTracker {
+    connections: DashMap<u64, Connection>,
+    /// Address to the clock time it stops being excessive
+    excessive: DashMap<IpAddr, Duration>,
+    options: StreamOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for StreamTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamTracker")
+            .field("connections", &self.connections.len())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// An open stream; ends when dropped.
+pub struct Stream {
+    tracker: Arc<StreamTracker>,
+    connection: u64,
+}
+
+impl Stream {
+    /// End the stream as cancelled by the client (`RST_STREAM`, or
+    /// `STOP_SENDING` on HTTP/3).
+    pub fn reset(self) {
+        self.tracker.count_reset(self.connection);
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Some(mut connection) = self.tracker.connections.get_mut(&self.connection) {
+            connection.open = connection.open.saturating_sub(1);
+        }
+    }
+}
+
+impl StreamTracker {
+    pub fn new(options: StreamOptions) -> Self {
+        Self {
+            connections: DashMap::new(),
+            excessive: DashMap::new(),
+            options,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn window(&self, now: Duration) -> u64 {
+        (now.as_millis() / self.options.window.as_millis().max(1)) as u64
+    }
+
+    /// Open a stream on `connection`, from `address`, unless the connection
+    /// has too many open.
+    pub fn start(self: &Arc<Self>, connection: u64, address: IpAddr) -> Result<Stream, StreamRejection> {
+        let now = self.clock.now();
+        let window = self.window(now);
+        let excessive = {
+            let mut state = self.connections.entry(connection).or_insert(Connection {
+                address,
+                open: 0,
+                window,
+                opened: 0,
+                resets: 0,
+            });
+            if state.open >= self.options.max_concurrent {
+                metrics::counter!("rate_limiter_stream_rejections_total").increment(1);
+                return Err(StreamRejection::TooManyConcurrent);
+            }
+            if state.window != window {
+                state.window = window;
+                state.opened = 0;
+                state.resets = 0;
+            }
+            state.open += 1;
+            state.opened += 1;
+            state.opened == self.options.max_opened.saturating_add(1)
+        };
+        if excessive {
+            self.penalize(address, now);
+        }
+        Ok(Stream { tracker: Arc::clone(self), connection })
+    }
+
+    fn count_reset(&self, connection: u64) {
+        let now = self.clock.now();
+        let window = self.window(now);
+        let address = match self.connections.get_mut(&connection) {
+            Some(mut state) => {
+                if state.window != window {
+                    state.window = window;
+                    state.opened = 0;
+                    state.resets = 0;
+                }
+                state.resets += 1;
+                (state.resets == self.options.max_resets.saturating_add(1)).then_some(state.address)
+            }
+            None => None,
+        };
+        if let Some(address) = address {
+            self.penalize(address, now);
+        }
+    }
+
+    fn penalize(&self, address: IpAddr, now: Duration) {
+        metrics::counter!("rate_limiter_excessive_streams_total").increment(1);
+        log::info!("{} opens or resets streams excessively; tightening its limit", address);
+        self.excessive.insert(address, now + self.options.penalty);
+    }
+
+    /// Forget a connection that has closed.
+    pub fn close(&self, connection: u64) {
+        self.connections.remove(&connection);
+    }
+
+    /// Streams open on `connection`.
+    pub fn open_streams(&self, connection: u64) -> u32 {
+        self.connections.get(&connection).map_or(0, |state| state.open)
+    }
+
+    pub fn is_excessive(&self, address: IpAddr) -> bool {
+        let now = self.clock.now();
+        self.excessive.get(&address).is_some_and(|until| *until > now)
+    }
+
+    /// The limit for `key`: tightened while its address is excessive.
+    pub fn limit_for(&self, key: &str, limit: u32) -> u32 {
+        match key.parse() {
+            Ok(address) if self.is_excessive(address) => {
+                ((f64::from(limit) * self.options.tighten).ceil() as u32).clamp(1, limit.max(1))
+            }
+            _ => limit,
+        }
+    }
+
+    /// Forget penalties that have run out.
+    pub fn purge(&self) {
+        let now = self.clock.now();
+        self.excessive.retain(|_, until| *until > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_streams_are_capped_and_floods_tighten_limits() {
+        let clock = Arc::new(ManualClock::default());
+        let options = StreamOptions { max_concurrent: 2, max_opened: 5, max_resets: 2, ..StreamOptions::default() };
+        let tracker = Arc::new(StreamTracker::new(options).with_clock(clock.clone()));
+        let client: IpAddr = "2001:db8::7".parse().unwrap();
+
+        let first = tracker.start(1, client).unwrap();
+        let _second = tracker.start(1, client).unwrap();
+        assert_eq!(tracker.start(1, client).err(), Some(StreamRejection::TooManyConcurrent));
+        // Other connections have their own streams
+        assert!(tracker.start(2, client).is_ok());
+        drop(first);
+        assert_eq!(tracker.open_streams(1), 1);
+
+        // Rapid reset: open and cancel over and over
+        for _ in 0..3 {
+            tracker.start(3, client).unwrap().reset();
+        }
+        assert!(tracker.is_excessive(client));
+        assert_eq!(tracker.limit_for("2001:db8::7", 100), 50);
+        assert_eq!(tracker.limit_for("192.0.2.1", 100), 100);
+
+        clock.advance(Duration::from_secs(301));
+        assert!(!tracker.is_excessive(client));
+        tracker.close(1);
+        assert_eq!(tracker.open_streams(1), 0);
+    }
+}
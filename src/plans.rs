@@ -0,0 +1,353 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use crate::events::{EventEmitter, EventKind};
+use crate::storage::{slot_key, StorageBackend, StorageError};
+
+/// Prefix of the backend keys read by `BackendPlans`.
+pub const PLAN_KEY_PREFIX: &str = "plan:";
+
+/// Response header that warns a client nearing its quota.
+pub const QUOTA_WARNING_HEADER: &str = "X-Quota-Warning";
+
+/// What one plan allows a single API key.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Plan {
+    /// Requests per window
+    pub requests: u32,
+    pub window: u32,
+    /// Most requests in any one second; unlimited by default
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// Requests per quota period, e.g. a monthly allowance; unlimited by
+    /// default
+    #[serde(default)]
+    pub quota: Option<u64>,
+    #[serde(default = "default_quota_period")]
+    pub quota_period: u32,
+}
+
+/// Thirty days.
+fn default_quota_period() -> u32 {
+    30 * 86400
+}
+
+/// Which limit of its plan a key ran into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exceeded {
+    Quota,
+    Burst,
+    Rate,
+}
+
+impl Exceeded {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Exceeded::Quota => "quota",
+            Exceeded::Burst => "burst",
+            Exceeded::Rate => "rate",
+        }
+    }
+}
+
+/// Outcome of `PlanLimiter::check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanDecision {
+    /// The plan the key was held to
+    pub plan: String,
+    /// Set when the request is rejected
+    pub exceeded: Option<Exceeded>,
+    /// Highest quota warning threshold the key has reached, in percent
+    pub quota_warning: Option<u8>,
+}
+
+impl PlanDecision {
+    /// Value for `QUOTA_WARNING_HEADER`, e.g. `95% of quota used`.
+    pub fn quota_warning_header(&self) -> Option<String> {
+        self.quota_warning.map(|percent| format!("{}% of quota used", percent))
+    }
+}
+
+/// Finds the plan an API key is on.
+#[async_trait]
+pub trait PlanLookup: Send + Sync {
+    /// The plan name, or `None` for a key with no plan of its own.
+    async fn plan_of(&self, api_key: &str) -> Result<Option<String>, StorageError>;
+}
+
+#[async_trait]
+impl PlanLookup for HashMap<String, String> {
+    async fn plan_of(&self, api_key: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.get(api_key).cloned())
+    }
+}
+
+/// Reads plan assignments from the storage backend, so they can change
+/// without reloading nginx.
+///
+/// Backends only hold counts, so `plan:{api_key}` holds the plan's
+/// position in `names`, from 1: with `["free", "pro", "enterprise"]`,
+/// `SET plan:k_live_abc 2` puts that key on `pro`. Missing keys and 0
+/// mean no plan.
+pub struct BackendPlans {
+    storage: Arc<dyn StorageBackend>,
+    names: Vec<String>,
+}
+
+impl BackendPlans {
+    pub fn new(storage: Arc<dyn StorageBackend>, names: Vec<String>) -> Self {
+        Self { storage, names }
+    }
+}
+
+#[async_trait]
+impl PlanLookup for BackendPlans {
+    async fn plan_of(&self, api_key: &str) -> Result<Option<String>, StorageError> {
+        let position = self.storage.get(&format!("{}{}", PLAN_KEY_PREFIX, api_key)).await?;
+        Ok(usize::try_from(position)
+            .ok()
+            .and_then(|position| position.checked_sub(1))
+            .and_then(|index| self.names.get(index))
+            .cloned())
+    }
+}
+
+/// Plans as written in a JSON file, with the key assignments inline:
+///
+/// ```json
+/// {"default": "free",
+///  "plans": {"free": {"requests": 60, "window": 60, "quota": 10000},
+///            "pro": {"requests": 600, "window": 60, "burst": 50, "quota": 1000000}},
+///  "keys": {"k_live_abc": "pro"}}
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlanConfig {
+    pub default: String,
+    pub plans: HashMap<String, Plan>,
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+}
+
+impl PlanConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+/// The counter of one of `api_key`'s limits, e.g. `plan:{k_live_abc}:rate`.
+fn plan_key(api_key: &str, limit: &str) -> String {
+    format!("{}{}", PLAN_KEY_PREFIX, slot_key(api_key, limit))
+}
+
+/// Holds each API key to the rate, burst and quota of its plan, so
+/// customers on different plans get different limits from one
+/// configuration.
+///
+/// Each limit is its own counter in the backend, under `plan:{api_key}:`.
+/// The API key is the counters' hash tag, so on Redis Cluster they share
+/// a slot and are read and counted in one atomic call. A request is
+/// counted against all of them only when none is used up.
+/// The quota period starts with a key's first request, not on the first
+/// of the month. Wrap the backend in `HashedKeyStorage` to keep API keys
+/// out of it.
+pub struct PlanLimiter {
+    storage: Arc<dyn StorageBackend>,
+    plans: HashMap<String, Plan>,
+    default: String,
+    lookup: Arc<dyn PlanLookup>,
+    /// Quota percentages that raise a warning, ascending
+    thresholds: Vec<u8>,
+    events: Option<Arc<EventEmitter>>,
+}
+
+impl PlanLimiter {
+    /// `default` must name one of `plans`; it applies to keys with no plan
+    /// or an unknown one.
+    pub fn new(
+        storage: Arc<dyn StorageBackend>,
+        plans: HashMap<String, Plan>,
+        default: &str,
+        lookup: Arc<dyn PlanLookup>,
+    ) -> Result<Self, String> {
+        if !plans.contains_key(default) {
+            return Err(format!("default plan {} is not defined", default));
+        }
+        Ok(Self {
+            storage,
+            plans,
+            default: default.to_string(),
+            lookup,
+            thresholds: Vec::new(),
+            events: None,
+        })
+    }
+
+    /// Warn once per quota period when a key's quota use crosses each of
+    /// `thresholds` percent, e.g. `[80, 95]`: the crossing is logged,
+    /// counted and published as `EventKind::QuotaWarning`, and decisions
+    /// carry `quota_warning` from then on.
+    pub fn with_quota_warnings(mut self, mut thresholds: Vec<u8>) -> Self {
+        thresholds.retain(|percent| (1..=100).contains(percent));
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Publish quota warnings to an event stream or webhook.
+    pub fn with_events(mut self, emitter: Arc<EventEmitter>) -> Self {
+        self.events = Some(emitter);
+        self
+    }
+
+    /// Use the plans and key assignments of a `PlanConfig`.
+    pub fn from_config(storage: Arc<dyn StorageBackend>, config: PlanConfig) -> Result<Self, String> {
+        Self::new(storage, config.plans, &config.default, Arc::new(config.keys))
+    }
+
+    pub fn plan(&self, name: &str) -> Option<&Plan> {
+        self.plans.get(name)
+    }
+
+    /// The plan `api_key` is held to. Lookup failures fall back to the
+    /// default plan rather than failing the request.
+    async fn plan_name(&self, api_key: &str) -> &str {
+        match self.lookup.plan_of(api_key).await {
+            Ok(Some(name)) => match self.plans.get_key_value(&name) {
+                Some((name, _)) => name,
+                None => {
+                    log::warn!("API key is on undefined plan {}, using {}", name, self.default);
+                    &self.default
+                }
+            },
+            Ok(None) => &self.default,
+            Err(e) => {
+                log::warn!("Plan lookup failed, using {}: {}", self.default, e);
+                &self.default
+            }
+        }
+    }
+
+    /// Decide one request for `api_key`, counting it when it is allowed.
+    pub async fn check(&self, api_key: &str) -> Result<PlanDecision, StorageError> {
+        let name = self.plan_name(api_key).await;
+        let plan = &self.plans[name];
+
+        let mut limits = Vec::with_capacity(3);
+        if let Some(quota) = plan.quota {
+            limits.push((Exceeded::Quota, plan_key(api_key, "quota"), plan.quota_period, quota));
+        }
+        if let Some(burst) = plan.burst {
+            limits.push((Exceeded::Burst, plan_key(api_key, "burst"), 1, u64::from(burst)));
+        }
+        limits.push((Exceeded::Rate, plan_key(api_key, "rate"), plan.window, u64::from(plan.requests)));
+
+        let keys: Vec<&str> = limits.iter().map(|(_, key, _, _)| key.as_str()).collect();
+        let counts = self.storage.get_many(&keys).await?;
+        let exceeded = limits
+            .iter()
+            .zip(&counts)
+            .find(|((_, _, _, limit), count)| **count >= *limit)
+            .map(|((exceeded, _, _, _), _)| *exceeded);
+
+        let mut quota_warning = None;
+        match exceeded {
+            Some(exceeded) => {
+                let plan = name.to_string();
+                metrics::counter!("rate_limiter_plan_rejections_total", "plan" => plan, "limit" => exceeded.as_str())
+                    .increment(1);
+            }
+            None => {
+                let windows: Vec<(&str, u32)> =
+                    limits.iter().map(|(_, key, window, _)| (key.as_str(), *window)).collect();
+                let increments = self.storage.increment_many(&windows).await?;
+                if let (Some(quota), Some(used)) = (plan.quota, increments.first()) {
+                    quota_warning = self.quota_warning(api_key, name, plan, quota, used.count);
+                }
+            }
+        }
+        Ok(PlanDecision { plan: name.to_string(), exceeded, quota_warning })
+    }
+
+    /// The highest threshold `count` has reached. Counts go up by one, so
+    /// exactly one request per quota period lands on each threshold's
+    /// mark, and only that one announces the crossing.
+    fn quota_warning(&self, api_key: &str, name: &str, plan: &Plan, quota: u64, count: u64) -> Option<u8> {
+        let mut reached = None;
+        for &percent in &self.thresholds {
+            let mark = (quota * u64::from(percent)).div_ceil(100).max(1);
+            if count < mark {
+                break;
+            }
+            reached = Some(percent);
+            if count == mark {
+                log::info!("API key on plan {} crossed {}% of its quota ({} of {})", name, percent, count, quota);
+                let (plan_label, threshold) = (name.to_string(), percent.to_string());
+                metrics::counter!("rate_limiter_quota_warnings_total", "plan" => plan_label, "threshold" => threshold)
+                    .increment(1);
+                if let Some(events) = &self.events {
+                    let limit = quota.min(u64::from(u32::MAX)) as u32;
+                    events.emit(EventKind::QuotaWarning, api_key, count, limit, plan.quota_period);
+                }
+            }
+        }
+        reached
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockStorage;
+
+    fn config_with_quota(quota: u64) -> PlanConfig {
+        let json = format!(
+            r#"{{"default": "free", "plans": {{"free": {{"requests": 100, "window": 60, "quota": {}}}}}}}"#,
+            quota
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_keys_get_the_limits_of_their_plan() {
+        let storage = Arc::new(MockStorage::new());
+        let config: PlanConfig = serde_json::from_str(
+            r#"{"default": "free",
+                "plans": {"free": {"requests": 10, "window": 60, "quota": 2},
+                          "pro": {"requests": 10, "window": 60, "burst": 3}},
+                "keys": {"k_pro": "pro", "k_lost": "gold"}}"#,
+        )
+        .unwrap();
+        let plans = PlanLimiter::from_config(storage.clone(), config).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(plans.check("k_free").await.unwrap().exceeded, None);
+        }
+        let decision = plans.check("k_free").await.unwrap();
+        assert_eq!((decision.plan.as_str(), decision.exceeded), ("free", Some(Exceeded::Quota)));
+
+        for _ in 0..3 {
+            assert_eq!(plans.check("k_pro").await.unwrap().exceeded, None);
+        }
+        assert_eq!(plans.check("k_pro").await.unwrap().exceeded, Some(Exceeded::Burst));
+        assert_eq!(plans.check("k_lost").await.unwrap().plan, "free");
+
+        let warned = PlanLimiter::from_config(storage.clone(), config_with_quota(10))
+            .unwrap()
+            .with_quota_warnings(vec![95, 80]);
+        let mut warnings = Vec::new();
+        for _ in 0..10 {
+            warnings.push(warned.check("k_warn").await.unwrap().quota_warning);
+        }
+        assert_eq!(warnings[6..], [None, Some(80), Some(80), Some(95)]);
+
+        let lookup = BackendPlans::new(storage.clone(), vec!["free".to_string(), "pro".to_string()]);
+        storage.increment("plan:k_db", 60).await.unwrap();
+        storage.increment("plan:k_db", 60).await.unwrap();
+        assert_eq!(lookup.plan_of("k_db").await.unwrap().as_deref(), Some("pro"));
+        assert_eq!(lookup.plan_of("k_none").await.unwrap(), None);
+    }
+}
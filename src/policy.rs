@@ -0,0 +1,101 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use std::fmt::Debug;
+
+/// What a policy sees for one request.
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyInput<'a> {
+    pub key: &'a str,
+    /// Requests counted for the key in the current window, before this one
+    pub count: u64,
+    pub limit: u32,
+    pub window: u32,
+    /// Whether the counters alone would reject the request
+    pub over_limit: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("Policy service unavailable: {0}")]
+    Unavailable(String),
+    #[error("Policy returned no decision: {0}")]
+    Undefined(String),
+}
+
+/// Makes the final allow/deny decision on top of the raw counters, e.g.
+/// to exempt partners, deny embargoed regions or tighten limits for a
+/// tenant without changing the module's configuration.
+///
+/// When a policy fails, the counters decide as if it were not there.
+#[async_trait]
+pub trait Policy: Debug + Send + Sync {
+    /// Whether the request is allowed.
+    async fn allow(&self, input: &PolicyInput<'_>) -> Result<bool, PolicyError>;
+}
+
+/// Asks an Open Policy Agent server through its data API.
+///
+/// Every request POSTs `{"input": PolicyInput}` to a rule URL such as
+/// `http://127.0.0.1:8181/v1/data/ratelimit/allow` and expects a boolean
+/// `result`. A rule that is undefined for the input is an error, so the
+/// counters decide.
+#[cfg(feature = "opa")]
+#[derive(Debug)]
+pub struct OpaPolicy {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[cfg(feature = "opa")]
+impl OpaPolicy {
+    /// A policy that gives OPA `timeout` to answer, including connecting.
+    pub fn new(url: &str, timeout: std::time::Duration) -> Result<Self, PolicyError> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .connect_timeout(timeout)
+            .build()
+            .map_err(|e| PolicyError::Unavailable(e.to_string()))?;
+        Ok(Self {
+            client,
+            url: url.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "opa")]
+#[derive(Serialize)]
+struct OpaRequest<'a> {
+    input: &'a PolicyInput<'a>,
+}
+
+#[cfg(feature = "opa")]
+#[derive(serde::Deserialize)]
+struct OpaResponse {
+    result: Option<bool>,
+}
+
+#[cfg(feature = "opa")]
+#[async_trait]
+impl Policy for OpaPolicy {
+    async fn allow(&self, input: &PolicyInput<'_>) -> Result<bool, PolicyError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&OpaRequest { input })
+            .send()
+            .await
+            .map_err(|e| PolicyError::Unavailable(e.to_string()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(PolicyError::Unavailable(format!("OPA returned {}", status)));
+        }
+        let response: OpaResponse = response
+            .json()
+            .await
+            .map_err(|e| PolicyError::Undefined(e.to_string()))?;
+        response
+            .result
+            .ok_or_else(|| PolicyError::Undefined(format!("{} is undefined for the input", self.url)))
+    }
+}
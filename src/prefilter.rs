@@ -0,0 +1,168 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use crate::clock::{self, Clock};
+
+/// Size and threshold of the `Prefilter`.
+#[derive(Debug, Clone)]
+pub struct PrefilterOptions {
+    /// Counters per sketch row; more columns mean fewer collisions
+    pub width: usize,
+    /// Sketch rows, each with its own hash
+    pub depth: usize,
+    /// Share of the limit a key may use before the backend is consulted
+    pub fraction: f64,
+}
+
+impl Default for PrefilterOptions {
+    fn default() -> Self {
+        Self {
+            width: 1 << 16,
+            depth: 4,
+            fraction: 0.1,
+        }
+    }
+}
+
+/// Count-min sketch: `depth` rows of `width` counters. A key's estimate
+/// is the smallest of its counters, which can only overestimate.
+#[derive(Debug)]
+pub struct CountMinSketch {
+    counters: Box<[AtomicU32]>,
+    width: usize,
+    depth: usize,
+    hasher: RandomState,
+}
+
+impl CountMinSketch {
+    pub fn new(width: usize, depth: usize) -> Self {
+        let (width, depth) = (width.max(1), depth.max(1));
+        Self {
+            counters: (0..width * depth).map(|_| AtomicU32::new(0)).collect(),
+            width,
+            depth,
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn cells(&self, key: &str) -> impl Iterator<Item = usize> + '_ {
+        let hash = self.hasher.hash_one(key);
+        let (first, step) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..self.depth).map(move |row| {
+            let column = first.wrapping_add((row as u64).wrapping_mul(step)) % self.width as u64;
+            row * self.width + column as usize
+        })
+    }
+
+    /// Count one occurrence of `key` and return its new estimate.
+    pub fn increment(&self, key: &str) -> u32 {
+        self.cells(key)
+            .map(|cell| self.counters[cell].fetch_add(1, Ordering::Relaxed).saturating_add(1))
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn estimate(&self, key: &str) -> u32 {
+        self.cells(key)
+            .map(|cell| self.counters[cell].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn clear(&self) {
+        for counter in self.counters.iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Lets the long tail of quiet clients through without touching the
+/// shared backend.
+///
+/// Each worker counts requests per key in a `CountMinSketch` that is reset
+/// every window. Until a key's estimate reaches `fraction` of the limit the
+/// request is allowed locally; from then on the backend decides as usual.
+/// Requests allowed locally are never written to the backend, so a client
+/// spread over N workers can get up to `N × fraction × limit` extra
+/// requests per window. Keep `fraction` small.
+#[derive(Debug)]
+pub struct Prefilter {
+    sketch: CountMinSketch,
+    fraction: f64,
+    /// Window number the sketch is counting
+    epoch: AtomicU64,
+    clock: Arc<dyn Clock>,
+}
+
+impl Prefilter {
+    pub fn new(options: PrefilterOptions) -> Self {
+        Self {
+            sketch: CountMinSketch::new(options.width, options.depth),
+            fraction: options.fraction.clamp(0.0, 1.0),
+            epoch: AtomicU64::new(0),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Count a request for `key` and return true if it can be allowed
+    /// without asking the backend.
+    pub fn allows(&self, key: &str, limit: u32, window: u32) -> bool {
+        let epoch = self.clock.unix_secs() / u64::from(window.max(1));
+        if self.epoch.swap(epoch, Ordering::Relaxed) != epoch {
+            self.sketch.clear();
+        }
+
+        let threshold = (f64::from(limit) * self.fraction) as u32;
+        let allowed = self.sketch.increment(key) <= threshold;
+        if allowed {
+            metrics::counter!("rate_limiter_prefilter_allowed_total").increment(1);
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sketch_never_underestimates() {
+        let sketch = CountMinSketch::new(64, 4);
+        for i in 0..1000u32 {
+            sketch.increment(&format!("key-{}", i % 100));
+        }
+        for i in 0..100 {
+            assert!(sketch.estimate(&format!("key-{}", i)) >= 10);
+        }
+    }
+
+    #[test]
+    fn test_backend_consulted_past_fraction() {
+        let prefilter = Prefilter::new(PrefilterOptions {
+            fraction: 0.05,
+            ..PrefilterOptions::default()
+        });
+        let allowed = (0..20).filter(|_| prefilter.allows("client", 100, 3600)).count();
+        assert_eq!(allowed, 5);
+        assert!(!prefilter.allows("other", 10, 3600));
+    }
+
+    #[test]
+    fn test_sketch_resets_each_window() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(3600)));
+        let prefilter = Prefilter::new(PrefilterOptions::default()).with_clock(clock.clone());
+        assert!(prefilter.allows("client", 10, 60));
+        assert!(!prefilter.allows("client", 10, 60));
+
+        clock.advance(Duration::from_secs(60));
+        assert!(prefilter.allows("client", 10, 60));
+    }
+}
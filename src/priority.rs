@@ -0,0 +1,117 @@
+use serde::Deserialize;
+use crate::storage::StorageBackend;
+
+/// How readily a request is admitted when capacity runs short; set per
+/// path rule with `"priority": "high"`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    /// `priority:{class}`, the counter of the class's consumption.
+    pub fn key(self) -> &'static str {
+        match self {
+            Priority::Low => "priority:low",
+            Priority::Normal => "priority:normal",
+            Priority::High => "priority:high",
+        }
+    }
+}
+
+const CLASSES: [Priority; 3] = [Priority::Low, Priority::Normal, Priority::High];
+
+/// A global limit shared by all keys, admitting lower priorities only
+/// while more of it is left.
+///
+/// Consumption is counted per class in the backend, under
+/// `priority:{class}`, and a request is admitted while the classes
+/// together have used less than its class's share of `capacity`: by
+/// default 70% for low, 90% for normal and all of it for high. Under
+/// contention anonymous traffic is turned away first, and paying customers
+/// keep the last of the capacity.
+#[derive(Debug, Clone)]
+pub struct PriorityAdmission {
+    pub capacity: u64,
+    pub window: u32,
+    /// Share of `capacity` each class is admitted up to, low to high
+    pub shares: [f64; 3],
+}
+
+impl PriorityAdmission {
+    pub fn new(capacity: u64, window: u32) -> Self {
+        Self {
+            capacity,
+            window,
+            shares: [0.7, 0.9, 1.0],
+        }
+    }
+
+    pub fn with_share(mut self, priority: Priority, share: f64) -> Self {
+        self.shares[priority as usize] = share;
+        self
+    }
+
+    /// Whether a request of `priority` is admitted. Nothing is counted; a
+    /// backend error admits it.
+    pub async fn admits(&self, storage: &dyn StorageBackend, priority: Priority) -> bool {
+        let counts = match storage.get_many(&CLASSES.map(Priority::key)).await {
+            Ok(counts) => counts,
+            Err(e) => {
+                log::warn!("Reading priority consumption failed: {}", e);
+                return true;
+            }
+        };
+        let used: u64 = counts.iter().sum();
+        let admitted = (used as f64) < self.capacity as f64 * self.shares[priority as usize];
+        if !admitted {
+            metrics::counter!("rate_limiter_priority_rejections_total", "priority" => priority.as_str()).increment(1);
+        }
+        admitted
+    }
+
+    /// Count an admitted request against its class.
+    pub async fn charge(&self, storage: &dyn StorageBackend, priority: Priority) {
+        if let Err(e) = storage.increment(priority.key(), self.window).await {
+            log::warn!("Counting {} priority consumption failed: {}", priority.as_str(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_low_priorities_are_turned_away_first() {
+        let storage = MemoryStorage::new();
+        let admission = PriorityAdmission::new(10, 60);
+
+        for _ in 0..7 {
+            assert!(admission.admits(&storage, Priority::Low).await);
+            admission.charge(&storage, Priority::Low).await;
+        }
+        assert!(!admission.admits(&storage, Priority::Low).await);
+        assert!(admission.admits(&storage, Priority::Normal).await);
+        for _ in 0..2 {
+            admission.charge(&storage, Priority::Normal).await;
+        }
+        assert!(!admission.admits(&storage, Priority::Normal).await);
+        assert!(admission.admits(&storage, Priority::High).await);
+        admission.charge(&storage, Priority::High).await;
+        assert!(!admission.admits(&storage, Priority::High).await);
+    }
+}
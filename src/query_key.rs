@@ -0,0 +1,118 @@
+use regex::Regex;
+use std::fmt::Write;
+use crate::storage::KeyBuf;
+use crate::tenant::RequestInfo;
+
+/// Keys requests by a query parameter, e.g. `?api_key=`.
+///
+/// Values are URL-decoded, and lowercased with `with_case_folding`, so
+/// `AbC%31` and `abc1` count as one key. Values that are not UTF-8 once
+/// decoded, longer than `max_len`, or that do not match the pattern set
+/// with `with_pattern` are not accepted, and the request keeps its own
+/// key.
+#[derive(Debug, Clone)]
+pub struct QueryKey {
+    param: String,
+    fold_case: bool,
+    pattern: Option<Regex>,
+    max_len: usize,
+}
+
+impl QueryKey {
+    pub fn new(param: &str) -> Self {
+        Self {
+            param: param.to_string(),
+            fold_case: false,
+            pattern: None,
+            max_len: 256,
+        }
+    }
+
+    pub fn with_case_folding(mut self) -> Self {
+        self.fold_case = true;
+        self
+    }
+
+    /// Only accept values the regular expression matches, after decoding
+    /// and case folding; anchor it with `^` and `$` to match whole values.
+    pub fn with_pattern(mut self, pattern: &str) -> Result<Self, regex::Error> {
+        self.pattern = Some(Regex::new(pattern)?);
+        Ok(self)
+    }
+
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// `{param}:{value}` for a request carrying an acceptable value.
+    pub fn key(&self, request: &RequestInfo<'_>) -> Option<KeyBuf> {
+        let raw = request.query_param(&self.param)?;
+        let accepted = self.canonicalize(raw);
+        if accepted.is_none() && !raw.is_empty() {
+            metrics::counter!("rate_limiter_query_key_rejections_total").increment(1);
+        }
+        let value = accepted?;
+        let mut key = KeyBuf::new();
+        let _ = write!(key, "{}:{}", self.param, value);
+        Some(key)
+    }
+
+    fn canonicalize(&self, raw: &str) -> Option<String> {
+        if raw.len() > self.max_len.saturating_mul(3) {
+            return None;
+        }
+        let mut value = String::from_utf8(percent_decode(raw)?).ok()?;
+        if self.fold_case {
+            value = value.to_lowercase();
+        }
+        let accepted = !value.is_empty()
+            && value.len() <= self.max_len
+            && self.pattern.as_ref().is_none_or(|pattern| pattern.is_match(&value));
+        accepted.then_some(value)
+    }
+}
+
+/// Decode `%XX` escapes and `+` as a space, as in form-encoded queries.
+fn percent_decode(raw: &str) -> Option<Vec<u8>> {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+                decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_values_are_canonicalized_and_checked() {
+        let keys = QueryKey::new("api_key").with_case_folding().with_pattern("^[a-z0-9]{4,32}$").unwrap();
+        let key = |path: &'static str| keys.key(&RequestInfo { path, ..RequestInfo::default() }).map(|key| key.as_str().to_string());
+
+        assert_eq!(key("/v1/items?page=2&api_key=AbC%31").as_deref(), Some("api_key:abc1"));
+        assert_eq!(key("/v1/items?api_key=abc1").as_deref(), Some("api_key:abc1"));
+        assert_eq!(key("/v1/items?api_key=ab%2F1"), None);
+        assert_eq!(key("/v1/items?api_key=abc%2"), None);
+        assert_eq!(key("/v1/items?api_key=%FF%FE%FD%FC"), None);
+        assert_eq!(key("/v1/items?token=abc1"), None);
+        assert_eq!(key("/v1/items"), None);
+    }
+}
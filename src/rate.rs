@@ -0,0 +1,54 @@
+use std::str::FromStr;
+
+/// A limit as nginx writes one: `10r/s`, `600r/m`, `1000r/h`, `50r/d`, or
+/// a window in seconds, `100r/30s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub requests: u32,
+    pub window: u32,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RateError {
+    #[error("Invalid rate {0:?}, expected e.g. 10r/s or 100r/30s")]
+    Invalid(String),
+}
+
+impl FromStr for Rate {
+    type Err = RateError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || RateError::Invalid(value.to_string());
+        let (requests, per) = value.trim().split_once("r/").ok_or_else(invalid)?;
+        let requests = requests.parse().map_err(|_| invalid())?;
+        let window = match per {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            seconds => seconds
+                .strip_suffix('s')
+                .and_then(|seconds| seconds.parse().ok())
+                .filter(|seconds| *seconds > 0)
+                .ok_or_else(invalid)?,
+        };
+        Ok(Self { requests, window })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rates_parse_like_nginx() {
+        let rate = |value: &str| value.parse::<Rate>().ok().map(|rate| (rate.requests, rate.window));
+        assert_eq!(rate("10r/s"), Some((10, 1)));
+        assert_eq!(rate(" 600r/m"), Some((600, 60)));
+        assert_eq!(rate("50r/d"), Some((50, 86400)));
+        assert_eq!(rate("100r/30s"), Some((100, 30)));
+        assert_eq!(rate("100r/0s"), None);
+        assert_eq!(rate("fast"), None);
+        assert_eq!(rate("-1r/s"), None);
+    }
+}
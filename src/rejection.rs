@@ -0,0 +1,169 @@
+use serde::Deserialize;
+use std::sync::OnceLock;
+
+/// A value filled in when a page is rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// `{retry_after}`: seconds until the client may retry
+    RetryAfter,
+    /// `{reset}`: Unix time the window resets
+    Reset,
+}
+
+/// A 429 response body rendered once, when the configuration is loaded,
+/// with only the `{retry_after}` and `{reset}` values left to fill in.
+///
+/// Rendering copies the pre-rendered parts and formats the two numbers on
+/// the stack, into a buffer the caller provides, so rejecting thousands of
+/// requests a second allocates nothing. Other `{...}` text is kept as is.
+/// In JSON configuration a page is its template string.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub struct RejectionPage {
+    /// Literal text around the fields; one more part than fields
+    parts: Vec<Box<[u8]>>,
+    fields: Vec<Field>,
+    content_type: &'static str,
+}
+
+impl From<String> for RejectionPage {
+    fn from(template: String) -> Self {
+        Self::compile(&template)
+    }
+}
+
+impl Default for RejectionPage {
+    fn default() -> Self {
+        Self::compile("Too many requests. Retry in {retry_after} seconds.\n")
+    }
+}
+
+/// Digits of `value`, written to the end of `buf`.
+fn digits(value: u64, buf: &mut [u8; 20]) -> &[u8] {
+    let mut value = value;
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            return &buf[start..];
+        }
+    }
+}
+
+impl RejectionPage {
+    /// Pre-render `template`. Its content type is JSON when it starts with
+    /// `{` or `[`, HTML when it starts with `<`, and plain text otherwise.
+    pub fn compile(template: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut fields = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            literal.push_str(&rest[..open]);
+            rest = &rest[open..];
+            let field = if rest.starts_with("{retry_after}") {
+                Some((Field::RetryAfter, "{retry_after}".len()))
+            } else if rest.starts_with("{reset}") {
+                Some((Field::Reset, "{reset}".len()))
+            } else {
+                None
+            };
+            match field {
+                Some((field, len)) => {
+                    parts.push(std::mem::take(&mut literal).into_bytes().into_boxed_slice());
+                    fields.push(field);
+                    rest = &rest[len..];
+                }
+                None => {
+                    literal.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        literal.push_str(rest);
+        parts.push(literal.into_bytes().into_boxed_slice());
+
+        let content_type = match template.trim_start().as_bytes().first() {
+            Some(b'{' | b'[') => "application/json",
+            Some(b'<') => "text/html; charset=utf-8",
+            _ => "text/plain; charset=utf-8",
+        };
+        Self { parts, fields, content_type }
+    }
+
+    /// The page used when a rule has none of its own.
+    pub fn standard() -> &'static RejectionPage {
+        static STANDARD: OnceLock<RejectionPage> = OnceLock::new();
+        STANDARD.get_or_init(RejectionPage::default)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    fn value(field: Field, retry_after: u64, reset: u64) -> u64 {
+        match field {
+            Field::RetryAfter => retry_after,
+            Field::Reset => reset,
+        }
+    }
+
+    /// Length of the rendered body, for `Content-Length`.
+    pub fn rendered_len(&self, retry_after: u64, reset: u64) -> usize {
+        let mut buf = [0; 20];
+        let literal: usize = self.parts.iter().map(|part| part.len()).sum();
+        let fields: usize = self
+            .fields
+            .iter()
+            .map(|field| digits(Self::value(*field, retry_after, reset), &mut buf).len())
+            .sum();
+        literal + fields
+    }
+
+    /// Render into `out` and return the length, or `None` when `out` is
+    /// too short; `rendered_len` gives the size needed.
+    pub fn render(&self, retry_after: u64, reset: u64, out: &mut [u8]) -> Option<usize> {
+        let mut buf = [0; 20];
+        let mut written = 0;
+        let mut copy = |bytes: &[u8]| {
+            let end = written + bytes.len();
+            out.get_mut(written..end)?.copy_from_slice(bytes);
+            written = end;
+            Some(())
+        };
+        for (part, field) in self.parts.iter().zip(&self.fields) {
+            copy(part)?;
+            copy(digits(Self::value(*field, retry_after, reset), &mut buf))?;
+        }
+        copy(self.parts.last().expect("a page has at least one part"))?;
+        Some(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pages_render_into_a_buffer() {
+        let page: RejectionPage =
+            serde_json::from_str(r#""{\"error\": \"slow down\", \"retry_after\": {retry_after}, \"reset\": {reset}}""#)
+                .unwrap();
+        assert_eq!(page.content_type(), "application/json");
+
+        let mut out = [0; 128];
+        let len = page.render(30, 1_700_000_060, &mut out).unwrap();
+        assert_eq!(len, page.rendered_len(30, 1_700_000_060));
+        assert_eq!(
+            std::str::from_utf8(&out[..len]).unwrap(),
+            r#"{"error": "slow down", "retry_after": 30, "reset": 1700000060}"#
+        );
+        assert_eq!(page.render(0, 0, &mut out[..10]), None);
+
+        let standard = RejectionPage::standard();
+        let len = standard.render(0, 0, &mut out).unwrap();
+        assert_eq!(&out[..len], b"Too many requests. Retry in 0 seconds.\n");
+    }
+}
@@ -0,0 +1,183 @@
+//! Limits loaded from a central key-value store and re-applied whenever
+//! they change, so a control plane can retune a whole fleet in seconds.
+//!
+//! The key holds `LimitsConfig` JSON. Each change is published with the
+//! store's version of the key (`consul:<ModifyIndex>` or
+//! `etcd:<mod_revision>`), which `Generation::version` reports. A value
+//! that does not parse is logged and the current limits stay in force.
+
+use std::time::Duration;
+use crate::config::{Limits, LimitsConfig};
+
+/// Delay before retrying after the store could not be reached.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+fn parse_limits(json: &[u8], generation: u64) -> Result<Limits, String> {
+    let config: LimitsConfig = serde_json::from_slice(json).map_err(|e| e.to_string())?;
+    config.compile(generation).map_err(|e| e.to_string())
+}
+
+fn apply(source: &str, json: &[u8], revision: u64, version: String, publish: &impl Fn(Limits, String) -> u64) {
+    match parse_limits(json, revision) {
+        Ok(limits) => {
+            let generation = publish(limits, version.clone());
+            log::info!("Applied rate limits {} from {} as generation {}", version, source, generation);
+        }
+        Err(e) => {
+            metrics::counter!("rate_limiter_config_rejected_total", "source" => "remote").increment(1);
+            log::error!("Ignoring rate limits {} from {}: {}", version, source, e);
+        }
+    }
+}
+
+/// Where and how to reach Consul.
+#[cfg(feature = "consul")]
+#[derive(Debug, Clone)]
+pub struct ConsulOptions {
+    /// HTTP API address, e.g. `http://127.0.0.1:8500`
+    pub address: String,
+    /// ACL token sent as `X-Consul-Token`
+    pub token: Option<String>,
+    /// How long Consul holds a blocking query open without a change
+    pub wait: Duration,
+}
+
+#[cfg(feature = "consul")]
+impl Default for ConsulOptions {
+    fn default() -> Self {
+        Self {
+            address: "http://127.0.0.1:8500".to_string(),
+            token: None,
+            wait: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Watch a Consul KV key with blocking queries and publish its limits on
+/// every change. Runs until the task is dropped.
+#[cfg(feature = "consul")]
+pub async fn watch_consul(options: ConsulOptions, key: &str, publish: impl Fn(Limits, String) -> u64) {
+    // Consul answers within `wait` plus up to 1/16 of jitter
+    let client = match reqwest::Client::builder().timeout(options.wait + options.wait / 8 + RETRY_DELAY).build() {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("Cannot build the Consul client: {}", e);
+            return;
+        }
+    };
+    let url = format!("{}/v1/kv/{}", options.address.trim_end_matches('/'), key.trim_start_matches('/'));
+    let source = format!("consul key {}", key);
+    let mut index = 0u64;
+
+    loop {
+        let mut request = client
+            .get(&url)
+            .query(&[("raw", String::new()), ("index", index.to_string()), ("wait", format!("{}s", options.wait.as_secs()))]);
+        if let Some(token) = &options.token {
+            request = request.header("X-Consul-Token", token);
+        }
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                log::warn!("Watching {} failed: {}", source, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        let next = response
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(0);
+        let status = response.status();
+        if !status.is_success() {
+            if status != reqwest::StatusCode::NOT_FOUND {
+                log::warn!("Watching {} failed: Consul returned {}", source, status);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+            index = next;
+            continue;
+        }
+        if next == index {
+            // The query timed out without a change
+            continue;
+        }
+
+        match response.bytes().await {
+            Ok(body) => apply(&source, &body, next, format!("consul:{}", next), &publish),
+            Err(e) => log::warn!("Reading {} failed: {}", source, e),
+        }
+        // An index going backwards means the store was reset; start over
+        index = if next < index { 0 } else { next };
+    }
+}
+
+/// Watch an etcd key and publish its limits on every change. Runs until
+/// the task is dropped, reconnecting after errors.
+#[cfg(feature = "etcd")]
+pub async fn watch_etcd(endpoints: &[String], key: &str, publish: impl Fn(Limits, String) -> u64) {
+    let source = format!("etcd key {}", key);
+    loop {
+        if let Err(e) = watch_etcd_once(endpoints, key, &source, &publish).await {
+            log::warn!("Watching {} failed: {}", source, e);
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+#[cfg(feature = "etcd")]
+async fn watch_etcd_once(
+    endpoints: &[String],
+    key: &str,
+    source: &str,
+    publish: &impl Fn(Limits, String) -> u64,
+) -> Result<(), etcd_client::Error> {
+    let mut client = etcd_client::Client::connect(endpoints, None).await?;
+
+    // Read the current value, then watch from the revision after it so no
+    // change in between is missed
+    let response = client.get(key, None).await?;
+    if let Some(kv) = response.kvs().first() {
+        let revision = kv.mod_revision();
+        apply(source, kv.value(), revision as u64, format!("etcd:{}", revision), publish);
+    }
+    let start = response.header().map_or(0, |header| header.revision()) + 1;
+
+    let (_watcher, mut stream) = client
+        .watch(key, Some(etcd_client::WatchOptions::new().with_start_revision(start)))
+        .await?;
+    while let Some(response) = stream.message().await? {
+        for event in response.events() {
+            if event.event_type() != etcd_client::EventType::Put {
+                log::warn!("{} was deleted; keeping the current limits", source);
+                continue;
+            }
+            if let Some(kv) = event.kv() {
+                let revision = kv.mod_revision();
+                apply(source, kv.value(), revision as u64, format!("etcd:{}", revision), publish);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigStore;
+
+    #[test]
+    fn test_apply_keeps_limits_on_invalid_json() {
+        let store = ConfigStore::new(Limits::new(10, 60));
+        let publish = |limits, version| store.publish_version(limits, version);
+
+        apply("test", br#"{"requests": 5, "window": 1}"#, 7, "consul:7".to_string(), &publish);
+        apply("test", b"{not json", 8, "consul:8".to_string(), &publish);
+
+        let current = store.load();
+        assert_eq!(current.limits.requests_per_second, 5);
+        assert_eq!(current.version.as_deref(), Some("consul:7"));
+    }
+}
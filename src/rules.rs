@@ -0,0 +1,358 @@
+use aho_corasick::AhoCorasick;
+use regex::RegexSet;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use crate::graphql::{self, OperationKind};
+use crate::priority::Priority;
+use crate::rejection::RejectionPage;
+
+/// How a rule selects request paths; `{"prefix": "/api/"}` or
+/// `{"regex": "^/login$"}` in JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathMatch {
+    /// Paths starting with the string, e.g. `/api/`
+    Prefix(String),
+    /// Paths the regular expression matches anywhere; anchor it with `^`
+    /// and `$` as needed
+    Regex(String),
+}
+
+/// Bytes of a request body that conditions look at; embedders need only
+/// read this much ahead.
+pub const MAX_INSPECTED_BODY: usize = 64 * 1024;
+
+/// A condition on the start of a request body, e.g.
+/// `{"contains": "mutation"}` or
+/// `{"json": {"pointer": "/operation", "equals": "delete"}}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyMatch {
+    Contains(String),
+    /// A regular expression matched anywhere in the body
+    Regex(String),
+    /// A JSON body whose value at `pointer` (RFC 6901) equals `equals`
+    Json { pointer: String, equals: serde_json::Value },
+    /// A GraphQL request running the named operation, or any operation of
+    /// `kind`, e.g. `{"graphql": {"kind": "mutation"}}`
+    Graphql {
+        #[serde(default)]
+        operation: Option<String>,
+        #[serde(default)]
+        kind: Option<OperationKind>,
+    },
+}
+
+/// A limit applied to the paths a `PathMatch` selects.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Rule {
+    pub path: PathMatch,
+    pub requests: u32,
+    pub window: u32,
+    /// Body of this rule's 429 responses
+    #[serde(default)]
+    pub rejection: Option<RejectionPage>,
+    /// Only requests whose body matches count under this rule; others
+    /// fall through to the next
+    #[serde(default)]
+    pub body: Option<BodyMatch>,
+    /// Count under `{counter}:{key}`, apart from the key's other requests,
+    /// e.g. so expensive operations are limited separately from cheap ones
+    /// on the same URL
+    #[serde(default)]
+    pub counter: Option<String>,
+    /// Only requests from OAuth2 clients granted this scope count under
+    /// this rule, e.g. higher limits for `admin`
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// Admission priority of the rule's requests under a global limit;
+    /// normal by default
+    #[serde(default)]
+    pub priority: Option<Priority>,
+    /// Name of the rule in the `rule` label of decision metrics
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+/// A `BodyMatch` ready to run.
+#[derive(Debug)]
+enum Condition {
+    Regex(regex::bytes::Regex),
+    Json { pointer: String, equals: serde_json::Value },
+    Graphql { operation: Option<String>, kind: Option<OperationKind> },
+}
+
+impl Condition {
+    fn compile(body: &BodyMatch) -> Result<Self, RuleError> {
+        let regex = |pattern: &str| {
+            regex::bytes::Regex::new(pattern).map_err(|e| RuleError::InvalidPattern(e.to_string()))
+        };
+        Ok(match body {
+            BodyMatch::Contains(text) => Condition::Regex(regex(&regex::escape(text))?),
+            BodyMatch::Regex(pattern) => Condition::Regex(regex(pattern)?),
+            BodyMatch::Json { pointer, equals } => Condition::Json {
+                pointer: pointer.clone(),
+                equals: equals.clone(),
+            },
+            BodyMatch::Graphql { operation, kind } => Condition::Graphql {
+                operation: operation.clone(),
+                kind: *kind,
+            },
+        })
+    }
+
+    fn matches(&self, body: &[u8]) -> bool {
+        let body = &body[..body.len().min(MAX_INSPECTED_BODY)];
+        match self {
+            Condition::Regex(regex) => regex.is_match(body),
+            Condition::Json { pointer, equals } => serde_json::from_slice::<serde_json::Value>(body)
+                .ok()
+                .is_some_and(|value| value.pointer(pointer) == Some(equals)),
+            Condition::Graphql { operation, kind } => graphql::parse(body).is_some_and(|parsed| {
+                operation.as_ref().is_none_or(|name| parsed.name.as_ref() == Some(name))
+                    && kind.is_none_or(|kind| parsed.kind == kind)
+            }),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RuleError {
+    #[error("Invalid path pattern: {0}")]
+    InvalidPattern(String),
+}
+
+/// The rules of one vhost, compiled once per configuration generation.
+///
+/// Prefixes go into one Aho-Corasick automaton and regular expressions into
+/// one `RegexSet`, so a lookup scans the path once per kind however many
+/// rules there are. When several rules match, the one declared first wins.
+#[derive(Debug)]
+pub struct RuleSet {
+    generation: u64,
+    rules: Vec<Rule>,
+    prefixes: AhoCorasick,
+    /// Index into `rules` of each prefix pattern
+    prefix_rules: Vec<usize>,
+    regexes: RegexSet,
+    /// Index into `rules` of each regex pattern
+    regex_rules: Vec<usize>,
+    /// Body condition of each rule
+    conditions: Vec<Option<Condition>>,
+}
+
+impl RuleSet {
+    pub fn compile(generation: u64, rules: Vec<Rule>) -> Result<Self, RuleError> {
+        let mut prefixes = Vec::new();
+        let mut prefix_rules = Vec::new();
+        let mut regexes = Vec::new();
+        let mut regex_rules = Vec::new();
+        for (index, rule) in rules.iter().enumerate() {
+            match &rule.path {
+                PathMatch::Prefix(prefix) => {
+                    prefixes.push(prefix.as_str());
+                    prefix_rules.push(index);
+                }
+                PathMatch::Regex(regex) => {
+                    regexes.push(regex.as_str());
+                    regex_rules.push(index);
+                }
+            }
+        }
+
+        let prefixes = AhoCorasick::new(&prefixes).map_err(|e| RuleError::InvalidPattern(e.to_string()))?;
+        let regexes = RegexSet::new(&regexes).map_err(|e| RuleError::InvalidPattern(e.to_string()))?;
+        let conditions = rules
+            .iter()
+            .map(|rule| rule.body.as_ref().map(Condition::compile).transpose())
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            generation,
+            rules,
+            prefixes,
+            prefix_rules,
+            regexes,
+            regex_rules,
+            conditions,
+        })
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The first declared rule matching `path`, skipping rules with a
+    /// body condition.
+    pub fn find(&self, path: &str) -> Option<&Rule> {
+        self.find_for(path, None)
+    }
+
+    /// The first declared rule matching `path` and, for rules with a body
+    /// condition, the start of `body`, skipping rules with a scope.
+    pub fn find_for(&self, path: &str, body: Option<&[u8]>) -> Option<&Rule> {
+        self.find_scoped(path, body, None)
+    }
+
+    /// As `find_for`, for a client granted `scope`, a space-separated list
+    /// as in OAuth2.
+    pub fn find_scoped(&self, path: &str, body: Option<&[u8]>, scope: Option<&str>) -> Option<&Rule> {
+        let applies = |index: &usize| {
+            let granted = self.rules[*index].scope.as_ref().is_none_or(|wanted| {
+                scope.is_some_and(|scope| scope.split_ascii_whitespace().any(|granted| granted == wanted))
+            });
+            granted
+                && self.conditions[*index]
+                    .as_ref()
+                    .is_none_or(|condition| body.is_some_and(|body| condition.matches(body)))
+        };
+        let by_prefix = self
+            .prefixes
+            .find_overlapping_iter(path)
+            .filter(|found| found.start() == 0)
+            .map(|found| self.prefix_rules[found.pattern().as_usize()])
+            .filter(applies)
+            .min();
+        let by_regex = if self.regex_rules.is_empty() {
+            None
+        } else {
+            self.regexes.matches(path).iter().map(|found| self.regex_rules[found]).filter(applies).min()
+        };
+
+        let index = match (by_prefix, by_regex) {
+            (Some(prefix), Some(regex)) => prefix.min(regex),
+            (found, None) | (None, found) => found?,
+        };
+        Some(&self.rules[index])
+    }
+}
+
+/// Keeps the `RuleSet` of the newest configuration generation, so workers
+/// and reloads that see the same generation share one compilation.
+#[derive(Debug, Default)]
+pub struct RuleCache {
+    current: Mutex<Option<Arc<RuleSet>>>,
+}
+
+impl RuleCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The compiled rules for `generation`, compiling `rules` only if that
+    /// generation has not been seen yet.
+    pub fn get_or_compile(
+        &self,
+        generation: u64,
+        rules: impl FnOnce() -> Vec<Rule>,
+    ) -> Result<Arc<RuleSet>, RuleError> {
+        let mut current = self.current.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(set) = current.as_ref().filter(|set| set.generation == generation) {
+            return Ok(Arc::clone(set));
+        }
+
+        let set = Arc::new(RuleSet::compile(generation, rules())?);
+        *current = Some(Arc::clone(&set));
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: PathMatch, requests: u32) -> Rule {
+        Rule {
+            path,
+            requests,
+            window: 60,
+            rejection: None,
+            body: None,
+            counter: None,
+            scope: None,
+            priority: None,
+            id: None,
+        }
+    }
+
+    #[test]
+    fn test_first_declared_rule_wins() {
+        let set = RuleSet::compile(1, vec![
+            rule(PathMatch::Regex(r"^/api/v\d+/login$".to_string()), 5),
+            rule(PathMatch::Prefix("/api/".to_string()), 100),
+            rule(PathMatch::Prefix("/api/v1/".to_string()), 50),
+            rule(PathMatch::Regex(r"\.php$".to_string()), 1),
+        ])
+        .unwrap();
+
+        assert_eq!(set.find("/api/v2/login").unwrap().requests, 5);
+        assert_eq!(set.find("/api/v1/users").unwrap().requests, 100);
+        assert_eq!(set.find("/wp-login.php").unwrap().requests, 1);
+        assert!(set.find("/static/app.js").is_none());
+        // Prefixes only match at the start of the path
+        assert!(set.find("/static/api/").is_none());
+    }
+
+    #[test]
+    fn test_body_conditions_pick_the_rule() {
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[{"path": {"prefix": "/graphql"}, "requests": 5, "window": 60, "body": {"contains": "mutation"}, "counter": "mutations"},
+                {"path": {"prefix": "/api/"}, "requests": 2, "window": 60, "body": {"json": {"pointer": "/op", "equals": "delete"}}},
+                {"path": {"prefix": "/"}, "requests": 100, "window": 60}]"#,
+        )
+        .unwrap();
+        let set = RuleSet::compile(1, rules).unwrap();
+
+        let mutation = set.find_for("/graphql", Some(b"mutation { deleteUser(id: 1) }")).unwrap();
+        assert_eq!((mutation.requests, mutation.counter.as_deref()), (5, Some("mutations")));
+        assert_eq!(set.find_for("/graphql", Some(b"query { user(id: 1) }")).unwrap().requests, 100);
+        assert_eq!(set.find_for("/api/items", Some(br#"{"op": "delete"}"#)).unwrap().requests, 2);
+        assert_eq!(set.find_for("/api/items", Some(b"not json")).unwrap().requests, 100);
+        assert_eq!(set.find("/graphql").unwrap().requests, 100);
+
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[{"path": {"prefix": "/graphql"}, "requests": 1, "window": 60, "body": {"graphql": {"operation": "Export"}}},
+                {"path": {"prefix": "/graphql"}, "requests": 5, "window": 60, "body": {"graphql": {"kind": "mutation"}}}]"#,
+        )
+        .unwrap();
+        let set = RuleSet::compile(2, rules).unwrap();
+        assert_eq!(set.find_for("/graphql", Some(br#"{"query": "query Export { all }"}"#)).unwrap().requests, 1);
+        assert_eq!(set.find_for("/graphql", Some(b"mutation Export { x }")).unwrap().requests, 1);
+        assert_eq!(set.find_for("/graphql", Some(b"mutation { like }")).unwrap().requests, 5);
+        assert!(set.find_for("/graphql", Some(b"{ feed }")).is_none());
+    }
+
+    #[test]
+    fn test_scoped_rules_apply_to_granted_clients() {
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[{"path": {"prefix": "/api/"}, "requests": 1000, "window": 60, "scope": "admin"},
+                {"path": {"prefix": "/api/"}, "requests": 10, "window": 60}]"#,
+        )
+        .unwrap();
+        let set = RuleSet::compile(1, rules).unwrap();
+
+        assert_eq!(set.find_scoped("/api/users", None, Some("read admin")).unwrap().requests, 1000);
+        assert_eq!(set.find_scoped("/api/users", None, Some("administrator")).unwrap().requests, 10);
+        assert_eq!(set.find("/api/users").unwrap().requests, 10);
+    }
+
+    #[test]
+    fn test_cache_recompiles_on_new_generation() {
+        let cache = RuleCache::new();
+        let first = cache.get_or_compile(1, || vec![rule(PathMatch::Prefix("/".to_string()), 10)]).unwrap();
+        let again = cache.get_or_compile(1, || unreachable!()).unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+
+        assert!(cache.get_or_compile(2, || vec![rule(PathMatch::Regex("(".to_string()), 1)]).is_err());
+        let next = cache.get_or_compile(2, Vec::new).unwrap();
+        assert_eq!((next.generation(), next.len()), (2, 0));
+    }
+}
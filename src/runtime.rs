@@ -0,0 +1,128 @@
+use std::future::Future;
+use std::sync::Arc;
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::sync::{oneshot, Semaphore};
+
+/// Threads and queue bounds of a worker's `WorkerRuntime`.
+#[derive(Debug, Clone)]
+pub struct RuntimeOptions {
+    /// Threads running backend I/O
+    pub worker_threads: usize,
+    /// Threads for blocking calls such as SQLite queries
+    pub blocking_threads: usize,
+    /// Decisions queued or in flight before new requests are turned away
+    pub queue_depth: usize,
+    /// Status returned to requests turned away (`rate_limit_overload_status`)
+    pub overload_status: u16,
+}
+
+impl Default for RuntimeOptions {
+    fn default() -> Self {
+        Self {
+            worker_threads: 2,
+            blocking_threads: 8,
+            queue_depth: 1024,
+            overload_status: 503,
+        }
+    }
+}
+
+/// The queue was full; the request should be answered with
+/// `overload_status` instead of waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overloaded;
+
+/// A tokio runtime owned by one nginx worker, separate from the event loop.
+///
+/// Build it in the init-worker phase: threads do not survive the fork from
+/// the master. Decisions are handed over with `submit`, which never blocks
+/// the event loop. At most `queue_depth` run at once. Past that, `submit`
+/// fails straight away, so a slow backend backs up into rejected requests,
+/// not into a growing queue of stalled connections.
+#[derive(Debug)]
+pub struct WorkerRuntime {
+    runtime: Runtime,
+    queue: Arc<Semaphore>,
+    options: RuntimeOptions,
+}
+
+impl WorkerRuntime {
+    pub fn new(options: RuntimeOptions) -> std::io::Result<Self> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(options.worker_threads.max(1))
+            .max_blocking_threads(options.blocking_threads.max(1))
+            .thread_name("rate-limit-io")
+            .enable_all()
+            .build()?;
+
+        Ok(Self {
+            runtime,
+            queue: Arc::new(Semaphore::new(options.queue_depth.max(1))),
+            options,
+        })
+    }
+
+    pub fn handle(&self) -> &Handle {
+        self.runtime.handle()
+    }
+
+    pub fn overload_status(&self) -> u16 {
+        self.options.overload_status
+    }
+
+    /// Decisions currently queued or running.
+    pub fn queued(&self) -> usize {
+        self.options.queue_depth.max(1) - self.queue.available_permits()
+    }
+
+    /// Run `future` on the runtime and return a receiver for its output, or
+    /// `Overloaded` when the queue is full.
+    pub fn submit<F>(&self, future: F) -> Result<oneshot::Receiver<F::Output>, Overloaded>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let permit = match Arc::clone(&self.queue).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                metrics::counter!("rate_limiter_runtime_rejected_total").increment(1);
+                return Err(Overloaded);
+            }
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        self.runtime.spawn(async move {
+            let _permit = permit;
+            let _ = sender.send(future.await);
+        });
+        metrics::gauge!("rate_limiter_runtime_queued").set(self.queued() as f64);
+        Ok(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_queue_is_rejected() {
+        let runtime = WorkerRuntime::new(RuntimeOptions {
+            queue_depth: 1,
+            ..RuntimeOptions::default()
+        })
+        .unwrap();
+
+        let (release, released) = oneshot::channel::<()>();
+        let first = runtime.submit(async move { released.await.is_ok() }).unwrap();
+        assert_eq!(runtime.submit(async {}).unwrap_err(), Overloaded);
+        assert_eq!(runtime.queued(), 1);
+
+        release.send(()).unwrap();
+        assert!(first.blocking_recv().unwrap());
+        // The permit is released once the task has finished
+        while runtime.queued() > 0 {
+            std::thread::yield_now();
+        }
+        assert_eq!(runtime.submit(async { 42 }).unwrap().blocking_recv().unwrap(), 42);
+    }
+}
@@ -0,0 +1,492 @@
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::server::{Grpc, UnaryService};
+use crate::config::{ConfigStore, Limits};
+use crate::denylist::{Denylist, Network};
+use crate::emergency;
+use crate::usage::{self, Granularity};
+use crate::storage::{
+    self, rls, DecisionRequest, DecisionResponse, Health, StorageBackend, StorageError, SHOULD_RATE_LIMIT_PATH,
+};
+
+/// Largest JSON decision request accepted.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Path answering nginx `auth_request` subrequests.
+pub const AUTH_REQUEST_PATH: &str = "/auth";
+
+/// Most hits one call may add, so a single request cannot run a backend
+/// batch of arbitrary size.
+const MAX_HITS: u32 = 10_000;
+
+/// Serves limit decisions over the network, for services that are not
+/// behind this module and for workers that delegate to a local sidecar.
+///
+/// One port speaks both protocols the delegating backends use:
+///
+/// - `POST` of `{"key", "limit", "window", "hits"}` JSON to any path, as
+///   sent by `HttpDecisionStorage`. The caller chooses the limit. `hits: 0`
+///   reads the state without counting.
+/// - Envoy RateLimit v3 `ShouldRateLimit` over gRPC, as sent by Envoy,
+///   Istio or `EnvoyRlsStorage`. Limits come from the sidecar's own
+///   `Limits`, with a descriptor entry named `path` selecting a path rule.
+///   As in Envoy, `hits_addend: 0` counts one hit.
+///
+/// Any request to `/auth` is decided for nginx's `auth_request`: the key
+/// comes from `X-Rate-Limit-Key`, `X-Real-IP` or `X-Forwarded-For`, the
+/// path rule from `X-Original-URI`, and the answer is 200 or 429 with
+/// `RateLimit-*` headers.
+///
+/// `GET /healthz` answers `ok` for load balancer and kubelet probes,
+/// `GET /status` reports the configuration in force, and `GET /usage`
+/// exports the usage recorded by `usage::UsageRecorder`. `PUT` and
+/// `DELETE` on `/emergency` switch the emergency brake, and on
+/// `/denylist/{address or CIDR}` edit the denylist given to
+/// `with_denylist`.
+pub struct Sidecar {
+    storage: Arc<dyn StorageBackend>,
+    config: ConfigStore,
+    denylist: Option<Arc<Denylist>>,
+}
+
+impl Sidecar {
+    pub fn new(storage: Arc<dyn StorageBackend>, limits: Limits) -> Self {
+        Self {
+            storage,
+            config: ConfigStore::new(limits),
+            denylist: None,
+        }
+    }
+
+    /// Serve `PUT /denylist/{network}?ttl=` and `DELETE
+    /// /denylist/{network}` on `denylist`, e.g. one shared with the
+    /// limiter in the same process.
+    pub fn with_denylist(mut self, denylist: Arc<Denylist>) -> Self {
+        self.denylist = Some(denylist);
+        self
+    }
+
+    /// The limits used for gRPC calls, reloadable while serving.
+    pub fn config(&self) -> &ConfigStore {
+        &self.config
+    }
+
+    /// Accept connections until the listener fails. HTTP/1.1 and HTTP/2
+    /// (including gRPC) are told apart per connection.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let sidecar = Arc::clone(&self);
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(move |request| {
+                    let sidecar = Arc::clone(&sidecar);
+                    async move { Ok::<_, Infallible>(sidecar.handle(request).await) }
+                });
+                if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    log::debug!("Sidecar connection from {} ended: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(self: Arc<Self>, request: Request<Incoming>) -> Response<BoxBody> {
+        match (request.method(), request.uri().path()) {
+            (&Method::POST, SHOULD_RATE_LIMIT_PATH) => {
+                let mut grpc = Grpc::new(ProstCodec::default());
+                grpc.unary(ShouldRateLimit(self), request).await
+            }
+            (&Method::GET, "/healthz") => text(StatusCode::OK, "ok"),
+            (&Method::GET, "/status") => self.status(),
+            (&Method::GET, "/usage") => self.usage(request.uri().query().unwrap_or("")).await,
+            (&Method::GET, path) if path.starts_with("/keys/") => self.inspect_key(&path["/keys/".len()..]).await,
+            (&Method::PUT, "/emergency") => self.engage_emergency(request.uri().query().unwrap_or("")).await,
+            (&Method::DELETE, "/emergency") => match emergency::release_everywhere(self.storage.as_ref()).await {
+                Ok(()) => text(StatusCode::OK, "released"),
+                Err(e) => text(StatusCode::SERVICE_UNAVAILABLE, &e.to_string()),
+            },
+            (_, AUTH_REQUEST_PATH) => self.auth_request(request.headers()).await,
+            (method @ (&Method::PUT | &Method::DELETE), path) if path.starts_with("/denylist/") => {
+                self.edit_denylist(*method == Method::PUT, path, request.uri().query().unwrap_or(""))
+            }
+            (&Method::POST, _) => self.handle_decision(request).await,
+            _ => text(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+
+    async fn handle_decision(&self, request: Request<Incoming>) -> Response<BoxBody> {
+        let body = match Limited::new(request.into_body(), MAX_BODY_BYTES).collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(e) => return text(StatusCode::PAYLOAD_TOO_LARGE, &e.to_string()),
+        };
+        let request: DecisionRequest = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return text(StatusCode::BAD_REQUEST, &e.to_string()),
+        };
+
+        match self.decide(&request).await {
+            // DecisionResponse only holds plain values, so this cannot fail
+            Ok(response) => json(serde_json::to_vec(&response).unwrap_or_default()),
+            // A 5xx puts the caller in its configured failure mode
+            Err(e) => {
+                log::warn!("Sidecar decision for {} failed: {}", request.key, e);
+                text(StatusCode::SERVICE_UNAVAILABLE, &e.to_string())
+            }
+        }
+    }
+
+    /// The active configuration generation and the source version it was
+    /// loaded from, if any.
+    fn status(&self) -> Response<BoxBody> {
+        let current = self.config.load();
+        let health = storage::backend_health();
+        let degraded = health.iter().any(|(_, report)| report.health != Health::Healthy);
+        let backends: serde_json::Map<String, serde_json::Value> =
+            health.into_iter().map(|(name, report)| (name, serde_json::json!(report))).collect();
+        let status = serde_json::json!({
+            "generation": current.number,
+            "version": current.version,
+            "requests": current.limits.requests_per_second,
+            "window": current.limits.window_size,
+            "backends": backends,
+            "degraded": degraded,
+        });
+        json(status.to_string().into_bytes())
+    }
+
+    /// Usage buckets as JSON, or CSV with `format=csv`. `granularity` is
+    /// `hour` or `day` (the default); `from` and `to` bound the bucket
+    /// starts in Unix seconds.
+    async fn usage(&self, query: &str) -> Response<BoxBody> {
+        let (mut granularity, mut from, mut to, mut csv) = (Granularity::Day, 0, u64::MAX, false);
+        for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match (name, value.parse::<u64>()) {
+                ("granularity", _) if value == "hour" => granularity = Granularity::Hour,
+                ("granularity", _) if value == "day" => granularity = Granularity::Day,
+                ("from", Ok(value)) => from = value,
+                ("to", Ok(value)) => to = value,
+                ("format", _) => csv = value == "csv",
+                ("granularity" | "from" | "to", _) => {
+                    return text(StatusCode::BAD_REQUEST, &format!("invalid {}", name));
+                }
+                _ => {}
+            }
+        }
+
+        match usage::report(self.storage.as_ref(), granularity, from, to).await {
+            Ok(rows) if csv => {
+                let mut response = text(StatusCode::OK, &usage::to_csv(&rows));
+                response
+                    .headers_mut()
+                    .insert(hyper::header::CONTENT_TYPE, hyper::header::HeaderValue::from_static("text/csv"));
+                response
+            }
+            // UsageRow only holds plain values, so this cannot fail
+            Ok(rows) => json(serde_json::to_vec(&rows).unwrap_or_default()),
+            Err(e) => text(StatusCode::SERVICE_UNAVAILABLE, &e.to_string()),
+        }
+    }
+
+    /// Engage the emergency brake on every worker watching the backend, for
+    /// `ttl` seconds, an hour by default.
+    async fn engage_emergency(&self, query: &str) -> Response<BoxBody> {
+        let mut ttl = 3600;
+        for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match (name, value.parse::<u32>()) {
+                ("ttl", Ok(value)) if value > 0 => ttl = value,
+                ("ttl", _) => return text(StatusCode::BAD_REQUEST, "invalid ttl"),
+                _ => {}
+            }
+        }
+        match emergency::engage_everywhere(self.storage.as_ref(), ttl).await {
+            Ok(()) => text(StatusCode::OK, "engaged"),
+            Err(e) => text(StatusCode::SERVICE_UNAVAILABLE, &e.to_string()),
+        }
+    }
+
+    /// Count one request for an `auth_request` subrequest and answer 200
+    /// while it is within its limit, 429 once it is not. A backend error
+    /// answers 200, as nginx turns any other status into a 500.
+    async fn auth_request(&self, headers: &HeaderMap) -> Response<BoxBody> {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(str::trim);
+        let key = header("x-rate-limit-key")
+            .or_else(|| header("x-real-ip"))
+            .or_else(|| header("x-forwarded-for").and_then(|chain| chain.split(',').next()).map(str::trim))
+            .filter(|key| !key.is_empty());
+        let Some(key) = key else {
+            return text(StatusCode::BAD_REQUEST, "no client key");
+        };
+        let uri = header("x-original-uri").unwrap_or("/");
+        let path = uri.split_once('?').map_or(uri, |(path, _)| path);
+        let (limit, window) = self.config.load().limits.for_path(path);
+
+        let count = match self.count(key, window, 1).await {
+            Ok(count) => count.unwrap_or(0),
+            Err(e) => {
+                log::warn!("Sidecar auth_request decision for {} failed: {}", key, e);
+                return text(StatusCode::OK, "");
+            }
+        };
+        let allowed = count <= u64::from(limit);
+        metrics::counter!("rate_limiter_sidecar_decisions_total", "protocol" => "auth_request", "allowed" => allowed.to_string())
+            .increment(1);
+        let reset = self.reset_after(key, window).await;
+
+        let status = if allowed { StatusCode::OK } else { StatusCode::TOO_MANY_REQUESTS };
+        let mut response = text(status, "");
+        let headers = response.headers_mut();
+        headers.insert("ratelimit-limit", HeaderValue::from(limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(u64::from(limit).saturating_sub(count)));
+        headers.insert("ratelimit-reset", HeaderValue::from(reset));
+        if !allowed {
+            headers.insert(hyper::header::RETRY_AFTER, HeaderValue::from(reset));
+        }
+        response
+    }
+
+    /// Seconds until the key's window resets, rounded up. A backend that
+    /// cannot tell gives the whole window, the longest it can be.
+    async fn reset_after(&self, key: &str, window: u32) -> u64 {
+        match self.storage.ttl(key).await {
+            Ok(Some(ttl)) => ttl.as_secs_f64().ceil() as u64,
+            _ => u64::from(window),
+        }
+    }
+
+    /// The key's count and the milliseconds left in its window, or 404
+    /// when it has no live counter.
+    async fn inspect_key(&self, key: &str) -> Response<BoxBody> {
+        if key.is_empty() {
+            return text(StatusCode::BAD_REQUEST, "no key");
+        }
+        let (count, ttl) = match (self.storage.get(key).await, self.storage.ttl(key).await) {
+            (Ok(count), Ok(ttl)) => (count, ttl),
+            (_, Err(StorageError::Unsupported(what))) => {
+                return text(StatusCode::NOT_IMPLEMENTED, &format!("{} is not supported by the backend", what));
+            }
+            (Err(e), _) | (_, Err(e)) => return text(StatusCode::SERVICE_UNAVAILABLE, &e.to_string()),
+        };
+        let Some(ttl) = ttl.filter(|_| count > 0) else {
+            return text(StatusCode::NOT_FOUND, "no live counter");
+        };
+        let key = serde_json::json!({
+            "key": key,
+            "count": count,
+            "ttl_ms": ttl.as_millis() as u64,
+        });
+        json(key.to_string().into_bytes())
+    }
+
+    /// Deny the network in `path` for `ttl` seconds, an hour by default,
+    /// or lift it.
+    fn edit_denylist(&self, deny: bool, path: &str, query: &str) -> Response<BoxBody> {
+        let Some(denylist) = &self.denylist else {
+            return text(StatusCode::NOT_FOUND, "no denylist");
+        };
+        let Some(network) = path.strip_prefix("/denylist/").and_then(Network::parse) else {
+            return text(StatusCode::BAD_REQUEST, "invalid address or network");
+        };
+        if !deny {
+            denylist.remove(network);
+            return text(StatusCode::OK, "removed");
+        }
+        let mut ttl = 3600;
+        for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match (name, value.parse::<u64>()) {
+                ("ttl", Ok(value)) if value > 0 => ttl = value,
+                ("ttl", _) => return text(StatusCode::BAD_REQUEST, "invalid ttl"),
+                _ => {}
+            }
+        }
+        denylist.insert(network, Duration::from_secs(ttl));
+        text(StatusCode::OK, "denied")
+    }
+
+    /// Count `hits` for the key and report whether it is within `limit`.
+    /// With no hits, the key is allowed while it has quota left.
+    async fn decide(&self, request: &DecisionRequest<'_>) -> Result<DecisionResponse, StorageError> {
+        let limit = u64::from(request.limit);
+        let (count, allowed) = match self.count(&request.key, request.window, request.hits).await? {
+            Some(count) => (count, count <= limit),
+            None => {
+                let count = self.storage.get(&request.key).await?;
+                (count, count < limit)
+            }
+        };
+
+        metrics::counter!("rate_limiter_sidecar_decisions_total", "protocol" => "http", "allowed" => allowed.to_string())
+            .increment(1);
+        // Only denied callers need to know when to come back
+        let reset = if allowed {
+            None
+        } else {
+            let ttl = self.storage.ttl(&request.key).await.ok().flatten();
+            ttl.map(crate::reset_at)
+        };
+        Ok(DecisionResponse {
+            allowed,
+            remaining: Some(limit.saturating_sub(count).min(u64::from(u32::MAX)) as u32),
+            reset,
+        })
+    }
+
+    /// Add `hits` to `key` and return the new count, or `None` for zero hits.
+    async fn count(&self, key: &str, window: u32, hits: u32) -> Result<Option<u64>, StorageError> {
+        if hits == 0 {
+            return Ok(None);
+        }
+        let keys = vec![(key, window); hits.min(MAX_HITS) as usize];
+        let increments = self.storage.increment_many(&keys).await?;
+        Ok(increments.last().map(|increment| increment.count))
+    }
+
+    async fn should_rate_limit(&self, request: rls::RateLimitRequest) -> Result<rls::RateLimitResponse, StorageError> {
+        let generation = self.config.load_full();
+        let hits = request.hits_addend.max(1);
+        let mut response = rls::RateLimitResponse {
+            overall_code: rls::Code::Ok as i32,
+            statuses: Vec::with_capacity(request.descriptors.len()),
+        };
+
+        for descriptor in &request.descriptors {
+            let (limit, window) = match descriptor.entries.iter().find(|entry| entry.key == "path") {
+                Some(entry) => generation.limits.for_path(&entry.value),
+                None => (generation.limits.requests_per_second, generation.limits.window_size),
+            };
+
+            let mut key = request.domain.clone();
+            for entry in &descriptor.entries {
+                key.push_str(&format!("|{}={}", entry.key, entry.value));
+            }
+            let count = self.count(&key, window, hits).await?.unwrap_or(0);
+
+            let code = if count <= u64::from(limit) { rls::Code::Ok } else { rls::Code::OverLimit };
+            if code == rls::Code::OverLimit {
+                response.overall_code = code as i32;
+            }
+            response.statuses.push(rls::DescriptorStatus {
+                code: code as i32,
+                current_limit: Some(rls::RateLimit {
+                    requests_per_unit: limit,
+                    unit: unit(window) as i32,
+                }),
+                limit_remaining: u64::from(limit).saturating_sub(count) as u32,
+            });
+        }
+
+        let allowed = response.overall_code == rls::Code::Ok as i32;
+        metrics::counter!("rate_limiter_sidecar_decisions_total", "protocol" => "grpc", "allowed" => allowed.to_string())
+            .increment(1);
+        Ok(response)
+    }
+}
+
+/// The Envoy unit for a window, if it is exactly one.
+fn unit(window: u32) -> rls::Unit {
+    match window {
+        1 => rls::Unit::Second,
+        60 => rls::Unit::Minute,
+        3600 => rls::Unit::Hour,
+        86400 => rls::Unit::Day,
+        _ => rls::Unit::Unknown,
+    }
+}
+
+fn text(status: StatusCode, body: &str) -> Response<BoxBody> {
+    let mut response = Response::new(tonic::body::boxed(Full::new(Bytes::from(body.to_string()))));
+    *response.status_mut() = status;
+    response
+}
+
+fn json(body: Vec<u8>) -> Response<BoxBody> {
+    let mut response = Response::new(tonic::body::boxed(Full::new(Bytes::from(body))));
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+/// `ShouldRateLimit` as a tonic unary service.
+struct ShouldRateLimit(Arc<Sidecar>);
+
+impl UnaryService<rls::RateLimitRequest> for ShouldRateLimit {
+    type Response = rls::RateLimitResponse;
+    type Future = Pin<Box<dyn Future<Output = Result<tonic::Response<Self::Response>, tonic::Status>> + Send>>;
+
+    fn call(&mut self, request: tonic::Request<rls::RateLimitRequest>) -> Self::Future {
+        let sidecar = Arc::clone(&self.0);
+        Box::pin(async move {
+            sidecar
+                .should_rate_limit(request.into_inner())
+                .await
+                .map(tonic::Response::new)
+                .map_err(|e| tonic::Status::unavailable(e.to_string()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::borrow::Cow;
+
+    #[tokio::test]
+    async fn test_decisions() {
+        let sidecar = Sidecar::new(Arc::new(MemoryStorage::new()), Limits::new(2, 60));
+
+        let request = |hits| DecisionRequest { key: Cow::Borrowed("client"), limit: 1, window: 60, hits };
+        assert!(sidecar.decide(&request(0)).await.unwrap().allowed);
+        assert!(sidecar.decide(&request(1)).await.unwrap().allowed);
+        let response = sidecar.decide(&request(0)).await.unwrap();
+        assert_eq!((response.allowed, response.remaining), (false, Some(0)));
+
+        let request = rls::RateLimitRequest {
+            domain: "edge".to_string(),
+            descriptors: vec![rls::RateLimitDescriptor {
+                entries: vec![rls::Entry { key: "remote_address".to_string(), value: "10.0.0.1".to_string() }],
+            }],
+            hits_addend: 0,
+        };
+        let mut codes = Vec::new();
+        for _ in 0..3 {
+            codes.push(sidecar.should_rate_limit(request.clone()).await.unwrap().overall_code);
+        }
+        // hits_addend 0 counts one hit, as in Envoy
+        assert_eq!(codes, vec![rls::Code::Ok as i32, rls::Code::Ok as i32, rls::Code::OverLimit as i32]);
+    }
+
+    #[tokio::test]
+    async fn test_auth_request() {
+        let sidecar = Sidecar::new(Arc::new(MemoryStorage::new()), Limits::new(1, 60));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.9, 10.0.0.1"));
+        headers.insert("x-original-uri", HeaderValue::from_static("/api/orders?page=2"));
+
+        let allowed = sidecar.auth_request(&headers).await;
+        assert_eq!(allowed.status(), StatusCode::OK);
+        assert_eq!(allowed.headers()["ratelimit-remaining"], "0");
+        let limited = sidecar.auth_request(&headers).await;
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.headers()["retry-after"], "60");
+        assert_eq!(sidecar.storage.get("203.0.113.9").await.unwrap(), 2);
+
+        assert_eq!(sidecar.auth_request(&HeaderMap::new()).await.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,239 @@
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+
+/// Limits of `SlowRequestTracker`.
+#[derive(Debug, Clone)]
+pub struct SlowRequestOptions {
+    /// A request still open after this long is slow
+    pub slow_after: Duration,
+    /// Most slow requests one address may hold open at once
+    pub max_slow: usize,
+    /// Slow time one address may use per `window`, counting only the time
+    /// each request spends past `slow_after`
+    pub budget: Duration,
+    pub window: Duration,
+}
+
+impl Default for SlowRequestOptions {
+    fn default() -> Self {
+        Self {
+            slow_after: Duration::from_secs(10),
+            max_slow: 4,
+            budget: Duration::from_secs(120),
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Why a request was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowRejection {
+    /// The address holds `max_slow` slow requests already
+    TooManySlow,
+    /// The address used up its slow time for the window
+    BudgetSpent,
+}
+
+impl SlowRejection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SlowRejection::TooManySlow => "too_many_slow",
+            SlowRejection::BudgetSpent => "budget_spent",
+        }
+    }
+}
+
+/// Slow time already charged to an address in one window.
+#[derive(Debug, Clone, Copy)]
+struct Spent {
+    window: u64,
+    spent: Duration,
+}
+
+/// Tracks each address's requests in flight and charges their slow time
+/// to a per-address budget, against slowloris-style clients that hold many
+/// connections open by sending slowly.
+///
+/// Call `start` when a connection or request begins and keep the returned
+/// `InFlight` until it ends. Time is charged until `InFlight::headers_read`
+/// is called, so header-read time alone can be budgeted, or until the
+/// guard is dropped. Requests still open count against the budget too, so
+/// connections that never finish are caught. `slow_clients` lists the
+/// addresses over their limit, for closing their connections.
+pub struct SlowRequestTracker {
+    /// Address to its requests in flight, by id, with their start times
+    in_flight: DashMap<IpAddr, HashMap<u64, Duration>>,
+    spent: DashMap<IpAddr, Spent>,
+    next_id: AtomicU64,
+    options: SlowRequestOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for SlowRequestTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SlowRequestTracker").field("options", &self.options).finish()
+    }
+}
+
+/// A tracked request; finishes when dropped.
+pub struct InFlight {
+    tracker: Arc<SlowRequestTracker>,
+    address: IpAddr,
+    id: u64,
+    finished: bool,
+}
+
+impl InFlight {
+    /// Stop charging this request: its headers are in, and what follows is
+    /// up to the application.
+    pub fn headers_read(mut self) {
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.tracker.finish(self.address, self.id);
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl SlowRequestTracker {
+    pub fn new(options: SlowRequestOptions) -> Self {
+        Self {
+            in_flight: DashMap::new(),
+            spent: DashMap::new(),
+            next_id: AtomicU64::new(0),
+            options,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn window(&self, now: Duration) -> u64 {
+        (now.as_millis() / self.options.window.as_millis().max(1)) as u64
+    }
+
+    /// Time past `slow_after` for a request that started at `start`.
+    fn overtime(&self, start: Duration, now: Duration) -> Duration {
+        now.saturating_sub(start).saturating_sub(self.options.slow_after)
+    }
+
+    /// Begin tracking a request from `address`, unless the address is over
+    /// its limits.
+    pub fn start(self: &Arc<Self>, address: IpAddr) -> Result<InFlight, SlowRejection> {
+        if let Some(rejection) = self.check(address) {
+            metrics::counter!("rate_limiter_slow_rejections_total", "reason" => rejection.as_str()).increment(1);
+            return Err(rejection);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.entry(address).or_default().insert(id, self.clock.now());
+        Ok(InFlight { tracker: Arc::clone(self), address, id, finished: false })
+    }
+
+    /// Why `address` is over its limits, if it is.
+    pub fn check(&self, address: IpAddr) -> Option<SlowRejection> {
+        let now = self.clock.now();
+        let (slow, overtime) = match self.in_flight.get(&address) {
+            Some(requests) => requests.values().fold((0, Duration::ZERO), |(slow, overtime), start| {
+                let over = self.overtime(*start, now);
+                (slow + usize::from(!over.is_zero()), overtime + over)
+            }),
+            None => (0, Duration::ZERO),
+        };
+        if slow >= self.options.max_slow {
+            return Some(SlowRejection::TooManySlow);
+        }
+        let window = self.window(now);
+        let spent = self
+            .spent
+            .get(&address)
+            .filter(|spent| spent.window == window)
+            .map_or(Duration::ZERO, |spent| spent.spent);
+        (spent + overtime >= self.options.budget).then_some(SlowRejection::BudgetSpent)
+    }
+
+    fn finish(&self, address: IpAddr, id: u64) {
+        let now = self.clock.now();
+        let start = match self.in_flight.get_mut(&address) {
+            Some(mut requests) => requests.remove(&id),
+            None => None,
+        };
+        self.in_flight.remove_if(&address, |_, requests| requests.is_empty());
+        let Some(start) = start else { return };
+
+        let overtime = self.overtime(start, now);
+        if overtime.is_zero() {
+            return;
+        }
+        let window = self.window(now);
+        let mut spent = self.spent.entry(address).or_insert(Spent { window, spent: Duration::ZERO });
+        if spent.window != window {
+            *spent = Spent { window, spent: Duration::ZERO };
+        }
+        spent.spent += overtime;
+    }
+
+    /// Addresses over their limits right now, e.g. to close their
+    /// connections.
+    pub fn slow_clients(&self) -> Vec<IpAddr> {
+        let addresses: Vec<IpAddr> = self.in_flight.iter().map(|entry| *entry.key()).collect();
+        addresses.into_iter().filter(|address| self.check(*address).is_some()).collect()
+    }
+
+    /// Forget budgets from past windows.
+    pub fn purge(&self) {
+        let window = self.window(self.clock.now());
+        self.spent.retain(|_, spent| spent.window == window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_slow_requests_are_limited_per_address() {
+        let clock = Arc::new(ManualClock::default());
+        let options = SlowRequestOptions { budget: Duration::from_secs(50), ..SlowRequestOptions::default() };
+        let tracker = Arc::new(SlowRequestTracker::new(options).with_clock(clock.clone()));
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let other: IpAddr = "192.0.2.8".parse().unwrap();
+
+        let held: Vec<InFlight> = (0..4).map(|_| tracker.start(client).unwrap()).collect();
+        // Fast requests cost nothing
+        tracker.start(other).unwrap().headers_read();
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(tracker.start(client).err(), Some(SlowRejection::TooManySlow));
+        assert_eq!(tracker.slow_clients(), vec![client]);
+        assert!(tracker.start(other).is_ok());
+
+        // 4 requests 10s past `slow_after` use up most of the budget
+        clock.advance(Duration::from_secs(9));
+        drop(held);
+        assert!(tracker.slow_clients().is_empty());
+        let slow = tracker.start(client).unwrap();
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(tracker.check(client), Some(SlowRejection::BudgetSpent));
+        drop(slow);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(tracker.check(client), None);
+    }
+}
@@ -0,0 +1,83 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+/// Rejects a growing fraction of a key's requests as its count nears and
+/// passes its limit, like RED queue management, instead of all of them at
+/// the limit.
+///
+/// No request is rejected below `start` times the limit, every request
+/// from `full` times the limit, and in between the chance of rejection
+/// grows linearly. A bursty client sees some 429s early and keeps getting
+/// some requests through for a while past its limit, rather than a wall.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Smoothing {
+    pub start: f64,
+    pub full: f64,
+}
+
+impl Default for Smoothing {
+    fn default() -> Self {
+        Self { start: 0.8, full: 1.2 }
+    }
+}
+
+impl Smoothing {
+    pub fn new(start: f64, full: f64) -> Self {
+        Self { start, full }
+    }
+
+    /// The chance of rejecting a request seen with `count` requests
+    /// already counted.
+    pub fn probability(&self, count: u64, limit: u32) -> f64 {
+        let limit = f64::from(limit);
+        let (start, full) = (self.start * limit, (self.full * limit).max(self.start * limit));
+        let count = count as f64;
+        if count >= full {
+            1.0
+        } else if count < start {
+            0.0
+        } else {
+            (count - start + 1.0) / (full - start + 1.0)
+        }
+    }
+
+    /// Whether to reject a request seen with `count` requests counted.
+    pub fn rejects(&self, count: u64, limit: u32) -> bool {
+        let probability = self.probability(count, limit);
+        probability >= 1.0 || (probability > 0.0 && random() < probability)
+    }
+}
+
+/// A uniform number in `[0, 1)` from a per-thread xorshift generator.
+fn random() -> f64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(std::thread::current().id()) | 1);
+    }
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejections_ramp_up_around_the_limit() {
+        let smoothing = Smoothing::default();
+        assert_eq!(smoothing.probability(79, 100), 0.0);
+        assert_eq!(smoothing.probability(120, 100), 1.0);
+        assert!((smoothing.probability(100, 100) - 21.0 / 41.0).abs() < 1e-9);
+        assert!(!smoothing.rejects(10, 100));
+        assert!(smoothing.rejects(500, 100));
+
+        let rejected = (0..10_000).filter(|_| smoothing.rejects(100, 100)).count();
+        assert!((4_500..5_800).contains(&rejected), "{} of 10000 rejected", rejected);
+    }
+}
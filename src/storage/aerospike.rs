@@ -0,0 +1,166 @@
+use aerospike::errors::{Error as AerospikeError, ErrorKind};
+use aerospike::operations;
+use aerospike::{
+    as_bin, as_key, Bins, Client, ClientPolicy, Expiration, ReadPolicy, Record, ResultCode, Value,
+    WritePolicy,
+};
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::storage::{Increment, StorageBackend, StorageError};
+
+const COUNT_BIN: &str = "count";
+
+/// Namespace, set and client policies for `AerospikeStorage`.
+#[derive(Debug, Clone)]
+pub struct AerospikeOptions {
+    pub namespace: String,
+    pub set: String,
+    /// Total transaction timeout per operation; `None` uses the client default
+    pub timeout: Option<Duration>,
+    /// Number of retries per operation; `None` uses the client default
+    pub max_retries: Option<usize>,
+}
+
+impl Default for AerospikeOptions {
+    fn default() -> Self {
+        Self {
+            namespace: "ratelimit".to_string(),
+            set: "rate_limits".to_string(),
+            timeout: None,
+            max_retries: None,
+        }
+    }
+}
+
+/// Aerospike backed storage.
+///
+/// Each key is one record with a `count` bin. The record TTL is the window,
+/// so Aerospike expires finished windows on its own. The client blocks,
+/// so every call runs on the blocking thread pool.
+pub struct AerospikeStorage {
+    client: Arc<Client>,
+    options: AerospikeOptions,
+}
+
+impl AerospikeStorage {
+    /// Connect to the cluster through a seed list such as
+    /// `"10.0.0.1:3000,10.0.0.2:3000"`.
+    pub fn new(hosts: &str) -> Result<Self, StorageError> {
+        Self::with_options(hosts, AerospikeOptions::default())
+    }
+
+    pub fn with_options(hosts: &str, options: AerospikeOptions) -> Result<Self, StorageError> {
+        let mut policy = ClientPolicy::default();
+        if let Some(timeout) = options.timeout {
+            policy.timeout = Some(timeout);
+        }
+
+        let client = Client::new(&policy, &hosts)
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        Ok(Self { client: Arc::new(client), options })
+    }
+
+    /// Run `f` with the client on the blocking thread pool.
+    async fn with_client<T, F>(&self, f: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Client) -> Result<T, StorageError> + Send + 'static,
+    {
+        let client = Arc::clone(&self.client);
+        tokio::task::spawn_blocking(move || f(&client))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?
+    }
+
+    fn read_policy(&self) -> ReadPolicy {
+        let mut policy = ReadPolicy::default();
+        policy.timeout = self.options.timeout;
+        policy.max_retries = self.options.max_retries;
+        policy
+    }
+
+    fn write_policy(&self, expiration: Expiration) -> WritePolicy {
+        let mut policy = WritePolicy::new(0, expiration);
+        policy.base_policy.timeout = self.options.timeout;
+        policy.base_policy.max_retries = self.options.max_retries;
+        policy
+    }
+
+    fn count(record: &Record) -> Result<u64, StorageError> {
+        match record.bins.get(COUNT_BIN) {
+            Some(Value::Int(count)) => {
+                u64::try_from(*count).map_err(|e| StorageError::InvalidValueType(e.to_string()))
+            }
+            Some(other) => Err(StorageError::InvalidValueType(other.to_string())),
+            None => Ok(0),
+        }
+    }
+
+    fn is_key_not_found(error: &AerospikeError) -> bool {
+        matches!(error.kind(), ErrorKind::ServerError(ResultCode::KeyNotFoundError))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for AerospikeStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let record_key = as_key!(self.options.namespace.as_str(), self.options.set.as_str(), key);
+        let policy = self.read_policy();
+
+        self.with_client(move |client| {
+            let record = match client.get(&policy, &record_key, Bins::Some(vec![COUNT_BIN.to_string()])) {
+                Ok(record) => record,
+                Err(e) if Self::is_key_not_found(&e) => return Ok(0),
+                Err(e) => return Err(StorageError::DatabaseError(e.to_string())),
+            };
+            Self::count(&record)
+        })
+        .await
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.increment_by(key, 1, expire).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        if amount == 0 {
+            return self.get(key).await.map(|count| Increment { count });
+        }
+        let amount = amount.min(i64::MAX as u64) as i64;
+        let record_key = as_key!(self.options.namespace.as_str(), self.options.set.as_str(), key);
+        let policy = self.write_policy(Expiration::Seconds(expire));
+
+        self.with_client(move |client| {
+            // add + touch + read run atomically on the record; writing also
+            // resets the TTL
+            let bin = as_bin!(COUNT_BIN, amount);
+            let ops = vec![operations::add(&bin), operations::touch(), operations::get_bin(COUNT_BIN)];
+            let record = client
+                .operate(&policy, &record_key, &ops)
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            Ok(Increment { count: Self::count(&record)? })
+        })
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let record_key = as_key!(self.options.namespace.as_str(), self.options.set.as_str(), key);
+        let policy = self.write_policy(Expiration::NamespaceDefault);
+
+        self.with_client(move |client| {
+            client
+                .delete(&policy, &record_key)
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        // Aerospike automatically removes expired records,
+        // so no special implementation is needed
+        Ok(0)
+    }
+}
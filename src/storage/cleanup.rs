@@ -0,0 +1,105 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use crate::storage::StorageBackend;
+
+/// Schedule for the background expired-key sweep.
+#[derive(Debug, Clone)]
+pub struct CleanupOptions {
+    pub interval: Duration,
+    /// Up to this much is added to every wait, so instances started together
+    /// do not sweep in lockstep
+    pub jitter: Duration,
+}
+
+impl Default for CleanupOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            jitter: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Periodically call `cleanup_expired` on `storage` until the returned
+/// handle is aborted.
+///
+/// SQL backends take an advisory lock around the sweep, so when several
+/// instances share a database only one of them deletes rows each round.
+/// Every run is recorded in the `rate_limiter_cleanup_*` metrics.
+pub fn spawn_cleanup(
+    storage: Arc<dyn StorageBackend>,
+    options: CleanupOptions,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(options.interval + jitter(options.jitter)).await;
+
+            let started = Instant::now();
+            let result = storage.cleanup_expired().await;
+            metrics::histogram!("rate_limiter_cleanup_duration_seconds")
+                .record(started.elapsed().as_secs_f64());
+
+            match result {
+                Ok(removed) => {
+                    metrics::counter!("rate_limiter_cleanup_runs_total").increment(1);
+                    metrics::counter!("rate_limiter_cleanup_reclaimed_total").increment(removed);
+                    if removed > 0 {
+                        log::debug!("Removed {} expired rate limit keys", removed);
+                    }
+                }
+                Err(e) => {
+                    metrics::counter!("rate_limiter_cleanup_errors_total").increment(1);
+                    log::warn!("Expired key cleanup failed: {}", e);
+                }
+            }
+        }
+    })
+}
+
+fn jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    // RandomState is seeded randomly per instance, which is all we need here
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_nanos(random % max.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cleanup_runs_on_schedule() {
+        let clock = Arc::new(ManualClock::default());
+        let memory = MemoryStorage::new().with_clock(clock.clone());
+        memory.increment("expired", 1).await.unwrap();
+        let storage: Arc<dyn StorageBackend> = Arc::new(memory);
+        clock.advance(Duration::from_secs(2));
+
+        let handle = spawn_cleanup(
+            Arc::clone(&storage),
+            CleanupOptions {
+                interval: Duration::from_secs(60),
+                jitter: Duration::ZERO,
+            },
+        );
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        handle.abort();
+
+        assert_eq!(storage.cleanup_expired().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(jitter(Duration::from_secs(5)) < Duration::from_secs(5));
+        }
+    }
+}
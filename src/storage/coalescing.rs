@@ -0,0 +1,270 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use crate::storage::{CounterSnapshot, Increment, StorageBackend, StorageError};
+
+type Waiter<T> = oneshot::Sender<Result<T, StorageError>>;
+
+/// Backend calls in flight, per key, with the callers waiting on them.
+struct Flights<T> {
+    calls: Mutex<HashMap<String, Vec<Waiter<T>>>>,
+}
+
+impl<T> Flights<T> {
+    fn new() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Vec<Waiter<T>>>> {
+        self.calls.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Wait on the call in flight for `key`, or lead a new one.
+    fn join<'a>(&'a self, key: &'a str) -> Result<oneshot::Receiver<Result<T, StorageError>>, Leader<'a, T>> {
+        let mut calls = self.lock();
+        match calls.get_mut(key) {
+            Some(waiters) => {
+                let (sender, receiver) = oneshot::channel();
+                waiters.push(sender);
+                Ok(receiver)
+            }
+            None => {
+                calls.insert(key.to_string(), Vec::new());
+                Err(Leader {
+                    flights: self,
+                    key,
+                    landed: false,
+                })
+            }
+        }
+    }
+}
+
+/// The caller making the backend call for a flight.
+struct Leader<'a, T> {
+    flights: &'a Flights<T>,
+    key: &'a str,
+    landed: bool,
+}
+
+impl<T> Leader<'_, T> {
+    /// Close the flight and take its waiters; callers arriving from now on
+    /// start a new one.
+    fn land(&mut self) -> Vec<Waiter<T>> {
+        self.landed = true;
+        self.flights.lock().remove(self.key).unwrap_or_default()
+    }
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        // A cancelled leader drops the senders, and its waiters make the
+        // call themselves
+        if !self.landed {
+            self.flights.lock().remove(self.key);
+        }
+    }
+}
+
+/// Shares one backend call among concurrent requests for the same key
+/// (singleflight).
+///
+/// A `get` issued while another `get` for the key is in flight waits for
+/// that call and returns its count. Increments for a key are gathered for
+/// `window` and sent as one `increment_many` with the key repeated, so
+/// each request still gets its own count, in arrival order. A zero window
+/// only gathers requests that arrive while the first one yields. Either
+/// way a hot key costs one round trip per flight instead of one per
+/// request.
+pub struct CoalescingStorage {
+    inner: Box<dyn StorageBackend>,
+    window: Duration,
+    gets: Flights<u64>,
+    increments: Flights<Increment>,
+}
+
+impl CoalescingStorage {
+    pub fn new(inner: Box<dyn StorageBackend>, window: Duration) -> Self {
+        Self {
+            inner,
+            window,
+            gets: Flights::new(),
+            increments: Flights::new(),
+        }
+    }
+
+    fn coalesced(operation: &'static str, waiters: usize) {
+        if waiters > 0 {
+            metrics::counter!("rate_limiter_coalesced_total", "operation" => operation).increment(waiters as u64);
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for CoalescingStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let mut leader = match self.gets.join(key) {
+            Ok(receiver) => match receiver.await {
+                Ok(result) => return result,
+                Err(_) => return self.inner.get(key).await,
+            },
+            Err(leader) => leader,
+        };
+
+        let result = self.inner.get(key).await;
+        let waiters = leader.land();
+        Self::coalesced("get", waiters.len());
+        for waiter in waiters {
+            let _ = waiter.send(result.clone());
+        }
+        result
+    }
+
+    /// Requests gathered into one batch all use the first one's `expire`.
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        let mut leader = match self.increments.join(key) {
+            Ok(receiver) => match receiver.await {
+                Ok(result) => return result,
+                Err(_) => return self.inner.increment(key, expire).await,
+            },
+            Err(leader) => leader,
+        };
+
+        if self.window.is_zero() {
+            tokio::task::yield_now().await;
+        } else {
+            tokio::time::sleep(self.window).await;
+        }
+
+        let waiters = leader.land();
+        if waiters.is_empty() {
+            return self.inner.increment(key, expire).await;
+        }
+
+        Self::coalesced("increment", waiters.len());
+        let batch = vec![(key, expire); waiters.len() + 1];
+        match self.inner.increment_many(&batch).await {
+            Ok(increments) => {
+                let mut increments = increments.into_iter();
+                let own = increments.next();
+                for (waiter, increment) in waiters.into_iter().zip(increments) {
+                    let _ = waiter.send(Ok(increment));
+                }
+                own.ok_or_else(|| StorageError::InvalidValueType("empty increment batch".to_string()))
+            }
+            Err(e) => {
+                for waiter in waiters {
+                    let _ = waiter.send(Err(e.clone()));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.inner.delete(key).await
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        self.inner.cleanup_expired().await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        self.inner.ttl(key).await
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.inner.warm_up().await
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        self.inner.get_many(keys).await
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        self.inner.increment_many(keys).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        self.inner.increment_by(key, amount, expire).await
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        self.inner.export_all().await
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        self.inner.import_all(counters).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// MemoryStorage that counts the calls reaching it
+    #[derive(Default)]
+    struct CountingStorage {
+        inner: MemoryStorage,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl StorageBackend for CountingStorage {
+        async fn get(&self, key: &str) -> Result<u64, StorageError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.get(key).await
+        }
+
+        async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.increment(key, expire).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), StorageError> {
+            self.inner.delete(key).await
+        }
+
+        async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+            self.inner.cleanup_expired().await
+        }
+
+        async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.increment_many(keys).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_increments_share_one_call() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let inner = CountingStorage {
+            calls: Arc::clone(&calls),
+            ..CountingStorage::default()
+        };
+        let storage = Arc::new(CoalescingStorage::new(Box::new(inner), Duration::from_millis(20)));
+
+        let tasks: Vec<_> = (0..50)
+            .map(|_| {
+                let storage = Arc::clone(&storage);
+                tokio::spawn(async move { storage.increment("hot", 60).await.unwrap().count })
+            })
+            .collect();
+        let mut counts = Vec::new();
+        for task in tasks {
+            counts.push(task.await.unwrap());
+        }
+        counts.sort_unstable();
+
+        assert_eq!(counts, (1..=50).collect::<Vec<u64>>());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(storage.get("hot").await.unwrap(), 50);
+    }
+}
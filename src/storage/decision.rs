@@ -0,0 +1,28 @@
+//! Wire format of the HTTP decision API, shared by `HttpDecisionStorage`,
+//! the sidecar that serves it and the Proxy-Wasm filter.
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Body of a decision call.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DecisionRequest<'a> {
+    #[serde(borrow)]
+    pub key: Cow<'a, str>,
+    pub limit: u32,
+    pub window: u32,
+    /// Requests to count; 0 asks for the current state only
+    #[serde(default)]
+    pub hits: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DecisionResponse {
+    pub allowed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u32>,
+    /// Unix timestamp at which the window resets
+    #[allow(dead_code)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset: Option<u64>,
+}
@@ -0,0 +1,434 @@
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use trust_dns_resolver::TokioAsyncResolver;
+use crate::storage::{
+    CounterSnapshot, Increment, RedisOptions, RedisStorage, ShardedStorage, StorageBackend, StorageError,
+    DEFAULT_VIRTUAL_NODES,
+};
+use crate::storage::sharded::DEFAULT_DOWN_INTERVAL;
+
+/// Builds the backend for one resolved address.
+pub type EndpointFactory =
+    Arc<dyn Fn(SocketAddr) -> BoxFuture<'static, Result<Arc<dyn StorageBackend>, StorageError>> + Send + Sync>;
+
+/// A DNS name standing for one or more backends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsName {
+    /// An SRV name such as `_redis._tcp.cache.internal`, giving the port
+    Srv(String),
+    /// A host name with one or more A or AAAA records, and the port to use
+    Host(String, u16),
+}
+
+impl DnsName {
+    /// `_service._proto.domain` for SRV records, `host:port` otherwise.
+    pub fn parse(name: &str) -> Result<Self, StorageError> {
+        if name.starts_with('_') {
+            return Ok(DnsName::Srv(name.to_string()));
+        }
+        name.rsplit_once(':')
+            .and_then(|(host, port)| Some(DnsName::Host(host.to_string(), port.parse().ok()?)))
+            .filter(|name| !matches!(name, DnsName::Host(host, _) if host.is_empty()))
+            .ok_or_else(|| {
+                StorageError::ConnectionError(format!(
+                    "invalid DNS name '{}', expected host:port or an SRV name",
+                    name
+                ))
+            })
+    }
+}
+
+/// How often the records are looked up again.
+#[derive(Debug, Clone)]
+pub struct DiscoveryOptions {
+    /// Shortest wait between lookups, however low the records' TTL
+    pub min_ttl: Duration,
+    /// Longest wait between lookups, however high the records' TTL
+    pub max_ttl: Duration,
+    /// Points per address on the hash ring when a name has several
+    pub virtual_nodes: usize,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self {
+            min_ttl: Duration::from_secs(5),
+            max_ttl: Duration::from_secs(300),
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+        }
+    }
+}
+
+/// Looks up the addresses behind a `DnsName`.
+#[async_trait]
+pub trait Resolve: Send + Sync {
+    /// The addresses, sorted, and how long they may be cached.
+    async fn resolve(&self, name: &DnsName) -> Result<(Vec<SocketAddr>, Duration), StorageError>;
+}
+
+/// Resolves through the system's resolver configuration.
+pub struct SystemResolver(TokioAsyncResolver);
+
+impl SystemResolver {
+    pub fn new() -> Result<Self, StorageError> {
+        TokioAsyncResolver::tokio_from_system_conf()
+            .map(SystemResolver)
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl Resolve for SystemResolver {
+    /// SRV targets are resolved in turn; only those of the lowest priority
+    /// are used, and weights are ignored.
+    async fn resolve(&self, name: &DnsName) -> Result<(Vec<SocketAddr>, Duration), StorageError> {
+        let error = |e: trust_dns_resolver::error::ResolveError| StorageError::ConnectionError(e.to_string());
+        let mut addresses = Vec::new();
+        let valid_until = match name {
+            DnsName::Srv(name) => {
+                let records = self.0.srv_lookup(name.as_str()).await.map_err(error)?;
+                let mut valid_until = records.valid_until();
+                let priority = records.iter().map(|srv| srv.priority()).min();
+                for srv in records.iter().filter(|srv| Some(srv.priority()) == priority) {
+                    let ips = self.0.lookup_ip(srv.target().clone()).await.map_err(error)?;
+                    valid_until = valid_until.min(ips.valid_until());
+                    addresses.extend(ips.iter().map(|ip| SocketAddr::new(ip, srv.port())));
+                }
+                valid_until
+            }
+            DnsName::Host(host, port) => {
+                let ips = self.0.lookup_ip(host.as_str()).await.map_err(error)?;
+                addresses.extend(ips.iter().map(|ip| SocketAddr::new(ip, *port)));
+                ips.valid_until()
+            }
+        };
+        addresses.sort_unstable();
+        addresses.dedup();
+        Ok((addresses, valid_until.saturating_duration_since(Instant::now())))
+    }
+}
+
+struct Resolved {
+    endpoints: Vec<(SocketAddr, Arc<dyn StorageBackend>)>,
+    storage: Arc<dyn StorageBackend>,
+}
+
+/// Backends found by DNS, looked up again as their records expire.
+///
+/// A name with several addresses spreads keys over them on a consistent
+/// hash ring, so an address joining or leaving only moves its share of the
+/// keys. Connections to addresses that stay are kept; those to addresses
+/// that go are closed. A connection error triggers an early lookup, so a
+/// failover that repoints the name, as ElastiCache does, is followed
+/// without a reload. Lookups that fail keep the current addresses.
+pub struct DiscoveredStorage {
+    name: DnsName,
+    options: DiscoveryOptions,
+    resolver: Arc<dyn Resolve>,
+    factory: EndpointFactory,
+    resolved: ArcSwap<Resolved>,
+    /// Wakes the refresh task ahead of the TTL
+    stale: Notify,
+}
+
+impl DiscoveredStorage {
+    /// Resolve `name` and connect to every address found. Call `spawn` to
+    /// keep following the records.
+    pub async fn new(
+        name: DnsName,
+        options: DiscoveryOptions,
+        resolver: Arc<dyn Resolve>,
+        factory: EndpointFactory,
+    ) -> Result<(Arc<Self>, Duration), StorageError> {
+        let (addresses, ttl) = resolver.resolve(&name).await?;
+        let resolved = build(&addresses, &[], &factory, options.virtual_nodes).await?;
+        let storage = Arc::new(Self {
+            name,
+            options,
+            resolver,
+            factory,
+            resolved: ArcSwap::from_pointee(resolved),
+            stale: Notify::new(),
+        });
+        Ok((storage, ttl))
+    }
+
+    /// Standalone Redis servers behind `name`, e.g.
+    /// `_redis._tcp.cache.internal` or `cache.internal:6379`, followed in
+    /// the background. Servers are connected to by address, so with TLS
+    /// their certificates must name the addresses.
+    pub async fn redis(name: &str, redis: RedisOptions, options: DiscoveryOptions) -> Result<Arc<Self>, StorageError> {
+        let factory: EndpointFactory = Arc::new(move |address: SocketAddr| {
+            let redis = redis.clone();
+            async move {
+                let scheme = if redis.tls.is_some() { "rediss" } else { "redis" };
+                let url = format!("{}://{}/", scheme, address);
+                Ok(Arc::new(RedisStorage::with_options(&url, &redis)?) as Arc<dyn StorageBackend>)
+            }
+            .boxed()
+        });
+        let resolver = Arc::new(SystemResolver::new()?);
+        let (storage, ttl) = Self::new(DnsName::parse(name)?, options, resolver, factory).await?;
+        storage.spawn(ttl);
+        Ok(storage)
+    }
+
+    /// The addresses currently in use.
+    pub fn endpoints(&self) -> Vec<SocketAddr> {
+        self.resolved.load().endpoints.iter().map(|(address, _)| *address).collect()
+    }
+
+    /// Look the name up again and, if its addresses changed, connect to the
+    /// new ones. Returns how long the answer may be cached.
+    pub async fn refresh(&self) -> Result<Duration, StorageError> {
+        let (addresses, ttl) = self.resolver.resolve(&self.name).await?;
+        let current = self.resolved.load_full();
+        if current.endpoints.iter().map(|(address, _)| *address).ne(addresses.iter().copied()) {
+            log::info!("Backends behind {:?} changed to {:?}", self.name, addresses);
+            let resolved = build(&addresses, &current.endpoints, &self.factory, self.options.virtual_nodes).await?;
+            self.resolved.store(Arc::new(resolved));
+            metrics::counter!("rate_limiter_discovery_changes_total").increment(1);
+        }
+        metrics::gauge!("rate_limiter_discovery_endpoints").set(addresses.len() as f64);
+        Ok(ttl)
+    }
+
+    /// Refresh in the background after each answer's TTL, clamped to the
+    /// options, and after connection errors. Stops once the storage is
+    /// dropped.
+    pub fn spawn(self: &Arc<Self>, ttl: Duration) {
+        let storage: Weak<Self> = Arc::downgrade(self);
+        let (min, max) = (self.options.min_ttl, self.options.max_ttl.max(self.options.min_ttl));
+        tokio::spawn(async move {
+            let mut wait = ttl.clamp(min, max);
+            loop {
+                let Some(current) = storage.upgrade() else { return };
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = current.stale.notified() => tokio::time::sleep(min).await,
+                }
+                wait = match current.refresh().await {
+                    Ok(ttl) => ttl.clamp(min, max),
+                    Err(e) => {
+                        log::warn!("Failed to resolve {:?}, keeping the current backends: {}", current.name, e);
+                        min
+                    }
+                };
+            }
+        });
+    }
+
+    fn storage(&self) -> Arc<dyn StorageBackend> {
+        Arc::clone(&self.resolved.load().storage)
+    }
+
+    /// Pass `result` through, asking for an early lookup on a connection
+    /// error.
+    fn watch<T>(&self, result: Result<T, StorageError>) -> Result<T, StorageError> {
+        if let Err(StorageError::ConnectionError(_)) = &result {
+            self.stale.notify_one();
+        }
+        result
+    }
+}
+
+/// Backends for `addresses`, reusing those of `current` that remain.
+async fn build(
+    addresses: &[SocketAddr],
+    current: &[(SocketAddr, Arc<dyn StorageBackend>)],
+    factory: &EndpointFactory,
+    virtual_nodes: usize,
+) -> Result<Resolved, StorageError> {
+    let existing: HashMap<SocketAddr, &Arc<dyn StorageBackend>> =
+        current.iter().map(|(address, backend)| (*address, backend)).collect();
+    let mut endpoints = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        let backend = match existing.get(address) {
+            Some(backend) => Arc::clone(backend),
+            None => factory(*address).await?,
+        };
+        endpoints.push((*address, backend));
+    }
+
+    let storage: Arc<dyn StorageBackend> = match &endpoints[..] {
+        [] => return Err(StorageError::ConnectionError("no addresses found".to_string())),
+        [(_, backend)] => Arc::clone(backend),
+        _ => {
+            let shards = endpoints
+                .iter()
+                .map(|(address, backend)| {
+                    (address.to_string(), Box::new(Shared(Arc::clone(backend))) as Box<dyn StorageBackend>)
+                })
+                .collect();
+            Arc::new(ShardedStorage::new(shards, virtual_nodes, DEFAULT_DOWN_INTERVAL)?)
+        }
+    };
+    Ok(Resolved { endpoints, storage })
+}
+
+/// A backend shared between successive hash rings, or with the caller.
+pub(crate) struct Shared(pub(crate) Arc<dyn StorageBackend>);
+
+#[async_trait]
+impl StorageBackend for Shared {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        self.0.get(key).await
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.0.increment(key, expire).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.0.delete(key).await
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        self.0.cleanup_expired().await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        self.0.ttl(key).await
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.0.warm_up().await
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        self.0.get_many(keys).await
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        self.0.increment_many(keys).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        self.0.increment_by(key, amount, expire).await
+    }
+
+    async fn add_distinct(&self, key: &str, member: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.0.add_distinct(key, member, expire).await
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        self.0.export_all().await
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        self.0.import_all(counters).await
+    }
+}
+
+#[async_trait]
+impl StorageBackend for DiscoveredStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        self.watch(self.storage().get(key).await)
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.watch(self.storage().increment(key, expire).await)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.watch(self.storage().delete(key).await)
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        self.watch(self.storage().cleanup_expired().await)
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        self.watch(self.storage().ttl(key).await)
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.watch(self.storage().warm_up().await)
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        self.watch(self.storage().get_many(keys).await)
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        self.watch(self.storage().increment_many(keys).await)
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        self.watch(self.storage().increment_by(key, amount, expire).await)
+    }
+
+    async fn add_distinct(&self, key: &str, member: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.watch(self.storage().add_distinct(key, member, expire).await)
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        self.watch(self.storage().export_all().await)
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        self.watch(self.storage().import_all(counters).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::sync::Mutex;
+
+    /// Answers with whatever addresses the test last set.
+    struct Records(Mutex<Vec<SocketAddr>>);
+
+    #[async_trait]
+    impl Resolve for Records {
+        async fn resolve(&self, _name: &DnsName) -> Result<(Vec<SocketAddr>, Duration), StorageError> {
+            Ok((self.0.lock().unwrap().clone(), Duration::from_secs(30)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follows_the_records() {
+        let srv = DnsName::parse("_redis._tcp.cache.internal").unwrap();
+        assert_eq!(srv, DnsName::Srv("_redis._tcp.cache.internal".to_string()));
+        let host = DnsName::parse("cache.internal:6379").unwrap();
+        assert_eq!(host, DnsName::Host("cache.internal".to_string(), 6379));
+        assert!(DnsName::parse("cache.internal").is_err());
+
+        let primary: SocketAddr = "10.0.0.1:6379".parse().unwrap();
+        let replica: SocketAddr = "10.0.0.2:6379".parse().unwrap();
+        let records = Arc::new(Records(Mutex::new(vec![primary])));
+        let factory: EndpointFactory = Arc::new(|_: SocketAddr| {
+            async { Ok(Arc::new(MemoryStorage::new()) as Arc<dyn StorageBackend>) }.boxed()
+        });
+        let (storage, ttl) =
+            DiscoveredStorage::new(host, DiscoveryOptions::default(), records.clone(), factory).await.unwrap();
+        assert_eq!(ttl, Duration::from_secs(30));
+        storage.increment("client", 60).await.unwrap();
+
+        // Failover repoints the name; the old counters are gone with the old server
+        *records.0.lock().unwrap() = vec![replica];
+        storage.refresh().await.unwrap();
+        assert_eq!(storage.endpoints(), vec![replica]);
+        assert_eq!(storage.get("client").await.unwrap(), 0);
+        storage.increment("client", 60).await.unwrap();
+
+        // A new address joins the ring and the existing connection is kept
+        *records.0.lock().unwrap() = vec![primary, replica];
+        storage.refresh().await.unwrap();
+        assert_eq!(storage.endpoints(), vec![primary, replica]);
+        let (_, kept) = &storage.resolved.load().endpoints[1];
+        assert_eq!(kept.get("client").await.unwrap(), 1);
+
+        *records.0.lock().unwrap() = vec![];
+        assert!(storage.refresh().await.is_err());
+        assert_eq!(storage.endpoints(), vec![primary, replica]);
+    }
+}
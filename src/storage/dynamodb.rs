@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use aws_sdk_dynamodb::error::ProvideErrorMetadata;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use aws_sdk_dynamodb::Client;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::storage::{Increment, StorageBackend, StorageError};
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(25);
+const MAX_BACKOFF: Duration = Duration::from_millis(800);
+
+// Error codes DynamoDB returns when a table or account is over its throughput
+const THROTTLING_ERRORS: &[&str] = &[
+    "ProvisionedThroughputExceededException",
+    "RequestLimitExceeded",
+    "ThrottlingException",
+];
+
+/// DynamoDB backed storage.
+///
+/// Expects a table with a string partition key `key_name`; `count` holds the
+/// counter and `expire_at` (epoch seconds) should be enabled as the table's
+/// TTL attribute so DynamoDB removes stale items.
+pub struct DynamoDBStorage {
+    client: Client,
+    table_name: String,
+}
+
+impl DynamoDBStorage {
+    /// Create a storage using credentials and region from the default AWS
+    /// provider chain (environment, profile, IMDS, ...).
+    pub async fn new(table_name: &str) -> Result<Self, StorageError> {
+        let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        Ok(Self::with_client(Client::new(&config), table_name))
+    }
+
+    pub fn with_client(client: Client, table_name: &str) -> Self {
+        Self {
+            client,
+            table_name: table_name.to_string(),
+        }
+    }
+
+    fn get_current_timestamp() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    fn key_attribute(key: &str) -> AttributeValue {
+        AttributeValue::S(key.to_string())
+    }
+
+    fn number(value: u64) -> AttributeValue {
+        AttributeValue::N(value.to_string())
+    }
+
+    /// Read `count` from an item. DynamoDB numbers can exceed u64, so larger
+    /// counts saturate.
+    fn count(item: &HashMap<String, AttributeValue>) -> Result<u64, StorageError> {
+        let n = match item.get("count").and_then(|v| v.as_n().ok()) {
+            Some(n) => n,
+            None => return Ok(0),
+        };
+        match n.parse::<u64>() {
+            Ok(count) => Ok(count),
+            Err(e) if *e.kind() == std::num::IntErrorKind::PosOverflow => Ok(u64::MAX),
+            Err(e) => Err(StorageError::InvalidValueType(e.to_string())),
+        }
+    }
+
+    /// Increment a live counter and return the new count. Fails the
+    /// condition check if the item is missing or its window has already
+    /// expired.
+    async fn increment_live(&self, key: &str, amount: u64, now: u64, expire_at: u64) -> Result<Option<u64>, StorageError> {
+        let result = with_backoff(|| {
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("key_name", Self::key_attribute(key))
+                .update_expression("ADD #count :amount SET expire_at = :expire_at")
+                .condition_expression("attribute_exists(key_name) AND expire_at > :now")
+                .expression_attribute_names("#count", "count")
+                .expression_attribute_values(":amount", Self::number(amount))
+                .expression_attribute_values(":expire_at", Self::number(expire_at))
+                .expression_attribute_values(":now", Self::number(now))
+                .return_values(ReturnValue::UpdatedNew)
+                .send()
+        })
+        .await;
+
+        match result {
+            Ok(output) => match output.attributes() {
+                Some(attributes) => Self::count(attributes).map(Some),
+                None => Err(StorageError::InvalidValueType("update returned no attributes".to_string())),
+            },
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => Ok(None),
+            Err(e) => Err(StorageError::DatabaseError(e.to_string())),
+        }
+    }
+
+    /// Start a new window. Fails the condition check if another writer
+    /// started one in the meantime.
+    async fn start_window(&self, key: &str, amount: u64, now: u64, expire_at: u64) -> Result<bool, StorageError> {
+        let result = with_backoff(|| {
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("key_name", Self::key_attribute(key))
+                .update_expression("SET #count = :amount, expire_at = :expire_at")
+                .condition_expression("attribute_not_exists(key_name) OR expire_at <= :now")
+                .expression_attribute_names("#count", "count")
+                .expression_attribute_values(":amount", Self::number(amount))
+                .expression_attribute_values(":expire_at", Self::number(expire_at))
+                .expression_attribute_values(":now", Self::number(now))
+                .send()
+        })
+        .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => Ok(false),
+            Err(e) => Err(StorageError::DatabaseError(e.to_string())),
+        }
+    }
+}
+
+/// Run a DynamoDB request, retrying throttled attempts with exponential backoff.
+async fn with_backoff<T, E, F, Fut>(mut request: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: ProvideErrorMetadata,
+{
+    let mut attempt = 1;
+    let mut delay = INITIAL_BACKOFF;
+
+    loop {
+        match request().await {
+            Err(e) if attempt < MAX_ATTEMPTS && is_throttled(&e) => {
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_throttled<E: ProvideErrorMetadata>(error: &E) -> bool {
+    error.code().is_some_and(|code| THROTTLING_ERRORS.contains(&code))
+}
+
+#[async_trait]
+impl StorageBackend for DynamoDBStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let output = with_backoff(|| {
+            self.client
+                .get_item()
+                .table_name(&self.table_name)
+                .key("key_name", Self::key_attribute(key))
+                .consistent_read(true)
+                .send()
+        })
+        .await
+        .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let item = match output.item() {
+            Some(item) => item,
+            None => return Ok(0),
+        };
+
+        // TTL deletion is lazy, so expired items can still be returned
+        let expire_at = item
+            .get("expire_at")
+            .and_then(|v| v.as_n().ok())
+            .and_then(|n| n.parse::<u64>().ok())
+            .unwrap_or(0);
+        if expire_at <= Self::get_current_timestamp() {
+            return Ok(0);
+        }
+
+        Self::count(item)
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.increment_by(key, 1, expire).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        if amount == 0 {
+            return self.get(key).await.map(|count| Increment { count });
+        }
+        // Either the live-window update or the new-window update succeeds;
+        // losing both conditions means another writer raced us, so try again.
+        for _ in 0..MAX_ATTEMPTS {
+            let now = Self::get_current_timestamp();
+            let expire_at = now + expire as u64;
+
+            if let Some(count) = self.increment_live(key, amount, now, expire_at).await? {
+                return Ok(Increment { count });
+            }
+            if self.start_window(key, amount, now, expire_at).await? {
+                return Ok(Increment { count: amount });
+            }
+        }
+
+        Err(StorageError::DatabaseError(format!(
+            "conditional update for '{}' kept conflicting",
+            key
+        )))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        with_backoff(|| {
+            self.client
+                .delete_item()
+                .table_name(&self.table_name)
+                .key("key_name", Self::key_attribute(key))
+                .send()
+        })
+        .await
+        .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        // DynamoDB removes items through the TTL attribute,
+        // so no special implementation is needed
+        Ok(0)
+    }
+}
@@ -0,0 +1,251 @@
+use async_trait::async_trait;
+use std::time::Duration;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Code as GrpcCode;
+use crate::storage::{FailureMode, Increment, StorageBackend, StorageError};
+
+pub(crate) const SHOULD_RATE_LIMIT_PATH: &str = "/envoy.service.ratelimit.v3.RateLimitService/ShouldRateLimit";
+
+/// Domain, descriptor and deadline settings for `EnvoyRlsStorage`.
+#[derive(Debug, Clone)]
+pub struct EnvoyRlsOptions {
+    /// Rate limit domain configured in the service
+    pub domain: String,
+    /// Descriptor entry key the rate limit key is sent under
+    pub descriptor_key: String,
+    /// Deadline for each `ShouldRateLimit` call, propagated as `grpc-timeout`
+    pub timeout: Duration,
+    pub failure_mode: FailureMode,
+}
+
+impl Default for EnvoyRlsOptions {
+    fn default() -> Self {
+        Self {
+            domain: "nginx".to_string(),
+            descriptor_key: "remote_address".to_string(),
+            timeout: Duration::from_millis(50),
+            failure_mode: FailureMode::Allow,
+        }
+    }
+}
+
+/// Delegates decisions to an external service speaking the Envoy RateLimit
+/// v3 gRPC protocol, sharing quotas with an Envoy/Istio fleet.
+///
+/// The service owns limits and windows: `increment` reports one hit and the
+/// `expire` argument is ignored, while `get` asks with zero hits and derives
+/// a count from the limit and remaining quota. A key the service reports as
+/// over limit reads as `u64::MAX`. `delete` and `cleanup_expired` are no-ops.
+pub struct EnvoyRlsStorage {
+    channel: Channel,
+    options: EnvoyRlsOptions,
+}
+
+impl EnvoyRlsStorage {
+    pub fn new(url: &str) -> Result<Self, StorageError> {
+        Self::with_options(url, EnvoyRlsOptions::default())
+    }
+
+    /// Connect lazily to `http://host:port`; the HTTP/2 connection is shared
+    /// by all calls and re-established on failure.
+    pub fn with_options(url: &str, options: EnvoyRlsOptions) -> Result<Self, StorageError> {
+        let channel = Endpoint::from_shared(url.to_string())
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?
+            .connect_timeout(options.timeout)
+            .timeout(options.timeout)
+            .tcp_keepalive(Some(Duration::from_secs(60)))
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .connect_lazy();
+
+        Ok(Self { channel, options })
+    }
+
+    async fn should_rate_limit(&self, key: &str, hits: u32) -> Result<proto::RateLimitResponse, StorageError> {
+        let request = proto::RateLimitRequest {
+            domain: self.options.domain.clone(),
+            descriptors: vec![proto::RateLimitDescriptor {
+                entries: vec![proto::Entry {
+                    key: self.options.descriptor_key.clone(),
+                    value: key.to_string(),
+                }],
+            }],
+            hits_addend: hits,
+        };
+
+        let mut grpc = tonic::client::Grpc::new(self.channel.clone());
+        grpc.ready()
+            .await
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        let mut request = tonic::Request::new(request);
+        request.set_timeout(self.options.timeout);
+
+        let response = grpc
+            .unary(
+                request,
+                PathAndQuery::from_static(SHOULD_RATE_LIMIT_PATH),
+                ProstCodec::default(),
+            )
+            .await
+            .map_err(|status| match status.code() {
+                GrpcCode::Unavailable | GrpcCode::DeadlineExceeded | GrpcCode::Cancelled => {
+                    StorageError::ConnectionError(status.to_string())
+                }
+                _ => StorageError::DatabaseError(status.to_string()),
+            })?;
+
+        Ok(response.into_inner())
+    }
+}
+
+/// Translate a `ShouldRateLimit` answer into a request count.
+fn count_from_response(response: &proto::RateLimitResponse) -> u64 {
+    if response.overall_code == proto::Code::OverLimit as i32 {
+        return u64::MAX;
+    }
+
+    response
+        .statuses
+        .first()
+        .and_then(|status| {
+            let limit = status.current_limit.as_ref()?;
+            Some(u64::from(limit.requests_per_unit.saturating_sub(status.limit_remaining)))
+        })
+        .unwrap_or(0)
+}
+
+#[async_trait]
+impl StorageBackend for EnvoyRlsStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        match self.should_rate_limit(key, 0).await {
+            Ok(response) => Ok(count_from_response(&response)),
+            Err(e) => {
+                log::warn!("Rate limit service call failed: {}", e);
+                match self.options.failure_mode {
+                    FailureMode::Allow => Ok(0),
+                    FailureMode::Deny => Ok(u64::MAX),
+                }
+            }
+        }
+    }
+
+    async fn increment(&self, key: &str, _expire: u32) -> Result<Increment, StorageError> {
+        match self.should_rate_limit(key, 1).await {
+            Ok(response) => Ok(Increment { count: count_from_response(&response) }),
+            Err(e) if self.options.failure_mode == FailureMode::Allow => {
+                log::warn!("Rate limit service call failed: {}", e);
+                Ok(Increment { count: 0 })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, _key: &str) -> Result<(), StorageError> {
+        // The protocol has no way to reset a counter
+        Ok(())
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        // Windows are managed by the rate limit service
+        Ok(0)
+    }
+}
+
+/// The subset of `envoy.service.ratelimit.v3` used here and by the sidecar.
+/// Fields that are not listed are skipped when decoding.
+pub(crate) mod proto {
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct RateLimitRequest {
+        #[prost(string, tag = "1")]
+        pub domain: String,
+        #[prost(message, repeated, tag = "2")]
+        pub descriptors: Vec<RateLimitDescriptor>,
+        #[prost(uint32, tag = "3")]
+        pub hits_addend: u32,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct RateLimitDescriptor {
+        #[prost(message, repeated, tag = "1")]
+        pub entries: Vec<Entry>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Entry {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(string, tag = "2")]
+        pub value: String,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct RateLimitResponse {
+        #[prost(enumeration = "Code", tag = "1")]
+        pub overall_code: i32,
+        #[prost(message, repeated, tag = "2")]
+        pub statuses: Vec<DescriptorStatus>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct DescriptorStatus {
+        #[prost(enumeration = "Code", tag = "1")]
+        pub code: i32,
+        #[prost(message, optional, tag = "2")]
+        pub current_limit: Option<RateLimit>,
+        #[prost(uint32, tag = "3")]
+        pub limit_remaining: u32,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct RateLimit {
+        #[prost(uint32, tag = "1")]
+        pub requests_per_unit: u32,
+        #[prost(enumeration = "Unit", tag = "2")]
+        pub unit: i32,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Unit {
+        Unknown = 0,
+        Second = 1,
+        Minute = 2,
+        Hour = 3,
+        Day = 4,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Code {
+        Unknown = 0,
+        Ok = 1,
+        OverLimit = 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::{Code, DescriptorStatus, RateLimit, RateLimitResponse};
+
+    fn response(code: Code, limit: Option<u32>, remaining: u32) -> RateLimitResponse {
+        RateLimitResponse {
+            overall_code: code as i32,
+            statuses: vec![DescriptorStatus {
+                code: code as i32,
+                current_limit: limit.map(|requests_per_unit| RateLimit { requests_per_unit, ..RateLimit::default() }),
+                limit_remaining: remaining,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_count_from_response() {
+        assert_eq!(count_from_response(&response(Code::Ok, Some(100), 60)), 40);
+        assert_eq!(count_from_response(&response(Code::OverLimit, Some(100), 0)), u64::MAX);
+        // No limit configured for the descriptor
+        assert_eq!(count_from_response(&response(Code::Ok, None, 0)), 0);
+    }
+}
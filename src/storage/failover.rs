@@ -0,0 +1,462 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::storage::{BackendHealth, CounterSnapshot, Health, Increment, StorageBackend, StorageError};
+
+#[derive(Default)]
+struct FailoverState {
+    /// Set while the primary is considered down
+    degraded_since: Option<Instant>,
+    last_probe: Option<Instant>,
+    /// Keys written to the fallback during the outage, with their window
+    pending: HashMap<String, u32>,
+    /// Set while a task replays `pending` into the primary
+    reconciling: bool,
+}
+
+/// Primary backend with a fallback used while the primary is failing.
+///
+/// All traffic goes to the primary. When a call fails with a connection or
+/// database error the storage switches to the fallback and probes the
+/// primary again every `probe_interval`. On recovery, traffic goes back to
+/// the primary at once, and a background task adds the counts accumulated
+/// in the fallback to it, one `increment_by` per key, so no requests are
+/// forgotten. A primary that cannot add amounts loses them.
+///
+/// With `with_health`, the switch follows the primary's health instead of
+/// single errors: it happens once the primary is judged down, even before
+/// a call fails, and is undone once it no longer is. A failed call while
+/// the primary is not down is still served by the fallback, and its counts
+/// are replayed with the next success.
+pub struct FailoverStorage {
+    primary: Arc<dyn StorageBackend>,
+    fallback: Arc<dyn StorageBackend>,
+    probe_interval: Duration,
+    state: Arc<Mutex<FailoverState>>,
+    health: Option<Arc<BackendHealth>>,
+}
+
+impl FailoverStorage {
+    pub fn new(
+        primary: Box<dyn StorageBackend>,
+        fallback: Box<dyn StorageBackend>,
+        probe_interval: Duration,
+    ) -> Self {
+        Self {
+            primary: Arc::from(primary),
+            fallback: Arc::from(fallback),
+            probe_interval,
+            state: Arc::default(),
+            health: None,
+        }
+    }
+
+    /// Switch to the fallback when `health`, the primary's, is down, e.g.
+    /// from `ResilientStorage::health`.
+    pub fn with_health(mut self, health: Arc<BackendHealth>) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Whether requests are currently served by the fallback.
+    pub fn is_degraded(&self) -> bool {
+        let down = self.health.as_ref().is_some_and(|health| health.health() == Health::Down);
+        let mut state = self.state();
+        if down && state.degraded_since.is_none() {
+            log::warn!("Primary storage is down, switching to fallback");
+            state.degraded_since = Some(Instant::now());
+            state.last_probe = Some(Instant::now());
+        }
+        state.degraded_since.is_some()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, FailoverState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn is_outage(error: &StorageError) -> bool {
+        matches!(error, StorageError::ConnectionError(_) | StorageError::DatabaseError(_))
+    }
+
+    fn mark_degraded(&self, error: &StorageError) {
+        if self.health.as_ref().is_some_and(|health| health.health() != Health::Down) {
+            log::debug!("Primary storage call failed, serving it from the fallback: {}", error);
+            return;
+        }
+        let mut state = self.state();
+        if state.degraded_since.is_none() {
+            log::warn!("Primary storage failed, switching to fallback: {}", error);
+            state.degraded_since = Some(Instant::now());
+        }
+        state.last_probe = Some(Instant::now());
+    }
+
+    /// True when degraded and the primary is due for another attempt.
+    fn should_probe(&self) -> bool {
+        self.is_degraded();
+        let mut state = self.state();
+        match (state.degraded_since, state.last_probe) {
+            (None, _) => true,
+            (Some(_), Some(last)) if last.elapsed() < self.probe_interval => false,
+            (Some(_), _) => {
+                state.last_probe = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Leave degraded mode and replay the counts written to the fallback
+    /// during the outage, unless a replay is already running.
+    fn recover(&self) {
+        let mut state = self.state();
+        if let Some(since) = state.degraded_since.take() {
+            log::info!("Primary storage recovered after {:?}", since.elapsed());
+        }
+        if state.reconciling || state.pending.is_empty() {
+            return;
+        }
+        state.reconciling = true;
+        let pending = std::mem::take(&mut state.pending);
+        drop(state);
+
+        tokio::spawn(reconcile(
+            Arc::clone(&self.primary),
+            Arc::clone(&self.fallback),
+            Arc::clone(&self.state),
+            pending,
+        ));
+    }
+
+    /// Count `amount` hits, on the primary unless degraded.
+    async fn add(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        if self.should_probe() {
+            match add_to(self.primary.as_ref(), key, amount, expire).await {
+                Ok(increment) => {
+                    // With a health tracker, stay on the fallback until the
+                    // primary is no longer judged down
+                    let down = self.health.as_ref().is_some_and(|health| health.health() == Health::Down);
+                    if !down && (self.is_degraded() || !self.state().pending.is_empty()) {
+                        self.recover();
+                    }
+                    return Ok(increment);
+                }
+                Err(e) if Self::is_outage(&e) => self.mark_degraded(&e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let increment = add_to(self.fallback.as_ref(), key, amount, expire).await?;
+        self.state().pending.insert(key.to_string(), expire);
+        Ok(increment)
+    }
+}
+
+async fn add_to(backend: &dyn StorageBackend, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+    match amount {
+        1 => backend.increment(key, expire).await,
+        _ => backend.increment_by(key, amount, expire).await,
+    }
+}
+
+/// Add each key's count in the fallback to the primary and drop it from the
+/// fallback. On an error the key and those not reached yet go back to
+/// `pending` for the next recovery; keys already added are not replayed
+/// again.
+async fn reconcile(
+    primary: Arc<dyn StorageBackend>,
+    fallback: Arc<dyn StorageBackend>,
+    state: Arc<Mutex<FailoverState>>,
+    pending: HashMap<String, u32>,
+) {
+    let lock = || state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let keys = pending.len();
+    let mut pending = pending.into_iter();
+
+    while let Some((key, expire)) = pending.next() {
+        let count = fallback.get(&key).await.unwrap_or(0);
+        if count > 0 {
+            match primary.increment_by(&key, count, expire).await {
+                Ok(_) => {}
+                Err(StorageError::Unsupported(_)) => {
+                    log::warn!("Primary storage cannot add the {} outage hits of {}", count, key);
+                }
+                Err(e) => {
+                    log::warn!("Reconciling {} with the primary storage failed: {}", key, e);
+                    let mut state = lock();
+                    for (key, expire) in std::iter::once((key, expire)).chain(pending) {
+                        state.pending.entry(key).or_insert(expire);
+                    }
+                    state.reconciling = false;
+                    return;
+                }
+            }
+        }
+        let _ = fallback.delete(&key).await;
+    }
+
+    lock().reconciling = false;
+    log::info!("Reconciled {} keys with the primary storage", keys);
+}
+
+#[async_trait]
+impl StorageBackend for FailoverStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        if !self.is_degraded() {
+            match self.primary.get(key).await {
+                Err(e) if Self::is_outage(&e) => self.mark_degraded(&e),
+                result => return result,
+            }
+        }
+
+        // While degraded the fallback only holds increments made during the
+        // outage, so the result undercounts; that is preferable to failing.
+        self.fallback.get(key).await
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.add(key, 1, expire).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        self.add(key, amount, expire).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.state().pending.remove(key);
+        self.fallback.delete(key).await?;
+
+        if !self.is_degraded() {
+            match self.primary.delete(key).await {
+                Err(e) if Self::is_outage(&e) => self.mark_degraded(&e),
+                result => return result,
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let removed = self.fallback.cleanup_expired().await?;
+
+        if !self.is_degraded() {
+            match self.primary.cleanup_expired().await {
+                Err(e) if Self::is_outage(&e) => self.mark_degraded(&e),
+                result => return result.map(|primary| primary + removed),
+            }
+        }
+
+        Ok(removed)
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        if !self.is_degraded() {
+            match self.primary.ttl(key).await {
+                Err(e) if Self::is_outage(&e) => self.mark_degraded(&e),
+                result => return result,
+            }
+        }
+
+        self.fallback.ttl(key).await
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.fallback.warm_up().await?;
+
+        // An unreachable primary is what the fallback is for, so start
+        // degraded instead of failing
+        match self.primary.warm_up().await {
+            Err(e) if Self::is_outage(&e) => {
+                self.mark_degraded(&e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        if !self.is_degraded() {
+            match self.primary.get_many(keys).await {
+                Err(e) if Self::is_outage(&e) => self.mark_degraded(&e),
+                result => return result,
+            }
+        }
+
+        self.fallback.get_many(keys).await
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        if !self.is_degraded() {
+            match self.primary.export_all().await {
+                Err(e) if Self::is_outage(&e) => self.mark_degraded(&e),
+                result => return result,
+            }
+        }
+
+        self.fallback.export_all().await
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        // Restores are rare and run by an operator, who is better served
+        // by an error than by counts parked in the fallback
+        self.primary.import_all(counters).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::storage::{HealthThresholds, MemoryStorage};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// MemoryStorage that can be switched off to simulate an outage
+    struct FlakyStorage {
+        inner: MemoryStorage,
+        down: Arc<AtomicBool>,
+    }
+
+    impl FlakyStorage {
+        fn check(&self) -> Result<(), StorageError> {
+            if self.down.load(Ordering::SeqCst) {
+                Err(StorageError::ConnectionError("connection refused".to_string()))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[async_trait]
+    impl StorageBackend for FlakyStorage {
+        async fn get(&self, key: &str) -> Result<u64, StorageError> {
+            self.check()?;
+            self.inner.get(key).await
+        }
+
+        async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+            self.check()?;
+            self.inner.increment(key, expire).await
+        }
+
+        async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+            self.check()?;
+            self.inner.increment_by(key, amount, expire).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), StorageError> {
+            self.check()?;
+            self.inner.delete(key).await
+        }
+
+        async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+            self.check()?;
+            self.inner.cleanup_expired().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover_and_reconcile() {
+        let down = Arc::new(AtomicBool::new(false));
+        let primary = FlakyStorage {
+            inner: MemoryStorage::new(),
+            down: Arc::clone(&down),
+        };
+        let storage = FailoverStorage::new(
+            Box::new(primary),
+            Box::new(MemoryStorage::new()),
+            Duration::ZERO,
+        );
+
+        storage.increment("key", 60).await.unwrap();
+        assert_eq!(storage.get("key").await.unwrap(), 1);
+
+        // Primary goes down: requests keep succeeding on the fallback
+        down.store(true, Ordering::SeqCst);
+        storage.increment("key", 60).await.unwrap();
+        storage.increment("key", 60).await.unwrap();
+        assert!(storage.is_degraded());
+        assert_eq!(storage.get("key").await.unwrap(), 2);
+
+        // Primary recovers: the outage increments are replayed into it
+        down.store(false, Ordering::SeqCst);
+        storage.increment("key", 60).await.unwrap();
+        assert!(!storage.is_degraded());
+        reconciled(&storage).await;
+        assert_eq!(storage.get("key").await.unwrap(), 4);
+    }
+
+    async fn reconciled(storage: &FailoverStorage) {
+        while storage.state().reconciling {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_reconcile_is_retried_once() {
+        let down = Arc::new(AtomicBool::new(false));
+        let primary = FlakyStorage {
+            inner: MemoryStorage::new(),
+            down: Arc::clone(&down),
+        };
+        let storage = FailoverStorage::new(
+            Box::new(primary),
+            Box::new(MemoryStorage::new()),
+            Duration::ZERO,
+        );
+
+        down.store(true, Ordering::SeqCst);
+        storage.increment("a", 60).await.unwrap();
+        storage.increment("a", 60).await.unwrap();
+        storage.increment_by("b", 5, 60).await.unwrap();
+
+        // The primary is back for one request and gone again by the replay
+        down.store(false, Ordering::SeqCst);
+        storage.increment("c", 60).await.unwrap();
+        down.store(true, Ordering::SeqCst);
+        reconciled(&storage).await;
+        assert_eq!(storage.state().pending.len(), 2);
+
+        down.store(false, Ordering::SeqCst);
+        storage.increment("c", 60).await.unwrap();
+        reconciled(&storage).await;
+        assert!(storage.state().pending.is_empty());
+        assert_eq!(storage.get("a").await.unwrap(), 2);
+        assert_eq!(storage.get("b").await.unwrap(), 5);
+        assert_eq!(storage.get("c").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failover_follows_health() {
+        let clock = Arc::new(ManualClock::default());
+        let health = Arc::new(BackendHealth::new("primary", HealthThresholds::default()).with_clock(clock.clone()));
+        let storage = FailoverStorage::new(
+            Box::new(MemoryStorage::new()),
+            Box::new(MemoryStorage::new()),
+            Duration::ZERO,
+        )
+        .with_health(Arc::clone(&health));
+
+        // A single failure is not enough to switch
+        storage.mark_degraded(&StorageError::ConnectionError("connection refused".to_string()));
+        assert!(!storage.is_degraded());
+
+        // Failures seen elsewhere take the primary down, and the fallback over
+        for _ in 0..30 {
+            health.record(false, Duration::from_millis(1));
+        }
+        clock.advance(Duration::from_secs(1));
+        health.record(false, Duration::from_millis(1));
+        assert!(storage.is_degraded());
+        storage.increment("key", 60).await.unwrap();
+        assert!(storage.is_degraded());
+
+        // Once the failures leave the window, the next success switches back
+        clock.advance(Duration::from_secs(61));
+        health.record(true, Duration::from_millis(1));
+        storage.increment("key", 60).await.unwrap();
+        assert!(!storage.is_degraded());
+        reconciled(&storage).await;
+        assert_eq!(storage.get("key").await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,326 @@
+use async_trait::async_trait;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use crate::clock::{self, Clock};
+use crate::storage::{CounterSnapshot, Increment, StorageBackend, StorageError};
+
+/// Counters per datagram, which keeps datagrams near one MTU for typical
+/// keys.
+const COUNTERS_PER_DATAGRAM: usize = 8;
+
+/// Largest datagram read.
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// Peers and schedule for `GossipStorage`.
+#[derive(Debug, Clone)]
+pub struct GossipOptions {
+    /// Address this node receives gossip on
+    pub bind: SocketAddr,
+    /// Every other node of the cluster
+    pub peers: Vec<SocketAddr>,
+    /// How often changed counters are sent to the peers
+    pub interval: Duration,
+    /// Unique per node; random by default
+    pub node_id: u64,
+}
+
+impl GossipOptions {
+    pub fn new(bind: SocketAddr, peers: Vec<SocketAddr>) -> Self {
+        Self {
+            bind,
+            peers,
+            interval: Duration::from_millis(100),
+            node_id: RandomState::new().hash_one(bind),
+        }
+    }
+}
+
+/// One node's count for a key in one window, as gossiped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GossipCounter {
+    pub key: String,
+    /// Unix time the window started
+    pub epoch: u64,
+    pub window: u32,
+    pub count: u64,
+}
+
+/// A datagram: the sender's own counts for the keys it changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GossipMessage {
+    pub node: u64,
+    pub counters: Vec<GossipCounter>,
+}
+
+/// A key's G-counter for the current window: one count per node, merged
+/// by taking the maximum, so duplicated or reordered datagrams are
+/// harmless.
+#[derive(Debug)]
+struct Counter {
+    epoch: u64,
+    window: u32,
+    nodes: HashMap<u64, u64>,
+    /// Whether this node's count changed since it was last gossiped
+    dirty: bool,
+}
+
+impl Counter {
+    fn new(epoch: u64, window: u32) -> Self {
+        Self {
+            epoch,
+            window,
+            nodes: HashMap::new(),
+            dirty: false,
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.nodes.values().fold(0u64, |total, count| total.saturating_add(*count))
+    }
+}
+
+/// Shares counters between a small cluster of nginx instances by gossip
+/// over UDP, with no central store.
+///
+/// Windows are fixed and aligned to the Unix epoch, so every node agrees
+/// which window a hit belongs to. Each node counts its own hits and, every
+/// `interval`, sends its counts for the keys that changed to every peer.
+/// A key's count is the sum of the latest counts of all nodes. Limits are
+/// therefore global but approximate: each node may admit up to one
+/// interval's worth of hits that the others have not heard of yet. A lost
+/// datagram is made up for by the next change to the same key.
+///
+/// Gossip is unauthenticated; bind it to a private network.
+pub struct GossipStorage {
+    counters: DashMap<String, Counter>,
+    options: GossipOptions,
+    socket: UdpSocket,
+    clock: Arc<dyn Clock>,
+}
+
+impl GossipStorage {
+    pub async fn bind(options: GossipOptions) -> Result<Self, StorageError> {
+        let socket = UdpSocket::bind(options.bind)
+            .await
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+        Ok(Self {
+            counters: DashMap::new(),
+            options,
+            socket,
+            clock: clock::system(),
+        })
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The address gossip is received on, with the port the OS chose for
+    /// port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, StorageError> {
+        self.socket.local_addr().map_err(|e| StorageError::ConnectionError(e.to_string()))
+    }
+
+    fn epoch(now: u64, window: u32) -> u64 {
+        let window = u64::from(window.max(1));
+        now / window * window
+    }
+
+    /// Merge a peer's counts.
+    pub fn merge(&self, message: &GossipMessage) {
+        if message.node == self.options.node_id {
+            return;
+        }
+        let now = self.clock.unix_secs();
+        for counter in &message.counters {
+            if counter.epoch + u64::from(counter.window) <= now {
+                continue;
+            }
+            let mut entry = self
+                .counters
+                .entry(counter.key.clone())
+                .or_insert_with(|| Counter::new(counter.epoch, counter.window));
+            if counter.epoch > entry.epoch {
+                *entry = Counter::new(counter.epoch, counter.window);
+            }
+            if counter.epoch == entry.epoch {
+                let count = entry.nodes.entry(message.node).or_insert(0);
+                *count = (*count).max(counter.count);
+            }
+        }
+    }
+
+    /// This node's counts for the keys changed since the last call, split
+    /// into datagram-sized messages.
+    pub fn take_changes(&self) -> Vec<GossipMessage> {
+        let node = self.options.node_id;
+        let mut counters = Vec::new();
+        for mut entry in self.counters.iter_mut() {
+            if entry.dirty {
+                entry.dirty = false;
+                counters.push(GossipCounter {
+                    key: entry.key().clone(),
+                    epoch: entry.epoch,
+                    window: entry.window,
+                    count: entry.nodes.get(&node).copied().unwrap_or(0),
+                });
+            }
+        }
+        counters
+            .chunks(COUNTERS_PER_DATAGRAM)
+            .map(|chunk| GossipMessage { node, counters: chunk.to_vec() })
+            .collect()
+    }
+
+    async fn send_changes(&self) {
+        for message in self.take_changes() {
+            let Ok(datagram) = serde_json::to_vec(&message) else {
+                continue;
+            };
+            for peer in &self.options.peers {
+                if let Err(e) = self.socket.send_to(&datagram, peer).await {
+                    metrics::counter!("rate_limiter_gossip_send_errors_total").increment(1);
+                    log::debug!("Gossip to {} failed: {}", peer, e);
+                }
+            }
+        }
+    }
+
+    /// Receive gossip and send changes every `interval` until the returned
+    /// handle is aborted.
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let storage = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(storage.options.interval);
+            let mut buffer = vec![0u8; MAX_DATAGRAM];
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => storage.send_changes().await,
+                    received = storage.socket.recv_from(&mut buffer) => match received {
+                        Ok((len, peer)) => match serde_json::from_slice::<GossipMessage>(&buffer[..len]) {
+                            Ok(message) => storage.merge(&message),
+                            Err(e) => log::debug!("Ignoring gossip from {}: {}", peer, e),
+                        },
+                        Err(e) => log::debug!("Receiving gossip failed: {}", e),
+                    },
+                }
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for GossipStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let now = self.clock.unix_secs();
+        Ok(match self.counters.get(key) {
+            Some(counter) if counter.epoch == Self::epoch(now, counter.window) => counter.total(),
+            _ => 0,
+        })
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.increment_by(key, 1, expire).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        if amount == 0 {
+            return self.get(key).await.map(|count| Increment { count });
+        }
+        let epoch = Self::epoch(self.clock.unix_secs(), expire);
+        let mut counter = self
+            .counters
+            .entry(key.to_string())
+            .or_insert_with(|| Counter::new(epoch, expire));
+        if counter.epoch != epoch {
+            *counter = Counter::new(epoch, expire);
+        }
+        let own = counter.nodes.entry(self.options.node_id).or_insert(0);
+        *own = own.saturating_add(amount);
+        counter.dirty = true;
+        Ok(Increment { count: counter.total() })
+    }
+
+    /// Forgets the key on this node only; peers keep their counts.
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.counters.remove(key);
+        Ok(())
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let now = self.clock.unix_secs();
+        let before = self.counters.len();
+        self.counters
+            .retain(|_, counter| counter.epoch + u64::from(counter.window) > now);
+        Ok((before - self.counters.len()) as u64)
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let now = self.clock.now();
+        Ok(self.counters.get(key).and_then(|counter| {
+            let expire_at = Duration::from_secs(counter.epoch + u64::from(counter.window));
+            expire_at.checked_sub(now).filter(|ttl| !ttl.is_zero())
+        }))
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        let now = self.clock.unix_secs();
+        Ok(self
+            .counters
+            .iter()
+            .filter(|counter| counter.epoch + u64::from(counter.window) > now)
+            .map(|counter| CounterSnapshot {
+                key: counter.key().clone(),
+                count: counter.total(),
+                expire_at: counter.epoch + u64::from(counter.window),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    async fn node(id: u64, clock: &Arc<ManualClock>) -> GossipStorage {
+        let mut options = GossipOptions::new("127.0.0.1:0".parse().unwrap(), Vec::new());
+        options.node_id = id;
+        GossipStorage::bind(options).await.unwrap().with_clock(clock.clone())
+    }
+
+    #[tokio::test]
+    async fn test_counts_converge_and_reset_per_window() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1_700_000_040)));
+        let (a, b) = (node(1, &clock).await, node(2, &clock).await);
+
+        a.increment("client", 60).await.unwrap();
+        a.increment("client", 60).await.unwrap();
+        b.increment("client", 60).await.unwrap();
+        for message in a.take_changes() {
+            b.merge(&message);
+            // Duplicates are harmless
+            b.merge(&message);
+        }
+        for message in b.take_changes() {
+            a.merge(&message);
+        }
+        assert_eq!(a.get("client").await.unwrap(), 3);
+        assert_eq!(b.get("client").await.unwrap(), 3);
+        assert!(a.take_changes().is_empty());
+
+        // The window is aligned to the epoch and ends at 1_700_000_100
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(a.get("client").await.unwrap(), 0);
+        assert_eq!(b.increment("client", 60).await.unwrap().count, 1);
+    }
+}
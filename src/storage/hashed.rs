@@ -0,0 +1,240 @@
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::time::Duration;
+use crate::storage::{key_tag, CounterSnapshot, Increment, KeyBuf, StorageBackend, StorageError};
+
+/// Bytes of the HMAC kept in the stored key; 128 bits leave no realistic
+/// chance of two clients sharing a counter.
+const DIGEST_LEN: usize = 16;
+
+/// Bytes of the HMAC of a hash tag kept in the stored key; it only has to
+/// spread tags over cluster slots.
+const TAG_DIGEST_LEN: usize = 8;
+
+/// A secret used to derive stored keys, with a short id that is stored in
+/// front of every key it produced.
+#[derive(Clone)]
+pub struct KeySecret {
+    pub id: String,
+    pub secret: Vec<u8>,
+}
+
+impl KeySecret {
+    pub fn new(id: &str, secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            id: id.to_string(),
+            secret: secret.into(),
+        }
+    }
+}
+
+impl std::fmt::Debug for KeySecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeySecret").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+/// Replaces every key with `{secret id}:{HMAC-SHA256(secret, key)}` before
+/// it reaches the wrapped backend, so client IPs and other identifiers are
+/// never stored in the clear. A key with a `{hash tag}` gets the HMAC of
+/// its tag as a tag of its own, so keys that shared a Redis Cluster slot
+/// still do.
+///
+/// To rotate, make the new secret current and keep the old one in
+/// `previous` for at least the longest window. Reads and returned counts
+/// include the counters kept under previous secrets, so limits carry over;
+/// new hits are only written under the current secret.
+pub struct HashedKeyStorage {
+    inner: Box<dyn StorageBackend>,
+    current: KeySecret,
+    previous: Vec<KeySecret>,
+}
+
+impl HashedKeyStorage {
+    pub fn new(inner: Box<dyn StorageBackend>, current: KeySecret) -> Self {
+        Self {
+            inner,
+            current,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Secrets retired by a rotation whose counters should still be counted.
+    pub fn with_previous(mut self, previous: Vec<KeySecret>) -> Self {
+        self.previous = previous;
+        self
+    }
+
+    fn hashed(secret: &KeySecret, key: &str) -> KeyBuf {
+        let digest = |data: &str, len: usize, hashed: &mut KeyBuf| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(&secret.secret)
+                .expect("HMAC accepts keys of any length");
+            mac.update(data.as_bytes());
+            for byte in &mac.finalize().into_bytes()[..len] {
+                let _ = write!(hashed, "{:02x}", byte);
+            }
+        };
+
+        let mut hashed = KeyBuf::from(secret.id.as_str());
+        hashed.push_str(":");
+        if let Some(tag) = key_tag(key) {
+            hashed.push_str("{");
+            digest(tag, TAG_DIGEST_LEN, &mut hashed);
+            hashed.push_str("}:");
+        }
+        digest(key, DIGEST_LEN, &mut hashed);
+        hashed
+    }
+
+    fn previous_keys(&self, key: &str) -> Vec<KeyBuf> {
+        self.previous.iter().map(|secret| Self::hashed(secret, key)).collect()
+    }
+
+    /// Sum of the counts kept under previous secrets.
+    async fn previous_count(&self, key: &str) -> Result<u64, StorageError> {
+        if self.previous.is_empty() {
+            return Ok(0);
+        }
+        let keys = self.previous_keys(key);
+        let keys: Vec<&str> = keys.iter().map(KeyBuf::as_str).collect();
+        let counts = self.inner.get_many(&keys).await?;
+        Ok(counts.into_iter().fold(0, u64::saturating_add))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for HashedKeyStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let current = self.inner.get(&Self::hashed(&self.current, key)).await?;
+        Ok(current.saturating_add(self.previous_count(key).await?))
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        let hashed = Self::hashed(&self.current, key);
+        let mut increment = self.inner.increment(&hashed, expire).await?;
+        increment.count = increment.count.saturating_add(self.previous_count(key).await?);
+        Ok(increment)
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        let hashed = Self::hashed(&self.current, key);
+        let mut increment = self.inner.increment_by(&hashed, amount, expire).await?;
+        increment.count = increment.count.saturating_add(self.previous_count(key).await?);
+        Ok(increment)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.inner.delete(&Self::hashed(&self.current, key)).await?;
+        for hashed in self.previous_keys(key) {
+            self.inner.delete(&hashed).await?;
+        }
+        Ok(())
+    }
+
+    /// The latest expiry among the counters under every secret, since the
+    /// count only resets once all of them have.
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let mut ttl = self.inner.ttl(&Self::hashed(&self.current, key)).await?;
+        for hashed in self.previous_keys(key) {
+            ttl = ttl.max(self.inner.ttl(&hashed).await?);
+        }
+        Ok(ttl)
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        self.inner.cleanup_expired().await
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.inner.warm_up().await
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        // Every variant of every key in one batch: current first, then one
+        // block per previous secret
+        let hashed: Vec<KeyBuf> = std::iter::once(&self.current)
+            .chain(&self.previous)
+            .flat_map(|secret| keys.iter().map(move |key| Self::hashed(secret, key)))
+            .collect();
+        let hashed: Vec<&str> = hashed.iter().map(KeyBuf::as_str).collect();
+        let counts = self.inner.get_many(&hashed).await?;
+
+        let mut totals = vec![0u64; keys.len()];
+        for block in counts.chunks(keys.len().max(1)) {
+            for (total, count) in totals.iter_mut().zip(block) {
+                *total = total.saturating_add(*count);
+            }
+        }
+        Ok(totals)
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        let hashed: Vec<(KeyBuf, u32)> = keys
+            .iter()
+            .map(|(key, expire)| (Self::hashed(&self.current, key), *expire))
+            .collect();
+        let hashed: Vec<(&str, u32)> = hashed.iter().map(|(key, expire)| (key.as_str(), *expire)).collect();
+        let mut increments = self.inner.increment_many(&hashed).await?;
+
+        if !self.previous.is_empty() {
+            for (increment, (key, _)) in increments.iter_mut().zip(keys) {
+                increment.count = increment.count.saturating_add(self.previous_count(key).await?);
+            }
+        }
+        Ok(increments)
+    }
+
+    /// Counters under their stored (hashed) keys, since the raw keys cannot
+    /// be recovered. Import them through a `HashedKeyStorage` with the same
+    /// secrets.
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        self.inner.export_all().await
+    }
+
+    /// Expects hashed keys, as produced by `export_all`.
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        self.inner.import_all(counters).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_keys_are_hashed_and_rotated() {
+        let old = KeySecret::new("k1", "old secret");
+        let storage = HashedKeyStorage::new(Box::new(MemoryStorage::new()), old.clone());
+
+        storage.increment("192.0.2.1", 60).await.unwrap();
+        let hashed = HashedKeyStorage::hashed(&old, "192.0.2.1");
+        assert!(hashed.starts_with("k1:") && !hashed.contains("192.0.2.1"));
+        assert_eq!(hashed.len(), 3 + DIGEST_LEN * 2);
+        assert_eq!(storage.inner.get(&hashed).await.unwrap(), 1);
+
+        // Rotate: counts under the old secret still apply
+        let storage = HashedKeyStorage::new(storage.inner, KeySecret::new("k2", "new secret"))
+            .with_previous(vec![old]);
+        assert_eq!(storage.increment("192.0.2.1", 60).await.unwrap().count, 2);
+        assert_eq!(storage.get("192.0.2.1").await.unwrap(), 2);
+        assert_eq!(storage.get_many(&["192.0.2.1", "192.0.2.2"]).await.unwrap(), vec![2, 0]);
+
+        storage.delete("192.0.2.1").await.unwrap();
+        assert_eq!(storage.get("192.0.2.1").await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_hash_tags_are_kept() {
+        let secret = KeySecret::new("k1", "secret");
+        let quota = HashedKeyStorage::hashed(&secret, "plan:{k_live}:quota");
+        let rate = HashedKeyStorage::hashed(&secret, "plan:{k_live}:rate");
+        assert!(!quota.contains("k_live"));
+        assert_eq!(key_tag(&quota).map(str::len), Some(TAG_DIGEST_LEN * 2));
+        assert_eq!(key_tag(&quota), key_tag(&rate));
+        assert_ne!(quota.as_str(), rate.as_str());
+        assert_ne!(key_tag(&quota), key_tag(&HashedKeyStorage::hashed(&secret, "plan:{k_test}:rate")));
+    }
+}
@@ -0,0 +1,270 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+use crate::clock::{self, Clock};
+
+/// Latency buckets: up to 1ms, 2ms, 4ms... 32s, and slower.
+const LATENCY_BUCKETS: usize = 17;
+
+/// How a backend has been doing over the recent window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Healthy,
+    /// Answering, but failing more than a few calls or slowly
+    Degraded,
+    /// Failing most calls
+    Down,
+}
+
+impl Health {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Health::Healthy => "healthy",
+            Health::Degraded => "degraded",
+            Health::Down => "down",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Health::Degraded,
+            2 => Health::Down,
+            _ => Health::Healthy,
+        }
+    }
+}
+
+/// When a backend counts as degraded or down.
+#[derive(Debug, Clone)]
+pub struct HealthThresholds {
+    /// Rolling window the success rate and latency are taken over, in
+    /// whole seconds
+    pub window: Duration,
+    /// Calls needed in the window before a backend is judged at all
+    pub min_calls: u64,
+    /// Below this share of successful calls a backend is degraded
+    pub degraded_success_rate: f64,
+    /// Below this share it is down
+    pub down_success_rate: f64,
+    /// Above this p99 latency it is degraded
+    pub degraded_p99: Duration,
+}
+
+impl Default for HealthThresholds {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            min_calls: 20,
+            degraded_success_rate: 0.99,
+            down_success_rate: 0.5,
+            degraded_p99: Duration::from_millis(250),
+        }
+    }
+}
+
+/// A backend's health and the figures it was judged on.
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub health: Health,
+    /// Calls in the window
+    pub calls: u64,
+    /// Share of them that succeeded; 1 without calls
+    pub success_rate: f64,
+    /// 99th percentile latency in milliseconds, as the upper bound of its
+    /// bucket
+    pub p99_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    second: u64,
+    calls: u64,
+    failures: u64,
+    latencies: [u64; LATENCY_BUCKETS],
+}
+
+/// Rolling success rate and p99 latency of one backend, judged healthy,
+/// degraded or down against `HealthThresholds`.
+///
+/// Calls are tallied in one bucket per second. The verdict is worked out
+/// again once a second, when a call lands in a new bucket, and reported in
+/// `rate_limiter_storage_health{backend}` (0 healthy, 1 degraded, 2 down),
+/// `rate_limiter_storage_success_ratio` and
+/// `rate_limiter_storage_p99_seconds`.
+#[derive(Debug)]
+pub struct BackendHealth {
+    name: String,
+    thresholds: HealthThresholds,
+    buckets: Mutex<Vec<Bucket>>,
+    health: AtomicU8,
+    clock: Arc<dyn Clock>,
+}
+
+impl BackendHealth {
+    pub fn new(name: &str, thresholds: HealthThresholds) -> Self {
+        let seconds = thresholds.window.as_secs().clamp(1, 3600) as usize;
+        Self {
+            name: name.to_string(),
+            thresholds,
+            buckets: Mutex::new(vec![Bucket::default(); seconds]),
+            health: AtomicU8::new(0),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The verdict as of the last second with a call.
+    pub fn health(&self) -> Health {
+        Health::from_u8(self.health.load(Ordering::Relaxed))
+    }
+
+    /// Tally one call that took `latency`.
+    pub fn record(&self, success: bool, latency: Duration) {
+        let second = self.clock.unix_secs();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let len = buckets.len();
+        let bucket = &mut buckets[second as usize % len];
+        let rolled = bucket.second != second;
+        if rolled {
+            *bucket = Bucket { second, ..Bucket::default() };
+        }
+        bucket.calls += 1;
+        bucket.failures += u64::from(!success);
+        let millis = latency.as_millis().max(1) as u64;
+        let index = (64 - (millis - 1).leading_zeros() as usize).min(LATENCY_BUCKETS - 1);
+        bucket.latencies[index] += 1;
+
+        if rolled {
+            let report = self.judge(&buckets, second);
+            drop(buckets);
+            self.publish(&report);
+        }
+    }
+
+    /// The figures over the window ending now.
+    pub fn report(&self) -> HealthReport {
+        let buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        self.judge(&buckets, self.clock.unix_secs())
+    }
+
+    fn judge(&self, buckets: &[Bucket], now: u64) -> HealthReport {
+        let live = buckets.iter().filter(|bucket| now.saturating_sub(bucket.second) < buckets.len() as u64);
+        let (mut calls, mut failures, mut latencies) = (0, 0, [0u64; LATENCY_BUCKETS]);
+        for bucket in live {
+            calls += bucket.calls;
+            failures += bucket.failures;
+            for (total, count) in latencies.iter_mut().zip(&bucket.latencies) {
+                *total += count;
+            }
+        }
+
+        let success_rate = if calls == 0 { 1.0 } else { (calls - failures) as f64 / calls as f64 };
+        let target = (calls as f64 * 0.99).ceil() as u64;
+        let mut seen = 0;
+        let p99_bucket = latencies.iter().position(|count| {
+            seen += count;
+            seen >= target
+        });
+        let p99_ms = p99_bucket.filter(|_| calls > 0).map_or(0, |index| 1u64 << index);
+
+        let thresholds = &self.thresholds;
+        let health = if calls < thresholds.min_calls {
+            Health::Healthy
+        } else if success_rate < thresholds.down_success_rate {
+            Health::Down
+        } else if success_rate < thresholds.degraded_success_rate
+            || Duration::from_millis(p99_ms) > thresholds.degraded_p99
+        {
+            Health::Degraded
+        } else {
+            Health::Healthy
+        };
+        HealthReport { health, calls, success_rate, p99_ms }
+    }
+
+    fn publish(&self, report: &HealthReport) {
+        let previous = Health::from_u8(self.health.swap(report.health as u8, Ordering::Relaxed));
+        if previous != report.health {
+            log::warn!(
+                "Storage backend {} is {} ({} calls, {:.1}% succeeded, p99 {}ms)",
+                self.name,
+                report.health.as_str(),
+                report.calls,
+                report.success_rate * 100.0,
+                report.p99_ms
+            );
+        }
+        let backend = self.name.clone();
+        metrics::gauge!("rate_limiter_storage_health", "backend" => backend.clone()).set(f64::from(report.health as u8));
+        metrics::gauge!("rate_limiter_storage_success_ratio", "backend" => backend.clone()).set(report.success_rate);
+        metrics::gauge!("rate_limiter_storage_p99_seconds", "backend" => backend).set(report.p99_ms as f64 / 1000.0);
+    }
+}
+
+static REGISTRY: OnceLock<Mutex<HashMap<String, Arc<BackendHealth>>>> = OnceLock::new();
+
+fn registry() -> &'static Mutex<HashMap<String, Arc<BackendHealth>>> {
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Make `health` part of `backend_health`, replacing any tracker
+/// registered under the same backend name.
+pub fn register_health(health: Arc<BackendHealth>) {
+    let mut registry = registry().lock().unwrap_or_else(PoisonError::into_inner);
+    registry.insert(health.name().to_string(), health);
+}
+
+/// The report of every registered backend, by name.
+pub fn backend_health() -> Vec<(String, HealthReport)> {
+    let registry = registry().lock().unwrap_or_else(PoisonError::into_inner);
+    let mut reports: Vec<(String, HealthReport)> =
+        registry.iter().map(|(name, health)| (name.clone(), health.report())).collect();
+    reports.sort_by(|a, b| a.0.cmp(&b.0));
+    reports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_health_follows_success_rate_and_latency() {
+        let clock = Arc::new(ManualClock::default());
+        let health = BackendHealth::new("redis", HealthThresholds::default()).with_clock(clock.clone());
+
+        for _ in 0..100 {
+            health.record(true, Duration::from_millis(3));
+        }
+        let report = health.report();
+        assert_eq!((report.health, report.calls, report.p99_ms), (Health::Healthy, 100, 4));
+
+        // Slow calls push the p99 over the threshold
+        for _ in 0..5 {
+            health.record(true, Duration::from_millis(600));
+        }
+        assert_eq!(health.report().health, Health::Degraded);
+
+        // Once those calls leave the window, failures take it down
+        clock.advance(Duration::from_secs(61));
+        for _ in 0..30 {
+            health.record(false, Duration::from_millis(1));
+        }
+        let report = health.report();
+        assert_eq!((report.health, report.calls, report.success_rate), (Health::Down, 30, 0.0));
+        clock.advance(Duration::from_secs(1));
+        health.record(false, Duration::from_millis(1));
+        assert_eq!(health.health(), Health::Down);
+    }
+}
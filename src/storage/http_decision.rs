@@ -0,0 +1,177 @@
+use async_trait::async_trait;
+use std::borrow::Cow;
+use std::time::Duration;
+use crate::storage::{DecisionRequest, DecisionResponse, FailureMode, Increment, StorageBackend, StorageError};
+
+/// Limit, window and transport settings for `HttpDecisionStorage`.
+#[derive(Debug, Clone)]
+pub struct HttpDecisionOptions {
+    /// Requests allowed per window, sent with every call
+    pub limit: u32,
+    /// Window in seconds used for reads; increments send their own window
+    pub window: u32,
+    /// Deadline for each call, including connecting
+    pub timeout: Duration,
+    pub failure_mode: FailureMode,
+    /// Extra headers such as an `Authorization` token
+    pub headers: Vec<(String, String)>,
+}
+
+impl Default for HttpDecisionOptions {
+    fn default() -> Self {
+        Self {
+            limit: 100,
+            window: 60,
+            timeout: Duration::from_millis(100),
+            failure_mode: FailureMode::Allow,
+            headers: Vec::new(),
+        }
+    }
+}
+
+/// Delegates decisions to an in-house quota service over HTTP.
+///
+/// Every call POSTs `{"key", "limit", "window", "hits"}` as JSON to the
+/// configured URL and expects `{"allowed", "remaining", "reset"}` back.
+/// `increment` sends one hit and `get` sends zero; a denied key reads as
+/// `u64::MAX`. `delete` and `cleanup_expired` are no-ops.
+pub struct HttpDecisionStorage {
+    client: reqwest::Client,
+    url: String,
+    options: HttpDecisionOptions,
+}
+
+impl HttpDecisionStorage {
+    pub fn new(url: &str) -> Result<Self, StorageError> {
+        Self::with_options(url, HttpDecisionOptions::default())
+    }
+
+    pub fn with_options(url: &str, options: HttpDecisionOptions) -> Result<Self, StorageError> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in &options.headers {
+            let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+            let value = reqwest::header::HeaderValue::from_str(value)
+                .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+            headers.insert(name, value);
+        }
+
+        // The client keeps idle connections alive and reuses them
+        let client = reqwest::Client::builder()
+            .timeout(options.timeout)
+            .connect_timeout(options.timeout)
+            .default_headers(headers)
+            .build()
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        Ok(Self {
+            client,
+            url: url.to_string(),
+            options,
+        })
+    }
+
+    async fn decide(&self, key: &str, window: u32, hits: u32) -> Result<DecisionResponse, StorageError> {
+        let request = DecisionRequest {
+            key: Cow::Borrowed(key),
+            limit: self.options.limit,
+            window,
+            hits,
+        };
+
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(StorageError::ConnectionError(format!("decision service returned {}", status)));
+        }
+        if !status.is_success() {
+            return Err(StorageError::DatabaseError(format!("decision service returned {}", status)));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| StorageError::InvalidValueType(e.to_string()))
+    }
+
+    fn count_from_response(&self, response: &DecisionResponse) -> u64 {
+        if !response.allowed {
+            return u64::MAX;
+        }
+        match response.remaining {
+            Some(remaining) => u64::from(self.options.limit.saturating_sub(remaining)),
+            None => 0,
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for HttpDecisionStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        match self.decide(key, self.options.window, 0).await {
+            Ok(response) => Ok(self.count_from_response(&response)),
+            Err(e) => {
+                log::warn!("Decision service call failed: {}", e);
+                match self.options.failure_mode {
+                    FailureMode::Allow => Ok(0),
+                    FailureMode::Deny => Ok(u64::MAX),
+                }
+            }
+        }
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        match self.decide(key, expire, 1).await {
+            Ok(response) => Ok(Increment { count: self.count_from_response(&response) }),
+            Err(e) if self.options.failure_mode == FailureMode::Allow => {
+                log::warn!("Decision service call failed: {}", e);
+                Ok(Increment { count: 0 })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, _key: &str) -> Result<(), StorageError> {
+        // The decision API has no reset call
+        Ok(())
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        // Windows are managed by the decision service
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decision_json() {
+        let request = DecisionRequest {
+            key: Cow::Borrowed("10.0.0.1"),
+            limit: 100,
+            window: 60,
+            hits: 1,
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"key":"10.0.0.1","limit":100,"window":60,"hits":1}"#
+        );
+
+        let storage = HttpDecisionStorage::new("http://quota.internal/decide").unwrap();
+        let response: DecisionResponse =
+            serde_json::from_str(r#"{"allowed":true,"remaining":60,"reset":1700000000}"#).unwrap();
+        assert_eq!(storage.count_from_response(&response), 40);
+
+        let response: DecisionResponse = serde_json::from_str(r#"{"allowed":false}"#).unwrap();
+        assert_eq!(storage.count_from_response(&response), u64::MAX);
+    }
+}
@@ -0,0 +1,115 @@
+use smallvec::SmallVec;
+use std::fmt;
+use std::ops::Deref;
+
+/// Bytes a key can hold before it spills to the heap; enough for an IPv6
+/// address behind a zone prefix and a hashed-key id.
+pub const INLINE_KEY_LEN: usize = 96;
+
+/// The non-empty `{hash tag}` of `key`, if it has one: the part Redis
+/// Cluster hashes to pick the key's slot, so keys sharing a tag share a
+/// slot.
+pub fn key_tag(key: &str) -> Option<&str> {
+    let open = key.find('{')?;
+    let len = key[open + 1..].find('}')?;
+    (len > 0).then(|| &key[open + 1..open + 1 + len])
+}
+
+/// A key assembled on the stack.
+///
+/// Building `rl:{zone}:{addr}` with `format!` costs a heap allocation per
+/// request; `KeyBuf` keeps keys up to `INLINE_KEY_LEN` bytes inline and
+/// derefs to `&str`, so it can be passed straight to `StorageBackend`.
+#[derive(Clone, Default)]
+pub struct KeyBuf {
+    bytes: SmallVec<[u8; INLINE_KEY_LEN]>,
+}
+
+impl KeyBuf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: the buffer is only ever extended with whole `str`s
+        unsafe { std::str::from_utf8_unchecked(&self.bytes) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Whether the key outgrew the inline buffer and was moved to the heap.
+    pub fn spilled(&self) -> bool {
+        self.bytes.spilled()
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+impl From<&str> for KeyBuf {
+    fn from(s: &str) -> Self {
+        let mut key = Self::new();
+        key.push_str(s);
+        key
+    }
+}
+
+impl fmt::Write for KeyBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+impl Deref for KeyBuf {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for KeyBuf {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Debug for KeyBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for KeyBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write;
+    use std::net::{IpAddr, Ipv6Addr};
+
+    #[test]
+    fn test_keys_stay_inline() {
+        let addr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff));
+        let mut key = KeyBuf::from("rl:api-login:");
+        write!(key, "{}", addr).unwrap();
+        assert_eq!(key.as_str(), "rl:api-login:2001:db8:ffff:ffff:ffff:ffff:ffff:ffff");
+        assert!(!key.spilled());
+
+        key.push_str(&"x".repeat(INLINE_KEY_LEN));
+        assert!(key.spilled());
+        assert!(key.ends_with('x'));
+    }
+}
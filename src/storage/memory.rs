@@ -1,108 +1,847 @@
 use async_trait::async_trait;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
-use crate::storage::{StorageBackend, StorageError};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use crate::clock::{self, Clock};
+use crate::storage::{CounterSnapshot, Increment, StorageBackend, StorageError};
 
-#[derive(Debug)]
+/// Bytes charged per counter on top of its key: the `String` header, the
+/// counter itself and roughly one hash table slot.
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<String>() + std::mem::size_of::<RateLimit>() + 16;
+
+/// Hash functions of the doorkeeper bloom filter.
+const DOORKEEPER_HASHES: u64 = 4;
+
+/// Eviction stops once the map is back under this share of its caps, so
+/// the sweep is paid once per burst of new keys rather than per key.
+const EVICT_TO_PERCENT: usize = 90;
+
+/// Which counters `MemoryStorage` drops once it is over its cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eviction {
+    /// Least recently hit first, at one-second resolution
+    #[default]
+    Lru,
+    /// The first counters in the map's randomly seeded hash order; skips
+    /// the ranking pass
+    Random,
+}
+
+/// Size bounds for `MemoryStorage`. The defaults are unbounded, as before.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryOptions {
+    /// Counters kept before evicting
+    pub max_entries: Option<usize>,
+    /// Approximate bytes (keys plus per-counter overhead) kept before evicting
+    pub max_bytes: Option<usize>,
+    pub eviction: Eviction,
+    /// Bits of a bloom filter that absorbs the first hit of every key, so
+    /// clients seen only once never allocate a counter. Split between two
+    /// filters that take turns, so a first hit is forgotten after one to
+    /// two windows. `None` disables it.
+    pub doorkeeper_bits: Option<usize>,
+}
+
+impl MemoryOptions {
+    /// Parse the `rate_limit_storage memory` config string, a comma
+    /// separated list such as `max_entries=100000,max_bytes=64m,eviction=random,doorkeeper=8m`.
+    /// Sizes take an optional `k`, `m` or `g` suffix. An empty string is
+    /// the unbounded default.
+    pub fn parse(config: &str) -> Result<Self, StorageError> {
+        let invalid = |reason: String| {
+            StorageError::ConnectionError(format!("invalid memory storage config '{}': {}", config, reason))
+        };
+
+        let mut options = Self::default();
+        for setting in config.split(',').map(str::trim).filter(|setting| !setting.is_empty()) {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected name=value, got '{}'", setting)))?;
+            let size = || parse_size(value).ok_or_else(|| invalid(format!("invalid size '{}'", value)));
+            match name.trim() {
+                "max_entries" => options.max_entries = Some(size()?),
+                "max_bytes" => options.max_bytes = Some(size()?),
+                "doorkeeper" => options.doorkeeper_bits = Some(size()?),
+                "eviction" => {
+                    options.eviction = match value.trim() {
+                        "lru" => Eviction::Lru,
+                        "random" => Eviction::Random,
+                        other => return Err(invalid(format!("unknown eviction '{}'", other))),
+                    }
+                }
+                other => return Err(invalid(format!("unknown setting '{}'", other))),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn share(max: usize, percent: usize) -> usize {
+    max.saturating_mul(percent) / 100
+}
+
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim().to_ascii_lowercase();
+    let (digits, multiplier) = match value.chars().last()? {
+        'k' => (&value[..value.len() - 1], 1 << 10),
+        'm' => (&value[..value.len() - 1], 1 << 20),
+        'g' => (&value[..value.len() - 1], 1 << 30),
+        _ => (value.as_str(), 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+#[derive(Debug, Default)]
 struct RateLimit {
-    count: u32,
-    expire_at: u64,
+    count: AtomicU64,
+    expire_at: AtomicU64,
+    /// Unix seconds of the last hit, for LRU eviction
+    last_hit: AtomicU64,
 }
 
+/// One half of the doorkeeper.
+struct Filter {
+    bits: Box<[AtomicU64]>,
+    inserted: AtomicUsize,
+}
+
+impl Filter {
+    fn new(bits: usize) -> Self {
+        Self {
+            bits: (0..bits.div_ceil(64).max(1)).map(|_| AtomicU64::new(0)).collect(),
+            inserted: AtomicUsize::new(0),
+        }
+    }
+
+    fn contains(&self, positions: &[usize]) -> bool {
+        positions
+            .iter()
+            .all(|&bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    /// Set the bits; true if they were all set already.
+    fn check_and_set(&self, positions: &[usize]) -> bool {
+        let mut seen = true;
+        for &bit in positions {
+            let mask = 1 << (bit % 64);
+            seen &= self.bits[bit / 64].fetch_or(mask, Ordering::Relaxed) & mask != 0;
+        }
+        if !seen {
+            self.inserted.fetch_add(1, Ordering::Relaxed);
+        }
+        seen
+    }
+
+    /// Holds as many keys as it can at ~2% false positives.
+    fn full(&self) -> bool {
+        self.inserted.load(Ordering::Relaxed) >= self.bits.len() * 64 / 8
+    }
+
+    fn clear(&self) {
+        for word in self.bits.iter() {
+            word.store(0, Ordering::Relaxed);
+        }
+        self.inserted.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Bloom filter in front of the map, as two filters that take turns.
+///
+/// First hits go into the current filter and are looked up in both. Once
+/// a window has passed since the last turn, or the current filter is full,
+/// the older one is cleared and becomes current, so a first hit is
+/// remembered for at least one window and at most two. Keys hit with
+/// different windows age with the shortest, which only ever forgets a
+/// first hit early. Bits cannot be unset, so deleted keys are listed in
+/// `forgotten` until their next hit or until their bits have aged out.
+struct Doorkeeper {
+    filters: [Filter; 2],
+    current: AtomicUsize,
+    /// Unix seconds of the last turn
+    rotated_at: AtomicU64,
+    /// Held by the one caller taking a turn
+    rotating: Mutex<()>,
+    /// Deleted keys, with the unix seconds they were deleted at
+    forgotten: Mutex<HashMap<String, u64>>,
+    hasher: RandomState,
+}
+
+impl Doorkeeper {
+    fn new(bits: usize) -> Self {
+        Self {
+            filters: [Filter::new(bits / 2), Filter::new(bits / 2)],
+            current: AtomicUsize::new(0),
+            rotated_at: AtomicU64::new(0),
+            rotating: Mutex::new(()),
+            forgotten: Mutex::default(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn positions(&self, key: &str) -> [usize; DOORKEEPER_HASHES as usize] {
+        let hash = self.hasher.hash_one(key);
+        let (first, step) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let len = self.filters[0].bits.len() as u64 * 64;
+        std::array::from_fn(|i| (first.wrapping_add((i as u64).wrapping_mul(step)) % len) as usize)
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        let positions = self.positions(key);
+        self.filters.iter().any(|filter| filter.contains(&positions))
+    }
+
+    /// Record a first hit of `key` with a window of `window` seconds; true
+    /// if it had (probably) been recorded before.
+    fn check_and_set(&self, key: &str, current_time: u64, window: u64) -> bool {
+        self.rotate(current_time, window);
+
+        let positions = self.positions(key);
+        let current = self.current.load(Ordering::Relaxed);
+        let mut seen = self.filters[current].check_and_set(&positions);
+        seen |= self.filters[current ^ 1].contains(&positions);
+        if seen {
+            let mut forgotten = self.forgotten.lock().unwrap_or_else(PoisonError::into_inner);
+            seen = forgotten.is_empty() || forgotten.remove(key).is_none();
+        }
+        seen
+    }
+
+    /// Take a turn if one is due. Callers that find another turn being
+    /// taken skip it; first hits recorded while the old filter is cleared
+    /// may be lost, which only costs their key one more absorbed hit.
+    fn rotate(&self, current_time: u64, window: u64) {
+        let due = || {
+            current_time >= self.rotated_at.load(Ordering::Relaxed).saturating_add(window)
+                || self.filters[self.current.load(Ordering::Relaxed)].full()
+        };
+        if !due() {
+            return;
+        }
+        let Ok(_rotating) = self.rotating.try_lock() else {
+            return;
+        };
+        if !due() {
+            return;
+        }
+
+        let next = self.current.load(Ordering::Relaxed) ^ 1;
+        self.filters[next].clear();
+        self.current.store(next, Ordering::Relaxed);
+        // Keys deleted before the last turn had their bits in the filter
+        // just cleared
+        let rotated_at = self.rotated_at.swap(current_time, Ordering::Relaxed);
+        self.forgotten
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, deleted_at| *deleted_at >= rotated_at);
+    }
+
+    /// Treat `key`'s next hit as its first again.
+    fn forget(&self, key: &str, current_time: u64) {
+        if self.contains(key) {
+            self.forgotten
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key.to_string(), current_time);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Bounds {
+    options: MemoryOptions,
+    doorkeeper: Option<Doorkeeper>,
+    /// Approximate size of all counters, see `ENTRY_OVERHEAD`
+    bytes: AtomicUsize,
+    /// Held by the one caller running an eviction pass
+    evicting: Mutex<()>,
+}
+
+/// Counters kept in the worker's own memory.
+///
+/// The map is sharded, and a live counter is bumped with atomics under its
+/// shard's read lock, so concurrent requests only contend when they create
+/// or reset a counter in the same shard. Clones share the same counters.
+///
+/// Unbounded by default; `with_options` caps the number of counters or
+/// their size, evicting expired counters first and then by `Eviction`, so
+/// a client spraying random keys cannot exhaust the worker's memory.
+#[derive(Clone)]
 pub struct MemoryStorage {
-    store: Mutex<HashMap<String, RateLimit>>,
+    store: Arc<DashMap<String, RateLimit>>,
+    bounds: Arc<Bounds>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::with_options(MemoryOptions::default())
+    }
 }
 
 impl MemoryStorage {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: MemoryOptions) -> Self {
         Self {
-            store: Mutex::new(HashMap::new()),
+            store: Arc::default(),
+            bounds: Arc::new(Bounds {
+                doorkeeper: options.doorkeeper_bits.map(Doorkeeper::new),
+                options,
+                ..Bounds::default()
+            }),
+            clock: clock::system(),
         }
     }
 
-    fn get_current_timestamp() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs()
+    /// Read expiry times from `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
     }
-}
 
-#[async_trait]
-impl StorageBackend for MemoryStorage {
-    async fn get(&self, key: &str) -> Result<u32, StorageError> {
-        let store = self.store.lock().map_err(|e| StorageError::DatabaseError(e.to_string()))?;
-        let current_time = Self::get_current_timestamp();
+    /// Counters currently held, including expired ones not yet swept.
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    /// Approximate memory held by the counters, as checked against
+    /// `max_bytes`.
+    pub fn size_bytes(&self) -> usize {
+        self.bounds.bytes.load(Ordering::Relaxed)
+    }
 
-        if let Some(rate_limit) = store.get(key) {
-            if rate_limit.expire_at > current_time {
-                return Ok(rate_limit.count);
+    /// Current count for `key`; `get` without the trait's `async`. A key
+    /// whose only hit was absorbed by the doorkeeper reads as 0, since a
+    /// bloom filter hit may be a false positive; the count `hit` returns
+    /// for its second hit is 2, which is what the limiter checks.
+    pub fn count(&self, key: &str) -> u64 {
+        let current_time = self.clock.unix_secs();
+        match self.store.get(key) {
+            Some(rate_limit) if rate_limit.expire_at.load(Ordering::Relaxed) > current_time => {
+                rate_limit.count.load(Ordering::Relaxed)
             }
+            _ => 0,
         }
+    }
 
-        Ok(0)
+    /// Count one request for `key`; `increment` through a shared reference.
+    pub fn hit(&self, key: &str, expire: u32) -> Increment {
+        self.add(key, 1, expire)
     }
 
-    async fn increment(&mut self, key: &str, expire: u32) -> Result<(), StorageError> {
-        let mut store = self.store.lock().map_err(|e| StorageError::DatabaseError(e.to_string()))?;
-        let current_time = Self::get_current_timestamp();
+    /// Add `amount` to `key`; `increment_by` through a shared reference.
+    pub fn add(&self, key: &str, amount: u64, expire: u32) -> Increment {
+        let current_time = self.clock.unix_secs();
         let expire_at = current_time + expire as u64;
 
-        match store.get_mut(key) {
-            Some(rate_limit) if rate_limit.expire_at > current_time => {
-                rate_limit.count += 1;
-                rate_limit.expire_at = expire_at;
+        // Fast path: a live counter only needs atomics under the read lock
+        if let Some(rate_limit) = self.store.get(key) {
+            if rate_limit.expire_at.load(Ordering::Relaxed) > current_time {
+                let previous = rate_limit.count
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| Some(count.saturating_add(amount)))
+                    .unwrap_or(u64::MAX);
+                rate_limit.expire_at.store(expire_at, Ordering::Relaxed);
+                rate_limit.last_hit.store(current_time, Ordering::Relaxed);
+                return Increment { count: previous.saturating_add(amount) };
             }
-            _ => {
-                store.insert(key.to_string(), RateLimit {
-                    count: 1,
-                    expire_at,
+        }
+
+        // A key's first hit only sets bits in the doorkeeper; the counter is
+        // allocated on the second, starting at 2, with its window from then.
+        // Larger amounts are not worth absorbing
+        let mut absorbed = 0;
+        if let Some(doorkeeper) = self.bounds.doorkeeper.as_ref().filter(|_| amount == 1) {
+            if !self.store.contains_key(key) {
+                if !doorkeeper.check_and_set(key, current_time, expire as u64) {
+                    return Increment { count: 1 };
+                }
+                absorbed = 1;
+            }
+        }
+
+        // New or expired counter: the entry holds the shard's write lock,
+        // so no fast-path update can interleave with the reset
+        let (count, inserted) = match self.store.entry(key.to_string()) {
+            Entry::Occupied(mut entry) => {
+                let rate_limit = entry.get_mut();
+                let count = if *rate_limit.expire_at.get_mut() > current_time {
+                    rate_limit.count.get_mut().saturating_add(amount)
+                } else {
+                    amount
+                };
+                *rate_limit.count.get_mut() = count;
+                *rate_limit.expire_at.get_mut() = expire_at;
+                *rate_limit.last_hit.get_mut() = current_time;
+                (count, false)
+            }
+            Entry::Vacant(entry) => {
+                let count = amount + absorbed;
+                entry.insert(RateLimit {
+                    count: AtomicU64::new(count),
+                    expire_at: AtomicU64::new(expire_at),
+                    last_hit: AtomicU64::new(current_time),
                 });
+                (count, true)
             }
+        };
+
+        if inserted {
+            self.bounds.bytes.fetch_add(Self::entry_bytes(key), Ordering::Relaxed);
+            self.enforce_bounds(current_time);
         }
+        Increment { count }
+    }
 
-        Ok(())
+    fn entry_bytes(key: &str) -> usize {
+        key.len() + ENTRY_OVERHEAD
     }
 
-    async fn delete(&mut self, key: &str) -> Result<(), StorageError> {
-        let mut store = self.store.lock().map_err(|e| StorageError::DatabaseError(e.to_string()))?;
-        store.remove(key);
-        Ok(())
+    fn over(&self, percent: usize) -> bool {
+        let options = &self.bounds.options;
+        options.max_entries.is_some_and(|max| self.store.len() > share(max, percent))
+            || options.max_bytes.is_some_and(|max| self.size_bytes() > share(max, percent))
+    }
+
+    /// Remove the counters `evict` selects, keeping the byte count in step,
+    /// and return how many were removed.
+    fn remove_where(&self, mut evict: impl FnMut(&RateLimit) -> bool) -> u64 {
+        let mut removed = 0;
+        let mut freed = 0;
+        self.store.retain(|key, rate_limit| {
+            let evicted = evict(rate_limit);
+            if evicted {
+                removed += 1;
+                freed += Self::entry_bytes(key);
+            }
+            !evicted
+        });
+        self.bounds.bytes.fetch_sub(freed, Ordering::Relaxed);
+        removed
+    }
+
+    /// Once over a cap, drop expired counters and then evict by policy
+    /// until back under `EVICT_TO_PERCENT` of the caps. Callers that find
+    /// another pass running skip it, so the map may briefly overshoot.
+    fn enforce_bounds(&self, current_time: u64) {
+        if !self.over(100) {
+            return;
+        }
+        let Ok(_evicting) = self.bounds.evicting.try_lock() else {
+            return;
+        };
+
+        let expired = self.remove_where(|rate_limit| rate_limit.expire_at.load(Ordering::Relaxed) <= current_time);
+        metrics::counter!("rate_limiter_memory_evictions_total", "reason" => "expired").increment(expired);
+        if !self.over(EVICT_TO_PERCENT) {
+            return;
+        }
+
+        let excess = self.excess();
+        let evicted = match self.bounds.options.eviction {
+            Eviction::Random => {
+                let mut left = excess;
+                self.remove_where(|_| {
+                    let evict = left > 0;
+                    left = left.saturating_sub(1);
+                    evict
+                })
+            }
+            Eviction::Lru => {
+                // Everything hit before the cutoff goes, plus enough of the
+                // ties at the cutoff to make up the excess
+                let mut last_hits: Vec<u64> = self.store
+                    .iter()
+                    .map(|entry| entry.last_hit.load(Ordering::Relaxed))
+                    .collect();
+                let excess = excess.min(last_hits.len());
+                if excess == 0 {
+                    return;
+                }
+                let (_, &mut cutoff, _) = last_hits.select_nth_unstable(excess - 1);
+                let mut ties = excess - last_hits.iter().filter(|&&last_hit| last_hit < cutoff).count();
+                self.remove_where(|rate_limit| {
+                    let last_hit = rate_limit.last_hit.load(Ordering::Relaxed);
+                    if last_hit < cutoff {
+                        true
+                    } else if last_hit == cutoff && ties > 0 {
+                        ties -= 1;
+                        true
+                    } else {
+                        false
+                    }
+                })
+            }
+        };
+
+        let reason = match self.bounds.options.eviction {
+            Eviction::Lru => "lru",
+            Eviction::Random => "random",
+        };
+        metrics::counter!("rate_limiter_memory_evictions_total", "reason" => reason).increment(evicted);
+    }
+
+    /// Counters to evict to get under `EVICT_TO_PERCENT` of both caps.
+    fn excess(&self) -> usize {
+        let options = &self.bounds.options;
+        let len = self.store.len();
+        let by_entries = options
+            .max_entries
+            .map_or(0, |max| len.saturating_sub(share(max, EVICT_TO_PERCENT)));
+        let by_bytes = options.max_bytes.map_or(0, |max| {
+            let bytes = self.size_bytes();
+            let average = (bytes / len.max(1)).max(1);
+            bytes.saturating_sub(share(max, EVICT_TO_PERCENT)).div_ceil(average)
+        });
+        by_entries.max(by_bytes)
     }
+}
 
-    async fn cleanup_expired(&mut self) -> Result<(), StorageError> {
-        let mut store = self.store.lock().map_err(|e| StorageError::DatabaseError(e.to_string()))?;
-        let current_time = Self::get_current_timestamp();
-        store.retain(|_, rate_limit| rate_limit.expire_at > current_time);
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        Ok(self.count(key))
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        Ok(self.hit(key, expire))
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        Ok(self.add(key, amount, expire))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        if let Some((key, _)) = self.store.remove(key) {
+            self.bounds.bytes.fetch_sub(Self::entry_bytes(&key), Ordering::Relaxed);
+        }
+        if let Some(doorkeeper) = &self.bounds.doorkeeper {
+            doorkeeper.forget(key, self.clock.unix_secs());
+        }
         Ok(())
     }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let current_time = self.clock.unix_secs();
+        Ok(self.remove_where(|rate_limit| rate_limit.expire_at.load(Ordering::Relaxed) <= current_time))
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let now = self.clock.now();
+        Ok(self.store.get(key).and_then(|rate_limit| {
+            let expire_at = Duration::from_secs(rate_limit.expire_at.load(Ordering::Relaxed));
+            expire_at.checked_sub(now).filter(|ttl| !ttl.is_zero())
+        }))
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        Ok(keys.iter().map(|key| self.count(key)).collect())
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        Ok(keys.iter().map(|(key, expire)| self.hit(key, *expire)).collect())
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        let current_time = self.clock.unix_secs();
+
+        Ok(self.store
+            .iter()
+            .filter_map(|entry| {
+                let expire_at = entry.expire_at.load(Ordering::Relaxed);
+                (expire_at > current_time).then(|| CounterSnapshot {
+                    key: entry.key().clone(),
+                    count: entry.count.load(Ordering::Relaxed),
+                    expire_at,
+                })
+            })
+            .collect())
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        let current_time = self.clock.unix_secs();
+        let mut imported = 0;
+
+        for counter in counters.iter().filter(|counter| counter.expire_at > current_time) {
+            match self.store.entry(counter.key.clone()) {
+                Entry::Occupied(mut entry) => {
+                    let rate_limit = entry.get_mut();
+                    let expire_at = rate_limit.expire_at.get_mut();
+                    let count = rate_limit.count.get_mut();
+                    if *expire_at > current_time {
+                        *expire_at = (*expire_at).max(counter.expire_at);
+                        *count = count.saturating_add(counter.count);
+                    } else {
+                        *expire_at = counter.expire_at;
+                        *count = counter.count;
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(RateLimit {
+                        count: AtomicU64::new(counter.count),
+                        expire_at: AtomicU64::new(counter.expire_at),
+                        last_hit: AtomicU64::new(current_time),
+                    });
+                    self.bounds.bytes.fetch_add(Self::entry_bytes(&counter.key), Ordering::Relaxed);
+                }
+            }
+            imported += 1;
+        }
+
+        self.enforce_bounds(current_time);
+        Ok(imported)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread;
+    use crate::clock::ManualClock;
+    use proptest::prelude::*;
+    use std::collections::HashMap;
     use std::time::Duration;
 
     #[tokio::test]
     async fn test_memory_storage() {
-        let mut storage = MemoryStorage::new();
+        let clock = Arc::new(ManualClock::default());
+        let storage = MemoryStorage::new().with_clock(clock.clone());
 
         // Test increment and get
-        storage.increment("test_key", 2).await.unwrap();
+        assert_eq!(storage.increment("test_key", 2).await.unwrap().count, 1);
         assert_eq!(storage.get("test_key").await.unwrap(), 1);
 
-        storage.increment("test_key", 2).await.unwrap();
+        let increment = storage.increment("test_key", 2).await.unwrap();
+        assert_eq!(increment.count, 2);
+        assert_eq!(increment.remaining(5), 3);
         assert_eq!(storage.get("test_key").await.unwrap(), 2);
 
         // Test expiration
         storage.increment("expire_key", 1).await.unwrap();
-        thread::sleep(Duration::from_secs(2));
+        clock.advance(Duration::from_secs(2));
         assert_eq!(storage.get("expire_key").await.unwrap(), 0);
 
         // Test cleanup
         storage.cleanup_expired().await.unwrap();
         assert_eq!(storage.get("expire_key").await.unwrap(), 0);
     }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let clock = Arc::new(ManualClock::default());
+        let storage = MemoryStorage::new().with_clock(clock.clone());
+        assert_eq!(storage.ttl("key").await.unwrap(), None);
+
+        storage.increment("key", 60).await.unwrap();
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(storage.ttl("key").await.unwrap(), Some(Duration::from_millis(58_500)));
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(storage.ttl("key").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_count_saturates() {
+        let storage = MemoryStorage::new();
+        storage.increment("key", 60).await.unwrap();
+        storage.store.get("key").unwrap().count.store(u64::MAX - 1, Ordering::Relaxed);
+
+        assert_eq!(storage.increment("key", 60).await.unwrap().count, u64::MAX);
+        let increment = storage.increment("key", 60).await.unwrap();
+        assert_eq!(increment.count, u64::MAX);
+        assert_eq!(increment.remaining(100), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bounded_lru_eviction() {
+        let storage = MemoryStorage::with_options(MemoryOptions {
+            max_entries: Some(10),
+            ..MemoryOptions::default()
+        });
+        for i in 0..10 {
+            storage.hit(&format!("old-{}", i), 60);
+        }
+        // Make the last five look stale
+        for i in 5..10 {
+            storage.store.get(&format!("old-{}", i)).unwrap().last_hit.fetch_sub(10, Ordering::Relaxed);
+        }
+
+        storage.hit("new", 60);
+        assert_eq!(storage.len(), 9);
+        assert_eq!(storage.count("new"), 1);
+        assert_eq!(storage.count("old-0"), 1);
+        assert_eq!((5..10).filter(|i| storage.count(&format!("old-{}", i)) == 1).count(), 3);
+
+        storage.cleanup_expired().await.unwrap();
+        for i in 0..10 {
+            storage.delete(&format!("old-{}", i)).await.unwrap();
+        }
+        storage.delete("new").await.unwrap();
+        assert_eq!(storage.size_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_doorkeeper_absorbs_first_hit() {
+        let storage = MemoryStorage::with_options(MemoryOptions {
+            doorkeeper_bits: Some(1 << 16),
+            ..MemoryOptions::default()
+        });
+
+        assert_eq!(storage.increment("once", 60).await.unwrap().count, 1);
+        assert!(storage.is_empty());
+        assert_eq!(storage.get("once").await.unwrap(), 0);
+
+        assert_eq!(storage.increment("once", 60).await.unwrap().count, 2);
+        assert_eq!(storage.increment("once", 60).await.unwrap().count, 3);
+        assert_eq!(storage.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_doorkeeper_forgets_deleted_and_old_keys() {
+        let clock = Arc::new(ManualClock::default());
+        let storage = MemoryStorage::with_options(MemoryOptions {
+            doorkeeper_bits: Some(1 << 16),
+            ..MemoryOptions::default()
+        })
+        .with_clock(clock.clone());
+
+        storage.increment("deleted", 60).await.unwrap();
+        storage.increment("deleted", 60).await.unwrap();
+        storage.delete("deleted").await.unwrap();
+        assert_eq!(storage.increment("deleted", 60).await.unwrap().count, 1);
+        assert!(storage.is_empty());
+        assert_eq!(storage.increment("deleted", 60).await.unwrap().count, 2);
+
+        // Still remembered one window on, gone after two
+        storage.increment("old", 60).await.unwrap();
+        clock.advance(Duration::from_secs(60));
+        storage.increment("other", 60).await.unwrap();
+        assert_eq!(storage.increment("old", 60).await.unwrap().count, 2);
+
+        storage.increment("older", 60).await.unwrap();
+        clock.advance(Duration::from_secs(60));
+        storage.increment("other", 60).await.unwrap();
+        clock.advance(Duration::from_secs(60));
+        storage.increment("other", 60).await.unwrap();
+        assert_eq!(storage.increment("older", 60).await.unwrap().count, 1);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = MemoryOptions::parse("max_entries=100k, max_bytes=64m,eviction=random,doorkeeper=1m").unwrap();
+        assert_eq!(options.max_entries, Some(100 << 10));
+        assert_eq!(options.max_bytes, Some(64 << 20));
+        assert_eq!(options.eviction, Eviction::Random);
+        assert_eq!(options.doorkeeper_bits, Some(1 << 20));
+        assert!(MemoryOptions::parse("").unwrap().max_entries.is_none());
+        assert!(MemoryOptions::parse("eviction=lfu").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_hits_are_counted() {
+        let storage = MemoryStorage::new();
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    for _ in 0..1000 {
+                        storage.hit("shared", 60);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(storage.count("shared"), 8000);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_hits_get_distinct_counts() {
+        // Every hit sees its own count, so admitting `count <= limit` lets
+        // exactly `limit` requests through however they interleave
+        let storage = MemoryStorage::new();
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move { (0..500).map(|_| storage.hit("shared", 60).count).collect::<Vec<_>>() })
+            })
+            .collect();
+        let mut counts = Vec::new();
+        for task in tasks {
+            counts.extend(task.await.unwrap());
+        }
+
+        counts.sort_unstable();
+        assert_eq!(counts, (1..=4000).collect::<Vec<u64>>());
+        assert_eq!(counts.iter().filter(|&&count| count <= 100).count(), 100);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Step {
+        Hit(usize, u32),
+        Advance(u64),
+        Cleanup,
+    }
+
+    fn step() -> impl Strategy<Value = Step> {
+        prop_oneof![
+            4 => (0..4usize, 1..5u32).prop_map(|(key, expire)| Step::Hit(key, expire)),
+            2 => (0..6u64).prop_map(Step::Advance),
+            1 => Just(Step::Cleanup),
+        ]
+    }
+
+    proptest! {
+        /// Counts match a plain model: they start at 1, grow by one per hit
+        /// within the window, restart once it has passed and never exceed
+        /// the hits made.
+        #[test]
+        fn prop_counts_follow_model(steps in proptest::collection::vec(step(), 1..200)) {
+            let clock = Arc::new(ManualClock::default());
+            let storage = MemoryStorage::new().with_clock(clock.clone());
+            let mut model: HashMap<usize, (u64, u64)> = HashMap::new();
+            let mut hits = [0u64; 4];
+
+            for step in steps {
+                let now = clock.unix_secs();
+                match step {
+                    Step::Hit(key, expire) => {
+                        let (count, expire_at) = model.entry(key).or_insert((0, 0));
+                        *count = if *expire_at > now { *count + 1 } else { 1 };
+                        *expire_at = now + u64::from(expire);
+                        hits[key] += 1;
+
+                        let got = storage.hit(&format!("key-{}", key), expire).count;
+                        prop_assert_eq!(got, *count);
+                        prop_assert!(got >= 1 && got <= hits[key]);
+                    }
+                    Step::Advance(secs) => clock.advance(Duration::from_secs(secs)),
+                    Step::Cleanup => {
+                        storage.remove_where(|rate_limit| rate_limit.expire_at.load(Ordering::Relaxed) <= now);
+                    }
+                }
+
+                let now = clock.unix_secs();
+                for key in 0..4 {
+                    let expected = match model.get(&key) {
+                        Some(&(count, expire_at)) if expire_at > now => count,
+                        _ => 0,
+                    };
+                    prop_assert_eq!(storage.count(&format!("key-{}", key)), expected);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,122 @@
+/// Table recording which migrations have been applied to a database.
+pub const SCHEMA_VERSION_TABLE: &str = "rate_limiter_schema_version";
+
+/// SQL flavours with their own migration lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlDialect {
+    MySql,
+    Postgres,
+    Sqlite,
+}
+
+/// One versioned schema change.
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    /// Statements run in order; each is a single SQL statement
+    pub statements: &'static [&'static str],
+}
+
+const MYSQL: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create rate_limits",
+        statements: &[r"CREATE TABLE IF NOT EXISTS rate_limits (
+            key_name VARCHAR(255) PRIMARY KEY,
+            count INT UNSIGNED NOT NULL DEFAULT 0,
+            expire_at TIMESTAMP NOT NULL,
+            INDEX idx_expire_at (expire_at)
+        )"],
+    },
+    Migration {
+        version: 2,
+        description: "widen count to 64 bits",
+        statements: &["ALTER TABLE rate_limits MODIFY count BIGINT UNSIGNED NOT NULL DEFAULT 0"],
+    },
+];
+
+const POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create rate_limits",
+        statements: &[
+            r"CREATE TABLE IF NOT EXISTS rate_limits (
+                key_name VARCHAR(255) PRIMARY KEY,
+                count INTEGER NOT NULL DEFAULT 0,
+                expire_at TIMESTAMP WITH TIME ZONE NOT NULL
+            )",
+            "CREATE INDEX IF NOT EXISTS idx_expire_at ON rate_limits(expire_at)",
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "widen count to 64 bits",
+        statements: &["ALTER TABLE rate_limits ALTER COLUMN count TYPE BIGINT"],
+    },
+];
+
+// SQLite integers are always 64-bit, so there is nothing to widen
+const SQLITE: &[Migration] = &[Migration {
+    version: 1,
+    description: "create rate_limits",
+    statements: &[
+        r"CREATE TABLE IF NOT EXISTS rate_limits (
+            key_name TEXT PRIMARY KEY,
+            count INTEGER NOT NULL DEFAULT 0,
+            expire_at INTEGER NOT NULL
+        )",
+        "CREATE INDEX IF NOT EXISTS idx_expire_at ON rate_limits(expire_at)",
+    ],
+}];
+
+/// Every migration for `dialect`, oldest first. Useful for DBAs who run
+/// the schema themselves with `manage_schema` off.
+pub fn migrations(dialect: SqlDialect) -> &'static [Migration] {
+    match dialect {
+        SqlDialect::MySql => MYSQL,
+        SqlDialect::Postgres => POSTGRES,
+        SqlDialect::Sqlite => SQLITE,
+    }
+}
+
+/// Migrations newer than `current`, oldest first.
+pub(crate) fn pending(dialect: SqlDialect, current: u32) -> impl Iterator<Item = &'static Migration> {
+    migrations(dialect).iter().filter(move |m| m.version > current)
+}
+
+/// DDL for the version table itself.
+pub(crate) fn version_table(dialect: SqlDialect) -> &'static str {
+    match dialect {
+        SqlDialect::MySql => r"CREATE TABLE IF NOT EXISTS rate_limiter_schema_version (
+            version INT UNSIGNED PRIMARY KEY,
+            description VARCHAR(255) NOT NULL,
+            applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+        SqlDialect::Postgres => r"CREATE TABLE IF NOT EXISTS rate_limiter_schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        )",
+        SqlDialect::Sqlite => r"CREATE TABLE IF NOT EXISTS rate_limiter_schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        )",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions_increase() {
+        for dialect in [SqlDialect::MySql, SqlDialect::Postgres, SqlDialect::Sqlite] {
+            let versions: Vec<u32> = migrations(dialect).iter().map(|m| m.version).collect();
+            assert_eq!(versions.first(), Some(&1), "{:?}", dialect);
+            assert!(versions.windows(2).all(|w| w[1] == w[0] + 1), "{:?}", dialect);
+        }
+        assert_eq!(pending(SqlDialect::MySql, 1).map(|m| m.version).collect::<Vec<_>>(), vec![2]);
+    }
+}
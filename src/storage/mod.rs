@@ -1,21 +1,148 @@
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::time::Duration;
 
+#[cfg(not(target_arch = "wasm32"))]
 mod redis;
+#[cfg(not(target_arch = "wasm32"))]
+mod redis_sentinel;
+#[cfg(not(target_arch = "wasm32"))]
+mod redis_tracking;
+#[cfg(not(target_arch = "wasm32"))]
 mod memcached;
+#[cfg(not(target_arch = "wasm32"))]
+mod sql;
+mod migrations;
+#[cfg(any(feature = "http-decision", feature = "proxy-wasm"))]
+mod decision;
+#[cfg(not(target_arch = "wasm32"))]
 mod mysql;
+#[cfg(not(target_arch = "wasm32"))]
 mod postgresql;
+#[cfg(not(target_arch = "wasm32"))]
 mod sqlite;
 mod memory;
+#[cfg(not(target_arch = "wasm32"))]
+mod shm;
+#[cfg(not(target_arch = "wasm32"))]
+mod failover;
+#[cfg(not(target_arch = "wasm32"))]
+mod replicated;
+#[cfg(not(target_arch = "wasm32"))]
+mod resilient;
+#[cfg(not(target_arch = "wasm32"))]
+mod health;
+mod prefixed;
+mod hashed;
+mod key;
+#[cfg(not(target_arch = "wasm32"))]
+mod sharded;
+#[cfg(not(target_arch = "wasm32"))]
+mod coalescing;
+#[cfg(not(target_arch = "wasm32"))]
+mod split_rate;
+#[cfg(not(target_arch = "wasm32"))]
+mod gossip;
+#[cfg(not(target_arch = "wasm32"))]
+mod registry;
+#[cfg(not(target_arch = "wasm32"))]
+mod cleanup;
+#[cfg(not(target_arch = "wasm32"))]
+mod snapshot;
+#[cfg(feature = "dynamodb")]
+mod dynamodb;
+#[cfg(feature = "mongodb")]
+mod mongodb;
+#[cfg(feature = "rocksdb")]
+mod rocksdb;
+#[cfg(feature = "aerospike")]
+mod aerospike;
+#[cfg(feature = "envoy-rls")]
+mod envoy_rls;
+#[cfg(feature = "http-decision")]
+mod http_decision;
+#[cfg(feature = "dns")]
+mod discovery;
 
-pub use redis::RedisStorage;
-pub use memcached::MemcachedStorage;
+#[cfg(not(target_arch = "wasm32"))]
+pub use redis::{RedisStorage, RedisOptions, RedisTlsConfig, hash_tag, slot_key};
+#[cfg(not(target_arch = "wasm32"))]
+pub use redis_sentinel::SentinelConfig;
+#[cfg(not(target_arch = "wasm32"))]
+pub use redis_tracking::{ClientCacheOptions, Invalidation, RedisFlavor};
+#[cfg(not(target_arch = "wasm32"))]
+pub use memcached::{MemcachedOptions, MemcachedStorage};
+#[cfg(not(target_arch = "wasm32"))]
+pub use sql::SqlPoolOptions;
+pub use migrations::{migrations, Migration, SqlDialect, SCHEMA_VERSION_TABLE};
+#[cfg(not(target_arch = "wasm32"))]
 pub use mysql::MySQLStorage;
+#[cfg(not(target_arch = "wasm32"))]
 pub use postgresql::PostgresStorage;
-pub use sqlite::SQLiteStorage;
-pub use memory::MemoryStorage;
+#[cfg(not(target_arch = "wasm32"))]
+pub use sqlite::{SQLiteOptions, SQLiteStorage};
+pub use memory::{Eviction, MemoryOptions, MemoryStorage};
+#[cfg(not(target_arch = "wasm32"))]
+pub use shm::ShmZoneStorage;
+#[cfg(not(target_arch = "wasm32"))]
+pub use failover::FailoverStorage;
+#[cfg(not(target_arch = "wasm32"))]
+pub use replicated::{ReplicaOptions, ReplicatedStorage};
+#[cfg(not(target_arch = "wasm32"))]
+pub use resilient::{ResilientStorage, RetryPolicy};
+#[cfg(not(target_arch = "wasm32"))]
+pub use health::{backend_health, register_health, BackendHealth, Health, HealthReport, HealthThresholds};
+pub use prefixed::{PrefixedStorage, DEFAULT_NAMESPACE};
+pub use hashed::{HashedKeyStorage, KeySecret};
+pub use key::{key_tag, KeyBuf, INLINE_KEY_LEN};
+#[cfg(not(target_arch = "wasm32"))]
+pub use sharded::{ShardedStorage, DEFAULT_VIRTUAL_NODES};
+#[cfg(not(target_arch = "wasm32"))]
+pub use coalescing::CoalescingStorage;
+#[cfg(not(target_arch = "wasm32"))]
+pub use split_rate::{SplitRateOptions, SplitRateStorage};
+#[cfg(not(target_arch = "wasm32"))]
+pub use gossip::{GossipCounter, GossipMessage, GossipOptions, GossipStorage};
+#[cfg(not(target_arch = "wasm32"))]
+pub use registry::{create_backend, register_backend, registered_backends};
+#[cfg(not(target_arch = "wasm32"))]
+pub use cleanup::{spawn_cleanup, CleanupOptions};
+#[cfg(not(target_arch = "wasm32"))]
+pub use snapshot::{load_snapshot, migrate, save_snapshot, spawn_snapshot_on_signal, MIGRATE_BATCH};
+#[cfg(feature = "dynamodb")]
+pub use dynamodb::DynamoDBStorage;
+#[cfg(feature = "mongodb")]
+pub use self::mongodb::{MongoOptions, MongoStorage};
+#[cfg(feature = "rocksdb")]
+pub use self::rocksdb::RocksDBStorage;
+#[cfg(feature = "aerospike")]
+pub use self::aerospike::{AerospikeOptions, AerospikeStorage};
+#[cfg(feature = "envoy-rls")]
+pub use envoy_rls::{EnvoyRlsOptions, EnvoyRlsStorage};
+#[cfg(feature = "http-decision")]
+pub use http_decision::{HttpDecisionOptions, HttpDecisionStorage};
+#[cfg(feature = "dns")]
+pub use discovery::{DiscoveredStorage, DiscoveryOptions, DnsName, EndpointFactory, Resolve, SystemResolver};
+#[cfg(feature = "dns")]
+pub(crate) use discovery::Shared;
+#[cfg(feature = "sidecar")]
+pub(crate) use envoy_rls::{proto as rls, SHOULD_RATE_LIMIT_PATH};
+#[cfg(any(feature = "http-decision", feature = "proxy-wasm"))]
+pub(crate) use decision::{DecisionRequest, DecisionResponse};
 
-#[derive(Debug, thiserror::Error)]
+/// What backends that delegate to an external decision service report when
+/// the service cannot be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureMode {
+    /// Let requests through
+    Allow,
+    /// Treat every key as over its limit
+    Deny,
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum StorageError {
     #[error("Connection error: {0}")]
     ConnectionError(String),
@@ -25,19 +152,135 @@ pub enum StorageError {
     InvalidValueType(String),
     #[error("Database error: {0}")]
     DatabaseError(String),
+    #[error("Unsupported operation: {0}")]
+    Unsupported(String),
+}
+
+/// Outcome of counting one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Increment {
+    /// Count in the current window, including this request
+    pub count: u64,
+}
+
+impl Increment {
+    /// Requests still allowed in the window under `limit`.
+    pub fn remaining(&self, limit: u64) -> u64 {
+        limit.saturating_sub(self.count)
+    }
+}
+
+/// One live counter, as produced by `export_all` and read by `import_all`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CounterSnapshot {
+    pub key: String,
+    pub count: u64,
+    /// Unix time in seconds at which the counter's window ends
+    pub expire_at: u64,
 }
 
+/// A counter store shared by every request in a worker.
+///
+/// All operations take `&self`, so concurrent requests call the backend
+/// directly; implementations keep their mutable state behind pools,
+/// atomics or locks of their own.
 #[async_trait]
 pub trait StorageBackend: Send + Sync {
     /// Get the current count value for the key
-    async fn get(&self, key: &str) -> Result<u32, StorageError>;
+    async fn get(&self, key: &str) -> Result<u64, StorageError>;
 
-    /// Increment the count value for the key
-    async fn increment(&mut self, key: &str, expire: u32) -> Result<(), StorageError>;
+    /// Increment the count value for the key and return the new count
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError>;
 
     /// Delete the value for the key
-    async fn delete(&mut self, key: &str) -> Result<(), StorageError>;
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Clean up expired keys, returning how many were removed. Backends
+    /// whose server expires keys on its own return 0.
+    async fn cleanup_expired(&self) -> Result<u64, StorageError>;
+
+    /// Time left until the counter for the key expires, or `None` when it
+    /// does not exist or has expired. Tells clients when their window
+    /// resets. Backends that cannot tell return `StorageError::Unsupported`,
+    /// which is the default.
+    async fn ttl(&self, _key: &str) -> Result<Option<Duration>, StorageError> {
+        Err(StorageError::Unsupported("ttl".to_string()))
+    }
+
+    /// Open connections, load scripts and prepare statements ahead of the
+    /// first request, so a worker fails at startup rather than on live
+    /// traffic. Called from nginx's init-worker phase; the default does
+    /// nothing.
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Get the counts for several keys, in the order given.
+    ///
+    /// The default issues one `get` per key; backends that can batch
+    /// (pipelines, multi-row queries) override it.
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        let mut counts = Vec::with_capacity(keys.len());
+        for key in keys {
+            counts.push(self.get(key).await?);
+        }
+        Ok(counts)
+    }
+
+    /// Increment several `(key, expire)` pairs and return the new counts in
+    /// the order given. A key listed twice is counted twice.
+    ///
+    /// The default issues one `increment` per key; backends that can batch
+    /// override it.
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        let mut increments = Vec::with_capacity(keys.len());
+        for (key, expire) in keys {
+            increments.push(self.increment(key, *expire).await?);
+        }
+        Ok(increments)
+    }
+
+    /// Add `amount` to the count for the key, e.g. the bytes of a request
+    /// body, and return the new count.
+    ///
+    /// The default only handles amounts of zero and one, and returns
+    /// `Unsupported` for anything larger without touching the counter.
+    /// Backends that can add atomically override it.
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        match amount {
+            0 => self.get(key).await.map(|count| Increment { count }),
+            1 => self.increment(key, expire).await,
+            _ => Err(StorageError::Unsupported("increment_by".to_string())),
+        }
+    }
+
+    /// Count `member` once for `key` in the window, however often it is
+    /// added, and return the count. Lets retries of one operation, e.g.
+    /// with the same `Idempotency-Key`, count as a single request.
+    ///
+    /// The default increments a marker counter per member and counts the
+    /// member on its first increment. A marker outlives a window that
+    /// resets before it. Backends with native sets override it.
+    async fn add_distinct(&self, key: &str, member: &str, expire: u32) -> Result<Increment, StorageError> {
+        let marker = format!("{}:ids:{}", key, member);
+        if self.increment(&marker, expire).await?.count == 1 {
+            self.increment(key, expire).await
+        } else {
+            self.get(key).await.map(|count| Increment { count })
+        }
+    }
+
+    /// Every live counter, so state can be snapshotted or copied to another
+    /// backend. Backends that cannot list their keys return
+    /// `StorageError::Unsupported`, which is the default.
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        Err(StorageError::Unsupported("export_all".to_string()))
+    }
 
-    /// Clean up expired keys
-    async fn cleanup_expired(&mut self) -> Result<(), StorageError>;
+    /// Load counters from `export_all` and return how many were imported.
+    /// A counter that is still live here keeps counting: the imported count
+    /// is added and the later expiry wins. Expired entries are skipped.
+    async fn import_all(&self, _counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        Err(StorageError::Unsupported("import_all".to_string()))
+    }
 }
@@ -0,0 +1,197 @@
+use async_trait::async_trait;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::error::{Error as MongoError, ErrorKind, WriteFailure};
+use mongodb::options::{
+    CollectionOptions, FindOneAndUpdateOptions, IndexOptions, ReturnDocument, WriteConcern,
+};
+use mongodb::{Client, Collection, IndexModel};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::storage::{Increment, StorageBackend, StorageError};
+
+const DUPLICATE_KEY_ERROR: i32 = 11000;
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Database, collection and durability settings for `MongoStorage`.
+#[derive(Debug, Clone)]
+pub struct MongoOptions {
+    pub database: String,
+    pub collection: String,
+    /// Write concern for counter updates; `None` uses the client default
+    pub write_concern: Option<WriteConcern>,
+}
+
+impl Default for MongoOptions {
+    fn default() -> Self {
+        Self {
+            database: "ratelimit".to_string(),
+            collection: "rate_limits".to_string(),
+            write_concern: None,
+        }
+    }
+}
+
+/// MongoDB backed storage.
+///
+/// Each key is stored as `{ _id: key, count, expire_at }`. A TTL index on
+/// `expire_at` lets the server remove finished windows on its own.
+pub struct MongoStorage {
+    collection: Collection<Document>,
+}
+
+impl MongoStorage {
+    pub async fn new(uri: &str) -> Result<Self, StorageError> {
+        Self::with_options(uri, &MongoOptions::default()).await
+    }
+
+    pub async fn with_options(uri: &str, options: &MongoOptions) -> Result<Self, StorageError> {
+        let client = Client::with_uri_str(uri)
+            .await
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        let collection_options = CollectionOptions::builder()
+            .write_concern(options.write_concern.clone())
+            .build();
+        let collection = client
+            .database(&options.database)
+            .collection_with_options(&options.collection, collection_options);
+
+        Self::create_ttl_index(&collection).await?;
+
+        Ok(Self { collection })
+    }
+
+    async fn create_ttl_index(collection: &Collection<Document>) -> Result<(), StorageError> {
+        let index = IndexModel::builder()
+            .keys(doc! { "expire_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name("idx_expire_at".to_string())
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+
+        collection
+            .create_index(index, None)
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    fn get_current_time() -> DateTime {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        DateTime::from_millis(millis as i64)
+    }
+
+    fn count(document: &Document) -> Result<u64, StorageError> {
+        let count = document
+            .get_i64("count")
+            .map_err(|e| StorageError::InvalidValueType(e.to_string()))?;
+        u64::try_from(count).map_err(|e| StorageError::InvalidValueType(e.to_string()))
+    }
+
+    fn is_duplicate_key(error: &MongoError) -> bool {
+        match error.kind.as_ref() {
+            ErrorKind::Command(e) => e.code == DUPLICATE_KEY_ERROR,
+            ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY_ERROR,
+            _ => false,
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MongoStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        // The TTL monitor only runs once a minute, so filter on expire_at too
+        let document = self.collection
+            .find_one(doc! { "_id": key, "expire_at": { "$gt": Self::get_current_time() } }, None)
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        match document {
+            Some(document) => Self::count(&document),
+            None => Ok(0),
+        }
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.increment_by(key, 1, expire).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        if amount == 0 {
+            return self.get(key).await.map(|count| Increment { count });
+        }
+        let delta = amount.min(i64::MAX as u64) as i64;
+        for _ in 0..MAX_ATTEMPTS {
+            let now = Self::get_current_time();
+            let expire_at = DateTime::from_millis(now.timestamp_millis() + expire as i64 * 1000);
+
+            // Bump a live window in place
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            let updated = self.collection
+                .find_one_and_update(
+                    doc! { "_id": key, "expire_at": { "$gt": now } },
+                    doc! { "$inc": { "count": delta }, "$set": { "expire_at": expire_at } },
+                    options,
+                )
+                .await
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            if let Some(document) = updated {
+                return Ok(Increment { count: Self::count(&document)? });
+            }
+
+            // Missing or expired: start a new window. If another writer
+            // inserted a live document first the upsert hits a duplicate
+            // key, and the next attempt increments that document instead.
+            let options = FindOneAndUpdateOptions::builder()
+                .upsert(true)
+                .return_document(ReturnDocument::After)
+                .build();
+            let started = self.collection
+                .find_one_and_update(
+                    doc! { "_id": key, "expire_at": { "$lte": now } },
+                    doc! { "$set": { "count": delta, "expire_at": expire_at } },
+                    options,
+                )
+                .await;
+
+            match started {
+                Ok(_) => return Ok(Increment { count: delta as u64 }),
+                Err(e) if Self::is_duplicate_key(&e) => continue,
+                Err(e) => return Err(StorageError::DatabaseError(e.to_string())),
+            }
+        }
+
+        Err(StorageError::DatabaseError(format!(
+            "concurrent updates for '{}' kept conflicting",
+            key
+        )))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.collection
+            .delete_one(doc! { "_id": key }, None)
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        // The TTL index removes expired documents eventually; this clears
+        // whatever the TTL monitor has not reached yet.
+        let result = self.collection
+            .delete_many(doc! { "expire_at": { "$lte": Self::get_current_time() } }, None)
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(result.deleted_count)
+    }
+}
@@ -1,102 +1,378 @@
 use async_trait::async_trait;
-use mysql::{Pool, PooledConn, Opts, OptsBuilder};
-use crate::storage::{StorageBackend, StorageError};
+use futures_util::future::try_join_all;
+use mysql_async::prelude::Queryable;
+use mysql_async::{Conn, Opts, OptsBuilder, Params, Pool, PoolConstraints, PoolOpts, TxOpts, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::storage::migrations::{pending, version_table, SqlDialect, SCHEMA_VERSION_TABLE};
+use crate::storage::sql::{group_hits, merge_snapshots, spread_counts, IMPORT_BATCH};
+use crate::storage::{CounterSnapshot, Increment, SqlPoolOptions, StorageBackend, StorageError};
+
+const CLEANUP_LOCK: &str = "rate_limits_cleanup";
+const MIGRATION_LOCK: &str = "rate_limits_migrate";
+/// Seconds a starting worker waits for another one to finish migrating
+const MIGRATION_LOCK_TIMEOUT: u32 = 30;
+
+const GET_SQL: &str = "SELECT count FROM rate_limits WHERE key_name = ? AND expire_at > NOW()";
+
+const TTL_SQL: &str =
+    "SELECT TIMESTAMPDIFF(MICROSECOND, NOW(6), expire_at) FROM rate_limits WHERE key_name = ? AND expire_at > NOW()";
+
+// LAST_INSERT_ID(expr) hands the updated count back on this connection
+// without a second round trip
+const INCREMENT_SQL: &str = r"INSERT INTO rate_limits (key_name, count, expire_at)
+  VALUES (?, 1, NOW() + INTERVAL ? SECOND)
+  ON DUPLICATE KEY UPDATE
+    count = LAST_INSERT_ID(IF(expire_at > NOW(), IF(count < 18446744073709551615, count + 1, count), 1)),
+    expire_at = NOW() + INTERVAL ? SECOND";
+
+// Like INCREMENT_SQL for any amount, saturating at the largest BIGINT
+// UNSIGNED
+const INCREMENT_BY_SQL: &str = r"INSERT INTO rate_limits (key_name, count, expire_at)
+  VALUES (?, ?, NOW() + INTERVAL ? SECOND)
+  ON DUPLICATE KEY UPDATE
+    count = LAST_INSERT_ID(IF(expire_at > NOW(),
+      IF(count > 18446744073709551615 - VALUES(count), 18446744073709551615, count + VALUES(count)),
+      VALUES(count))),
+    expire_at = NOW() + INTERVAL ? SECOND";
 
 pub struct MySQLStorage {
     pool: Pool,
+    options: SqlPoolOptions,
 }
 
 impl MySQLStorage {
-    pub fn new(url: &str) -> Result<Self, StorageError> {
+    pub async fn new(url: &str) -> Result<Self, StorageError> {
+        Self::with_options(url, SqlPoolOptions::default()).await
+    }
+
+    pub async fn with_options(url: &str, options: SqlPoolOptions) -> Result<Self, StorageError> {
         let opts = Opts::from_url(url)
             .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
 
-        let builder = OptsBuilder::from_opts(opts);
-        let pool = Pool::new(builder)
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+        let max_connections = options.max_connections.max(1);
+        let constraints = PoolConstraints::new(options.min_connections.min(max_connections), max_connections)
+            .ok_or_else(|| StorageError::ConnectionError("invalid pool size".to_string()))?;
 
-        // Create table if it doesn't exist
-        let mut conn = pool.get_conn()
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+        let mut builder = OptsBuilder::from_opts(opts)
+            .pool_opts(PoolOpts::default().with_constraints(constraints))
+            .stmt_cache_size(options.statement_cache_size);
+        if let Some(timeout) = options.statement_timeout {
+            // max_execution_time only bounds SELECTs; writes are bounded by
+            // the acquire timeout and the server's lock wait timeout
+            builder = builder.init(vec![format!(
+                "SET SESSION max_execution_time = {}",
+                timeout.as_millis()
+            )]);
+        }
+
+        let storage = Self {
+            pool: Pool::new(builder),
+            options,
+        };
 
-        Self::create_table(&mut conn)?;
+        if storage.options.manage_schema {
+            let mut conn = storage.conn().await?;
+            Self::migrate(&mut conn).await?;
+        }
+
+        Ok(storage)
+    }
+
+    async fn conn(&self) -> Result<Conn, StorageError> {
+        tokio::time::timeout(self.options.acquire_timeout, self.pool.get_conn())
+            .await
+            .map_err(|_| StorageError::ConnectionError("timed out waiting for a MySQL connection".to_string()))?
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))
+    }
+
+    /// Apply pending schema migrations. MySQL commits DDL implicitly, so
+    /// migrations run one by one under a named lock that keeps workers
+    /// starting together from racing.
+    async fn migrate(conn: &mut Conn) -> Result<(), StorageError> {
+        let locked: Option<i32> = conn
+            .exec_first("SELECT GET_LOCK(?, ?)", (MIGRATION_LOCK, MIGRATION_LOCK_TIMEOUT))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        if locked != Some(1) {
+            return Err(StorageError::DatabaseError("timed out waiting for the migration lock".to_string()));
+        }
+
+        let result = Self::apply_migrations(conn).await;
+
+        conn.exec_drop("DO RELEASE_LOCK(?)", (MIGRATION_LOCK,))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
-        Ok(Self { pool })
+        result
     }
 
-    fn create_table(conn: &mut PooledConn) -> Result<(), StorageError> {
-        conn.query_drop(
-            r"CREATE TABLE IF NOT EXISTS rate_limits (
-                key_name VARCHAR(255) PRIMARY KEY,
-                count INT UNSIGNED NOT NULL DEFAULT 0,
-                expire_at TIMESTAMP NOT NULL
-            )"
-        ).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+    async fn apply_migrations(conn: &mut Conn) -> Result<(), StorageError> {
+        conn.query_drop(version_table(SqlDialect::MySql))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let current: Option<u32> = conn
+            .query_first(format!("SELECT MAX(version) FROM {}", SCHEMA_VERSION_TABLE))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?
+            .flatten();
 
-        // Create index for expiration time
-        conn.query_drop(
-            "CREATE INDEX IF NOT EXISTS idx_expire_at ON rate_limits(expire_at)"
-        ).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        for migration in pending(SqlDialect::MySql, current.unwrap_or(0)) {
+            log::info!("Applying MySQL migration {}: {}", migration.version, migration.description);
+            for statement in migration.statements {
+                conn.query_drop(*statement)
+                    .await
+                    .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            }
+            conn.exec_drop(
+                format!("INSERT INTO {} (version, description) VALUES (?, ?)", SCHEMA_VERSION_TABLE),
+                (migration.version, migration.description),
+            ).await.map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        }
 
         Ok(())
     }
 }
 
+/// `?, ?, ...` for an `IN (...)` list of `n` keys.
+fn placeholders(n: usize) -> String {
+    vec!["?"; n].join(", ")
+}
+
 #[async_trait]
 impl StorageBackend for MySQLStorage {
-    async fn get(&self, key: &str) -> Result<u32, StorageError> {
-        let mut conn = self.pool
-            .get_conn()
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let mut conn = self.conn().await?;
 
-        let result: Option<u32> = conn
-            .query_first(
-                "SELECT count FROM rate_limits WHERE key_name = ? AND expire_at > NOW()",
-            )
+        let result: Option<u64> = conn
+            .exec_first(GET_SQL, (key,))
+            .await
             .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
         Ok(result.unwrap_or(0))
     }
 
-    async fn increment(&mut self, key: &str, expire: u32) -> Result<(), StorageError> {
-        let mut conn = self.pool
-            .get_conn()
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        let mut conn = self.conn().await?;
 
-        conn.exec_drop(
-            r"INSERT INTO rate_limits (key_name, count, expire_at)
-              VALUES (?, 1, NOW() + INTERVAL ? SECOND)
-              ON DUPLICATE KEY UPDATE
-                count = IF(expire_at > NOW(), count + 1, 1),
-                expire_at = NOW() + INTERVAL ? SECOND",
-            (key, expire, expire)
-        ).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        conn.exec_drop(INCREMENT_SQL, (key, expire, expire))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
-        Ok(())
+        // One affected row means a fresh insert, two an update
+        let count = if conn.affected_rows() == 1 {
+            1
+        } else {
+            conn.last_insert_id().unwrap_or(1)
+        };
+
+        Ok(Increment { count })
     }
 
-    async fn delete(&mut self, key: &str) -> Result<(), StorageError> {
-        let mut conn = self.pool
-            .get_conn()
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        if amount == 0 {
+            return self.get(key).await.map(|count| Increment { count });
+        }
+        let mut conn = self.conn().await?;
+
+        conn.exec_drop(INCREMENT_BY_SQL, (key, amount, expire, expire))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let count = if conn.affected_rows() == 1 {
+            amount
+        } else {
+            conn.last_insert_id().unwrap_or(amount)
+        };
+
+        Ok(Increment { count })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let mut conn = self.conn().await?;
 
         conn.exec_drop(
             "DELETE FROM rate_limits WHERE key_name = ?",
             (key,)
-        ).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        ).await.map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
         Ok(())
     }
 
-    async fn cleanup_expired(&mut self) -> Result<(), StorageError> {
-        let mut conn = self.pool
-            .get_conn()
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+    /// Worked out by the database, so the worker's clock does not matter.
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let mut conn = self.conn().await?;
 
-        conn.query_drop(
-            "DELETE FROM rate_limits WHERE expire_at <= NOW()"
-        ).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        let micros: Option<i64> = conn
+            .exec_first(TTL_SQL, (key,))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(micros.map(|micros| Duration::from_micros(micros.max(0) as u64)))
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let mut conn = self.conn().await?;
+
+        // Only one instance sweeps at a time; the others skip this round
+        let locked: Option<i32> = conn
+            .exec_first("SELECT GET_LOCK(?, 0)", (CLEANUP_LOCK,))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        if locked != Some(1) {
+            return Ok(0);
+        }
+
+        let result = conn
+            .query_drop("DELETE FROM rate_limits WHERE expire_at <= NOW()")
+            .await
+            .map(|_| conn.affected_rows())
+            .map_err(|e| StorageError::DatabaseError(e.to_string()));
+
+        conn.exec_drop("DO RELEASE_LOCK(?)", (CLEANUP_LOCK,))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        result
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        let conns = try_join_all((0..self.options.min_connections.max(1)).map(|_| self.conn())).await?;
+
+        for mut conn in conns {
+            // Fails if the table is missing or lacks a column
+            conn.query_drop("SELECT key_name, count, expire_at FROM rate_limits LIMIT 0")
+                .await
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            // Preparing fills the per-connection statement cache
+            for sql in [GET_SQL, INCREMENT_SQL] {
+                conn.prep(sql)
+                    .await
+                    .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            }
+        }
 
         Ok(())
     }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.conn().await?;
+
+        let rows: Vec<(String, u64)> = conn
+            .exec(
+                format!(
+                    "SELECT key_name, count FROM rate_limits WHERE key_name IN ({}) AND expire_at > NOW()",
+                    placeholders(keys.len())
+                ),
+                keys.to_vec(),
+            )
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let counts: HashMap<String, u64> = rows.into_iter().collect();
+        Ok(keys.iter().map(|key| counts.get(*key).copied().unwrap_or(0)).collect())
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let grouped = group_hits(keys);
+        let mut conn = self.conn().await?;
+        let mut tx = conn
+            .start_transaction(TxOpts::default())
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        // One row per distinct key; VALUES(count) carries that key's hits
+        let mut params: Vec<Value> = Vec::with_capacity(grouped.len() * 3);
+        for (key, hits, expire) in &grouped {
+            params.push((*key).into());
+            params.push((*hits).into());
+            params.push((*expire).into());
+        }
+        tx.exec_drop(
+            format!(
+                r"INSERT INTO rate_limits (key_name, count, expire_at)
+                  VALUES {}
+                  ON DUPLICATE KEY UPDATE
+                    count = IF(expire_at > NOW(),
+                               IF(count > 18446744073709551615 - VALUES(count), 18446744073709551615, count + VALUES(count)),
+                               VALUES(count)),
+                    expire_at = VALUES(expire_at)",
+                vec!["(?, ?, NOW() + INTERVAL ? SECOND)"; grouped.len()].join(", ")
+            ),
+            Params::Positional(params),
+        ).await.map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        // The rows stay locked by this transaction, so the counts read back
+        // are exactly the ones written above
+        let rows: Vec<(String, u64)> = tx
+            .exec(
+                format!(
+                    "SELECT key_name, count FROM rate_limits WHERE key_name IN ({})",
+                    placeholders(grouped.len())
+                ),
+                grouped.iter().map(|(key, _, _)| *key).collect::<Vec<_>>(),
+            )
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        tx.commit().await.map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(spread_counts(keys, &rows.into_iter().collect()))
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        let mut conn = self.conn().await?;
+
+        let rows: Vec<(String, u64, u64)> = conn
+            .query("SELECT key_name, count, UNIX_TIMESTAMP(expire_at) FROM rate_limits WHERE expire_at > NOW()")
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(key, count, expire_at)| CounterSnapshot { key, count, expire_at })
+            .collect())
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let counters = merge_snapshots(counters, now);
+        let mut conn = self.conn().await?;
+
+        for batch in counters.chunks(IMPORT_BATCH) {
+            let mut params: Vec<Value> = Vec::with_capacity(batch.len() * 3);
+            for counter in batch {
+                params.push(counter.key.as_str().into());
+                params.push(counter.count.into());
+                params.push(counter.expire_at.into());
+            }
+            // count is assigned first, so expire_at still holds the old
+            // expiry when the second assignment reads it
+            conn.exec_drop(
+                format!(
+                    r"INSERT INTO rate_limits (key_name, count, expire_at)
+                      VALUES {}
+                      ON DUPLICATE KEY UPDATE
+                        count = IF(expire_at > NOW(),
+                                   IF(count > 18446744073709551615 - VALUES(count), 18446744073709551615, count + VALUES(count)),
+                                   VALUES(count)),
+                        expire_at = IF(expire_at > NOW(), GREATEST(expire_at, VALUES(expire_at)), VALUES(expire_at))",
+                    vec!["(?, ?, FROM_UNIXTIME(?))"; batch.len()].join(", ")
+                ),
+                Params::Positional(params),
+            ).await.map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        }
+
+        Ok(counters.len() as u64)
+    }
 }
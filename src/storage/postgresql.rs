@@ -1,103 +1,426 @@
 use async_trait::async_trait;
-use tokio_postgres::{Client, NoTls, Error as PgError};
-use crate::storage::{StorageBackend, StorageError};
+use futures_util::future::try_join_all;
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod, Runtime};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use tokio_postgres::{Config, NoTls};
+use crate::storage::migrations::{pending, version_table, SqlDialect, SCHEMA_VERSION_TABLE};
+use crate::storage::sql::{group_hits, merge_snapshots, spread_counts, IMPORT_BATCH};
+use crate::storage::{CounterSnapshot, Increment, SqlPoolOptions, StorageBackend, StorageError};
+
+/// Advisory lock key held by the instance currently sweeping expired rows
+const CLEANUP_LOCK_ID: i64 = 0x726c_636c_6e75; // "rlclnu"
+/// Advisory lock key held while migrations run
+const MIGRATION_LOCK_ID: i64 = 0x726c_6d69_6772; // "rlmigr"
+
+const GET_SQL: &str = "SELECT count FROM rate_limits WHERE key_name = $1 AND expire_at > NOW()";
+
+const TTL_SQL: &str =
+    "SELECT EXTRACT(EPOCH FROM expire_at - NOW())::float8 FROM rate_limits WHERE key_name = $1 AND expire_at > NOW()";
+
+const INCREMENT_SQL: &str = r"
+    INSERT INTO rate_limits (key_name, count, expire_at)
+    VALUES ($1, 1, NOW() + make_interval(secs => $2))
+    ON CONFLICT (key_name) DO UPDATE
+    SET count = CASE
+        WHEN rate_limits.expire_at > NOW()
+        THEN rate_limits.count + (rate_limits.count < 9223372036854775807)::int
+        ELSE 1
+        END,
+        expire_at = EXCLUDED.expire_at
+    RETURNING count, expire_at
+";
+
+// Like INCREMENT_SQL for any amount, saturating at i64::MAX
+const INCREMENT_BY_SQL: &str = r"
+    INSERT INTO rate_limits (key_name, count, expire_at)
+    VALUES ($1, $2, NOW() + make_interval(secs => $3))
+    ON CONFLICT (key_name) DO UPDATE
+    SET count = CASE
+        WHEN rate_limits.expire_at <= NOW() THEN EXCLUDED.count
+        WHEN rate_limits.count > 9223372036854775807 - EXCLUDED.count THEN 9223372036854775807
+        ELSE rate_limits.count + EXCLUDED.count
+        END,
+        expire_at = EXCLUDED.expire_at
+    RETURNING count
+";
 
 pub struct PostgresStorage {
-    client: Client,
+    pool: Pool,
+    min_connections: usize,
 }
 
 impl PostgresStorage {
     pub async fn new(connection_str: &str) -> Result<Self, StorageError> {
-        let (client, connection) = tokio_postgres::connect(connection_str, NoTls)
+        Self::with_options(connection_str, SqlPoolOptions::default()).await
+    }
+
+    pub async fn with_options(connection_str: &str, options: SqlPoolOptions) -> Result<Self, StorageError> {
+        let storage = Self {
+            pool: Self::build_pool(connection_str, &options)?,
+            min_connections: options.min_connections,
+        };
+
+        if options.manage_schema {
+            let mut client = storage.client().await?;
+            Self::migrate(&mut client).await?;
+        }
+
+        Ok(storage)
+    }
+
+    /// Connect to a hot standby. Migrations are not run, since a standby
+    /// rejects DDL; only reads should be sent to this storage.
+    pub async fn new_read_only(connection_str: &str) -> Result<Self, StorageError> {
+        let options = SqlPoolOptions::default();
+        let storage = Self {
+            pool: Self::build_pool(connection_str, &options)?,
+            min_connections: options.min_connections,
+        };
+
+        // Fail early on an unreachable replica
+        storage.client().await?;
+
+        Ok(storage)
+    }
+
+    fn build_pool(connection_str: &str, options: &SqlPoolOptions) -> Result<Pool, StorageError> {
+        let mut config: Config = connection_str
+            .parse()
+            .map_err(|e: tokio_postgres::Error| StorageError::ConnectionError(e.to_string()))?;
+        if let Some(timeout) = options.statement_timeout {
+            config.options(&format!("-c statement_timeout={}", timeout.as_millis()));
+        }
+
+        let manager = Manager::from_config(
+            config,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        Pool::builder(manager)
+            .max_size(options.max_connections.max(1))
+            .wait_timeout(Some(options.acquire_timeout))
+            .create_timeout(Some(options.acquire_timeout))
+            .runtime(Runtime::Tokio1)
+            .build()
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))
+    }
+
+    async fn client(&self) -> Result<Object, StorageError> {
+        self.pool
+            .get()
             .await
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))
+    }
 
-        // Handle connection in background
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("PostgreSQL connection error: {}", e);
-            }
-        });
+    /// Count a request and return the new count and when the window ends,
+    /// in a single round trip.
+    pub async fn increment_returning(&self, key: &str, expire: u32) -> Result<(u64, SystemTime), StorageError> {
+        let client = self.client().await?;
+        let statement = client
+            .prepare_cached(INCREMENT_SQL)
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
-        // Create table
-        Self::create_table(&client).await?;
+        let row = client
+            .query_one(&statement, &[&key, &(expire as f64)])
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
-        Ok(Self { client })
+        // BIGINT is signed; the count saturates at i64::MAX above
+        let count: i64 = row.get(0);
+        let expire_at: SystemTime = row.get(1);
+        Ok((count.max(0) as u64, expire_at))
     }
 
-    async fn create_table(client: &Client) -> Result<(), StorageError> {
-        client.batch_execute(
-            r"
-            CREATE TABLE IF NOT EXISTS rate_limits (
-                key_name VARCHAR(255) PRIMARY KEY,
-                count INTEGER NOT NULL DEFAULT 0,
-                expire_at TIMESTAMP WITH TIME ZONE NOT NULL
-            );
-            CREATE INDEX IF NOT EXISTS idx_expire_at ON rate_limits(expire_at);
-            "
-        ).await.map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+    /// Apply pending schema migrations in one transaction. The advisory
+    /// lock makes workers starting together wait for the first one.
+    async fn migrate(client: &mut Object) -> Result<(), StorageError> {
+        let tx = client
+            .transaction()
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
-        Ok(())
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_ID])
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        tx.batch_execute(version_table(SqlDialect::Postgres))
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let current: Option<i32> = tx
+            .query_one(&format!("SELECT MAX(version) FROM {}", SCHEMA_VERSION_TABLE), &[])
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?
+            .get(0);
+
+        for migration in pending(SqlDialect::Postgres, current.unwrap_or(0) as u32) {
+            log::info!("Applying PostgreSQL migration {}: {}", migration.version, migration.description);
+            for statement in migration.statements {
+                tx.batch_execute(statement)
+                    .await
+                    .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            }
+            tx.execute(
+                &format!("INSERT INTO {} (version, description) VALUES ($1, $2)", SCHEMA_VERSION_TABLE),
+                &[&(migration.version as i32), &migration.description],
+            ).await.map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        }
+
+        tx.commit()
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))
     }
 }
 
 #[async_trait]
 impl StorageBackend for PostgresStorage {
-    async fn get(&self, key: &str) -> Result<u32, StorageError> {
-        let row = self.client
-            .query_opt(
-                "SELECT count FROM rate_limits WHERE key_name = $1 AND expire_at > NOW()",
-                &[&key]
-            )
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let client = self.client().await?;
+        let statement = client
+            .prepare_cached(GET_SQL)
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let row = client
+            .query_opt(&statement, &[&key])
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(row.map(|r| r.get::<_, i64>(0).max(0) as u64).unwrap_or(0))
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        let (count, _) = self.increment_returning(key, expire).await?;
+        Ok(Increment { count })
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        if amount == 0 {
+            return self.get(key).await.map(|count| Increment { count });
+        }
+        let client = self.client().await?;
+        let statement = client
+            .prepare_cached(INCREMENT_BY_SQL)
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let amount = amount.min(i64::MAX as u64) as i64;
+        let row = client
+            .query_one(&statement, &[&key, &amount, &(expire as f64)])
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(Increment { count: row.get::<_, i64>(0).max(0) as u64 })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let client = self.client().await?;
+        let statement = client
+            .prepare_cached("DELETE FROM rate_limits WHERE key_name = $1")
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        client
+            .execute(&statement, &[&key])
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Worked out by the database, so the worker's clock does not matter.
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let client = self.client().await?;
+        let statement = client
+            .prepare_cached(TTL_SQL)
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let row = client
+            .query_opt(&statement, &[&key])
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(row.map(|r| Duration::from_secs_f64(r.get::<_, f64>(0).max(0.0))))
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let mut client = self.client().await?;
+        let tx = client
+            .transaction()
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        // Only one instance sweeps at a time; the lock is released on commit
+        let locked: bool = tx
+            .query_one("SELECT pg_try_advisory_xact_lock($1)", &[&CLEANUP_LOCK_ID])
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?
+            .get(0);
+        if !locked {
+            return Ok(0);
+        }
+
+        let removed = tx
+            .execute("DELETE FROM rate_limits WHERE expire_at <= NOW()", &[])
             .await
             .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
-        Ok(row.map(|r| r.get::<_, i32>(0) as u32).unwrap_or(0))
+        tx.commit()
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(removed)
     }
 
-    async fn increment(&mut self, key: &str, expire: u32) -> Result<(), StorageError> {
-        self.client
-            .execute(
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        let clients = try_join_all((0..self.min_connections.max(1)).map(|_| self.client())).await?;
+
+        for client in clients {
+            // Fails if the table is missing or lacks a column
+            client
+                .batch_execute("SELECT key_name, count, expire_at FROM rate_limits LIMIT 0")
+                .await
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            // Statements are cached per connection, so prepare on each
+            for sql in [GET_SQL, INCREMENT_SQL] {
+                client
+                    .prepare_cached(sql)
+                    .await
+                    .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let client = self.client().await?;
+        let statement = client
+            .prepare_cached("SELECT key_name, count FROM rate_limits WHERE key_name = ANY($1) AND expire_at > NOW()")
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let rows = client
+            .query(&statement, &[&keys])
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let counts: HashMap<String, u64> = rows
+            .iter()
+            .map(|row| (row.get(0), row.get::<_, i64>(1).max(0) as u64))
+            .collect();
+        Ok(keys.iter().map(|key| counts.get(*key).copied().unwrap_or(0)).collect())
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        // ON CONFLICT may touch each row only once per statement, so repeated
+        // keys are folded into a single row carrying their number of hits
+        let grouped = group_hits(keys);
+        let names: Vec<&str> = grouped.iter().map(|(key, _, _)| *key).collect();
+        let hits: Vec<i64> = grouped.iter().map(|(_, hits, _)| *hits as i64).collect();
+        let expires: Vec<f64> = grouped.iter().map(|(_, _, expire)| *expire as f64).collect();
+
+        let client = self.client().await?;
+        let statement = client
+            .prepare_cached(
                 r"
                 INSERT INTO rate_limits (key_name, count, expire_at)
-                VALUES ($1, 1, NOW() + ($2 || ' seconds')::INTERVAL)
+                SELECT key_name, hits, NOW() + make_interval(secs => expire)
+                FROM unnest($1::varchar[], $2::bigint[], $3::float8[]) AS batch(key_name, hits, expire)
                 ON CONFLICT (key_name) DO UPDATE
                 SET count = CASE
-                    WHEN rate_limits.expire_at > NOW()
-                    THEN rate_limits.count + 1
-                    ELSE 1
+                    WHEN rate_limits.expire_at <= NOW() THEN EXCLUDED.count
+                    WHEN rate_limits.count > 9223372036854775807 - EXCLUDED.count THEN 9223372036854775807
+                    ELSE rate_limits.count + EXCLUDED.count
                     END,
-                    expire_at = NOW() + ($2 || ' seconds')::INTERVAL
+                    expire_at = EXCLUDED.expire_at
+                RETURNING key_name, count
                 ",
-                &[&key, &(expire as i32)]
             )
             .await
             .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
-        Ok(())
+        let rows = client
+            .query(&statement, &[&names, &hits, &expires])
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        let totals: HashMap<String, u64> = rows
+            .iter()
+            .map(|row| (row.get(0), row.get::<_, i64>(1).max(0) as u64))
+            .collect();
+        Ok(spread_counts(keys, &totals))
     }
 
-    async fn delete(&mut self, key: &str) -> Result<(), StorageError> {
-        self.client
-            .execute(
-                "DELETE FROM rate_limits WHERE key_name = $1",
-                &[&key]
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT key_name, count, EXTRACT(EPOCH FROM expire_at)::bigint FROM rate_limits WHERE expire_at > NOW()",
+                &[],
             )
             .await
             .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
-        Ok(())
+        Ok(rows
+            .iter()
+            .map(|row| CounterSnapshot {
+                key: row.get(0),
+                count: row.get::<_, i64>(1).max(0) as u64,
+                expire_at: row.get::<_, i64>(2).max(0) as u64,
+            })
+            .collect())
     }
 
-    async fn cleanup_expired(&mut self) -> Result<(), StorageError> {
-        self.client
-            .execute(
-                "DELETE FROM rate_limits WHERE expire_at <= NOW()",
-                &[]
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let counters = merge_snapshots(counters, now);
+
+        let client = self.client().await?;
+        let statement = client
+            .prepare_cached(
+                r"
+                INSERT INTO rate_limits (key_name, count, expire_at)
+                SELECT key_name, count, to_timestamp(expire_at)
+                FROM unnest($1::varchar[], $2::bigint[], $3::float8[]) AS batch(key_name, count, expire_at)
+                ON CONFLICT (key_name) DO UPDATE
+                SET count = CASE
+                    WHEN rate_limits.expire_at <= NOW() THEN EXCLUDED.count
+                    WHEN rate_limits.count > 9223372036854775807 - EXCLUDED.count THEN 9223372036854775807
+                    ELSE rate_limits.count + EXCLUDED.count
+                    END,
+                    expire_at = CASE
+                    WHEN rate_limits.expire_at <= NOW() THEN EXCLUDED.expire_at
+                    ELSE GREATEST(rate_limits.expire_at, EXCLUDED.expire_at)
+                    END
+                ",
             )
             .await
             .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
-        Ok(())
+        for batch in counters.chunks(IMPORT_BATCH) {
+            let names: Vec<&str> = batch.iter().map(|counter| counter.key.as_str()).collect();
+            let counts: Vec<i64> = batch.iter().map(|counter| counter.count.min(i64::MAX as u64) as i64).collect();
+            let expires: Vec<f64> = batch.iter().map(|counter| counter.expire_at as f64).collect();
+            client
+                .execute(&statement, &[&names, &counts, &expires])
+                .await
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        }
+
+        Ok(counters.len() as u64)
     }
 }
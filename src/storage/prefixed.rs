@@ -0,0 +1,141 @@
+use async_trait::async_trait;
+use std::time::Duration;
+use crate::storage::{CounterSnapshot, Increment, KeyBuf, StorageBackend, StorageError};
+
+/// Namespace used by `PrefixedStorage::for_zone`.
+pub const DEFAULT_NAMESPACE: &str = "rl";
+
+/// Prefixes every key before handing it to the wrapped backend.
+///
+/// Lets several nginx clusters or limit zones share one Redis or database
+/// without their counters colliding, and makes the module's keys easy to
+/// find (`SCAN MATCH rl:*`). `cleanup_expired` is passed through unchanged
+/// and still sweeps every namespace stored in the backend.
+pub struct PrefixedStorage {
+    inner: Box<dyn StorageBackend>,
+    prefix: String,
+}
+
+impl PrefixedStorage {
+    /// Use `prefix` verbatim, e.g. `"edge-eu:"`.
+    pub fn new(inner: Box<dyn StorageBackend>, prefix: &str) -> Self {
+        Self {
+            inner,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// Keys become `rl:{zone}:{key}`.
+    pub fn for_zone(inner: Box<dyn StorageBackend>, zone: &str) -> Self {
+        Self::new(inner, &format!("{}:{}:", DEFAULT_NAMESPACE, zone))
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn key(&self, key: &str) -> KeyBuf {
+        let mut prefixed = KeyBuf::from(self.prefix.as_str());
+        prefixed.push_str(key);
+        prefixed
+    }
+}
+
+#[async_trait]
+impl StorageBackend for PrefixedStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        self.inner.get(&self.key(key)).await
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        let key = self.key(key);
+        self.inner.increment(&key, expire).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let key = self.key(key);
+        self.inner.delete(&key).await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        self.inner.ttl(&self.key(key)).await
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        self.inner.cleanup_expired().await
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.inner.warm_up().await
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        let prefixed: Vec<KeyBuf> = keys.iter().map(|key| self.key(key)).collect();
+        let prefixed: Vec<&str> = prefixed.iter().map(KeyBuf::as_str).collect();
+        self.inner.get_many(&prefixed).await
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        let prefixed: Vec<(KeyBuf, u32)> = keys.iter().map(|(key, expire)| (self.key(key), *expire)).collect();
+        let prefixed: Vec<(&str, u32)> = prefixed.iter().map(|(key, expire)| (key.as_str(), *expire)).collect();
+        self.inner.increment_many(&prefixed).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        let key = self.key(key);
+        self.inner.increment_by(&key, amount, expire).await
+    }
+
+    async fn add_distinct(&self, key: &str, member: &str, expire: u32) -> Result<Increment, StorageError> {
+        let key = self.key(key);
+        self.inner.add_distinct(&key, member, expire).await
+    }
+
+    /// Only counters under this prefix, with the prefix removed.
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        let counters = self.inner.export_all().await?;
+        Ok(counters
+            .into_iter()
+            .filter_map(|counter| {
+                let key = counter.key.strip_prefix(&self.prefix)?.to_string();
+                Some(CounterSnapshot { key, ..counter })
+            })
+            .collect())
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        let prefixed: Vec<CounterSnapshot> = counters
+            .iter()
+            .map(|counter| CounterSnapshot {
+                key: self.key(&counter.key).to_string(),
+                ..counter.clone()
+            })
+            .collect();
+        self.inner.import_all(&prefixed).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_zones_do_not_collide() {
+        let api = PrefixedStorage::for_zone(Box::new(MemoryStorage::new()), "api");
+        assert_eq!(api.prefix(), "rl:api:");
+        assert_eq!(api.key("10.0.0.1").as_str(), "rl:api:10.0.0.1");
+
+        // Prefixes compose: login keys live under rl:api:login:
+        let nested = PrefixedStorage::new(Box::new(api), "login:");
+        nested.increment("10.0.0.1", 60).await.unwrap();
+        assert_eq!(nested.get("10.0.0.1").await.unwrap(), 1);
+
+        let inner = &nested.inner;
+        assert_eq!(inner.get("login:10.0.0.1").await.unwrap(), 1);
+        assert_eq!(inner.get("10.0.0.1").await.unwrap(), 0);
+
+        nested.delete("10.0.0.1").await.unwrap();
+        assert_eq!(nested.get("10.0.0.1").await.unwrap(), 0);
+    }
+}
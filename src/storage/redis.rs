@@ -1,69 +1,882 @@
 use async_trait::async_trait;
-use redis::{Client, AsyncCommands};
-use crate::storage::{StorageBackend, StorageError};
+use futures_util::future::try_join_all;
+use redis::aio::ConnectionLike;
+use redis::cluster::ClusterClient;
+use redis::{
+    AsyncCommands, Client, ClientTlsConfig, Cmd, IntoConnectionInfo, Pipeline, ProtocolVersion,
+    RedisFuture, Script, TlsCertificates, Value,
+};
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::clock::{self, Clock};
+use crate::storage::redis_sentinel::{SentinelConfig, SentinelMaster};
+use crate::storage::redis_tracking::{
+    ClientCache, ClientCacheOptions, Invalidation, RedisFlavor, Tracking, TrackingMode,
+};
+use crate::storage::{key_tag, CounterSnapshot, Increment, StorageBackend, StorageError};
+
+// INCR and EXPIRE as one script so the update stays atomic on a single slot,
+// which MULTI/EXEC pipelines can't guarantee through the cluster client.
+const INCREMENT_SCRIPT: &str = r"
+local count = redis.call('INCR', KEYS[1])
+redis.call('EXPIRE', KEYS[1], ARGV[1])
+return count
+";
+
+const INCREMENT_BY_SCRIPT: &str = r"
+local count = redis.call('INCRBY', KEYS[1], ARGV[1])
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+return count
+";
+
+// INCR and EXPIRE for several keys of one slot, e.g. the rate, burst and
+// quota counters of one client, in one atomic call on a cluster.
+const INCREMENT_MANY_SCRIPT: &str = r"
+local counts = {}
+for i, key in ipairs(KEYS) do
+  counts[i] = redis.call('INCR', key)
+  redis.call('EXPIRE', key, ARGV[i])
+end
+return counts
+";
+
+// Counts a member once: SADD to the member set, and INCR the counter only
+// for a member the set did not hold. KEYS[2] shares KEYS[1]'s slot.
+const DISTINCT_SCRIPT: &str = r"
+if redis.call('SADD', KEYS[2], ARGV[1]) == 0 then
+  return tonumber(redis.call('GET', KEYS[1]) or '0')
+end
+redis.call('EXPIRE', KEYS[2], ARGV[2])
+local count = redis.call('INCR', KEYS[1])
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+return count
+";
+
+// Adds imported hits to the key and extends its TTL to the imported one if
+// that is longer. TTL is -2 for a missing key and -1 for one without expiry,
+// so both get the imported TTL.
+const IMPORT_SCRIPT: &str = r"
+local ttl = redis.call('TTL', KEYS[1])
+redis.call('INCRBY', KEYS[1], ARGV[1])
+if ttl < tonumber(ARGV[2]) then
+  redis.call('EXPIRE', KEYS[1], ARGV[2])
+end
+return 1
+";
+
+/// Keys fetched per SCAN round trip by `export_all`
+const SCAN_COUNT: usize = 1000;
+
+// Number of times a command is retried after MOVED/ASK redirects or
+// connection errors while the cluster topology is being refreshed.
+const CLUSTER_RETRIES: u32 = 5;
+
+/// Authentication and transport settings shared by every Redis topology.
+///
+/// Credentials set here override any embedded in the URL.
+#[derive(Debug, Clone, Default)]
+pub struct RedisOptions {
+    /// Redis 6 ACL username; `None` authenticates as the `default` user
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Custom certificates for `rediss://` connections
+    pub tls: Option<RedisTlsConfig>,
+    /// Speak RESP3 (`HELLO 3`) instead of RESP2; also enabled by
+    /// `?protocol=resp3` in the URL
+    pub resp3: bool,
+}
+
+/// PEM files used for `rediss://` connections.
+///
+/// Without a CA certificate the system trust store is used. The client
+/// certificate and key enable mutual TLS and must be given together.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RedisTlsConfig {
+    pub ca_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl RedisTlsConfig {
+    pub(crate) fn load(&self) -> Result<TlsCertificates, StorageError> {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).map_err(|e| {
+                StorageError::ConnectionError(format!("failed to read {}: {}", path.display(), e))
+            })
+        };
+
+        let client_tls = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some(ClientTlsConfig {
+                client_cert: read(cert)?,
+                client_key: read(key)?,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(StorageError::ConnectionError(
+                    "client certificate and key must be configured together".to_string(),
+                ))
+            }
+        };
+
+        let root_cert = self.ca_cert.as_ref().map(read).transpose()?;
+
+        Ok(TlsCertificates { client_tls, root_cert })
+    }
+}
+
+enum RedisClient {
+    Single(Client),
+    Cluster(ClusterClient),
+    Sentinel(Arc<SentinelMaster>),
+}
+
+#[derive(Clone)]
+enum RedisConnection {
+    Single(redis::aio::MultiplexedConnection),
+    Cluster(redis::cluster_async::ClusterConnection),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConnection::Single(conn) => conn.req_packed_command(cmd),
+            RedisConnection::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConnection::Single(conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConnection::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Single(conn) => conn.get_db(),
+            RedisConnection::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
+
+/// The connection every operation clones, with the Sentinel generation of
+/// the master it was opened to.
+struct KeptConnection {
+    conn: RedisConnection,
+    generation: u64,
+}
 
 pub struct RedisStorage {
-    client: Client,
+    client: RedisClient,
+    /// Opened on first use and shared by every operation; dropped after an
+    /// I/O error, or when Sentinel reports a new master, and opened again
+    kept: Mutex<Option<KeptConnection>>,
+    /// Held while opening, so concurrent callers wait for one connection
+    opening: tokio::sync::Mutex<()>,
+    increment_script: Script,
+    distinct_script: Script,
+    increment_by_script: Script,
+    increment_many_script: Script,
+    tracking: Option<Tracking>,
+    cache: Option<Arc<ClientCache>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl RedisStorage {
     pub fn new(redis_url: &str) -> Result<Self, StorageError> {
-        let client = Client::open(redis_url)
+        Self::with_options(redis_url, &RedisOptions::default())
+    }
+
+    /// Connect to a single Redis server with explicit credentials and TLS
+    /// settings. `rediss://` URLs use TLS with the system trust store unless
+    /// a CA certificate is configured. A server on the same host can be
+    /// reached over a Unix domain socket with
+    /// `redis+unix:///var/run/redis.sock[?db=N]`.
+    pub fn with_options(redis_url: &str, options: &RedisOptions) -> Result<Self, StorageError> {
+        let mut info = redis_url
+            .into_connection_info()
             .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
-        Ok(Self { client })
+        if options.username.is_some() {
+            info.redis.username = options.username.clone();
+        }
+        if options.password.is_some() {
+            info.redis.password = options.password.clone();
+        }
+        if options.resp3 {
+            info.redis.protocol = ProtocolVersion::RESP3;
+        }
+
+        let client = match &options.tls {
+            Some(tls) => Client::build_with_tls(info, tls.load()?),
+            None => Client::open(info),
+        }
+        .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        Ok(Self {
+            client: RedisClient::Single(client),
+            increment_script: Script::new(INCREMENT_SCRIPT),
+            distinct_script: Script::new(DISTINCT_SCRIPT),
+            increment_by_script: Script::new(INCREMENT_BY_SCRIPT),
+            increment_many_script: Script::new(INCREMENT_MANY_SCRIPT),
+            tracking: None,
+            cache: None,
+            kept: Mutex::new(None),
+            opening: tokio::sync::Mutex::new(()),
+            clock: clock::system(),
+        })
+    }
+
+    /// Connect to a Redis Cluster using the given seed nodes.
+    ///
+    /// Only one reachable seed is needed; the rest of the topology is
+    /// discovered from `CLUSTER SLOTS`. MOVED and ASK redirects are followed
+    /// by the cluster client, refreshing the slot map as needed.
+    pub fn new_cluster<S: AsRef<str>>(seed_nodes: &[S]) -> Result<Self, StorageError> {
+        Self::new_cluster_with_options(seed_nodes, &RedisOptions::default())
+    }
+
+    /// Connect to a Redis Cluster with explicit credentials and TLS settings,
+    /// applied to every node in the cluster.
+    pub fn new_cluster_with_options<S: AsRef<str>>(
+        seed_nodes: &[S],
+        options: &RedisOptions,
+    ) -> Result<Self, StorageError> {
+        if seed_nodes.is_empty() {
+            return Err(StorageError::ConnectionError(
+                "at least one cluster seed node is required".to_string(),
+            ));
+        }
+
+        let nodes: Vec<&str> = seed_nodes.iter().map(|node| node.as_ref()).collect();
+        let mut builder = ClusterClient::builder(nodes).retries(CLUSTER_RETRIES);
+        if let Some(username) = &options.username {
+            builder = builder.username(username.clone());
+        }
+        if let Some(password) = &options.password {
+            builder = builder.password(password.clone());
+        }
+        if let Some(tls) = &options.tls {
+            builder = builder.certs(tls.load()?);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        Ok(Self {
+            client: RedisClient::Cluster(client),
+            increment_script: Script::new(INCREMENT_SCRIPT),
+            distinct_script: Script::new(DISTINCT_SCRIPT),
+            increment_by_script: Script::new(INCREMENT_BY_SCRIPT),
+            increment_many_script: Script::new(INCREMENT_MANY_SCRIPT),
+            tracking: None,
+            cache: None,
+            kept: Mutex::new(None),
+            opening: tokio::sync::Mutex::new(()),
+            clock: clock::system(),
+        })
+    }
+
+    /// Connect to a Redis Cluster from a comma separated seed list,
+    /// e.g. `redis://10.0.0.1:6379,redis://10.0.0.2:6379`.
+    pub fn from_seed_list(seed_list: &str) -> Result<Self, StorageError> {
+        let nodes: Vec<&str> = seed_list
+            .split(',')
+            .map(str::trim)
+            .filter(|node| !node.is_empty())
+            .collect();
+        Self::new_cluster(&nodes)
+    }
+
+    /// Connect through Redis Sentinel using a
+    /// `sentinel://host:port[,host:port...]/master_name[/db]` URL.
+    ///
+    /// The master is looked up through the sentinels and re-resolved after
+    /// a failover, so callers keep working without reconfiguration.
+    pub async fn new_sentinel(sentinel_url: &str) -> Result<Self, StorageError> {
+        Self::new_sentinel_with_options(sentinel_url, &RedisOptions::default()).await
+    }
+
+    /// Connect through Redis Sentinel with explicit credentials and TLS
+    /// settings for the master. With `tls` set the master is reached over
+    /// TLS; the sentinels themselves are still asked in plain text.
+    pub async fn new_sentinel_with_options(
+        sentinel_url: &str,
+        options: &RedisOptions,
+    ) -> Result<Self, StorageError> {
+        let config = SentinelConfig::parse(sentinel_url)?.with_options(options);
+        let master = SentinelMaster::connect(config).await?;
+        Ok(Self {
+            client: RedisClient::Sentinel(master),
+            increment_script: Script::new(INCREMENT_SCRIPT),
+            distinct_script: Script::new(DISTINCT_SCRIPT),
+            increment_by_script: Script::new(INCREMENT_BY_SCRIPT),
+            increment_many_script: Script::new(INCREMENT_MANY_SCRIPT),
+            tracking: None,
+            cache: None,
+            kept: Mutex::new(None),
+            opening: tokio::sync::Mutex::new(()),
+            clock: clock::system(),
+        })
+    }
+
+    /// Read the time from `clock` instead of the system clock, when
+    /// converting between TTLs and the expiry times of `export_all` and
+    /// `import_all`, and for the client cache.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn is_cluster(&self) -> bool {
+        matches!(self.client, RedisClient::Cluster(_))
+    }
+
+    /// Detect whether the server is Redis, KeyDB or Dragonfly.
+    pub async fn flavor(&self) -> Result<RedisFlavor, StorageError> {
+        let mut conn = self.connection().await?;
+        let info: String = redis::cmd("INFO")
+            .arg("server")
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| self.read_error(e))?;
+        Ok(RedisFlavor::detect(&info))
+    }
+
+    /// Enable RESP3 client tracking and return the stream of invalidations,
+    /// so a local cache can drop counters another instance has changed.
+    ///
+    /// On Redis and KeyDB this uses `BCAST` mode limited to `prefixes`
+    /// (all keys when empty). Dragonfly lacks `BCAST`, so there reads are
+    /// routed through the tracking connection and only keys this instance
+    /// has read are reported. Only standalone servers are supported.
+    pub async fn track_invalidations(
+        &mut self,
+        prefixes: &[&str],
+    ) -> Result<mpsc::UnboundedReceiver<Invalidation>, StorageError> {
+        let client = match &self.client {
+            RedisClient::Single(client) => client,
+            _ => {
+                return Err(StorageError::ConnectionError(
+                    "client tracking requires a standalone Redis connection".to_string(),
+                ))
+            }
+        };
+
+        let flavor = self.flavor().await?;
+        let (tracking, invalidations) = Tracking::enable(client, flavor, prefixes).await?;
+        self.tracking = Some(tracking);
+        Ok(invalidations)
+    }
+
+    /// Cache counters read with `get` and `get_many` locally, dropping them
+    /// as the server's invalidation pushes report them changed, so keys
+    /// read over and over, such as hot keys checked before counting, cost
+    /// one round trip per change instead of one per read.
+    ///
+    /// Tracking is enabled as with `track_invalidations`. If the tracking
+    /// connection is lost the cache is emptied and reads go to the server
+    /// again. Only standalone servers are supported.
+    pub async fn enable_client_cache(&mut self, options: ClientCacheOptions) -> Result<(), StorageError> {
+        let prefixes: Vec<&str> = options.prefixes.iter().map(String::as_str).collect();
+        let mut invalidations = self.track_invalidations(&prefixes).await?;
+        let cache = Arc::new(ClientCache::new(options).with_clock(Arc::clone(&self.clock)));
+
+        let watched = Arc::downgrade(&cache);
+        tokio::spawn(async move {
+            while let Some(invalidation) = invalidations.recv().await {
+                let Some(cache) = watched.upgrade() else { return };
+                cache.invalidate(invalidation);
+            }
+            if let Some(cache) = watched.upgrade() {
+                log::warn!("Redis tracking connection lost, client-side caching disabled");
+                cache.disable();
+            }
+        });
+        self.cache = Some(cache);
+        Ok(())
+    }
+
+    /// The client cache, while invalidations still arrive.
+    fn cache(&self) -> Option<&ClientCache> {
+        self.cache.as_deref().filter(|cache| cache.is_enabled())
+    }
+
+    /// Drop the cached count of a key this node is writing.
+    fn forget(&self, key: &str) {
+        if let Some(cache) = self.cache() {
+            cache.forget(key);
+        }
+    }
+
+    /// The kept connection, opened if there is none yet or it was opened
+    /// to a master Sentinel has since replaced. Both kinds multiplex
+    /// concurrent requests, so callers get a clone of the same one.
+    async fn connection(&self) -> Result<RedisConnection, StorageError> {
+        if let Some(conn) = self.kept_connection() {
+            return Ok(conn);
+        }
+        let _opening = self.opening.lock().await;
+        // Another caller may have opened it while we waited
+        if let Some(conn) = self.kept_connection() {
+            return Ok(conn);
+        }
+
+        let (conn, generation) = self.open().await?;
+        *self.kept.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(KeptConnection { conn: conn.clone(), generation });
+        Ok(conn)
+    }
+
+    fn kept_connection(&self) -> Option<RedisConnection> {
+        let generation = self.generation();
+        let kept = self.kept.lock().unwrap_or_else(PoisonError::into_inner);
+        kept.as_ref()
+            .filter(|kept| kept.generation == generation)
+            .map(|kept| kept.conn.clone())
+    }
+
+    /// The Sentinel generation of the master; always 0 without Sentinel.
+    fn generation(&self) -> u64 {
+        match &self.client {
+            RedisClient::Sentinel(sentinel) => sentinel.generation(),
+            _ => 0,
+        }
+    }
+
+    async fn open(&self) -> Result<(RedisConnection, u64), StorageError> {
+        match &self.client {
+            RedisClient::Single(client) => client
+                .get_multiplexed_async_connection()
+                .await
+                .map(|conn| (RedisConnection::Single(conn), 0))
+                .map_err(|e| StorageError::ConnectionError(e.to_string())),
+            RedisClient::Sentinel(sentinel) => {
+                let (master, generation) = sentinel.current();
+                match master.get_multiplexed_async_connection().await {
+                    Ok(conn) => Ok((RedisConnection::Single(conn), generation)),
+                    Err(_) => {
+                        // The master may have moved; ask the sentinels and retry once
+                        let master = sentinel.refresh().await?;
+                        let generation = sentinel.generation();
+                        master
+                            .get_multiplexed_async_connection()
+                            .await
+                            .map(|conn| (RedisConnection::Single(conn), generation))
+                            .map_err(|e| StorageError::ConnectionError(e.to_string()))
+                    }
+                }
+            }
+            RedisClient::Cluster(client) => client
+                .get_async_connection()
+                .await
+                .map(|conn| (RedisConnection::Cluster(conn), 0))
+                .map_err(|e| StorageError::ConnectionError(e.to_string())),
+        }
+    }
+
+    /// Drop the kept connection if `e` means it is broken, so the next
+    /// operation opens a new one.
+    fn discard_broken(&self, e: &redis::RedisError) {
+        if e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout() {
+            self.kept.lock().unwrap_or_else(PoisonError::into_inner).take();
+        }
+    }
+
+    /// Convert a failed read into a `StorageError`.
+    fn read_error(&self, e: redis::RedisError) -> StorageError {
+        self.discard_broken(&e);
+        StorageError::DatabaseError(e.to_string())
+    }
+
+    /// Convert a failed write into a `StorageError`.
+    ///
+    /// A READONLY reply means we are still talking to a demoted master, so
+    /// the Sentinel view is refreshed before the next request; if that finds
+    /// a new master, the next request opens a connection to it.
+    async fn write_error(&self, e: redis::RedisError) -> StorageError {
+        self.discard_broken(&e);
+        if let RedisClient::Sentinel(sentinel) = &self.client {
+            if e.kind() == redis::ErrorKind::ReadOnly || e.is_connection_dropped() {
+                if let Err(refresh_err) = sentinel.refresh().await {
+                    log::warn!("Failed to refresh Sentinel master: {}", refresh_err);
+                }
+            }
+        }
+        StorageError::DatabaseError(e.to_string())
+    }
+}
+
+/// Return the key with a `{hash tag}` so that every key derived from it
+/// hashes to the same cluster slot.
+///
+/// Keys that already carry a non-empty hash tag are returned unchanged.
+pub fn hash_tag(key: &str) -> Cow<'_, str> {
+    match key_tag(key) {
+        Some(_) => Cow::Borrowed(key),
+        None => Cow::Owned(format!("{{{}}}", key)),
     }
 }
 
+/// Whether every key is known to hash to one cluster slot: they are all
+/// the same key, or all carry the same hash tag.
+fn same_slot<'a>(mut keys: impl Iterator<Item = &'a str>) -> bool {
+    let Some(first) = keys.next() else { return true };
+    let tag = key_tag(first);
+    keys.all(|key| key == first || (tag.is_some() && key_tag(key) == tag))
+}
+
+/// Build a key that lives in the same slot as `key`, for multi-key scripts.
+pub fn slot_key(key: &str, suffix: &str) -> String {
+    format!("{}:{}", hash_tag(key), suffix)
+}
+
 #[async_trait]
 impl StorageBackend for RedisStorage {
-    async fn get(&self, key: &str) -> Result<u32, StorageError> {
-        let mut conn = self.client
-            .get_async_connection()
-            .await
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let cache = self.cache();
+        if let Some(cache) = cache {
+            if let Some(count) = cache.get(key) {
+                return Ok(count);
+            }
+            cache.start_read(key);
+        }
 
-        let count: Option<u32> = conn.get(key)
-            .await
-            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        let count: Option<u64> = match &self.tracking {
+            // In per-connection tracking mode a key is only tracked once it
+            // has been read over the tracking connection
+            Some(tracking) if tracking.mode == TrackingMode::ReadKeys => {
+                let mut conn = tracking.connection.clone();
+                conn.get(key).await
+            }
+            _ => {
+                let mut conn = self.connection().await?;
+                conn.get(key).await
+            }
+        }
+        .map_err(|e| self.read_error(e))?;
 
-        Ok(count.unwrap_or(0))
+        let count = count.unwrap_or(0);
+        if let Some(cache) = cache {
+            cache.finish_read(key, count);
+        }
+        Ok(count)
     }
 
-    async fn increment(&mut self, key: &str, expire: u32) -> Result<(), StorageError> {
-        let mut conn = self.client
-            .get_async_connection()
-            .await
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.forget(key);
+        let mut conn = self.connection().await?;
 
-        // Execute increment and expiration setting using multi command
-        let mut pipe = redis::pipe();
-        pipe.atomic()
-            .incr(key, 1_u32)
-            .expire(key, expire as usize);
+        let result: Result<u64, _> = self.increment_script
+            .key(key)
+            .arg(expire)
+            .invoke_async(&mut conn)
+            .await;
+
+        match result {
+            Ok(count) => Ok(Increment { count }),
+            Err(e) => Err(self.write_error(e).await),
+        }
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        self.forget(key);
+        let mut conn = self.connection().await?;
+
+        let result: Result<u64, _> = self.increment_by_script
+            .key(key)
+            .arg(amount)
+            .arg(expire)
+            .invoke_async(&mut conn)
+            .await;
+
+        match result {
+            Ok(count) => Ok(Increment { count }),
+            Err(e) => Err(self.write_error(e).await),
+        }
+    }
+
+    /// Members go in a set next to the counter, `{key}:ids`, that expires
+    /// with it.
+    async fn add_distinct(&self, key: &str, member: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.forget(key);
+        let mut conn = self.connection().await?;
+
+        let result: Result<u64, _> = self.distinct_script
+            .key(key)
+            .key(slot_key(key, "ids"))
+            .arg(member)
+            .arg(expire)
+            .invoke_async(&mut conn)
+            .await;
+
+        match result {
+            Ok(count) => Ok(Increment { count }),
+            Err(e) => Err(self.write_error(e).await),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.forget(key);
+        let mut conn = self.connection().await?;
+
+        let result: Result<(), _> = conn.del(key).await;
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.write_error(e).await),
+        }
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        // Redis automatically removes expired keys,
+        // so no special implementation is needed
+        Ok(0)
+    }
 
-        pipe.query_async(&mut conn)
+    /// PTTL, so the answer has millisecond precision. A key without an
+    /// expiry, which the module never writes, counts as missing.
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let mut conn = self.connection().await?;
+        let millis: i64 = conn
+            .pttl(key)
             .await
-            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            .map_err(|e| self.read_error(e))?;
+
+        Ok((millis > 0).then(|| Duration::from_millis(millis as u64)))
+    }
+
+    /// Opens the connection requests will share and checks it with a PING,
+    /// so the first request finds it ready. A connection that fails the
+    /// check is not kept.
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        let mut conn = self.connection().await?;
+        if let Err(e) = redis::cmd("PING").query_async::<()>(&mut conn).await {
+            self.kept.lock().unwrap_or_else(PoisonError::into_inner).take();
+            return Err(StorageError::ConnectionError(e.to_string()));
+        }
+
+        // Load the scripts up front so the first request does not pay for
+        // the NOSCRIPT fallback; on a cluster this reaches every primary
+        for script in [
+            &self.increment_script,
+            &self.increment_by_script,
+            &self.increment_many_script,
+            &self.distinct_script,
+        ] {
+            script
+                .prepare_invoke()
+                .load_async(&mut conn)
+                .await
+                .map_err(|e| self.read_error(e))?;
+        }
 
         Ok(())
     }
 
-    async fn delete(&mut self, key: &str) -> Result<(), StorageError> {
-        let mut conn = self.client
-            .get_async_connection()
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // MGET needs every key in one slot, tracked reads must go over the
+        // tracking connection and cached reads through the cache; all fall
+        // back to concurrent GETs
+        let read_tracking = matches!(&self.tracking, Some(tracking) if tracking.mode == TrackingMode::ReadKeys);
+        let spread = self.is_cluster() && !same_slot(keys.iter().copied());
+        if spread || read_tracking || self.cache().is_some() {
+            return try_join_all(keys.iter().map(|key| self.get(key))).await;
+        }
+
+        let mut conn = self.connection().await?;
+        let counts: Vec<Option<u64>> = redis::cmd("MGET")
+            .arg(keys)
+            .query_async(&mut conn)
             .await
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+            .map_err(|e| self.read_error(e))?;
+
+        Ok(counts.into_iter().map(|count| count.unwrap_or(0)).collect())
+    }
 
-        conn.del(key)
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        for (key, _) in keys {
+            self.forget(key);
+        }
+        let mut conn = self.connection().await?;
+
+        let result: Result<Vec<u64>, _> = if self.is_cluster() && same_slot(keys.iter().map(|(key, _)| *key)) {
+            // One slot, so one atomic script call on the node owning it
+            let mut invocation = self.increment_many_script.prepare_invoke();
+            for (key, expire) in keys {
+                invocation.key(*key).arg(*expire);
+            }
+            invocation.invoke_async(&mut conn).await
+        } else if self.is_cluster() {
+            // Keys hash to different nodes, so run the script per key and
+            // let the cluster connection fan the calls out concurrently
+            try_join_all(keys.iter().map(|(key, expire)| {
+                let mut conn = conn.clone();
+                let mut invocation = self.increment_script.key(*key);
+                invocation.arg(*expire);
+                async move { invocation.invoke_async::<u64>(&mut conn).await }
+            }))
             .await
-            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        } else {
+            // A single MULTI/EXEC round trip; INCR replies come back in order
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for (key, expire) in keys {
+                pipe.incr(*key, 1u64).expire(*key, *expire as i64).ignore();
+            }
+            pipe.query_async(&mut conn).await
+        };
 
-        Ok(())
+        match result {
+            Ok(counts) => Ok(counts.into_iter().map(|count| Increment { count }).collect()),
+            Err(e) => Err(self.write_error(e).await),
+        }
     }
 
-    async fn cleanup_expired(&mut self) -> Result<(), StorageError> {
-        // Redis automatically removes expired keys,
-        // so no special implementation is needed
-        Ok(())
+    /// Scans every string key with a TTL whose value is a count. Keys of
+    /// other applications in the same database are skipped as long as they
+    /// are not plain integers with an expiry; use a dedicated database or
+    /// `PrefixedStorage` to be sure. Not supported on Redis Cluster.
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        if self.is_cluster() {
+            return Err(StorageError::Unsupported("export_all on Redis Cluster".to_string()));
+        }
+        let mut conn = self.connection().await?;
+        let now = self.clock.unix_secs();
+
+        let mut counters = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .arg("TYPE")
+                .arg("string")
+                .query_async(&mut conn)
+                .await
+                .map_err(|e| self.read_error(e))?;
+
+            if !keys.is_empty() {
+                let mut pipe = redis::pipe();
+                for key in &keys {
+                    pipe.get(key).ttl(key);
+                }
+                let values: Vec<(Option<String>, i64)> = pipe
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(|e| self.read_error(e))?;
+
+                for (key, (value, ttl)) in keys.into_iter().zip(values) {
+                    let count = value.and_then(|value| value.parse::<u64>().ok());
+                    if let (Some(count), true) = (count, ttl > 0) {
+                        counters.push(CounterSnapshot { key, count, expire_at: now + ttl as u64 });
+                    }
+                }
+            }
+
+            cursor = next;
+            if cursor == 0 {
+                return Ok(counters);
+            }
+        }
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        let now = self.clock.unix_secs();
+        let live: Vec<&CounterSnapshot> = counters.iter().filter(|counter| counter.expire_at > now).collect();
+        if live.is_empty() {
+            return Ok(0);
+        }
+        for counter in &live {
+            self.forget(&counter.key);
+        }
+        let conn = self.connection().await?;
+        let script = Script::new(IMPORT_SCRIPT);
+
+        // Concurrent calls share the multiplexed (or cluster) connection
+        let result: Result<Vec<u64>, _> = try_join_all(live.iter().map(|counter| {
+            let mut conn = conn.clone();
+            let mut invocation = script.key(&counter.key);
+            invocation.arg(counter.count).arg(counter.expire_at - now);
+            async move { invocation.invoke_async::<u64>(&mut conn).await }
+        }))
+        .await;
+
+        match result {
+            Ok(_) => Ok(live.len() as u64),
+            Err(e) => Err(self.write_error(e).await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_tag() {
+        assert_eq!(hash_tag("192.168.0.1"), "{192.168.0.1}");
+        assert_eq!(hash_tag("rl:{192.168.0.1}:count"), "rl:{192.168.0.1}:count");
+        // An empty tag is ignored by Redis, so the key must be wrapped
+        assert_eq!(hash_tag("rl:{}:count"), "{rl:{}:count}");
+        assert_eq!(slot_key("10.0.0.1", "ban"), "{10.0.0.1}:ban");
+    }
+
+    #[test]
+    fn test_unix_socket_urls() {
+        for url in ["redis+unix:///var/run/redis.sock", "unix:///var/run/redis/redis.sock?db=2"] {
+            match RedisStorage::new(url).unwrap().client {
+                RedisClient::Single(client) => {
+                    assert!(matches!(client.get_connection_info().addr, redis::ConnectionAddr::Unix(_)))
+                }
+                _ => panic!("{} is not a single server", url),
+            }
+        }
+    }
+
+    #[test]
+    fn test_same_slot() {
+        assert!(same_slot(["plan:{k_live}:quota", "plan:{k_live}:rate"].into_iter()));
+        assert!(same_slot(["10.0.0.1", "10.0.0.1"].into_iter()));
+        assert!(!same_slot(["plan:{k_live}:quota", "plan:{k_test}:rate"].into_iter()));
+        assert!(!same_slot(["10.0.0.1", "10.0.0.2"].into_iter()));
+    }
+
+    #[test]
+    fn test_from_seed_list_rejects_empty() {
+        assert!(RedisStorage::from_seed_list(" , ").is_err());
+    }
+
+    #[test]
+    fn test_tls_requires_rediss_scheme() {
+        let options = RedisOptions {
+            tls: Some(RedisTlsConfig::default()),
+            ..Default::default()
+        };
+        assert!(RedisStorage::with_options("redis://127.0.0.1/", &options).is_err());
+    }
+
+    #[test]
+    fn test_tls_client_cert_requires_key() {
+        let tls = RedisTlsConfig {
+            client_cert: Some(PathBuf::from("/etc/redis/client.crt")),
+            ..Default::default()
+        };
+        assert!(tls.load().is_err());
     }
 }
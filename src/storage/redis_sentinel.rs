@@ -0,0 +1,325 @@
+use futures_util::StreamExt;
+use redis::sentinel::{Sentinel, SentinelNodeConnectionInfo};
+use redis::{Client, ConnectionAddr, ConnectionInfo, ProtocolVersion, RedisConnectionInfo, TlsMode};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use crate::storage::{RedisOptions, RedisTlsConfig, StorageError};
+
+const SWITCH_MASTER_CHANNEL: &str = "+switch-master";
+const WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connection settings parsed from a `sentinel://` URL.
+///
+/// Format: `sentinel://[[username]:password@]host:port[,host:port...]/master_name[/db]`.
+/// The credentials, database and TLS settings apply to the master, not to
+/// the sentinels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentinelConfig {
+    pub sentinels: Vec<String>,
+    pub master_name: String,
+    pub db: i64,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Connect to the master over TLS with these certificates
+    pub tls: Option<RedisTlsConfig>,
+    pub resp3: bool,
+}
+
+impl SentinelConfig {
+    pub fn parse(url: &str) -> Result<Self, StorageError> {
+        let invalid = |reason: &str| {
+            StorageError::ConnectionError(format!("invalid sentinel url '{}': {}", url, reason))
+        };
+
+        let rest = url
+            .strip_prefix("sentinel://")
+            .ok_or_else(|| invalid("expected sentinel:// scheme"))?;
+
+        let (userinfo, rest) = match rest.rsplit_once('@') {
+            Some((userinfo, rest)) => (Some(userinfo), rest),
+            None => (None, rest),
+        };
+        let (username, password) = match userinfo {
+            Some(userinfo) => match userinfo.split_once(':') {
+                Some((user, pass)) => (
+                    Some(user.to_string()).filter(|u| !u.is_empty()),
+                    Some(pass.to_string()),
+                ),
+                None => (Some(userinfo.to_string()), None),
+            },
+            None => (None, None),
+        };
+
+        let (hosts, path) = rest
+            .split_once('/')
+            .ok_or_else(|| invalid("missing master name"))?;
+
+        let sentinels: Vec<String> = hosts
+            .split(',')
+            .map(str::trim)
+            .filter(|host| !host.is_empty())
+            .map(|host| format!("redis://{}", host))
+            .collect();
+        if sentinels.is_empty() {
+            return Err(invalid("at least one sentinel is required"));
+        }
+
+        let mut segments = path.split('/');
+        let master_name = segments
+            .next()
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| invalid("missing master name"))?
+            .to_string();
+        let db = match segments.next().filter(|db| !db.is_empty()) {
+            Some(db) => db.parse().map_err(|_| invalid("database must be a number"))?,
+            None => 0,
+        };
+
+        Ok(Self {
+            sentinels,
+            master_name,
+            db,
+            username,
+            password,
+            tls: None,
+            resp3: false,
+        })
+    }
+
+    /// Apply `options`; credentials set there override those in the URL.
+    pub fn with_options(mut self, options: &RedisOptions) -> Self {
+        if options.username.is_some() {
+            self.username = options.username.clone();
+        }
+        if options.password.is_some() {
+            self.password = options.password.clone();
+        }
+        self.tls = options.tls.clone();
+        self.resp3 = options.resp3;
+        self
+    }
+
+    fn redis_connection_info(&self) -> RedisConnectionInfo {
+        RedisConnectionInfo {
+            db: self.db,
+            username: self.username.clone(),
+            password: self.password.clone(),
+            protocol: if self.resp3 { ProtocolVersion::RESP3 } else { ProtocolVersion::RESP2 },
+        }
+    }
+
+    /// A client for the master at `info`, with the configured certificates.
+    fn master_client(&self, info: ConnectionInfo) -> Result<Client, StorageError> {
+        match &self.tls {
+            Some(tls) => Client::build_with_tls(info, tls.load()?),
+            None => Client::open(info),
+        }
+        .map_err(|e| StorageError::ConnectionError(e.to_string()))
+    }
+}
+
+/// Tracks the current master of a Sentinel-managed deployment.
+///
+/// The master is resolved through the sentinels on startup and whenever a
+/// connection to it fails. A background task also listens for
+/// `+switch-master` notifications so failovers are picked up before the old
+/// master starts refusing writes; it is aborted, closing its subscription,
+/// once the `SentinelMaster` is dropped.
+pub struct SentinelMaster {
+    config: SentinelConfig,
+    sentinel: Mutex<Sentinel>,
+    master: Arc<RwLock<Current>>,
+    watcher: JoinHandle<()>,
+}
+
+/// The master's client, and how many times the master has moved, so
+/// connections opened to an old master can be told apart.
+struct Current {
+    client: Client,
+    generation: u64,
+}
+
+impl Current {
+    /// Switch to `client`, counting a new generation if it is at another
+    /// address.
+    fn switch(lock: &RwLock<Current>, client: Client) {
+        let mut current = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if current.client.get_connection_info().addr != client.get_connection_info().addr {
+            current.generation += 1;
+        }
+        current.client = client;
+    }
+}
+
+impl SentinelMaster {
+    pub async fn connect(config: SentinelConfig) -> Result<Arc<Self>, StorageError> {
+        let mut sentinel = Sentinel::build(config.sentinels.clone())
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        let master = Self::resolve(&mut sentinel, &config).await?;
+
+        let master = Arc::new(RwLock::new(Current { client: master, generation: 0 }));
+        let watcher = tokio::spawn(Self::watch_failover(config.clone(), Arc::clone(&master)));
+
+        Ok(Arc::new(Self {
+            config,
+            sentinel: Mutex::new(sentinel),
+            master,
+            watcher,
+        }))
+    }
+
+    /// The client for the master as currently known.
+    pub fn master(&self) -> Client {
+        self.current().0
+    }
+
+    /// The client for the master as currently known, and its generation.
+    pub fn current(&self) -> (Client, u64) {
+        let current = self.master.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        (current.client.clone(), current.generation)
+    }
+
+    /// Bumped whenever the master moves to another address, by a refresh
+    /// or a `+switch-master` notification.
+    pub fn generation(&self) -> u64 {
+        self.master.read().unwrap_or_else(|poisoned| poisoned.into_inner()).generation
+    }
+
+    /// Ask the sentinels for the current master and switch to it.
+    pub async fn refresh(&self) -> Result<Client, StorageError> {
+        let mut sentinel = self.sentinel.lock().await;
+        let master = Self::resolve(&mut sentinel, &self.config).await?;
+        Current::switch(&self.master, master.clone());
+        Ok(master)
+    }
+
+    async fn resolve(sentinel: &mut Sentinel, config: &SentinelConfig) -> Result<Client, StorageError> {
+        let node_info = SentinelNodeConnectionInfo {
+            tls_mode: config.tls.as_ref().map(|_| TlsMode::Secure),
+            redis_connection_info: Some(config.redis_connection_info()),
+        };
+
+        let master = sentinel
+            .async_master_for(&config.master_name, Some(&node_info))
+            .await
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+        // The sentinel only knows the address; the certificates are ours
+        match config.tls {
+            Some(_) => config.master_client(master.get_connection_info().clone()),
+            None => Ok(master),
+        }
+    }
+
+    async fn watch_failover(config: SentinelConfig, master: Arc<RwLock<Current>>) {
+        loop {
+            for sentinel_url in &config.sentinels {
+                if let Err(e) = Self::listen(sentinel_url, &config, &master).await {
+                    log::warn!("Sentinel {} subscription lost: {}", sentinel_url, e);
+                }
+            }
+            tokio::time::sleep(WATCH_RETRY_INTERVAL).await;
+        }
+    }
+
+    async fn listen(
+        sentinel_url: &str,
+        config: &SentinelConfig,
+        master: &RwLock<Current>,
+    ) -> Result<(), redis::RedisError> {
+        let client = Client::open(sentinel_url)?;
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(SWITCH_MASTER_CHANNEL).await?;
+
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let payload: String = msg.get_payload()?;
+            if let Some((host, port)) = parse_switch_master(&payload, &config.master_name) {
+                log::info!(
+                    "Sentinel failover for {}: new master {}:{}",
+                    config.master_name, host, port
+                );
+                let addr = match config.tls {
+                    Some(_) => ConnectionAddr::TcpTls { host, port, insecure: false, tls_params: None },
+                    None => ConnectionAddr::Tcp(host, port),
+                };
+                let info = ConnectionInfo {
+                    addr,
+                    redis: config.redis_connection_info(),
+                };
+                match config.master_client(info) {
+                    Ok(client) => Current::switch(master, client),
+                    Err(e) => log::warn!("Cannot connect to new master of {}: {}", config.master_name, e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for SentinelMaster {
+    fn drop(&mut self) {
+        self.watcher.abort();
+    }
+}
+
+/// Parse a `+switch-master` payload: `<master name> <old ip> <old port> <new ip> <new port>`.
+fn parse_switch_master(payload: &str, master_name: &str) -> Option<(String, u16)> {
+    let fields: Vec<&str> = payload.split_whitespace().collect();
+    match fields.as_slice() {
+        [name, _, _, new_ip, new_port] if *name == master_name => {
+            Some((new_ip.to_string(), new_port.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sentinel_url() {
+        let config = SentinelConfig::parse("sentinel://:secret@10.0.0.1:26379,10.0.0.2:26379/mymaster/2").unwrap();
+        assert_eq!(config.sentinels, vec!["redis://10.0.0.1:26379", "redis://10.0.0.2:26379"]);
+        assert_eq!(config.master_name, "mymaster");
+        assert_eq!(config.db, 2);
+        assert_eq!(config.username, None);
+        assert_eq!(config.password.as_deref(), Some("secret"));
+        assert_eq!(config.tls, None);
+
+        assert!(SentinelConfig::parse("redis://10.0.0.1/mymaster").is_err());
+        assert!(SentinelConfig::parse("sentinel://10.0.0.1:26379").is_err());
+        assert!(SentinelConfig::parse("sentinel://10.0.0.1:26379/").is_err());
+    }
+
+    #[test]
+    fn test_options_override_url() {
+        let options = RedisOptions {
+            username: Some("limiter".to_string()),
+            tls: Some(RedisTlsConfig::default()),
+            resp3: true,
+            ..RedisOptions::default()
+        };
+        let config = SentinelConfig::parse("sentinel://:secret@10.0.0.1:26379/mymaster")
+            .unwrap()
+            .with_options(&options);
+        assert_eq!(config.username.as_deref(), Some("limiter"));
+        assert_eq!(config.password.as_deref(), Some("secret"));
+        assert_eq!(config.tls, Some(RedisTlsConfig::default()));
+        assert_eq!(config.redis_connection_info().protocol, ProtocolVersion::RESP3);
+    }
+
+    #[test]
+    fn test_parse_switch_master() {
+        assert_eq!(
+            parse_switch_master("mymaster 10.0.0.5 6379 10.0.0.6 6380", "mymaster"),
+            Some(("10.0.0.6".to_string(), 6380))
+        );
+        assert_eq!(parse_switch_master("other 10.0.0.5 6379 10.0.0.6 6380", "mymaster"), None);
+        assert_eq!(parse_switch_master("mymaster garbage", "mymaster"), None);
+    }
+}
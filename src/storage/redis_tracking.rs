@@ -0,0 +1,312 @@
+use dashmap::DashMap;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncConnectionConfig, Client, ProtocolVersion, PushInfo, PushKind, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::clock::{self, Clock};
+use crate::storage::StorageError;
+
+/// Server implementation behind a Redis-protocol endpoint.
+///
+/// KeyDB and Dragonfly both speak RESP2/RESP3 but differ in a few places
+/// that matter here; the flavor is detected from `INFO server`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedisFlavor {
+    Redis,
+    KeyDB,
+    /// Dragonfly only supports the default (per-connection) tracking mode,
+    /// not `BCAST`, so tracked keys must be read through the tracking
+    /// connection.
+    Dragonfly,
+}
+
+impl RedisFlavor {
+    pub fn detect(info: &str) -> Self {
+        if info.contains("dragonfly_version:") {
+            RedisFlavor::Dragonfly
+        } else if info.to_ascii_lowercase().contains("keydb") {
+            RedisFlavor::KeyDB
+        } else {
+            RedisFlavor::Redis
+        }
+    }
+
+    fn supports_broadcast_tracking(self) -> bool {
+        !matches!(self, RedisFlavor::Dragonfly)
+    }
+}
+
+/// A change notification delivered through RESP3 client tracking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+    /// These keys were modified or deleted by some client
+    Keys(Vec<String>),
+    /// The server was flushed or tracking was reset; drop everything
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TrackingMode {
+    /// `BCAST`: the server reports every change matching the prefixes
+    Broadcast,
+    /// Default mode: only keys read over the tracking connection are reported
+    ReadKeys,
+}
+
+/// RESP3 connection with `CLIENT TRACKING` enabled.
+pub(crate) struct Tracking {
+    pub(crate) connection: MultiplexedConnection,
+    pub(crate) mode: TrackingMode,
+}
+
+impl Tracking {
+    pub(crate) async fn enable(
+        client: &Client,
+        flavor: RedisFlavor,
+        prefixes: &[&str],
+    ) -> Result<(Self, mpsc::UnboundedReceiver<Invalidation>), StorageError> {
+        // Push messages need RESP3, whatever the storage connections use
+        let mut info = client.get_connection_info().clone();
+        info.redis.protocol = ProtocolVersion::RESP3;
+        let client = Client::open(info)
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let config = AsyncConnectionConfig::new().set_push_sender(move |push: PushInfo| {
+            match parse_invalidation(push) {
+                Some(invalidation) => tx.send(invalidation).map_err(|_| redis::aio::SendError),
+                None => Ok(()),
+            }
+        });
+
+        let mut connection = client
+            .get_multiplexed_async_connection_with_config(&config)
+            .await
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        let mode = if flavor.supports_broadcast_tracking() {
+            TrackingMode::Broadcast
+        } else {
+            TrackingMode::ReadKeys
+        };
+
+        let mut cmd = redis::cmd("CLIENT");
+        cmd.arg("TRACKING").arg("ON");
+        if mode == TrackingMode::Broadcast {
+            cmd.arg("BCAST");
+            for prefix in prefixes {
+                cmd.arg("PREFIX").arg(*prefix);
+            }
+        }
+        cmd.query_async::<()>(&mut connection)
+            .await
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok((Self { connection, mode }, rx))
+    }
+}
+
+/// Settings of the local counter cache kept with client tracking.
+#[derive(Debug, Clone)]
+pub struct ClientCacheOptions {
+    /// Key prefixes tracked; all keys when empty
+    pub prefixes: Vec<String>,
+    /// Counters cached at once; further reads are not cached
+    pub max_entries: usize,
+    /// How long a counter is served without an invalidation, a backstop
+    /// for a push that never arrives
+    pub ttl: Duration,
+}
+
+impl Default for ClientCacheOptions {
+    fn default() -> Self {
+        Self {
+            prefixes: Vec::new(),
+            max_entries: 10_000,
+            ttl: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    /// A read is in flight; an invalidation removes the slot, so the value
+    /// it returns is not cached
+    Reading,
+    Cached { count: u64, expires: Duration },
+}
+
+/// Counters read from Redis, kept until the server reports them changed.
+#[derive(Debug)]
+pub(crate) struct ClientCache {
+    slots: DashMap<String, Slot>,
+    options: ClientCacheOptions,
+    /// Cleared when the tracking connection is lost, as invalidations stop
+    enabled: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+impl ClientCache {
+    pub(crate) fn new(options: ClientCacheOptions) -> Self {
+        Self {
+            slots: DashMap::new(),
+            options,
+            enabled: AtomicBool::new(true),
+            clock: clock::system(),
+        }
+    }
+
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<u64> {
+        match *self.slots.get(key)? {
+            Slot::Cached { count, expires } if expires > self.clock.now() => {
+                metrics::counter!("rate_limiter_redis_cache_hits_total").increment(1);
+                Some(count)
+            }
+            _ => None,
+        }
+    }
+
+    /// Note that `key` is about to be read from the server.
+    pub(crate) fn start_read(&self, key: &str) {
+        if self.slots.len() < self.options.max_entries || self.slots.contains_key(key) {
+            self.slots.insert(key.to_string(), Slot::Reading);
+        }
+    }
+
+    /// Cache `count` for `key`, unless it was invalidated since the read
+    /// started.
+    pub(crate) fn finish_read(&self, key: &str, count: u64) {
+        if let Some(mut slot) = self.slots.get_mut(key) {
+            if matches!(*slot, Slot::Reading) {
+                *slot = Slot::Cached { count, expires: self.clock.now() + self.options.ttl };
+            }
+        }
+    }
+
+    /// Drop `key` ahead of its invalidation, e.g. after this node wrote it.
+    pub(crate) fn forget(&self, key: &str) {
+        self.slots.remove(key);
+    }
+
+    pub(crate) fn invalidate(&self, invalidation: Invalidation) {
+        metrics::counter!("rate_limiter_redis_cache_invalidations_total").increment(1);
+        match invalidation {
+            Invalidation::Keys(keys) => {
+                for key in keys {
+                    self.slots.remove(&key);
+                }
+            }
+            Invalidation::All => self.slots.clear(),
+        }
+    }
+
+    /// Stop caching for good, once invalidations can no longer arrive.
+    pub(crate) fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+        self.slots.clear();
+    }
+}
+
+/// Turn a RESP3 push into an invalidation. `invalidate` carries an array of
+/// keys, or a null when the whole keyspace was flushed; a disconnect means
+/// tracking state is lost, so everything must be dropped as well.
+fn parse_invalidation(push: PushInfo) -> Option<Invalidation> {
+    match push.kind {
+        PushKind::Invalidate => match push.data.into_iter().next() {
+            Some(Value::Array(keys)) => Some(Invalidation::Keys(
+                keys.into_iter()
+                    .filter_map(|key| match key {
+                        Value::BulkString(bytes) => String::from_utf8(bytes).ok(),
+                        Value::SimpleString(key) => Some(key),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+            _ => Some(Invalidation::All),
+        },
+        PushKind::Disconnection => Some(Invalidation::All),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_flavor() {
+        assert_eq!(RedisFlavor::detect("# Server\r\nredis_version:7.2.4\r\n"), RedisFlavor::Redis);
+        assert_eq!(
+            RedisFlavor::detect("# Server\r\nredis_version:6.2.6\r\ndragonfly_version:df-v1.14.0\r\n"),
+            RedisFlavor::Dragonfly
+        );
+        assert_eq!(
+            RedisFlavor::detect("# Server\r\nredis_version:6.3.4\r\nexecutable:/usr/local/bin/keydb-server\r\n"),
+            RedisFlavor::KeyDB
+        );
+    }
+
+    #[test]
+    fn test_parse_invalidation() {
+        let push = PushInfo {
+            kind: PushKind::Invalidate,
+            data: vec![Value::Array(vec![
+                Value::BulkString(b"10.0.0.1".to_vec()),
+                Value::BulkString(b"10.0.0.2".to_vec()),
+            ])],
+        };
+        assert_eq!(
+            parse_invalidation(push),
+            Some(Invalidation::Keys(vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()]))
+        );
+
+        let flush = PushInfo {
+            kind: PushKind::Invalidate,
+            data: vec![Value::Nil],
+        };
+        assert_eq!(parse_invalidation(flush), Some(Invalidation::All));
+
+        let message = PushInfo {
+            kind: PushKind::Message,
+            data: vec![],
+        };
+        assert_eq!(parse_invalidation(message), None);
+    }
+
+    #[test]
+    fn test_client_cache() {
+        let clock = Arc::new(crate::clock::ManualClock::default());
+        let cache = ClientCache::new(ClientCacheOptions::default()).with_clock(clock.clone());
+
+        cache.start_read("10.0.0.1");
+        assert_eq!(cache.get("10.0.0.1"), None);
+        cache.finish_read("10.0.0.1", 3);
+        assert_eq!(cache.get("10.0.0.1"), Some(3));
+
+        // A change pushed while a read is in flight keeps its value out
+        cache.start_read("10.0.0.2");
+        cache.invalidate(Invalidation::Keys(vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()]));
+        cache.finish_read("10.0.0.2", 5);
+        assert_eq!(cache.get("10.0.0.1"), None);
+        assert_eq!(cache.get("10.0.0.2"), None);
+
+        cache.start_read("10.0.0.1");
+        cache.finish_read("10.0.0.1", 4);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get("10.0.0.1"), None);
+
+        cache.disable();
+        assert!(!cache.is_enabled());
+    }
+}
@@ -0,0 +1,157 @@
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, OnceLock, RwLock};
+use crate::storage::{
+    MemcachedStorage, MemoryOptions, MemoryStorage, MySQLStorage, PostgresStorage, RedisStorage, SQLiteStorage,
+    StorageBackend, StorageError,
+};
+
+type Factory =
+    Arc<dyn Fn(String) -> BoxFuture<'static, Result<Box<dyn StorageBackend>, StorageError>> + Send + Sync>;
+
+static REGISTRY: OnceLock<RwLock<HashMap<String, Factory>>> = OnceLock::new();
+
+/// Make a backend constructible by name through [`create_backend`].
+///
+/// The factory receives the backend's connection string from the
+/// configuration (URL, path, seed list... whatever the backend expects).
+/// Names are unique; registering a name twice, including a built-in one,
+/// is an error.
+pub fn register_backend<F, Fut>(name: &str, factory: F) -> Result<(), StorageError>
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Box<dyn StorageBackend>, StorageError>> + Send + 'static,
+{
+    let mut registry = registry()
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if registry.contains_key(name) {
+        return Err(StorageError::ConnectionError(format!(
+            "storage backend '{}' is already registered",
+            name
+        )));
+    }
+
+    registry.insert(name.to_string(), Arc::new(move |config| factory(config).boxed()));
+    Ok(())
+}
+
+/// Build the backend registered as `name` from its connection string.
+pub async fn create_backend(name: &str, config: &str) -> Result<Box<dyn StorageBackend>, StorageError> {
+    let factory = registry()
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(name)
+        .cloned()
+        .ok_or_else(|| StorageError::ConnectionError(format!("unknown storage backend '{}'", name)))?;
+
+    factory(config.to_string()).await
+}
+
+/// Names of all registered backends, sorted.
+pub fn registered_backends() -> Vec<String> {
+    let mut names: Vec<String> = registry()
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .keys()
+        .cloned()
+        .collect();
+    names.sort();
+    names
+}
+
+fn registry() -> &'static RwLock<HashMap<String, Factory>> {
+    REGISTRY.get_or_init(|| RwLock::new(builtin_backends()))
+}
+
+fn builtin_backends() -> HashMap<String, Factory> {
+    let mut backends: HashMap<String, Factory> = HashMap::new();
+    let mut add = |name: &str, factory: Factory| {
+        backends.insert(name.to_string(), factory);
+    };
+
+    add("redis", Arc::new(|url: String| async move {
+        let storage = if url.starts_with("sentinel://") {
+            RedisStorage::new_sentinel(&url).await?
+        } else if url.contains(',') {
+            RedisStorage::from_seed_list(&url)?
+        } else {
+            RedisStorage::new(&url)?
+        };
+        Ok(Box::new(storage) as Box<dyn StorageBackend>)
+    }.boxed()));
+    add("memcached", Arc::new(|url: String| async move {
+        Ok(Box::new(MemcachedStorage::new(&url)?) as Box<dyn StorageBackend>)
+    }.boxed()));
+    add("mysql", Arc::new(|url: String| async move {
+        Ok(Box::new(MySQLStorage::new(&url).await?) as Box<dyn StorageBackend>)
+    }.boxed()));
+    add("postgresql", Arc::new(|url: String| async move {
+        Ok(Box::new(PostgresStorage::new(&url).await?) as Box<dyn StorageBackend>)
+    }.boxed()));
+    add("sqlite", Arc::new(|path: String| async move {
+        Ok(Box::new(SQLiteStorage::new(&path)?) as Box<dyn StorageBackend>)
+    }.boxed()));
+    add("memory", Arc::new(|config: String| async move {
+        let options = MemoryOptions::parse(&config)?;
+        Ok(Box::new(MemoryStorage::with_options(options)) as Box<dyn StorageBackend>)
+    }.boxed()));
+
+    #[cfg(feature = "dynamodb")]
+    add("dynamodb", Arc::new(|table: String| async move {
+        Ok(Box::new(crate::storage::DynamoDBStorage::new(&table).await?) as Box<dyn StorageBackend>)
+    }.boxed()));
+    #[cfg(feature = "mongodb")]
+    add("mongodb", Arc::new(|url: String| async move {
+        Ok(Box::new(crate::storage::MongoStorage::new(&url).await?) as Box<dyn StorageBackend>)
+    }.boxed()));
+    #[cfg(feature = "rocksdb")]
+    add("rocksdb", Arc::new(|path: String| async move {
+        Ok(Box::new(crate::storage::RocksDBStorage::new(&path)?) as Box<dyn StorageBackend>)
+    }.boxed()));
+    #[cfg(feature = "aerospike")]
+    add("aerospike", Arc::new(|hosts: String| async move {
+        Ok(Box::new(crate::storage::AerospikeStorage::new(&hosts)?) as Box<dyn StorageBackend>)
+    }.boxed()));
+    #[cfg(feature = "envoy-rls")]
+    add("envoy-rls", Arc::new(|url: String| async move {
+        Ok(Box::new(crate::storage::EnvoyRlsStorage::new(&url)?) as Box<dyn StorageBackend>)
+    }.boxed()));
+    #[cfg(feature = "http-decision")]
+    add("http", Arc::new(|url: String| async move {
+        Ok(Box::new(crate::storage::HttpDecisionStorage::new(&url)?) as Box<dyn StorageBackend>)
+    }.boxed()));
+    #[cfg(feature = "dns")]
+    add("redis-dns", Arc::new(|name: String| async move {
+        let storage = crate::storage::DiscoveredStorage::redis(&name, Default::default(), Default::default()).await?;
+        Ok(Box::new(crate::storage::Shared(storage)) as Box<dyn StorageBackend>)
+    }.boxed()));
+
+    backends
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_register_and_create_backend() {
+        register_backend("test-kv", |_config| async {
+            Ok(Box::new(MemoryStorage::new()) as Box<dyn StorageBackend>)
+        })
+        .unwrap();
+        assert!(registered_backends().contains(&"test-kv".to_string()));
+
+        let storage = create_backend("test-kv", "kv://internal").await.unwrap();
+        storage.increment("key", 60).await.unwrap();
+        assert_eq!(storage.get("key").await.unwrap(), 1);
+
+        assert!(register_backend("redis", |_config| async {
+            Ok(Box::new(MemoryStorage::new()) as Box<dyn StorageBackend>)
+        })
+        .is_err());
+        assert!(create_backend("no-such-backend", "").await.is_err());
+    }
+}
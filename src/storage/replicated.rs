@@ -0,0 +1,262 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::storage::{CounterSnapshot, Increment, PostgresStorage, RedisStorage, StorageBackend, StorageError};
+
+/// How much replication lag `ReplicatedStorage` tolerates on reads.
+#[derive(Debug, Clone)]
+pub struct ReplicaOptions {
+    /// A key incremented through this instance is read from the primary for
+    /// this long afterwards, as replicas may not have the write yet. Zero
+    /// sends every read to a replica.
+    pub max_staleness: Duration,
+    /// Retry a read on the primary when the replica fails with a connection
+    /// or database error
+    pub fallback_to_primary: bool,
+    /// Recently written keys remembered for `max_staleness`; beyond this the
+    /// oldest are forgotten and may be read from a replica early
+    pub max_tracked_keys: usize,
+}
+
+impl Default for ReplicaOptions {
+    fn default() -> Self {
+        Self {
+            max_staleness: Duration::from_secs(1),
+            fallback_to_primary: true,
+            max_tracked_keys: 100_000,
+        }
+    }
+}
+
+/// Sends writes to a primary and serves `get` from read replicas.
+///
+/// Replicas are used round-robin. Since a replica can lag behind, keys this
+/// instance wrote within `max_staleness` are still read from the primary,
+/// so a client cannot slip past its own most recent requests. Counts
+/// written by other instances may be read up to the replication lag late.
+pub struct ReplicatedStorage {
+    primary: Box<dyn StorageBackend>,
+    replicas: Vec<Box<dyn StorageBackend>>,
+    options: ReplicaOptions,
+    next_replica: AtomicUsize,
+    /// Keys written through this instance and when
+    recent_writes: Mutex<HashMap<String, Instant>>,
+}
+
+impl ReplicatedStorage {
+    pub fn new(
+        primary: Box<dyn StorageBackend>,
+        replicas: Vec<Box<dyn StorageBackend>>,
+        options: ReplicaOptions,
+    ) -> Self {
+        Self {
+            primary,
+            replicas,
+            options,
+            next_replica: AtomicUsize::new(0),
+            recent_writes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Redis primary with `replica_urls` serving reads.
+    pub fn redis<S: AsRef<str>>(
+        primary_url: &str,
+        replica_urls: &[S],
+        options: ReplicaOptions,
+    ) -> Result<Self, StorageError> {
+        let replicas = replica_urls
+            .iter()
+            .map(|url| Ok(Box::new(RedisStorage::new(url.as_ref())?) as Box<dyn StorageBackend>))
+            .collect::<Result<Vec<_>, StorageError>>()?;
+
+        Ok(Self::new(Box::new(RedisStorage::new(primary_url)?), replicas, options))
+    }
+
+    /// PostgreSQL primary with hot standbys serving reads. The table is
+    /// created through the primary only.
+    pub async fn postgres<S: AsRef<str>>(
+        primary_url: &str,
+        replica_urls: &[S],
+        options: ReplicaOptions,
+    ) -> Result<Self, StorageError> {
+        let primary = PostgresStorage::new(primary_url).await?;
+        let mut replicas: Vec<Box<dyn StorageBackend>> = Vec::with_capacity(replica_urls.len());
+        for url in replica_urls {
+            replicas.push(Box::new(PostgresStorage::new_read_only(url.as_ref()).await?));
+        }
+
+        Ok(Self::new(Box::new(primary), replicas, options))
+    }
+
+    fn recent_writes(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        self.recent_writes.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn written_recently(&self, key: &str) -> bool {
+        !self.options.max_staleness.is_zero()
+            && self
+                .recent_writes()
+                .get(key)
+                .is_some_and(|at| at.elapsed() < self.options.max_staleness)
+    }
+
+    fn record_write(&self, key: &str) {
+        if self.options.max_staleness.is_zero() {
+            return;
+        }
+        let mut recent = self.recent_writes();
+        if recent.len() >= self.options.max_tracked_keys {
+            let max_staleness = self.options.max_staleness;
+            recent.retain(|_, at| at.elapsed() < max_staleness);
+            if recent.len() >= self.options.max_tracked_keys {
+                recent.clear();
+            }
+        }
+        recent.insert(key.to_string(), Instant::now());
+    }
+
+    fn replica(&self) -> Option<&dyn StorageBackend> {
+        if self.replicas.is_empty() {
+            return None;
+        }
+        let index = self.next_replica.fetch_add(1, Ordering::Relaxed) % self.replicas.len();
+        Some(self.replicas[index].as_ref())
+    }
+
+    fn should_fall_back(&self, error: &StorageError) -> bool {
+        self.options.fallback_to_primary
+            && matches!(error, StorageError::ConnectionError(_) | StorageError::DatabaseError(_))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ReplicatedStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let replica = match self.replica() {
+            Some(replica) if !self.written_recently(key) => replica,
+            _ => return self.primary.get(key).await,
+        };
+
+        match replica.get(key).await {
+            Err(e) if self.should_fall_back(&e) => {
+                log::warn!("Replica read failed, using primary: {}", e);
+                self.primary.get(key).await
+            }
+            result => result,
+        }
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        let increment = self.primary.increment(key, expire).await?;
+        self.record_write(key);
+        Ok(increment)
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        let increment = self.primary.increment_by(key, amount, expire).await?;
+        self.record_write(key);
+        Ok(increment)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.primary.delete(key).await?;
+        self.record_write(key);
+        Ok(())
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        // Replicas receive the primary's deletes through replication
+        self.primary.cleanup_expired().await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let replica = match self.replica() {
+            Some(replica) if !self.written_recently(key) => replica,
+            _ => return self.primary.ttl(key).await,
+        };
+
+        match replica.ttl(key).await {
+            Err(e) if self.should_fall_back(&e) => {
+                log::warn!("Replica read failed, using primary: {}", e);
+                self.primary.ttl(key).await
+            }
+            result => result,
+        }
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.primary.warm_up().await?;
+        for replica in &self.replicas {
+            replica.warm_up().await?;
+        }
+        Ok(())
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        let replica = match self.replica() {
+            Some(replica) if !keys.iter().any(|key| self.written_recently(key)) => replica,
+            _ => return self.primary.get_many(keys).await,
+        };
+
+        match replica.get_many(keys).await {
+            Err(e) if self.should_fall_back(&e) => {
+                log::warn!("Replica read failed, using primary: {}", e);
+                self.primary.get_many(keys).await
+            }
+            result => result,
+        }
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        let increments = self.primary.increment_many(keys).await?;
+        for (key, _) in keys {
+            self.record_write(key);
+        }
+        Ok(increments)
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        self.primary.export_all().await
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        let imported = self.primary.import_all(counters).await?;
+        for counter in counters {
+            self.record_write(&counter.key);
+        }
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_reads_go_to_replicas() {
+        // The replica never receives writes, standing in for one that lags
+        let mut storage = ReplicatedStorage::new(
+            Box::new(MemoryStorage::new()),
+            vec![Box::new(MemoryStorage::new())],
+            ReplicaOptions {
+                max_staleness: Duration::from_secs(3600),
+                ..ReplicaOptions::default()
+            },
+        );
+
+        storage.increment("written", 60).await.unwrap();
+        // Recently written keys are read from the primary
+        assert_eq!(storage.get("written").await.unwrap(), 1);
+        assert_eq!(storage.get_many(&["written", "other"]).await.unwrap(), vec![1, 0]);
+
+        // Everything else is served by the replica
+        storage.primary.increment("other", 60).await.unwrap();
+        assert_eq!(storage.get("other").await.unwrap(), 0);
+
+        storage.options.max_staleness = Duration::ZERO;
+        assert_eq!(storage.get("written").await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,332 @@
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::storage::{
+    register_health, BackendHealth, CounterSnapshot, HealthThresholds, Increment, StorageBackend, StorageError,
+};
+
+/// Timeouts and retries applied by `ResilientStorage`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Deadline for a single `get`, `increment` or `delete` attempt
+    pub timeout: Duration,
+    /// Deadline for a single `cleanup_expired` attempt, which may scan a lot
+    pub cleanup_timeout: Duration,
+    /// Attempts for idempotent operations, including the first one.
+    /// `increment` is never retried, since a timed out attempt may still
+    /// have been applied.
+    pub max_attempts: u32,
+    /// Wait before the first retry; doubled after every further attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(200),
+            cleanup_timeout: Duration::from_secs(30),
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    fn should_retry(&self, error: &StorageError, attempt: u32) -> bool {
+        attempt < self.max_attempts
+            && matches!(error, StorageError::ConnectionError(_) | StorageError::DatabaseError(_))
+    }
+}
+
+/// Bounds every call to a backend with a timeout and retries idempotent
+/// operations that fail with a connection or database error.
+///
+/// Each wrapped backend has its own name and policy, so a slow SQL store can
+/// get a longer deadline than Redis. Calls are recorded in the
+/// `rate_limiter_storage_*` metrics, labelled by backend and operation.
+/// Timeouts surface as `StorageError::ConnectionError`.
+///
+/// Every attempt also feeds the backend's `BackendHealth`, registered
+/// under its name so status endpoints can list it with `backend_health`.
+/// Connection and database errors, timeouts included, count as failures.
+pub struct ResilientStorage {
+    name: String,
+    inner: Box<dyn StorageBackend>,
+    policy: RetryPolicy,
+    health: Arc<BackendHealth>,
+}
+
+impl ResilientStorage {
+    pub fn new(name: &str, inner: Box<dyn StorageBackend>, policy: RetryPolicy) -> Self {
+        Self::with_health_thresholds(name, inner, policy, HealthThresholds::default())
+    }
+
+    pub fn with_health_thresholds(
+        name: &str,
+        inner: Box<dyn StorageBackend>,
+        policy: RetryPolicy,
+        thresholds: HealthThresholds,
+    ) -> Self {
+        let health = Arc::new(BackendHealth::new(name, thresholds));
+        register_health(Arc::clone(&health));
+        Self {
+            name: name.to_string(),
+            inner,
+            policy,
+            health,
+        }
+    }
+
+    /// The backend's health, e.g. for `FailoverStorage::with_health`.
+    pub fn health(&self) -> Arc<BackendHealth> {
+        Arc::clone(&self.health)
+    }
+
+    async fn timed<T>(
+        &self,
+        operation: &'static str,
+        timeout: Duration,
+        call: impl Future<Output = Result<T, StorageError>>,
+    ) -> Result<T, StorageError> {
+        let name = self.name.as_str();
+        let started = Instant::now();
+        let result = match tokio::time::timeout(timeout, call).await {
+            Ok(result) => result,
+            Err(_) => {
+                metrics::counter!(
+                    "rate_limiter_storage_timeouts_total",
+                    "backend" => name.to_string(),
+                    "operation" => operation
+                )
+                .increment(1);
+                Err(StorageError::ConnectionError(format!(
+                    "{} {} timed out after {:?}",
+                    name, operation, timeout
+                )))
+            }
+        };
+
+        let elapsed = started.elapsed();
+        metrics::histogram!(
+            "rate_limiter_storage_operation_duration_seconds",
+            "backend" => name.to_string(),
+            "operation" => operation
+        )
+        .record(elapsed.as_secs_f64());
+        let failed = matches!(result, Err(StorageError::ConnectionError(_) | StorageError::DatabaseError(_)));
+        self.health.record(!failed, elapsed);
+        if result.is_err() {
+            metrics::counter!(
+                "rate_limiter_storage_errors_total",
+                "backend" => name.to_string(),
+                "operation" => operation
+            )
+            .increment(1);
+        }
+
+        result
+    }
+
+    async fn before_retry(name: &str, operation: &'static str, policy: &RetryPolicy, attempt: u32, error: &StorageError) {
+        log::debug!("Retrying {} {} after attempt {}: {}", name, operation, attempt, error);
+        metrics::counter!(
+            "rate_limiter_storage_retries_total",
+            "backend" => name.to_string(),
+            "operation" => operation
+        )
+        .increment(1);
+        tokio::time::sleep(policy.backoff(attempt)).await;
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ResilientStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let mut attempt = 1;
+        loop {
+            match self.timed("get", self.policy.timeout, self.inner.get(key)).await {
+                Err(e) if self.policy.should_retry(&e, attempt) => {
+                    Self::before_retry(&self.name, "get", &self.policy, attempt, &e).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.timed("increment", self.policy.timeout, self.inner.increment(key, expire)).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        self.timed("increment_by", self.policy.timeout, self.inner.increment_by(key, amount, expire)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let mut attempt = 1;
+        loop {
+            match self.timed("delete", self.policy.timeout, self.inner.delete(key)).await {
+                Err(e) if self.policy.should_retry(&e, attempt) => {
+                    Self::before_retry(&self.name, "delete", &self.policy, attempt, &e).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let mut attempt = 1;
+        loop {
+            match self.timed("cleanup_expired", self.policy.cleanup_timeout, self.inner.cleanup_expired()).await {
+                Err(e) if self.policy.should_retry(&e, attempt) => {
+                    Self::before_retry(&self.name, "cleanup_expired", &self.policy, attempt, &e).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let mut attempt = 1;
+        loop {
+            match self.timed("ttl", self.policy.timeout, self.inner.ttl(key)).await {
+                Err(e) if self.policy.should_retry(&e, attempt) => {
+                    Self::before_retry(&self.name, "ttl", &self.policy, attempt, &e).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        // Opening a pool can take longer than a single operation
+        self.timed("warm_up", self.policy.cleanup_timeout, self.inner.warm_up()).await
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        let mut attempt = 1;
+        loop {
+            match self.timed("get_many", self.policy.timeout, self.inner.get_many(keys)).await {
+                Err(e) if self.policy.should_retry(&e, attempt) => {
+                    Self::before_retry(&self.name, "get_many", &self.policy, attempt, &e).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        self.timed("increment_many", self.policy.timeout, self.inner.increment_many(keys)).await
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        // Like a cleanup sweep, a full export can visit every key
+        self.timed("export_all", self.policy.cleanup_timeout, self.inner.export_all()).await
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        // Not retried: a timed out import may have been applied and would
+        // add its counts twice
+        self.timed("import_all", self.policy.cleanup_timeout, self.inner.import_all(counters)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    /// Fails the first `failures` calls, and hangs on keys named "slow"
+    struct UnreliableStorage {
+        inner: MemoryStorage,
+        failures: Arc<AtomicU32>,
+    }
+
+    impl UnreliableStorage {
+        async fn check(&self, key: &str) -> Result<(), StorageError> {
+            if key == "slow" {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+            }
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(StorageError::ConnectionError("connection reset".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl StorageBackend for UnreliableStorage {
+        async fn get(&self, key: &str) -> Result<u64, StorageError> {
+            self.check(key).await?;
+            self.inner.get(key).await
+        }
+
+        async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+            self.check(key).await?;
+            self.inner.increment(key, expire).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), StorageError> {
+            self.check(key).await?;
+            self.inner.delete(key).await
+        }
+
+        async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+            self.inner.cleanup_expired().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_and_timeouts() {
+        let failures = Arc::new(AtomicU32::new(0));
+        let storage = ResilientStorage::new(
+            "test",
+            Box::new(UnreliableStorage {
+                inner: MemoryStorage::new(),
+                failures: Arc::clone(&failures),
+            }),
+            RetryPolicy::default(),
+        );
+
+        storage.increment("key", 60).await.unwrap();
+
+        // Two failures are absorbed by the three attempts of a read
+        failures.store(2, Ordering::SeqCst);
+        assert_eq!(storage.get("key").await.unwrap(), 1);
+
+        // Increments are not retried
+        failures.store(1, Ordering::SeqCst);
+        assert!(storage.increment("key", 60).await.is_err());
+        assert_eq!(storage.get("key").await.unwrap(), 1);
+
+        assert!(matches!(
+            storage.get("slow").await,
+            Err(StorageError::ConnectionError(_))
+        ));
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_millis(10));
+        assert_eq!(policy.backoff(2), Duration::from_millis(20));
+        assert_eq!(policy.backoff(10), Duration::from_millis(200));
+        assert_eq!(policy.backoff(40), Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,291 @@
+use async_trait::async_trait;
+use rocksdb::compaction_filter::Decision;
+use rocksdb::{IteratorMode, MergeOperands, Options, WriteBatch, DB};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use crate::clock::{self, Clock, SystemClock};
+use crate::storage::{Increment, StorageBackend, StorageError};
+
+const MERGE_OPERATOR_NAME: &str = "rate_limit_increment";
+const COMPACTION_FILTER_NAME: &str = "rate_limit_ttl";
+
+/// Stored value: `count` (u64 LE) followed by `expire_at` (u64 LE)
+const VALUE_LEN: usize = 16;
+/// Values written before counts were widened held a u32 count
+const LEGACY_VALUE_LEN: usize = 12;
+/// Merge operand: `now`, the new `expire_at` and the amount to add (u64 LE each)
+const OPERAND_LEN: usize = 24;
+/// Operands written before amounts were carried always added 1
+const LEGACY_OPERAND_LEN: usize = 16;
+/// Locks serializing increments, each guarding the keys that hash to it
+const INCREMENT_STRIPES: usize = 64;
+
+/// Embedded RocksDB storage for single node deployments.
+///
+/// Increments are issued as merge operands, so they never need a
+/// read-modify-write round trip; expired windows are dropped by a compaction
+/// filter and by `cleanup_expired`. The merge and the read of the new count
+/// run under a per-key stripe lock, so concurrent increments of one key
+/// each see their own count.
+pub struct RocksDBStorage {
+    db: DB,
+    stripes: Box<[Mutex<()>]>,
+    hasher: RandomState,
+    clock: Arc<dyn Clock>,
+}
+
+impl RocksDBStorage {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.set_merge_operator(MERGE_OPERATOR_NAME, full_merge, partial_merge);
+        // Compactions run on RocksDB's own threads for the life of the
+        // database, so they always read the system clock
+        opts.set_compaction_filter(COMPACTION_FILTER_NAME, |_level: u32, _key: &[u8], value: &[u8]| {
+            match decode_value(value) {
+                Some((_, expire_at)) if expire_at > SystemClock.unix_secs() => Decision::Keep,
+                _ => Decision::Remove,
+            }
+        });
+
+        let db = DB::open(&opts, path)
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+        Ok(Self {
+            db,
+            stripes: (0..INCREMENT_STRIPES).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+            clock: clock::system(),
+        })
+    }
+
+    /// Read windows from `clock` instead of the system clock. Compactions
+    /// still drop windows that have ended by the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn stripe(&self, key: &str) -> &Mutex<()> {
+        &self.stripes[self.hasher.hash_one(key) as usize % self.stripes.len()]
+    }
+
+    /// Count for `key` if its window is still open at `now`.
+    fn read(&self, key: &str, now: u64) -> Result<u64, StorageError> {
+        let value = self.db
+            .get(key)
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        match value {
+            Some(value) => {
+                let (count, expire_at) = decode_value(&value)
+                    .ok_or_else(|| StorageError::InvalidValueType(key.to_string()))?;
+                if expire_at > now {
+                    Ok(count)
+                } else {
+                    Ok(0)
+                }
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+fn encode_value(count: u64, expire_at: u64) -> Vec<u8> {
+    let mut value = Vec::with_capacity(VALUE_LEN);
+    value.extend_from_slice(&count.to_le_bytes());
+    value.extend_from_slice(&expire_at.to_le_bytes());
+    value
+}
+
+fn decode_value(value: &[u8]) -> Option<(u64, u64)> {
+    match value.len() {
+        VALUE_LEN => {
+            let count = u64::from_le_bytes(value[..8].try_into().ok()?);
+            let expire_at = u64::from_le_bytes(value[8..].try_into().ok()?);
+            Some((count, expire_at))
+        }
+        LEGACY_VALUE_LEN => {
+            let count = u32::from_le_bytes(value[..4].try_into().ok()?);
+            let expire_at = u64::from_le_bytes(value[4..].try_into().ok()?);
+            Some((count.into(), expire_at))
+        }
+        _ => None,
+    }
+}
+
+fn encode_operand(now: u64, expire_at: u64, amount: u64) -> Vec<u8> {
+    let mut operand = Vec::with_capacity(OPERAND_LEN);
+    operand.extend_from_slice(&now.to_le_bytes());
+    operand.extend_from_slice(&expire_at.to_le_bytes());
+    operand.extend_from_slice(&amount.to_le_bytes());
+    operand
+}
+
+fn decode_operand(operand: &[u8]) -> Option<(u64, u64, u64)> {
+    let amount = match operand.len() {
+        OPERAND_LEN => u64::from_le_bytes(operand[16..].try_into().ok()?),
+        LEGACY_OPERAND_LEN => 1,
+        _ => return None,
+    };
+    let now = u64::from_le_bytes(operand[..8].try_into().ok()?);
+    let expire_at = u64::from_le_bytes(operand[8..16].try_into().ok()?);
+    Some((now, expire_at, amount))
+}
+
+/// Apply increment operands in order. Each operand carries the time it was
+/// issued at, so a window that had expired by then restarts from the
+/// operand's amount — the same rule the SQL backends apply in their UPSERTs.
+fn full_merge(_key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
+    let (mut count, mut expire_at) = existing.and_then(decode_value).unwrap_or((0, 0));
+
+    for operand in operands {
+        let (now, new_expire_at, amount) = decode_operand(operand)?;
+        count = if expire_at > now { count.saturating_add(amount) } else { amount };
+        expire_at = new_expire_at;
+    }
+
+    Some(encode_value(count, expire_at))
+}
+
+/// Operands depend on the base value, so they can't be combined without it.
+fn partial_merge(_key: &[u8], _existing: Option<&[u8]>, _operands: &MergeOperands) -> Option<Vec<u8>> {
+    None
+}
+
+#[async_trait]
+impl StorageBackend for RocksDBStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        self.read(key, self.clock.unix_secs())
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.increment_by(key, 1, expire).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        let now = self.clock.unix_secs();
+        if amount == 0 {
+            return self.read(key, now).map(|count| Increment { count });
+        }
+        let _stripe = self.stripe(key).lock().unwrap_or_else(PoisonError::into_inner);
+
+        self.db
+            .merge(key, encode_operand(now, now + expire as u64, amount))
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        // Reading back resolves the pending operands; the window is live at
+        // `now` since the merge just extended it, and no other increment of
+        // the key can land in between
+        let count = self.read(key, now)?;
+        Ok(Increment { count })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.db
+            .delete(key)
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let current_time = self.clock.unix_secs();
+        let mut batch = WriteBatch::default();
+
+        for item in self.db.iterator(IteratorMode::Start) {
+            let (key, value) = item.map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            match decode_value(&value) {
+                Some((_, expire_at)) if expire_at > current_time => {}
+                _ => batch.delete(key),
+            }
+        }
+
+        let removed = batch.len() as u64;
+        self.db
+            .write(batch)
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_rocksdb_storage() {
+        let path = std::env::temp_dir().join(format!("rate_limiter_rocksdb_{}", std::process::id()));
+        // Start at the system time, so a compaction would keep live windows
+        let clock = Arc::new(ManualClock::new(SystemClock.now()));
+        let storage = RocksDBStorage::new(&path).unwrap().with_clock(clock.clone());
+
+        // Test increment and get
+        assert_eq!(storage.increment("test_key", 2).await.unwrap().count, 1);
+        assert_eq!(storage.get("test_key").await.unwrap(), 1);
+
+        assert_eq!(storage.increment("test_key", 2).await.unwrap().count, 2);
+        assert_eq!(storage.get("test_key").await.unwrap(), 2);
+
+        // Values written with the old u32 layout are still readable
+        let mut legacy = 7u32.to_le_bytes().to_vec();
+        legacy.extend_from_slice(&(clock.unix_secs() + 60).to_le_bytes());
+        storage.db.put("legacy_key", legacy).unwrap();
+        assert_eq!(storage.increment("legacy_key", 60).await.unwrap().count, 8);
+
+        // Amounts are carried in the operand; operands from before that add 1
+        assert_eq!(storage.increment_by("test_key", 40, 2).await.unwrap().count, 42);
+        storage.db.merge("test_key", &encode_operand(clock.unix_secs(), clock.unix_secs() + 2, 1)[..LEGACY_OPERAND_LEN]).unwrap();
+        assert_eq!(storage.get("test_key").await.unwrap(), 43);
+
+        // Test expiration
+        storage.increment("expire_key", 1).await.unwrap();
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(storage.get("expire_key").await.unwrap(), 0);
+
+        // An increment after expiry starts a new window
+        storage.increment("expire_key", 1).await.unwrap();
+        assert_eq!(storage.get("expire_key").await.unwrap(), 1);
+
+        // Test cleanup
+        clock.advance(Duration::from_secs(2));
+        storage.cleanup_expired().await.unwrap();
+        assert_eq!(storage.get("expire_key").await.unwrap(), 0);
+
+        drop(storage);
+        let _ = DB::destroy(&Options::default(), &path);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_increments_get_distinct_counts() {
+        let path = std::env::temp_dir().join(format!("rate_limiter_rocksdb_concurrent_{}", std::process::id()));
+        let storage = Arc::new(RocksDBStorage::new(&path).unwrap());
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let storage = Arc::clone(&storage);
+                tokio::spawn(async move {
+                    let mut counts = Vec::new();
+                    for _ in 0..100 {
+                        counts.push(storage.increment("shared", 60).await.unwrap().count);
+                    }
+                    counts
+                })
+            })
+            .collect();
+        let mut counts = Vec::new();
+        for task in tasks {
+            counts.extend(task.await.unwrap());
+        }
+
+        counts.sort_unstable();
+        assert_eq!(counts, (1..=400).collect::<Vec<u64>>());
+
+        drop(storage);
+        let _ = DB::destroy(&Options::default(), &path);
+    }
+}
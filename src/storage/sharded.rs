@@ -0,0 +1,336 @@
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+use crate::storage::{CounterSnapshot, Increment, RedisStorage, StorageBackend, StorageError};
+
+pub const DEFAULT_VIRTUAL_NODES: usize = 160;
+pub(crate) const DEFAULT_DOWN_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Shard {
+    name: String,
+    backend: Box<dyn StorageBackend>,
+    /// Epoch milliseconds until which the shard is skipped; 0 when healthy
+    down_until: AtomicU64,
+}
+
+/// Spreads keys over independent backends with a consistent hash ring.
+///
+/// Every shard is placed on the ring `virtual_nodes` times. A shard that
+/// fails with a connection error is marked down for `down_interval`; its keys
+/// move to the next shard on the ring and move back once it is retried
+/// successfully, while keys owned by other shards stay put.
+pub struct ShardedStorage {
+    shards: Vec<Shard>,
+    /// (point, shard index), sorted by point
+    ring: Vec<(u64, usize)>,
+    down_interval: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl ShardedStorage {
+    pub fn new(
+        shards: Vec<(String, Box<dyn StorageBackend>)>,
+        virtual_nodes: usize,
+        down_interval: Duration,
+    ) -> Result<Self, StorageError> {
+        if shards.is_empty() {
+            return Err(StorageError::ConnectionError(
+                "at least one shard is required".to_string(),
+            ));
+        }
+
+        let virtual_nodes = virtual_nodes.max(1);
+        let mut ring = Vec::with_capacity(shards.len() * virtual_nodes);
+        for (index, (name, _)) in shards.iter().enumerate() {
+            for replica in 0..virtual_nodes {
+                ring.push((hash(format!("{}#{}", name, replica).as_bytes()), index));
+            }
+        }
+        ring.sort_unstable();
+
+        let shards = shards
+            .into_iter()
+            .map(|(name, backend)| Shard {
+                name,
+                backend,
+                down_until: AtomicU64::new(0),
+            })
+            .collect();
+
+        Ok(Self {
+            shards,
+            ring,
+            down_interval,
+            clock: clock::system(),
+        })
+    }
+
+    /// Time `down_interval` with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Shard independent Redis instances, named by their URL.
+    pub fn redis<S: AsRef<str>>(urls: &[S], virtual_nodes: usize) -> Result<Self, StorageError> {
+        let shards = urls
+            .iter()
+            .map(|url| {
+                let backend: Box<dyn StorageBackend> = Box::new(RedisStorage::new(url.as_ref())?);
+                Ok((url.as_ref().to_string(), backend))
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+
+        Self::new(shards, virtual_nodes, DEFAULT_DOWN_INTERVAL)
+    }
+
+    fn now_millis(&self) -> u64 {
+        self.clock.now().as_millis() as u64
+    }
+
+    /// Names of the shards currently marked down.
+    pub fn down_shards(&self) -> Vec<&str> {
+        let now = self.now_millis();
+        self.shards
+            .iter()
+            .filter(|shard| shard.down_until.load(Ordering::Relaxed) > now)
+            .map(|shard| shard.name.as_str())
+            .collect()
+    }
+
+    /// Index of the shard owning `key`, skipping shards that are down.
+    /// Falls back to the key's natural owner when every shard is down.
+    fn shard_for(&self, key: &str) -> usize {
+        let point = hash(key.as_bytes());
+        let start = self.ring.partition_point(|(p, _)| *p < point);
+        let now = self.now_millis();
+
+        let mut seen = vec![false; self.shards.len()];
+        for offset in 0..self.ring.len() {
+            let (_, index) = self.ring[(start + offset) % self.ring.len()];
+            if seen[index] {
+                continue;
+            }
+            seen[index] = true;
+            if self.shards[index].down_until.load(Ordering::Relaxed) <= now {
+                return index;
+            }
+        }
+
+        self.ring[start % self.ring.len()].1
+    }
+
+    /// Positions of `keys` grouped by the shard that owns them.
+    fn group_by_shard<'a>(&self, keys: impl Iterator<Item = &'a str>) -> BTreeMap<usize, Vec<usize>> {
+        let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (position, key) in keys.enumerate() {
+            groups.entry(self.shard_for(key)).or_default().push(position);
+        }
+        groups
+    }
+
+    fn record<T>(&self, index: usize, result: Result<T, StorageError>) -> Result<T, StorageError> {
+        let shard = &self.shards[index];
+        match &result {
+            Err(StorageError::ConnectionError(e)) => {
+                log::warn!("Marking shard {} down: {}", shard.name, e);
+                let until = self.now_millis() + self.down_interval.as_millis() as u64;
+                shard.down_until.store(until, Ordering::Relaxed);
+            }
+            Ok(_) => shard.down_until.store(0, Ordering::Relaxed),
+            Err(_) => {}
+        }
+        result
+    }
+}
+
+/// FNV-1a followed by a 64-bit finalizer so that virtual node names that
+/// differ by one digit still spread evenly. Must stay stable across nodes.
+pub(crate) fn hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[async_trait]
+impl StorageBackend for ShardedStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let index = self.shard_for(key);
+        let result = self.shards[index].backend.get(key).await;
+        self.record(index, result)
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        let index = self.shard_for(key);
+        let result = self.shards[index].backend.increment(key, expire).await;
+        self.record(index, result)
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        let index = self.shard_for(key);
+        let result = self.shards[index].backend.increment_by(key, amount, expire).await;
+        self.record(index, result)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let index = self.shard_for(key);
+        let result = self.shards[index].backend.delete(key).await;
+        self.record(index, result)
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let index = self.shard_for(key);
+        let result = self.shards[index].backend.ttl(key).await;
+        self.record(index, result)
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let mut removed = 0;
+        for index in 0..self.shards.len() {
+            let result = self.shards[index].backend.cleanup_expired().await;
+            removed += self.record(index, result)?;
+        }
+        Ok(removed)
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        // A shard that is down at startup is marked down like at runtime;
+        // the worker can still serve its keys from the next shard
+        let mut ready = 0;
+        for index in 0..self.shards.len() {
+            let result = self.shards[index].backend.warm_up().await;
+            match self.record(index, result) {
+                Ok(()) => ready += 1,
+                Err(StorageError::ConnectionError(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if ready == 0 {
+            return Err(StorageError::ConnectionError("no shard could be reached".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        let mut counts = vec![0; keys.len()];
+        for (index, positions) in self.group_by_shard(keys.iter().copied()) {
+            let batch: Vec<&str> = positions.iter().map(|&i| keys[i]).collect();
+            let result = self.shards[index].backend.get_many(&batch).await;
+            for (i, count) in positions.into_iter().zip(self.record(index, result)?) {
+                counts[i] = count;
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        let mut increments = vec![Increment { count: 0 }; keys.len()];
+        for (index, positions) in self.group_by_shard(keys.iter().map(|(key, _)| *key)) {
+            let batch: Vec<(&str, u32)> = positions.iter().map(|&i| keys[i]).collect();
+            let result = self.shards[index].backend.increment_many(&batch).await;
+            for (i, increment) in positions.into_iter().zip(self.record(index, result)?) {
+                increments[i] = increment;
+            }
+        }
+        Ok(increments)
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        let mut counters = Vec::new();
+        for index in 0..self.shards.len() {
+            let result = self.shards[index].backend.export_all().await;
+            counters.extend(self.record(index, result)?);
+        }
+        Ok(counters)
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        let mut imported = 0;
+        for (index, positions) in self.group_by_shard(counters.iter().map(|counter| counter.key.as_str())) {
+            let batch: Vec<CounterSnapshot> = positions.iter().map(|&i| counters[i].clone()).collect();
+            let result = self.shards[index].backend.import_all(&batch).await;
+            imported += self.record(index, result)?;
+        }
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::storage::MemoryStorage;
+
+    fn memory_shards(count: usize) -> ShardedStorage {
+        let shards = (0..count)
+            .map(|i| {
+                let backend: Box<dyn StorageBackend> = Box::new(MemoryStorage::new());
+                (format!("shard-{}", i), backend)
+            })
+            .collect();
+        ShardedStorage::new(shards, DEFAULT_VIRTUAL_NODES, DEFAULT_DOWN_INTERVAL).unwrap()
+    }
+
+    #[test]
+    fn test_keys_spread_over_shards() {
+        let storage = memory_shards(4);
+        let mut counts = [0; 4];
+        for i in 0..4000 {
+            counts[storage.shard_for(&format!("10.0.{}.{}", i / 256, i % 256))] += 1;
+        }
+        for count in counts {
+            assert!(count > 600, "unbalanced ring: {:?}", counts);
+        }
+    }
+
+    #[test]
+    fn test_down_shard_only_moves_its_keys() {
+        let clock = Arc::new(ManualClock::default());
+        let storage = memory_shards(3).with_clock(clock.clone());
+        let keys: Vec<String> = (0..300).map(|i| format!("key-{}", i)).collect();
+        let before: Vec<usize> = keys.iter().map(|k| storage.shard_for(k)).collect();
+
+        let until = storage.now_millis() + DEFAULT_DOWN_INTERVAL.as_millis() as u64;
+        storage.shards[1].down_until.store(until, Ordering::Relaxed);
+        assert_eq!(storage.down_shards(), vec!["shard-1"]);
+
+        for (key, owner) in keys.iter().zip(before) {
+            let now = storage.shard_for(key);
+            if owner == 1 {
+                assert_ne!(now, 1);
+            } else {
+                assert_eq!(now, owner);
+            }
+        }
+
+        clock.advance(DEFAULT_DOWN_INTERVAL);
+        assert!(storage.down_shards().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batches_split_by_shard() {
+        let storage = memory_shards(3);
+        let keys: Vec<String> = (0..20).map(|i| format!("key-{}", i)).collect();
+        let batch: Vec<(&str, u32)> = keys.iter().map(|k| (k.as_str(), 60)).collect();
+
+        storage.increment_many(&batch).await.unwrap();
+        let increments = storage.increment_many(&batch).await.unwrap();
+        assert!(increments.iter().all(|increment| increment.count == 2));
+
+        let reads: Vec<&str> = keys.iter().map(String::as_str).collect();
+        for (key, count) in keys.iter().zip(storage.get_many(&reads).await.unwrap()) {
+            assert_eq!(storage.get(key).await.unwrap(), count);
+        }
+    }
+}
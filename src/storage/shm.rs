@@ -0,0 +1,403 @@
+use async_trait::async_trait;
+use nginx_module::bindings::{
+    ngx_conf_t, ngx_int_t, ngx_rbtree_delete, ngx_rbtree_insert, ngx_rbtree_key_t,
+    ngx_rbtree_min, ngx_rbtree_next, ngx_rbtree_node_t, ngx_rbtree_t, ngx_shared_memory_add,
+    ngx_shm_zone_t, ngx_shmtx_lock, ngx_shmtx_unlock, ngx_slab_alloc, ngx_slab_alloc_locked,
+    ngx_slab_free_locked, ngx_slab_pool_t, ngx_str_t, NGX_ERROR, NGX_OK,
+};
+use std::ffi::c_void;
+use std::mem;
+use std::ptr;
+use std::time::Duration;
+use crate::clock::{Clock, SystemClock};
+use crate::storage::{CounterSnapshot, Increment, StorageBackend, StorageError};
+
+/// Tree header living at the start of the zone, shared by all workers
+#[repr(C)]
+struct SharedState {
+    rbtree: ngx_rbtree_t,
+    sentinel: ngx_rbtree_node_t,
+}
+
+/// One counter. The key bytes are allocated directly after the struct.
+#[repr(C)]
+struct CounterNode {
+    node: ngx_rbtree_node_t,
+    count: u64,
+    expire_at: u64,
+    len: u32,
+    data: [u8; 0],
+}
+
+/// Counters kept in an nginx shared memory zone.
+///
+/// Works like the stock `limit_req` module: every worker on the host sees
+/// the same red-black tree, guarded by the slab pool mutex, so there is no
+/// network hop and no external dependency. Counters are lost on restart
+/// unless saved with `save_snapshot` and loaded again on startup.
+pub struct ShmZoneStorage {
+    zone: *mut ngx_shm_zone_t,
+}
+
+// The zone is mapped into every worker and all access goes through the
+// slab pool mutex.
+unsafe impl Send for ShmZoneStorage {}
+unsafe impl Sync for ShmZoneStorage {}
+
+impl ShmZoneStorage {
+    /// Register a shared memory zone of `size` bytes while nginx parses its
+    /// configuration. The zone is usable once nginx has initialised it,
+    /// i.e. in worker processes.
+    ///
+    /// # Safety
+    ///
+    /// `cf` must be the configuration currently being parsed and `tag` must
+    /// point to this module's `ngx_module_t`.
+    pub unsafe fn add_zone(
+        cf: *mut ngx_conf_t,
+        name: &'static str,
+        size: usize,
+        tag: *mut c_void,
+    ) -> Result<Self, StorageError> {
+        let mut zone_name = ngx_str_t {
+            len: name.len(),
+            data: name.as_ptr() as *mut u8,
+        };
+
+        let zone = ngx_shared_memory_add(cf, &mut zone_name, size, tag);
+        if zone.is_null() {
+            return Err(StorageError::ConnectionError(format!(
+                "failed to add shared memory zone \"{}\"",
+                name
+            )));
+        }
+
+        if (*zone).init.is_some() {
+            return Err(StorageError::ConnectionError(format!(
+                "shared memory zone \"{}\" is already in use",
+                name
+            )));
+        }
+
+        (*zone).init = Some(init_zone);
+
+        Ok(Self { zone })
+    }
+
+    /// Expiry times are shared by every worker process, so they all read
+    /// the system clock rather than one each could swap out.
+    fn get_current_timestamp() -> u64 {
+        SystemClock.unix_secs()
+    }
+
+    /// Lock the zone and return its slab pool and tree.
+    fn lock(&self) -> Result<ZoneGuard, StorageError> {
+        unsafe {
+            let state = (*self.zone).data as *mut SharedState;
+            let pool = (*self.zone).shm.addr as *mut ngx_slab_pool_t;
+            if state.is_null() || pool.is_null() {
+                return Err(StorageError::ConnectionError(
+                    "shared memory zone is not initialized".to_string(),
+                ));
+            }
+
+            ngx_shmtx_lock(&mut (*pool).mutex);
+            Ok(ZoneGuard { pool, state })
+        }
+    }
+}
+
+struct ZoneGuard {
+    pool: *mut ngx_slab_pool_t,
+    state: *mut SharedState,
+}
+
+impl Drop for ZoneGuard {
+    fn drop(&mut self) {
+        unsafe { ngx_shmtx_unlock(&mut (*self.pool).mutex) }
+    }
+}
+
+impl ZoneGuard {
+    unsafe fn lookup(&self, key: &[u8]) -> *mut CounterNode {
+        let hash = hash_key(key);
+        let sentinel = (*self.state).rbtree.sentinel;
+        let mut node = (*self.state).rbtree.root;
+
+        while node != sentinel {
+            if hash != (*node).key {
+                node = if hash < (*node).key { (*node).left } else { (*node).right };
+                continue;
+            }
+
+            let counter = node as *mut CounterNode;
+            match key.cmp(node_key(counter)) {
+                std::cmp::Ordering::Equal => return counter,
+                std::cmp::Ordering::Less => node = (*node).left,
+                std::cmp::Ordering::Greater => node = (*node).right,
+            }
+        }
+
+        ptr::null_mut()
+    }
+
+    unsafe fn insert(&self, key: &[u8], count: u64, expire_at: u64) -> Result<(), StorageError> {
+        let size = mem::size_of::<CounterNode>() + key.len();
+
+        let mut counter = ngx_slab_alloc_locked(self.pool, size) as *mut CounterNode;
+        if counter.is_null() {
+            // Out of memory: reclaim finished windows and try once more
+            self.remove_expired(Self::now());
+            counter = ngx_slab_alloc_locked(self.pool, size) as *mut CounterNode;
+        }
+        if counter.is_null() {
+            return Err(StorageError::DatabaseError(
+                "shared memory zone is full".to_string(),
+            ));
+        }
+
+        (*counter).node.key = hash_key(key);
+        (*counter).count = count;
+        (*counter).len = key.len() as u32;
+        (*counter).expire_at = expire_at;
+        ptr::copy_nonoverlapping(key.as_ptr(), ptr::addr_of_mut!((*counter).data) as *mut u8, key.len());
+
+        ngx_rbtree_insert(&mut (*self.state).rbtree, &mut (*counter).node);
+        Ok(())
+    }
+
+    unsafe fn remove(&self, counter: *mut CounterNode) {
+        ngx_rbtree_delete(&mut (*self.state).rbtree, &mut (*counter).node);
+        ngx_slab_free_locked(self.pool, counter as *mut c_void);
+    }
+
+    /// Calls `f` on every node in the tree.
+    unsafe fn for_each(&self, mut f: impl FnMut(*mut CounterNode)) {
+        let tree = ptr::addr_of_mut!((*self.state).rbtree);
+        if (*tree).root == (*tree).sentinel {
+            return;
+        }
+
+        let mut node = ngx_rbtree_min((*tree).root, (*tree).sentinel);
+        while !node.is_null() {
+            let next = ngx_rbtree_next(tree, node);
+            f(node as *mut CounterNode);
+            node = next;
+        }
+    }
+
+    unsafe fn remove_expired(&self, now: u64) -> u64 {
+        let mut removed = 0;
+        self.for_each(|counter| {
+            if (*counter).expire_at <= now {
+                self.remove(counter);
+                removed += 1;
+            }
+        });
+        removed
+    }
+
+    fn now() -> u64 {
+        ShmZoneStorage::get_current_timestamp()
+    }
+}
+
+unsafe fn node_key<'a>(counter: *const CounterNode) -> &'a [u8] {
+    std::slice::from_raw_parts(ptr::addr_of!((*counter).data) as *const u8, (*counter).len as usize)
+}
+
+/// FNV-1a, used as the rbtree key; collisions are resolved by comparing keys.
+fn hash_key(key: &[u8]) -> ngx_rbtree_key_t {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in key {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash as ngx_rbtree_key_t
+}
+
+/// rbtree insert callback ordering nodes by hash, then by key bytes.
+unsafe extern "C" fn insert_value(
+    mut temp: *mut ngx_rbtree_node_t,
+    node: *mut ngx_rbtree_node_t,
+    sentinel: *mut ngx_rbtree_node_t,
+) {
+    let mut link: *mut *mut ngx_rbtree_node_t;
+
+    loop {
+        link = if (*node).key < (*temp).key {
+            &mut (*temp).left
+        } else if (*node).key > (*temp).key {
+            &mut (*temp).right
+        } else {
+            let lhs = node_key(node as *const CounterNode);
+            let rhs = node_key(temp as *const CounterNode);
+            if lhs < rhs { &mut (*temp).left } else { &mut (*temp).right }
+        };
+
+        if *link == sentinel {
+            break;
+        }
+        temp = *link;
+    }
+
+    *link = node;
+    (*node).parent = temp;
+    (*node).left = sentinel;
+    (*node).right = sentinel;
+    (*node).color = 1; // ngx_rbt_red
+}
+
+/// `ngx_shm_zone_t.init` callback: create the tree, or reuse the one from
+/// the previous cycle on reload.
+unsafe extern "C" fn init_zone(zone: *mut ngx_shm_zone_t, data: *mut c_void) -> ngx_int_t {
+    if !data.is_null() {
+        (*zone).data = data;
+        return NGX_OK as ngx_int_t;
+    }
+
+    let pool = (*zone).shm.addr as *mut ngx_slab_pool_t;
+    if (*zone).shm.exists != 0 {
+        (*zone).data = (*pool).data;
+        return NGX_OK as ngx_int_t;
+    }
+
+    let state = ngx_slab_alloc(pool, mem::size_of::<SharedState>()) as *mut SharedState;
+    if state.is_null() {
+        return NGX_ERROR as ngx_int_t;
+    }
+
+    // ngx_rbtree_init()
+    (*state).sentinel.color = 0;
+    (*state).rbtree.root = &mut (*state).sentinel;
+    (*state).rbtree.sentinel = &mut (*state).sentinel;
+    (*state).rbtree.insert = Some(insert_value);
+
+    (*pool).data = state as *mut c_void;
+    (*zone).data = state as *mut c_void;
+
+    NGX_OK as ngx_int_t
+}
+
+#[async_trait]
+impl StorageBackend for ShmZoneStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let zone = self.lock()?;
+        let current_time = Self::get_current_timestamp();
+
+        unsafe {
+            let counter = zone.lookup(key.as_bytes());
+            if !counter.is_null() && (*counter).expire_at > current_time {
+                return Ok((*counter).count);
+            }
+        }
+
+        Ok(0)
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.increment_by(key, 1, expire).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        if amount == 0 {
+            return self.get(key).await.map(|count| Increment { count });
+        }
+        let zone = self.lock()?;
+        let current_time = Self::get_current_timestamp();
+        let expire_at = current_time + expire as u64;
+
+        unsafe {
+            let counter = zone.lookup(key.as_bytes());
+            if counter.is_null() {
+                zone.insert(key.as_bytes(), amount, expire_at)?;
+                return Ok(Increment { count: amount });
+            }
+
+            if (*counter).expire_at > current_time {
+                (*counter).count = (*counter).count.saturating_add(amount);
+            } else {
+                (*counter).count = amount;
+            }
+            (*counter).expire_at = expire_at;
+            Ok(Increment { count: (*counter).count })
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let zone = self.lock()?;
+
+        unsafe {
+            let counter = zone.lookup(key.as_bytes());
+            if !counter.is_null() {
+                zone.remove(counter);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let zone = self.lock()?;
+        Ok(unsafe { zone.remove_expired(Self::get_current_timestamp()) })
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let zone = self.lock()?;
+        let now = SystemClock.now();
+
+        let expire_at = unsafe {
+            let counter = zone.lookup(key.as_bytes());
+            if counter.is_null() {
+                return Ok(None);
+            }
+            (*counter).expire_at
+        };
+
+        Ok(Duration::from_secs(expire_at).checked_sub(now).filter(|ttl| !ttl.is_zero()))
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        let zone = self.lock()?;
+        let current_time = Self::get_current_timestamp();
+        let mut counters = Vec::new();
+
+        unsafe {
+            zone.for_each(|counter| {
+                if (*counter).expire_at > current_time {
+                    counters.push(CounterSnapshot {
+                        key: String::from_utf8_lossy(node_key(counter)).into_owned(),
+                        count: (*counter).count,
+                        expire_at: (*counter).expire_at,
+                    });
+                }
+            });
+        }
+
+        Ok(counters)
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        let zone = self.lock()?;
+        let current_time = Self::get_current_timestamp();
+        let mut imported = 0;
+
+        for counter in counters.iter().filter(|counter| counter.expire_at > current_time) {
+            unsafe {
+                let node = zone.lookup(counter.key.as_bytes());
+                if node.is_null() {
+                    zone.insert(counter.key.as_bytes(), counter.count, counter.expire_at)?;
+                } else if (*node).expire_at > current_time {
+                    (*node).count = (*node).count.saturating_add(counter.count);
+                    (*node).expire_at = (*node).expire_at.max(counter.expire_at);
+                } else {
+                    (*node).count = counter.count;
+                    (*node).expire_at = counter.expire_at;
+                }
+            }
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+}
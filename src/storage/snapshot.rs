@@ -0,0 +1,133 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use crate::storage::{CounterSnapshot, StorageBackend, StorageError};
+
+/// Counters handed to `import_all` at a time by `migrate`.
+pub const MIGRATE_BATCH: usize = 1000;
+
+/// Copy every live counter from `from` to `to` and return how many were
+/// copied.
+///
+/// Counters keep their expiry, so the target enforces the rest of each
+/// window, and are added to anything already counted in `to`. Running the
+/// copy twice counts those requests twice, so run it from one place only.
+pub async fn migrate(from: &dyn StorageBackend, to: &dyn StorageBackend) -> Result<u64, StorageError> {
+    let counters = from.export_all().await?;
+
+    let mut copied = 0;
+    for batch in counters.chunks(MIGRATE_BATCH) {
+        copied += to.import_all(batch).await?;
+    }
+
+    metrics::counter!("rate_limiter_migrated_total").increment(copied);
+    Ok(copied)
+}
+
+/// Write every live counter of `storage` to `path` as JSON lines and return
+/// how many were saved.
+///
+/// The file is written next to `path` and renamed into place, so a crash
+/// mid-write leaves the previous snapshot intact.
+pub async fn save_snapshot(storage: &dyn StorageBackend, path: &Path) -> Result<usize, StorageError> {
+    let counters = storage.export_all().await?;
+
+    let mut data = Vec::new();
+    for counter in &counters {
+        serde_json::to_writer(&mut data, counter)
+            .map_err(|e| StorageError::InvalidValueType(e.to_string()))?;
+        data.push(b'\n');
+    }
+
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    tokio::fs::write(&partial, &data)
+        .await
+        .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+    tokio::fs::rename(&partial, path)
+        .await
+        .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+    metrics::counter!("rate_limiter_snapshot_saved_total").increment(counters.len() as u64);
+    Ok(counters.len())
+}
+
+/// Import the counters saved in `path` into `storage` and return how many
+/// were still live. A missing file imports nothing.
+pub async fn load_snapshot(storage: &dyn StorageBackend, path: &Path) -> Result<u64, StorageError> {
+    let data = match tokio::fs::read_to_string(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(StorageError::DatabaseError(e.to_string())),
+    };
+
+    let counters = data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str::<CounterSnapshot>)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| StorageError::InvalidValueType(e.to_string()))?;
+
+    let imported = storage.import_all(&counters).await?;
+    metrics::counter!("rate_limiter_snapshot_restored_total").increment(imported);
+    Ok(imported)
+}
+
+/// Save a snapshot of `storage` to `path` every time the process receives
+/// `kind` (e.g. `SignalKind::user_defined2()`), until the returned handle
+/// is aborted. Lets operators checkpoint counters right before a restart.
+pub fn spawn_snapshot_on_signal(
+    storage: Arc<dyn StorageBackend>,
+    path: PathBuf,
+    kind: SignalKind,
+) -> Result<JoinHandle<()>, StorageError> {
+    let mut signals = signal(kind).map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+
+    Ok(tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            match save_snapshot(storage.as_ref(), &path).await {
+                Ok(saved) => log::info!("Saved {} rate limit counters to {}", saved, path.display()),
+                Err(e) => log::warn!("Saving rate limit snapshot to {} failed: {}", path.display(), e),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_snapshot_round_trip() {
+        let path = std::env::temp_dir().join(format!("ratelimit-{}.snapshot", std::process::id()));
+        let before = MemoryStorage::new();
+        before.increment("a", 60).await.unwrap();
+        before.increment("a", 60).await.unwrap();
+        before.increment("b", 60).await.unwrap();
+
+        assert_eq!(save_snapshot(&before, &path).await.unwrap(), 2);
+
+        let after = MemoryStorage::new();
+        assert_eq!(load_snapshot(&after, &path).await.unwrap(), 2);
+        assert_eq!(after.get_many(&["a", "b"]).await.unwrap(), vec![2, 1]);
+
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(load_snapshot(&after, &path).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_between_backends() {
+        let from = crate::storage::SQLiteStorage::new_in_memory().unwrap();
+        for i in 0..(MIGRATE_BATCH + 5) {
+            from.increment(&format!("key-{}", i), 60).await.unwrap();
+        }
+        from.increment("key-0", 60).await.unwrap();
+
+        let to = MemoryStorage::new();
+        assert_eq!(migrate(&from, &to).await.unwrap(), MIGRATE_BATCH as u64 + 5);
+        assert_eq!(to.get_many(&["key-0", "key-1"]).await.unwrap(), vec![2, 1]);
+    }
+}
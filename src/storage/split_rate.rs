@@ -0,0 +1,278 @@
+use async_trait::async_trait;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use crate::clock::{self, Clock};
+use crate::storage::{CounterSnapshot, Increment, MemoryStorage, StorageBackend, StorageError};
+
+/// Schedule for `SplitRateStorage`.
+#[derive(Debug, Clone)]
+pub struct SplitRateOptions {
+    /// How often local counts are pushed to the shared backend and global
+    /// counts read back
+    pub sync_interval: Duration,
+    /// Nodes announce themselves once per period; a node missing for two
+    /// periods no longer counts towards the split
+    pub heartbeat_interval: Duration,
+    /// Prefix of the heartbeat keys in the shared backend
+    pub namespace: String,
+}
+
+impl Default for SplitRateOptions {
+    fn default() -> Self {
+        Self {
+            sync_interval: Duration::from_millis(500),
+            heartbeat_interval: Duration::from_secs(10),
+            namespace: "rl:nodes".to_string(),
+        }
+    }
+}
+
+/// Hits counted locally and not yet pushed to the shared backend.
+struct Pending {
+    count: u64,
+    expire_at: u64,
+}
+
+/// A key's count in the shared backend as of the last sync.
+struct Global {
+    count: u64,
+    expire_at: u64,
+}
+
+/// Enforces the limit locally, split across the active nodes, and
+/// reconciles with a shared backend in the background ("split rate").
+///
+/// Requests are counted in a local `MemoryStorage` and never wait for the
+/// shared backend. The count handed to the limiter is the larger of
+///
+/// - the local count times the number of active nodes, so with even load
+///   balancing each node allows `limit / N`, and
+/// - the last global count plus the hits not yet pushed, so a client that
+///   sticks to one node or spreads over all of them is still caught.
+///
+/// Every `sync_interval`, `sync` adds the local hits to the shared backend
+/// with `import_all`, re-reads the global counts of the keys it pushed and
+/// refreshes the node count from heartbeat keys. Backend traffic is one
+/// batch per interval instead of two calls per request, at the price of
+/// admitting up to one interval's worth of extra requests cluster-wide.
+pub struct SplitRateStorage {
+    local: MemoryStorage,
+    shared: Arc<dyn StorageBackend>,
+    options: SplitRateOptions,
+    pending: DashMap<String, Pending>,
+    global: DashMap<String, Global>,
+    nodes: AtomicU64,
+    /// Heartbeat period this node last announced itself in
+    announced: AtomicU64,
+    clock: Arc<dyn Clock>,
+}
+
+impl SplitRateStorage {
+    pub fn new(shared: Arc<dyn StorageBackend>, options: SplitRateOptions) -> Self {
+        Self {
+            local: MemoryStorage::new(),
+            shared,
+            options,
+            pending: DashMap::new(),
+            global: DashMap::new(),
+            nodes: AtomicU64::new(1),
+            announced: AtomicU64::new(u64::MAX),
+            clock: clock::system(),
+        }
+    }
+
+    /// Use `clock` for expiry and heartbeat periods, here and in the local
+    /// counters.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.local = MemoryStorage::new().with_clock(Arc::clone(&clock));
+        self.clock = clock;
+        self
+    }
+
+    /// Nodes seen in the last two heartbeat periods, at least this one.
+    pub fn active_nodes(&self) -> u64 {
+        self.nodes.load(Ordering::Relaxed)
+    }
+
+    fn estimate(&self, key: &str, local: u64, now: u64) -> u64 {
+        let split = local.saturating_mul(self.active_nodes());
+        let global = match self.global.get(key) {
+            Some(global) if global.expire_at > now => global.count,
+            _ => 0,
+        };
+        let unsynced = self.pending.get(key).map_or(0, |pending| pending.count);
+        split.max(global.saturating_add(unsynced))
+    }
+
+    fn heartbeat_key(&self, period: u64) -> String {
+        format!("{}:{}", self.options.namespace, period)
+    }
+
+    /// Announce this node and re-count the active ones.
+    async fn heartbeat(&self, now: u64) -> Result<(), StorageError> {
+        let interval = self.options.heartbeat_interval.as_secs().max(1);
+        let period = now / interval;
+        let ttl = (interval * 3) as u32;
+
+        if self.announced.swap(period, Ordering::Relaxed) != period {
+            self.shared.increment(&self.heartbeat_key(period), ttl).await?;
+        }
+        let current = self.heartbeat_key(period);
+        let previous = self.heartbeat_key(period.saturating_sub(1));
+        let counts = self.shared.get_many(&[current.as_str(), previous.as_str()]).await?;
+
+        // The current period is still filling up, so take the larger of the two
+        let nodes = counts.into_iter().max().unwrap_or(1).max(1);
+        self.nodes.store(nodes, Ordering::Relaxed);
+        metrics::gauge!("rate_limiter_split_rate_nodes").set(nodes as f64);
+        Ok(())
+    }
+
+    /// Push local hits to the shared backend and refresh the global counts
+    /// and node count. Hits that fail to push are kept for the next round.
+    pub async fn sync(&self) -> Result<(), StorageError> {
+        let now = self.clock.unix_secs();
+        self.heartbeat(now).await?;
+
+        let mut pushed = Vec::new();
+        self.pending.retain(|key, pending| {
+            pushed.push(CounterSnapshot {
+                key: key.clone(),
+                count: pending.count,
+                expire_at: pending.expire_at,
+            });
+            false
+        });
+        self.global.retain(|_, global| global.expire_at > now);
+        if pushed.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(e) = self.shared.import_all(&pushed).await {
+            for counter in pushed {
+                let mut pending = self.pending.entry(counter.key).or_insert(Pending { count: 0, expire_at: 0 });
+                pending.count = pending.count.saturating_add(counter.count);
+                pending.expire_at = pending.expire_at.max(counter.expire_at);
+            }
+            return Err(e);
+        }
+        metrics::counter!("rate_limiter_split_rate_pushed_total").increment(pushed.len() as u64);
+
+        let keys: Vec<&str> = pushed.iter().map(|counter| counter.key.as_str()).collect();
+        let counts = self.shared.get_many(&keys).await?;
+        for (counter, count) in pushed.iter().zip(counts) {
+            self.global.insert(counter.key.clone(), Global { count, expire_at: counter.expire_at });
+        }
+        Ok(())
+    }
+
+    /// Run `sync` every `sync_interval` until the returned handle is aborted.
+    pub fn spawn_sync(self: &Arc<Self>) -> JoinHandle<()> {
+        let storage = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(storage.options.sync_interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = storage.sync().await {
+                    metrics::counter!("rate_limiter_split_rate_sync_errors_total").increment(1);
+                    log::warn!("Split rate sync failed, enforcing locally: {}", e);
+                }
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SplitRateStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        Ok(self.estimate(key, self.local.count(key), self.clock.unix_secs()))
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.increment_by(key, 1, expire).await
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        if amount == 0 {
+            return self.get(key).await.map(|count| Increment { count });
+        }
+        let now = self.clock.unix_secs();
+        let local = self.local.add(key, amount, expire).count;
+
+        let mut pending = self.pending.entry(key.to_string()).or_insert(Pending { count: 0, expire_at: 0 });
+        pending.count = pending.count.saturating_add(amount);
+        pending.expire_at = now + expire as u64;
+        drop(pending);
+
+        Ok(Increment { count: self.estimate(key, local, now) })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.local.delete(key).await?;
+        self.pending.remove(key);
+        self.global.remove(key);
+        self.shared.delete(key).await
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let now = self.clock.unix_secs();
+        self.global.retain(|_, global| global.expire_at > now);
+        self.local.cleanup_expired().await?;
+        self.shared.cleanup_expired().await
+    }
+
+    /// The local counter's, which expires with the shared one give or take
+    /// a sync interval.
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        self.local.ttl(key).await
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.shared.warm_up().await
+    }
+
+    /// The shared backend's counters; hits not yet synced are left out.
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        self.shared.export_all().await
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        self.shared.import_all(counters).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[tokio::test]
+    async fn test_split_and_reconcile() {
+        // A fixed clock keeps all syncs in one heartbeat period
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::default());
+        let shared: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new().with_clock(Arc::clone(&clock)));
+        let node = || SplitRateStorage::new(Arc::clone(&shared), SplitRateOptions::default()).with_clock(Arc::clone(&clock));
+        let (a, b) = (node(), node());
+        a.sync().await.unwrap();
+        b.sync().await.unwrap();
+        a.sync().await.unwrap();
+        assert_eq!(a.active_nodes(), 2);
+
+        // Two nodes: each local hit counts double
+        assert_eq!(a.increment("client", 60).await.unwrap().count, 2);
+        assert_eq!(a.increment("client", 60).await.unwrap().count, 4);
+        assert_eq!(shared.get("client").await.unwrap(), 0);
+
+        // A client hammering node b is seen by a after the next sync
+        for _ in 0..10 {
+            b.increment("client", 60).await.unwrap();
+        }
+        b.sync().await.unwrap();
+        a.sync().await.unwrap();
+        assert_eq!(shared.get("client").await.unwrap(), 12);
+        assert_eq!(a.increment("client", 60).await.unwrap().count, 13);
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::storage::{CounterSnapshot, Increment};
+
+/// Connection pool and schema settings shared by the MySQL and PostgreSQL
+/// backends.
+#[derive(Debug, Clone)]
+pub struct SqlPoolOptions {
+    pub max_connections: usize,
+    /// Connections opened by `warm_up` before traffic arrives (MySQL also
+    /// keeps this many open while idle)
+    pub min_connections: usize,
+    /// How long a request waits for a free pooled connection
+    pub acquire_timeout: Duration,
+    /// Server-side limit for a single statement; `None` keeps the server default
+    pub statement_timeout: Option<Duration>,
+    /// Prepared statements cached per connection (MySQL only; PostgreSQL
+    /// caches every statement the backend uses)
+    pub statement_cache_size: usize,
+    /// Create and migrate the schema on startup; turn off when DBAs manage
+    /// it and apply `migrations()` themselves
+    pub manage_schema: bool,
+}
+
+impl Default for SqlPoolOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: 16,
+            min_connections: 1,
+            acquire_timeout: Duration::from_secs(1),
+            statement_timeout: Some(Duration::from_secs(1)),
+            statement_cache_size: 32,
+            manage_schema: true,
+        }
+    }
+}
+
+/// Collapse a batch into one `(key, hits, expire)` entry per distinct key,
+/// in first-seen order, so a multi-row UPSERT touches each row once. The
+/// expiry of a key's last occurrence wins.
+pub(crate) fn group_hits<'a>(keys: &[(&'a str, u32)]) -> Vec<(&'a str, u64, u32)> {
+    let mut index: HashMap<&str, usize> = HashMap::with_capacity(keys.len());
+    let mut grouped: Vec<(&str, u64, u32)> = Vec::with_capacity(keys.len());
+    for &(key, expire) in keys {
+        match index.get(key) {
+            Some(&i) => {
+                grouped[i].1 += 1;
+                grouped[i].2 = expire;
+            }
+            None => {
+                index.insert(key, grouped.len());
+                grouped.push((key, 1, expire));
+            }
+        }
+    }
+    grouped
+}
+
+/// Turn the final count of every key in a grouped batch back into one
+/// `Increment` per input position, as if the keys had been counted one by
+/// one: earlier occurrences of a repeated key see the smaller counts.
+pub(crate) fn spread_counts(keys: &[(&str, u32)], totals: &HashMap<String, u64>) -> Vec<Increment> {
+    let mut later: HashMap<&str, u64> = HashMap::with_capacity(keys.len());
+    let mut increments = vec![Increment { count: 0 }; keys.len()];
+    for (i, &(key, _)) in keys.iter().enumerate().rev() {
+        let after = later.entry(key).or_insert(0);
+        let total = totals.get(key).copied().unwrap_or(0);
+        increments[i] = Increment { count: total.saturating_sub(*after) };
+        *after += 1;
+    }
+    increments
+}
+
+/// Rows per multi-row UPSERT when importing counters.
+pub(crate) const IMPORT_BATCH: usize = 500;
+
+/// Drop counters that ended before `now` and fold repeated keys into one
+/// entry (counts added, later expiry kept), since ON CONFLICT may only
+/// touch a row once per statement.
+pub(crate) fn merge_snapshots(counters: &[CounterSnapshot], now: u64) -> Vec<CounterSnapshot> {
+    let mut index: HashMap<&str, usize> = HashMap::with_capacity(counters.len());
+    let mut merged: Vec<CounterSnapshot> = Vec::with_capacity(counters.len());
+    for counter in counters.iter().filter(|counter| counter.expire_at > now) {
+        match index.get(counter.key.as_str()) {
+            Some(&i) => {
+                merged[i].count = merged[i].count.saturating_add(counter.count);
+                merged[i].expire_at = merged[i].expire_at.max(counter.expire_at);
+            }
+            None => {
+                index.insert(&counter.key, merged.len());
+                merged.push(counter.clone());
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_grouping() {
+        let keys = [("a", 60), ("b", 10), ("a", 30)];
+        assert_eq!(group_hits(&keys), vec![("a", 2, 30), ("b", 1, 10)]);
+
+        let totals = HashMap::from([("a".to_string(), 5), ("b".to_string(), 1)]);
+        let counts: Vec<u64> = spread_counts(&keys, &totals).iter().map(|i| i.count).collect();
+        assert_eq!(counts, vec![4, 1, 5]);
+    }
+
+    #[test]
+    fn test_merge_snapshots() {
+        let counter = |key: &str, count, expire_at| CounterSnapshot { key: key.to_string(), count, expire_at };
+        let counters = [counter("a", 2, 150), counter("b", 1, 90), counter("a", 3, 120)];
+        assert_eq!(merge_snapshots(&counters, 100), vec![counter("a", 5, 150)]);
+    }
+}
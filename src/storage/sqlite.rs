@@ -1,137 +1,494 @@
 use async_trait::async_trait;
-use rusqlite::{Connection, Result as SqliteResult, params};
-use std::path::Path;
-use crate::storage::{StorageBackend, StorageError};
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use crate::clock::{self, Clock};
+use crate::storage::migrations::{pending, version_table, SqlDialect, SCHEMA_VERSION_TABLE};
+use crate::storage::{CounterSnapshot, Increment, StorageBackend, StorageError};
 
+/// Pool, locking and WAL settings for `SQLiteStorage`.
+#[derive(Debug, Clone)]
+pub struct SQLiteOptions {
+    /// Connections opened to the database file
+    pub pool_size: usize,
+    /// How long a statement waits for a lock held by another connection or
+    /// process before failing with `SQLITE_BUSY`
+    pub busy_timeout: Duration,
+    /// How often writes trigger a passive WAL checkpoint
+    pub checkpoint_interval: Duration,
+    /// Create and migrate the schema on startup; turn off when the schema
+    /// is managed outside the module
+    pub manage_schema: bool,
+}
+
+impl Default for SQLiteOptions {
+    fn default() -> Self {
+        Self {
+            pool_size: 4,
+            busy_timeout: Duration::from_secs(5),
+            checkpoint_interval: Duration::from_secs(60),
+            manage_schema: true,
+        }
+    }
+}
+
+const GET_SQL: &str = "SELECT count FROM rate_limits WHERE key_name = ? AND expire_at > ?";
+
+// SQLite integers are signed 64-bit, so the count stops at i64::MAX
+const INCREMENT_SQL: &str = r"
+    INSERT INTO rate_limits (key_name, count, expire_at)
+    VALUES (?1, 1, ?2)
+    ON CONFLICT(key_name) DO UPDATE SET
+        count = CASE
+            WHEN expire_at > ?3 THEN count + (count < 9223372036854775807)
+            ELSE 1
+        END,
+        expire_at = ?2
+    RETURNING count
+";
+
+// Like INCREMENT_SQL for any amount, saturating at i64::MAX
+const INCREMENT_BY_SQL: &str = r"
+    INSERT INTO rate_limits (key_name, count, expire_at)
+    VALUES (?1, ?4, ?2)
+    ON CONFLICT(key_name) DO UPDATE SET
+        count = CASE
+            WHEN expire_at <= ?3 THEN ?4
+            WHEN count > 9223372036854775807 - ?4 THEN 9223372036854775807
+            ELSE count + ?4
+        END,
+        expire_at = ?2
+    RETURNING count
+";
+
+// Adds to a live counter and keeps the later of the two expiries
+const IMPORT_SQL: &str = r"
+    INSERT INTO rate_limits (key_name, count, expire_at)
+    VALUES (?1, ?2, ?3)
+    ON CONFLICT(key_name) DO UPDATE SET
+        count = CASE
+            WHEN expire_at > ?4 THEN MIN(count + excluded.count, 9223372036854775807)
+            ELSE excluded.count
+        END,
+        expire_at = CASE
+            WHEN expire_at > ?4 THEN MAX(expire_at, excluded.expire_at)
+            ELSE excluded.expire_at
+        END
+";
+
+struct Pool {
+    conns: Mutex<Vec<Connection>>,
+    available: Arc<Semaphore>,
+    size: usize,
+}
+
+/// SQLite backed storage.
+///
+/// rusqlite is blocking, so every statement runs on tokio's blocking thread
+/// pool with a connection taken from a small pool. The database uses WAL
+/// mode; the WAL is checkpointed passively from the write path every
+/// `checkpoint_interval` and truncated by `cleanup_expired`.
 pub struct SQLiteStorage {
-    conn: Connection,
+    pool: Arc<Pool>,
+    checkpoint_interval: Duration,
+    last_checkpoint: Mutex<Instant>,
+    clock: Arc<dyn Clock>,
 }
 
 impl SQLiteStorage {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
-        let conn = Connection::open(path)
-            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+        Self::with_options(path, SQLiteOptions::default())
+    }
+
+    pub fn with_options<P: AsRef<Path>>(path: P, options: SQLiteOptions) -> Result<Self, StorageError> {
+        let path: PathBuf = path.as_ref().to_path_buf();
+        let mut conns = (0..options.pool_size.max(1))
+            .map(|_| {
+                let conn = Connection::open(&path)
+                    .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+                Self::configure(&conn, &options)?;
+                Ok(conn)
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
 
         // Enable WAL mode for better concurrency
-        conn.execute_batch("PRAGMA journal_mode=WAL;")
+        conns[0].execute_batch("PRAGMA journal_mode=WAL;")
             .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
 
-        // Create table if not exists
-        Self::create_table(&conn)?;
+        if options.manage_schema {
+            Self::migrate(&mut conns[0])?;
+        }
 
-        Ok(Self { conn })
+        Ok(Self::from_connections(conns, &options))
     }
 
+    /// A private in-memory database, served by a single connection.
     pub fn new_in_memory() -> Result<Self, StorageError> {
-        let conn = Connection::open_in_memory()
+        let options = SQLiteOptions::default();
+        let mut conn = Connection::open_in_memory()
             .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+        Self::configure(&conn, &options)?;
+
+        Self::migrate(&mut conn)?;
 
-        Self::create_table(&conn)?;
+        Ok(Self::from_connections(vec![conn], &options))
+    }
 
-        Ok(Self { conn })
+    fn from_connections(conns: Vec<Connection>, options: &SQLiteOptions) -> Self {
+        let size = conns.len();
+        Self {
+            pool: Arc::new(Pool {
+                conns: Mutex::new(conns),
+                available: Arc::new(Semaphore::new(size)),
+                size,
+            }),
+            checkpoint_interval: options.checkpoint_interval,
+            last_checkpoint: Mutex::new(Instant::now()),
+            clock: clock::system(),
+        }
     }
 
-    fn create_table(conn: &Connection) -> Result<(), StorageError> {
-        conn.execute_batch(
-            r"
-            CREATE TABLE IF NOT EXISTS rate_limits (
-                key_name TEXT PRIMARY KEY,
-                count INTEGER NOT NULL DEFAULT 0,
-                expire_at INTEGER NOT NULL
-            );
-            CREATE INDEX IF NOT EXISTS idx_expire_at ON rate_limits(expire_at);
-            "
-        ).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+    /// Read expiry times from `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
 
-        Ok(())
+    fn configure(conn: &Connection, options: &SQLiteOptions) -> Result<(), StorageError> {
+        conn.busy_timeout(options.busy_timeout)
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+        conn.execute_batch("PRAGMA synchronous=NORMAL;")
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))
     }
 
-    fn get_current_timestamp() -> i64 {
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs() as i64
+    /// Apply pending schema migrations. `BEGIN IMMEDIATE` takes the write
+    /// lock up front, so processes opening the file together run them once.
+    fn migrate(conn: &mut Connection) -> Result<(), StorageError> {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        tx.execute_batch(version_table(SqlDialect::Sqlite))
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        let current: Option<u32> = tx
+            .query_row(&format!("SELECT MAX(version) FROM {}", SCHEMA_VERSION_TABLE), [], |row| row.get(0))
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        for migration in pending(SqlDialect::Sqlite, current.unwrap_or(0)) {
+            log::info!("Applying SQLite migration {}: {}", migration.version, migration.description);
+            for statement in migration.statements {
+                tx.execute_batch(statement)
+                    .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            }
+            tx.execute(
+                &format!("INSERT INTO {} (version, description) VALUES (?, ?)", SCHEMA_VERSION_TABLE),
+                params![migration.version, migration.description],
+            ).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        }
+
+        tx.commit().map_err(|e| StorageError::DatabaseError(e.to_string()))
+    }
+
+    fn get_current_timestamp(&self) -> i64 {
+        self.clock.unix_secs() as i64
+    }
+
+    /// Run `f` with a pooled connection on the blocking thread pool.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, StorageError> + Send + 'static,
+    {
+        let permit = Arc::clone(&self.pool.available)
+            .acquire_owned()
+            .await
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+        let pool = Arc::clone(&self.pool);
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let mut conn = pool.conns
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .pop()
+                .ok_or_else(|| StorageError::ConnectionError("no SQLite connection available".to_string()))?;
+
+            let result = f(&mut conn);
+
+            pool.conns
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push(conn);
+            result
+        })
+        .await
+        .map_err(|e| StorageError::DatabaseError(e.to_string()))?
+    }
+
+    fn checkpoint_due(&self) -> bool {
+        let mut last = self.last_checkpoint
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if last.elapsed() < self.checkpoint_interval {
+            return false;
+        }
+        *last = Instant::now();
+        true
+    }
+
+    fn get_row(conn: &Connection, key: &str, current_time: i64) -> Result<u64, StorageError> {
+        let count: Option<i64> = conn
+            .prepare_cached(GET_SQL)
+            .and_then(|mut statement| {
+                statement.query_row(params![key, current_time], |row| row.get(0)).optional()
+            })
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(count.unwrap_or(0).max(0) as u64)
+    }
+
+    fn increment_row(conn: &Connection, key: &str, expire_at: i64, current_time: i64) -> Result<u64, StorageError> {
+        let count: i64 = conn
+            .prepare_cached(INCREMENT_SQL)
+            .and_then(|mut statement| {
+                statement.query_row(params![key, expire_at, current_time], |row| row.get(0))
+            })
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(count.max(0) as u64)
+    }
+
+    fn increment_row_by(
+        conn: &Connection,
+        key: &str,
+        amount: i64,
+        expire_at: i64,
+        current_time: i64,
+    ) -> Result<u64, StorageError> {
+        let count: i64 = conn
+            .prepare_cached(INCREMENT_BY_SQL)
+            .and_then(|mut statement| {
+                statement.query_row(params![key, expire_at, current_time, amount], |row| row.get(0))
+            })
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+        Ok(count.max(0) as u64)
+    }
+
+    fn checkpoint(conn: &Connection, mode: &str) -> Result<(), StorageError> {
+        // Returns (busy, wal pages, checkpointed pages); in-memory databases
+        // have no WAL and report -1s
+        conn.query_row(&format!("PRAGMA wal_checkpoint({})", mode), [], |_| Ok(()))
+            .map_err(|e| StorageError::DatabaseError(e.to_string()))
     }
 }
 
 #[async_trait]
 impl StorageBackend for SQLiteStorage {
-    async fn get(&self, key: &str) -> Result<u32, StorageError> {
-        let current_time = Self::get_current_timestamp();
-
-        let result: Option<u32> = self.conn
-            .query_row(
-                "SELECT count FROM rate_limits WHERE key_name = ? AND expire_at > ?",
-                params![key, current_time],
-                |row| row.get(0)
-            )
-            .optional()
-            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        let current_time = self.get_current_timestamp();
+        let key = key.to_string();
 
-        Ok(result.unwrap_or(0))
+        self.with_conn(move |conn| Self::get_row(conn, &key, current_time)).await
     }
 
-    async fn increment(&mut self, key: &str, expire: u32) -> Result<(), StorageError> {
-        let current_time = Self::get_current_timestamp();
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        let current_time = self.get_current_timestamp();
         let expire_at = current_time + expire as i64;
+        let key = key.to_string();
+        let checkpoint = self.checkpoint_due();
 
-        let tx = self.conn.transaction()
-            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+        self.with_conn(move |conn| {
+            let count = Self::increment_row(conn, &key, expire_at, current_time)?;
 
-        tx.execute(
-            r"
-            INSERT INTO rate_limits (key_name, count, expire_at)
-            VALUES (?1, 1, ?2)
-            ON CONFLICT(key_name) DO UPDATE SET
-                count = CASE
-                    WHEN expire_at > ?3 THEN count + 1
-                    ELSE 1
-                END,
-                expire_at = ?2
-            ",
-            params![key, expire_at, current_time]
-        ).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
-
-        tx.commit()
-            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            if checkpoint {
+                // PASSIVE never blocks readers or writers; a busy checkpoint
+                // is simply retried next interval
+                if let Err(e) = Self::checkpoint(conn, "PASSIVE") {
+                    log::warn!("SQLite WAL checkpoint failed: {}", e);
+                }
+            }
+
+            Ok(Increment { count })
+        }).await
+    }
 
-        Ok(())
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        if amount == 0 {
+            return self.get(key).await.map(|count| Increment { count });
+        }
+        let current_time = self.get_current_timestamp();
+        let expire_at = current_time + expire as i64;
+        let amount = amount.min(i64::MAX as u64) as i64;
+        let key = key.to_string();
+
+        self.with_conn(move |conn| {
+            let count = Self::increment_row_by(conn, &key, amount, expire_at, current_time)?;
+            Ok(Increment { count })
+        }).await
     }
 
-    async fn delete(&mut self, key: &str) -> Result<(), StorageError> {
-        self.conn
-            .execute(
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let key = key.to_string();
+
+        self.with_conn(move |conn| {
+            conn.execute(
                 "DELETE FROM rate_limits WHERE key_name = ?",
                 params![key]
-            )
-            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            ).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            Ok(())
+        }).await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        let now = self.clock.now();
+        let current_time = now.as_secs() as i64;
+        let key = key.to_string();
 
-        Ok(())
+        let expire_at: Option<i64> = self.with_conn(move |conn| {
+            conn.prepare_cached("SELECT expire_at FROM rate_limits WHERE key_name = ? AND expire_at > ?")
+                .and_then(|mut statement| {
+                    statement.query_row(params![key, current_time], |row| row.get(0)).optional()
+                })
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))
+        }).await?;
+
+        Ok(expire_at.and_then(|expire_at| Duration::from_secs(expire_at.max(0) as u64).checked_sub(now)))
     }
 
-    async fn cleanup_expired(&mut self) -> Result<(), StorageError> {
-        let current_time = Self::get_current_timestamp();
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        let current_time = self.get_current_timestamp();
 
-        self.conn
-            .execute(
+        self.with_conn(move |conn| {
+            let removed = conn.execute(
                 "DELETE FROM rate_limits WHERE expire_at <= ?",
                 params![current_time]
-            )
-            .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            ).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+
+            // Shrink the WAL back after a large delete
+            Self::checkpoint(conn, "TRUNCATE")?;
+            Ok(removed as u64)
+        }).await
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        // Hold every connection so each one gets its statements prepared
+        let permits = Arc::clone(&self.pool.available)
+            .acquire_many_owned(self.pool.size as u32)
+            .await
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+        let pool = Arc::clone(&self.pool);
+
+        tokio::task::spawn_blocking(move || {
+            let _permits = permits;
+            let conns = pool.conns
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            for conn in conns.iter() {
+                // Preparing also checks the table and its columns exist
+                for sql in [GET_SQL, INCREMENT_SQL] {
+                    conn.prepare_cached(sql)
+                        .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+                }
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|e| StorageError::DatabaseError(e.to_string()))?
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        let current_time = self.get_current_timestamp();
+        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+
+        self.with_conn(move |conn| {
+            keys.iter()
+                .map(|key| Self::get_row(conn, key, current_time))
+                .collect()
+        }).await
+    }
 
-        Ok(())
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        let current_time = self.get_current_timestamp();
+        let keys: Vec<(String, u32)> = keys.iter().map(|(key, expire)| (key.to_string(), *expire)).collect();
+
+        // One transaction, so the batch costs a single WAL commit
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            let increments = keys.iter()
+                .map(|(key, expire)| {
+                    let count = Self::increment_row(&tx, key, current_time + *expire as i64, current_time)?;
+                    Ok(Increment { count })
+                })
+                .collect::<Result<Vec<_>, StorageError>>()?;
+            tx.commit().map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            Ok(increments)
+        }).await
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        let current_time = self.get_current_timestamp();
+
+        self.with_conn(move |conn| {
+            let mut statement = conn
+                .prepare("SELECT key_name, count, expire_at FROM rate_limits WHERE expire_at > ?")
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            let rows = statement
+                .query_map(params![current_time], |row| {
+                    Ok(CounterSnapshot {
+                        key: row.get(0)?,
+                        count: row.get::<_, i64>(1)?.max(0) as u64,
+                        expire_at: row.get::<_, i64>(2)?.max(0) as u64,
+                    })
+                })
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))
+        }).await
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        let current_time = self.get_current_timestamp();
+        let counters: Vec<CounterSnapshot> = counters
+            .iter()
+            .filter(|counter| counter.expire_at > current_time as u64)
+            .cloned()
+            .collect();
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            {
+                let mut statement = tx.prepare_cached(IMPORT_SQL)
+                    .map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+                for counter in &counters {
+                    statement.execute(params![
+                        counter.key,
+                        counter.count.min(i64::MAX as u64) as i64,
+                        counter.expire_at.min(i64::MAX as u64) as i64,
+                        current_time,
+                    ]).map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+                }
+            }
+            tx.commit().map_err(|e| StorageError::DatabaseError(e.to_string()))?;
+            Ok(counters.len() as u64)
+        }).await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread;
+    use crate::clock::ManualClock;
     use std::time::Duration;
 
     #[tokio::test]
     async fn test_sqlite_storage() {
-        let mut storage = SQLiteStorage::new_in_memory().unwrap();
+        let clock = Arc::new(ManualClock::default());
+        let storage = SQLiteStorage::new_in_memory().unwrap().with_clock(clock.clone());
+        storage.warm_up().await.unwrap();
 
         // Test increment and get
         storage.increment("test_key", 2).await.unwrap();
@@ -139,14 +496,77 @@ mod tests {
 
         storage.increment("test_key", 2).await.unwrap();
         assert_eq!(storage.get("test_key").await.unwrap(), 2);
+        assert_eq!(storage.ttl("test_key").await.unwrap(), Some(Duration::from_secs(2)));
+        assert_eq!(storage.ttl("missing").await.unwrap(), None);
 
         // Test expiration
         storage.increment("expire_key", 1).await.unwrap();
-        thread::sleep(Duration::from_secs(2));
+        clock.advance(Duration::from_secs(2));
         assert_eq!(storage.get("expire_key").await.unwrap(), 0);
 
         // Test cleanup
         storage.cleanup_expired().await.unwrap();
         assert_eq!(storage.get("expire_key").await.unwrap(), 0);
     }
+
+    #[tokio::test]
+    async fn test_export_and_import() {
+        let source = SQLiteStorage::new_in_memory().unwrap();
+        source.increment("a", 60).await.unwrap();
+        source.increment("a", 60).await.unwrap();
+        source.increment("b", 60).await.unwrap();
+
+        let mut counters = source.export_all().await.unwrap();
+        counters.sort_by(|x, y| x.key.cmp(&y.key));
+        assert_eq!(counters.iter().map(|c| (c.key.as_str(), c.count)).collect::<Vec<_>>(), vec![("a", 2), ("b", 1)]);
+
+        // Imported counts add to live ones
+        let target = SQLiteStorage::new_in_memory().unwrap();
+        target.increment("a", 60).await.unwrap();
+        assert_eq!(target.import_all(&counters).await.unwrap(), 2);
+        assert_eq!(target.get_many(&["a", "b"]).await.unwrap(), vec![3, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_increment_by() {
+        let storage = SQLiteStorage::new_in_memory().unwrap();
+        assert_eq!(storage.increment_by("bytes", 1500, 60).await.unwrap().count, 1500);
+        assert_eq!(storage.increment_by("bytes", 500, 60).await.unwrap().count, 2000);
+        assert_eq!(storage.increment_by("bytes", 0, 60).await.unwrap().count, 2000);
+        assert_eq!(storage.increment_by("bytes", u64::MAX, 60).await.unwrap().count, i64::MAX as u64);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_file_pool() {
+        let path = std::env::temp_dir().join(format!("ratelimit-{}.db", std::process::id()));
+        let options = SQLiteOptions {
+            pool_size: 2,
+            checkpoint_interval: Duration::ZERO,
+            ..SQLiteOptions::default()
+        };
+        let storage = SQLiteStorage::with_options(&path, options).unwrap();
+
+        for _ in 0..10 {
+            storage.increment("pool_key", 60).await.unwrap();
+        }
+        let (a, b) = tokio::join!(storage.get("pool_key"), storage.get("pool_key"));
+        assert_eq!((a.unwrap(), b.unwrap()), (10, 10));
+
+        storage.cleanup_expired().await.unwrap();
+        drop(storage);
+
+        // Reopening finds the schema up to date and leaves the data alone
+        let storage = SQLiteStorage::new(&path).unwrap();
+        assert_eq!(storage.get("pool_key").await.unwrap(), 10);
+        let applied: u32 = storage.with_conn(|conn| {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", SCHEMA_VERSION_TABLE), [], |row| row.get(0))
+                .map_err(|e| StorageError::DatabaseError(e.to_string()))
+        }).await.unwrap();
+        assert_eq!(applied as usize, crate::storage::migrations(SqlDialect::Sqlite).len());
+        drop(storage);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
 }
@@ -0,0 +1,93 @@
+use std::fmt::{self, Write};
+use std::net::IpAddr;
+use crate::storage::KeyBuf;
+use crate::RateLimiter;
+
+/// Protocol of a `stream` block's `listen` socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::Udp => "udp",
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Limits new connections in nginx `stream` blocks, for SMTP, MQTT and
+/// other non-HTTP services proxied by nginx.
+///
+/// Each new TCP connection, or each new UDP session, counts as one request
+/// from its source address, decided by a `RateLimiter` with the same
+/// backends, limits, deny cache and prefilter as HTTP. Stream keys are
+/// namespaced by transport, so a client's TCP, UDP and HTTP traffic are
+/// counted separately even on a shared backend.
+#[derive(Debug)]
+pub struct StreamRateLimiter {
+    limiter: RateLimiter,
+}
+
+impl StreamRateLimiter {
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self { limiter }
+    }
+
+    /// The underlying limiter, for `init_worker`, reloads and snapshots.
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    /// Decide a new connection from `peer`, counting it when it is allowed.
+    /// Returns whether the connection should be closed.
+    pub async fn on_connect(&self, peer: IpAddr, transport: Transport) -> bool {
+        let limited = self.limiter.is_rate_limited(&stream_key(peer, transport)).await;
+        if limited {
+            metrics::counter!("rate_limiter_stream_rejected_total", "transport" => transport.as_str()).increment(1);
+        }
+        limited
+    }
+}
+
+/// The storage key for a connection from `peer`, e.g. `stream:tcp:10.0.0.1`.
+pub fn stream_key(peer: IpAddr, transport: Transport) -> KeyBuf {
+    let mut key = KeyBuf::new();
+    let _ = write!(key, "stream:{}:{}", transport, peer);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_connections_limited_per_transport() {
+        let storage = Arc::new(MemoryStorage::new());
+        let stream = StreamRateLimiter::new(RateLimiter::with_storage(storage, 2, 60));
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7));
+
+        let mut closed = Vec::new();
+        for _ in 0..3 {
+            closed.push(stream.on_connect(peer, Transport::Tcp).await);
+        }
+        assert_eq!(closed, vec![false, false, true]);
+
+        // UDP sessions and HTTP requests keep their own counts
+        assert!(!stream.on_connect(peer, Transport::Udp).await);
+        assert!(!stream.limiter().is_rate_limited(&crate::request_key(peer)).await);
+        assert_eq!(stream_key(peer, Transport::Udp).as_str(), "stream:udp:192.0.2.7");
+    }
+}
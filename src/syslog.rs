@@ -0,0 +1,239 @@
+use async_trait::async_trait;
+use std::fmt::Write as _;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use crate::clock::civil_from_days;
+use crate::events::{EventKind, EventSink, RateLimitEvent};
+
+/// Private enterprise number in the structured data id. 32473 is the one
+/// RFC 5612 reserves for examples and documentation.
+const SD_ID: &str = "ratelimit@32473";
+
+const APP_NAME: &str = "nginx-rate-limiter";
+
+/// Syslog facility of the messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Facility {
+    User = 1,
+    Daemon = 3,
+    Auth = 4,
+    AuthPriv = 10,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// How messages reach the collector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogTransport {
+    /// One datagram per message (RFC 5426), e.g. `siem:514`
+    Udp(String),
+    /// Octet-counted frames over TCP (RFC 6587)
+    Tcp(String),
+    /// Octet-counted frames over TLS (RFC 5425), verified against the
+    /// Mozilla root store
+    Tls { address: String, server_name: String },
+}
+
+/// An RFC 5424 message for `event`, e.g.
+///
+/// ```text
+/// <37>1 2023-11-14T22:13:20.000Z web-1 nginx-rate-limiter - deny [ratelimit@32473 key="192.0.2.7" count="101" limit="100" window="60"] 192.0.2.7 denied
+/// ```
+///
+/// Denials are notices, and everything but allowed requests otherwise
+/// warnings; the example uses `Facility::Auth`.
+pub fn format_message(event: &RateLimitEvent, facility: Facility) -> String {
+    let (severity, kind) = match event.kind {
+        EventKind::Allow => (6, "allow"),
+        EventKind::Deny => (5, "deny"),
+        EventKind::Ban => (4, "ban"),
+        EventKind::QuotaWarning => (4, "quota_warning"),
+        EventKind::Anomaly => (4, "anomaly"),
+        EventKind::Honeypot => (4, "honeypot"),
+    };
+    let mut message = format!(
+        "<{}>1 {} {} {} - {} [{} key=\"",
+        facility as u8 * 8 + severity,
+        timestamp(event.timestamp),
+        hostname(),
+        APP_NAME,
+        kind,
+        SD_ID
+    );
+    escape_param(&mut message, &event.key);
+    let _ = write!(
+        message,
+        "\" count=\"{}\" limit=\"{}\" window=\"{}\"] {} {}",
+        event.count,
+        event.limit,
+        event.window,
+        event.key,
+        match event.kind {
+            EventKind::Allow => "allowed",
+            EventKind::Deny => "denied",
+            EventKind::Ban => "banned",
+            EventKind::QuotaWarning => "nearing its quota",
+            EventKind::Anomaly => "behaving anomalously",
+            EventKind::Honeypot => "caught in a honeypot",
+        }
+    );
+    message
+}
+
+/// Structured data values escape `"`, `\` and `]`.
+fn escape_param(out: &mut String, value: &str) {
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+/// Unix milliseconds as an RFC 3339 UTC timestamp.
+fn timestamp(millis: u64) -> String {
+    let seconds = millis / 1000;
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        millis % 1000
+    )
+}
+
+fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .ok()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty() && name.is_ascii() && !name.contains(' '))
+            .unwrap_or_else(|| "-".to_string())
+    })
+}
+
+type Stream = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Sends events to a syslog collector, for SIEMs that should see
+/// rejections regardless of how nginx's own logs are shipped.
+///
+/// Use it with `EventFormat::Syslog`. TCP and TLS connections are opened
+/// on first use and reopened after a failed write; the batch that failed
+/// is dropped.
+pub struct SyslogSink {
+    transport: SyslogTransport,
+    udp: OnceLock<UdpSocket>,
+    stream: Mutex<Option<Stream>>,
+}
+
+impl SyslogSink {
+    pub fn new(transport: SyslogTransport) -> Self {
+        Self {
+            transport,
+            udp: OnceLock::new(),
+            stream: Mutex::new(None),
+        }
+    }
+
+    async fn connect(&self) -> std::io::Result<Stream> {
+        match &self.transport {
+            SyslogTransport::Udp(_) => unreachable!("datagrams need no connection"),
+            SyslogTransport::Tcp(address) => Ok(Box::new(TcpStream::connect(address).await?)),
+            SyslogTransport::Tls { address, server_name } => {
+                use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+                let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+                let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+                let name = ServerName::try_from(server_name.clone())
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                let tcp = TcpStream::connect(address).await?;
+                let tls = tokio_rustls::TlsConnector::from(Arc::new(config)).connect(name, tcp).await?;
+                Ok(Box::new(tls))
+            }
+        }
+    }
+
+    async fn send_datagrams(&self, address: &str, events: &[(String, Vec<u8>)]) -> std::io::Result<()> {
+        if self.udp.get().is_none() {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.connect(address).await?;
+            let _ = self.udp.set(socket);
+        }
+        let socket = self.udp.get().expect("socket was just set");
+        for (_, message) in events {
+            socket.send(message).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_frames(&self, events: &[(String, Vec<u8>)]) -> std::io::Result<()> {
+        let mut stream = self.stream.lock().await;
+        if stream.is_none() {
+            *stream = Some(self.connect().await?);
+        }
+        let mut frames = Vec::new();
+        for (_, message) in events {
+            frames.extend_from_slice(format!("{} ", message.len()).as_bytes());
+            frames.extend_from_slice(message);
+        }
+        let writer = stream.as_mut().expect("stream was just set");
+        let written = async {
+            writer.write_all(&frames).await?;
+            writer.flush().await
+        }
+        .await;
+        if written.is_err() {
+            *stream = None;
+        }
+        written
+    }
+}
+
+#[async_trait]
+impl EventSink for SyslogSink {
+    async fn publish(&self, events: &[(String, Vec<u8>)]) -> Result<(), String> {
+        let sent = match &self.transport {
+            SyslogTransport::Udp(address) => self.send_datagrams(address, events).await,
+            SyslogTransport::Tcp(_) | SyslogTransport::Tls { .. } => self.send_frames(events).await,
+        };
+        sent.map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc5424_message() {
+        let event = RateLimitEvent {
+            kind: EventKind::Deny,
+            key: "a\"b]".to_string(),
+            count: 101,
+            limit: 100,
+            window: 60,
+            timestamp: 1_700_000_000_123,
+        };
+        let message = format_message(&event, Facility::Auth);
+        let expected = format!(
+            "<37>1 2023-11-14T22:13:20.123Z {} nginx-rate-limiter - deny [ratelimit@32473 key=\"a\\\"b\\]\" count=\"101\" limit=\"100\" window=\"60\"] a\"b] denied",
+            hostname()
+        );
+        assert_eq!(message, expected);
+        assert_eq!(timestamp(951_782_400_000), "2000-02-29T00:00:00.000Z");
+    }
+}
@@ -0,0 +1,246 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use crate::config::{ConfigStore, Limits, LimitsConfig};
+use crate::storage::{self, KeyBuf, StorageBackend};
+
+/// Where the tenant of a request is read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TenantSource {
+    /// The `Host` header, matched against each tenant's hosts; a host of
+    /// `*.example.com` matches any single label below it
+    Host,
+    /// A header carrying the tenant name, e.g. `X-Tenant-Id`
+    Header(String),
+}
+
+/// What tenant resolution and path rules need to know about a request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestInfo<'a> {
+    pub host: Option<&'a str>,
+    pub path: &'a str,
+    pub headers: &'a [(&'a str, &'a str)],
+    /// The start of the body, read ahead for rules with body conditions;
+    /// the first `rules::MAX_INSPECTED_BODY` bytes are enough
+    pub body: Option<&'a [u8]>,
+    /// Space-separated OAuth2 scopes of the client, for rules with a
+    /// scope; filled in by `check` from token introspection
+    pub scope: Option<&'a str>,
+    /// A subrequest or internal redirect (`r != r->main || r->internal`)
+    pub internal: bool,
+}
+
+impl<'a> RequestInfo<'a> {
+    /// The first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    /// The raw value of the first query parameter called `name`.
+    pub fn query_param(&self, name: &str) -> Option<&'a str> {
+        let (_, query) = self.path.split_once('?')?;
+        query
+            .split('&')
+            .find_map(|pair| pair.split_once('=').filter(|(key, _)| *key == name).map(|(_, value)| value))
+    }
+}
+
+/// One tenant's limits and where its counters live.
+#[derive(Debug)]
+pub struct Tenant {
+    pub name: String,
+    /// The tenant's limits and path rules, reloadable on their own
+    pub config: ConfigStore,
+    /// Its own backend, e.g. with its own credentials; the limiter's
+    /// otherwise
+    pub storage: Option<Arc<dyn StorageBackend>>,
+    /// Prefix of the tenant's keys, so tenants sharing a backend never
+    /// share counters
+    pub namespace: Option<String>,
+}
+
+impl Tenant {
+    /// A tenant whose keys are namespaced by its name.
+    pub fn new(name: &str, limits: Limits) -> Self {
+        Self {
+            name: name.to_string(),
+            config: ConfigStore::new(limits),
+            storage: None,
+            namespace: Some(name.to_string()),
+        }
+    }
+
+    pub fn with_storage(mut self, storage: Arc<dyn StorageBackend>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Replace the key prefix; `None` leaves keys as they are, e.g. for a
+    /// backend of the tenant's own.
+    pub fn with_namespace(mut self, namespace: Option<&str>) -> Self {
+        self.namespace = namespace.map(str::to_string);
+        self
+    }
+
+    /// The storage key for `key` under this tenant: `{namespace}:{key}`.
+    pub fn key(&self, key: &str) -> KeyBuf {
+        let mut tenant_key = KeyBuf::new();
+        if let Some(namespace) = &self.namespace {
+            let _ = write!(tenant_key, "{}:", namespace);
+        }
+        tenant_key.push_str(key);
+        tenant_key
+    }
+}
+
+/// A registered backend of a tenant's own, as in `storage::create_backend`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TenantBackend {
+    pub name: String,
+    #[serde(default)]
+    pub config: String,
+}
+
+/// A tenant as written in a JSON configuration file:
+///
+/// ```json
+/// {"name": "acme", "hosts": ["acme.example.com", "*.acme.example.com"], "requests": 1000, "window": 60,
+///  "backend": {"name": "redis", "config": "redis://:acme-secret@redis-acme:6379"}}
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct TenantConfig {
+    pub name: String,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(flatten)]
+    pub limits: LimitsConfig,
+    /// Key prefix; the tenant name by default, none with a backend of its
+    /// own
+    #[serde(default)]
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub backend: Option<TenantBackend>,
+}
+
+/// Finds the tenant a request belongs to.
+///
+/// Requests that match no tenant get the default tenant if one is set, or
+/// else the limiter's own limits and keys.
+#[derive(Debug)]
+pub struct TenantResolver {
+    source: TenantSource,
+    tenants: HashMap<String, Arc<Tenant>>,
+    /// Lowercased host, or `*.` pattern, to its tenant
+    hosts: HashMap<String, Arc<Tenant>>,
+    default: Option<Arc<Tenant>>,
+}
+
+impl TenantResolver {
+    pub fn new(source: TenantSource) -> Self {
+        Self {
+            source,
+            tenants: HashMap::new(),
+            hosts: HashMap::new(),
+            default: None,
+        }
+    }
+
+    /// Build every tenant in `configs`, connecting the backends of those
+    /// that have their own.
+    pub async fn from_config(source: TenantSource, configs: Vec<TenantConfig>) -> Result<Self, String> {
+        let mut resolver = Self::new(source);
+        for config in configs {
+            let limits = config
+                .limits
+                .compile(1)
+                .map_err(|e| format!("tenant {}: {}", config.name, e))?;
+            let mut tenant = Tenant::new(&config.name, limits);
+            if let Some(backend) = &config.backend {
+                let storage = storage::create_backend(&backend.name, &backend.config)
+                    .await
+                    .map_err(|e| format!("tenant {}: {}", config.name, e))?;
+                tenant = tenant.with_storage(Arc::from(storage)).with_namespace(None);
+            }
+            if let Some(namespace) = &config.namespace {
+                tenant = tenant.with_namespace(Some(namespace));
+            }
+            let hosts: Vec<&str> = config.hosts.iter().map(String::as_str).collect();
+            resolver = resolver.with_tenant(tenant, &hosts);
+        }
+        Ok(resolver)
+    }
+
+    /// Add `tenant`, reached by its name and by any of `hosts`.
+    pub fn with_tenant(mut self, tenant: Tenant, hosts: &[&str]) -> Self {
+        let tenant = Arc::new(tenant);
+        for host in hosts {
+            self.hosts.insert(host.to_ascii_lowercase(), Arc::clone(&tenant));
+        }
+        self.tenants.insert(tenant.name.clone(), tenant);
+        self
+    }
+
+    /// Tenant for requests that match no other.
+    pub fn with_default(mut self, tenant: Tenant) -> Self {
+        self.default = Some(Arc::new(tenant));
+        self
+    }
+
+    /// The tenant called `name`, e.g. to reload its limits.
+    pub fn tenant(&self, name: &str) -> Option<&Arc<Tenant>> {
+        self.tenants.get(name)
+    }
+
+    pub fn resolve(&self, request: &RequestInfo<'_>) -> Option<&Arc<Tenant>> {
+        let tenant = match &self.source {
+            TenantSource::Host => request.host.and_then(|host| self.by_host(host)),
+            TenantSource::Header(name) => request.header(name).and_then(|value| self.tenants.get(value.trim())),
+        };
+        tenant.or(self.default.as_ref())
+    }
+
+    fn by_host(&self, host: &str) -> Option<&Arc<Tenant>> {
+        // Drop the port, leaving bracketed IPv6 addresses whole
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if !name.ends_with(':') && port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        };
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(tenant) = self.hosts.get(&host) {
+            return Some(tenant);
+        }
+        let (_, parent) = host.split_once('.')?;
+        self.hosts.get(&format!("*.{}", parent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_by_host_and_header() {
+        let resolver = TenantResolver::new(TenantSource::Host)
+            .with_tenant(Tenant::new("acme", Limits::new(1000, 60)), &["acme.example.com", "*.acme.example.com"])
+            .with_default(Tenant::new("free", Limits::new(10, 60)));
+        let name = |host: &str| {
+            let request = RequestInfo { host: Some(host), ..RequestInfo::default() };
+            resolver.resolve(&request).map(|tenant| tenant.name.clone())
+        };
+        assert_eq!(name("ACME.example.com:8443").as_deref(), Some("acme"));
+        assert_eq!(name("eu.acme.example.com").as_deref(), Some("acme"));
+        assert_eq!(name("a.b.acme.example.com").as_deref(), Some("free"));
+        assert_eq!(resolver.tenant("acme").unwrap().key("192.0.2.7").as_str(), "acme:192.0.2.7");
+
+        let resolver = TenantResolver::new(TenantSource::Header("X-Tenant-Id".to_string()))
+            .with_tenant(Tenant::new("acme", Limits::new(1000, 60)).with_namespace(None), &[]);
+        let headers = [("x-tenant-id", "acme")];
+        let tenant = resolver.resolve(&RequestInfo { headers: &headers, ..RequestInfo::default() }).unwrap();
+        assert_eq!(tenant.key("192.0.2.7").as_str(), "192.0.2.7");
+        assert!(resolver.resolve(&RequestInfo::default()).is_none());
+    }
+}
@@ -0,0 +1,283 @@
+//! Test doubles for code built on this crate (`--features testing`).
+
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use crate::clock::Clock;
+use crate::storage::{CounterSnapshot, Increment, MemoryStorage, StorageBackend, StorageError};
+
+/// A `StorageBackend` method, for scripting and inspecting `MockStorage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Get,
+    Increment,
+    Delete,
+    CleanupExpired,
+    Ttl,
+    WarmUp,
+    GetMany,
+    IncrementMany,
+    IncrementBy,
+    ExportAll,
+    ImportAll,
+}
+
+/// One call made to a `MockStorage`, with the keys it named.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    pub operation: Operation,
+    pub keys: Vec<String>,
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<Call>,
+    gets: VecDeque<Result<u64, StorageError>>,
+    increments: VecDeque<Result<Increment, StorageError>>,
+    failures: HashMap<Operation, VecDeque<StorageError>>,
+    /// Every nth call fails with this error
+    fail_every: Option<(usize, StorageError)>,
+    latency: Duration,
+    /// `increment_many` applies this many keys, then fails
+    partial_batch: Option<usize>,
+}
+
+/// Backend for unit tests that records every call and lets the test decide
+/// what each call returns.
+///
+/// Unscripted calls behave like `MemoryStorage`. On top of that a test can
+/// queue responses (`push_get`, `push_increment`), make the next call of an
+/// operation fail (`fail_next`), fail every nth call (`fail_every`), delay
+/// every call (`set_latency`) or have batches fail halfway
+/// (`fail_batches_after`). This covers limit policies and error handling
+/// without running Redis.
+#[derive(Default)]
+pub struct MockStorage {
+    inner: MemoryStorage,
+    state: Mutex<State>,
+}
+
+impl MockStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expire unscripted counters by `clock`, e.g. a `ManualClock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.inner = self.inner.with_clock(clock);
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Return `result` from the next `get` instead of the stored count.
+    pub fn push_get(&self, result: Result<u64, StorageError>) {
+        self.state().gets.push_back(result);
+    }
+
+    /// Return `result` from the next `increment` without counting it.
+    pub fn push_increment(&self, result: Result<Increment, StorageError>) {
+        self.state().increments.push_back(result);
+    }
+
+    /// Fail the next call of `operation` with `error`; queued errors are
+    /// used in order.
+    pub fn fail_next(&self, operation: Operation, error: StorageError) {
+        self.state().failures.entry(operation).or_default().push_back(error);
+    }
+
+    /// Fail every `n`th call, of any operation, with `error`.
+    pub fn fail_every(&self, n: usize, error: StorageError) {
+        self.state().fail_every = Some((n.max(1), error));
+    }
+
+    /// Delay every call by `latency`.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state().latency = latency;
+    }
+
+    /// Make `increment_many` count the first `applied` keys and then fail,
+    /// like a pipeline cut off by a dropped connection.
+    pub fn fail_batches_after(&self, applied: usize) {
+        self.state().partial_batch = Some(applied);
+    }
+
+    /// Every call so far, in order.
+    pub fn calls(&self) -> Vec<Call> {
+        self.state().calls.clone()
+    }
+
+    pub fn call_count(&self, operation: Operation) -> usize {
+        self.state().calls.iter().filter(|call| call.operation == operation).count()
+    }
+
+    pub fn clear_calls(&self) {
+        self.state().calls.clear();
+    }
+
+    /// Record the call, apply latency and return any injected failure.
+    async fn enter(&self, operation: Operation, keys: &[&str]) -> Result<(), StorageError> {
+        let (latency, failure) = {
+            let mut state = self.state();
+            state.calls.push(Call {
+                operation,
+                keys: keys.iter().map(|key| key.to_string()).collect(),
+            });
+            let numbered = state.calls.len();
+
+            let failure = match state.failures.get_mut(&operation).and_then(VecDeque::pop_front) {
+                Some(error) => Some(error),
+                None => match &state.fail_every {
+                    Some((n, error)) if numbered % n == 0 => Some(error.clone()),
+                    _ => None,
+                },
+            };
+            (state.latency, failure)
+        };
+
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+        failure.map_or(Ok(()), Err)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MockStorage {
+    async fn get(&self, key: &str) -> Result<u64, StorageError> {
+        self.enter(Operation::Get, &[key]).await?;
+        let scripted = self.state().gets.pop_front();
+        match scripted {
+            Some(result) => result,
+            None => self.inner.get(key).await,
+        }
+    }
+
+    async fn increment(&self, key: &str, expire: u32) -> Result<Increment, StorageError> {
+        self.enter(Operation::Increment, &[key]).await?;
+        let scripted = self.state().increments.pop_front();
+        match scripted {
+            Some(result) => result,
+            None => self.inner.increment(key, expire).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.enter(Operation::Delete, &[key]).await?;
+        self.inner.delete(key).await
+    }
+
+    async fn cleanup_expired(&self) -> Result<u64, StorageError> {
+        self.enter(Operation::CleanupExpired, &[]).await?;
+        self.inner.cleanup_expired().await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, StorageError> {
+        self.enter(Operation::Ttl, &[key]).await?;
+        self.inner.ttl(key).await
+    }
+
+    async fn warm_up(&self) -> Result<(), StorageError> {
+        self.enter(Operation::WarmUp, &[]).await
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<u64>, StorageError> {
+        self.enter(Operation::GetMany, keys).await?;
+        self.inner.get_many(keys).await
+    }
+
+    async fn increment_many(&self, keys: &[(&str, u32)]) -> Result<Vec<Increment>, StorageError> {
+        let names: Vec<&str> = keys.iter().map(|(key, _)| *key).collect();
+        self.enter(Operation::IncrementMany, &names).await?;
+
+        let partial = self.state().partial_batch;
+        match partial {
+            Some(applied) if applied < keys.len() => {
+                self.inner.increment_many(&keys[..applied]).await?;
+                Err(StorageError::ConnectionError(format!(
+                    "connection lost after {} of {} keys",
+                    applied,
+                    keys.len()
+                )))
+            }
+            _ => self.inner.increment_many(keys).await,
+        }
+    }
+
+    async fn increment_by(&self, key: &str, amount: u64, expire: u32) -> Result<Increment, StorageError> {
+        self.enter(Operation::IncrementBy, &[key]).await?;
+        self.inner.increment_by(key, amount, expire).await
+    }
+
+    async fn export_all(&self) -> Result<Vec<CounterSnapshot>, StorageError> {
+        self.enter(Operation::ExportAll, &[]).await?;
+        self.inner.export_all().await
+    }
+
+    async fn import_all(&self, counters: &[CounterSnapshot]) -> Result<u64, StorageError> {
+        let keys: Vec<&str> = counters.iter().map(|counter| counter.key.as_str()).collect();
+        self.enter(Operation::ImportAll, &keys).await?;
+        self.inner.import_all(counters).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scripted_responses_and_faults() {
+        let storage = MockStorage::new();
+        storage.push_get(Ok(99));
+        storage.fail_next(Operation::Increment, StorageError::ConnectionError("reset".to_string()));
+
+        assert_eq!(storage.get("a").await.unwrap(), 99);
+        assert!(storage.increment("a", 60).await.is_err());
+        assert_eq!(storage.increment("a", 60).await.unwrap().count, 1);
+        assert_eq!(storage.get("a").await.unwrap(), 1);
+
+        storage.fail_batches_after(1);
+        assert!(storage.increment_many(&[("a", 60), ("b", 60)]).await.is_err());
+        assert_eq!(storage.get_many(&["a", "b"]).await.unwrap(), vec![2, 0]);
+
+        storage.fail_every(2, StorageError::DatabaseError("flaky".to_string()));
+        let mut failed = 0;
+        for _ in 0..4 {
+            failed += storage.get("a").await.is_err() as usize;
+        }
+        assert_eq!(failed, 2);
+        assert_eq!(storage.call_count(Operation::Get), 6);
+        assert_eq!(storage.calls()[0], Call { operation: Operation::Get, keys: vec!["a".to_string()] });
+    }
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_failed_increments_are_not_counted() {
+        let storage = Arc::new(MockStorage::new());
+        storage.fail_every(7, StorageError::ConnectionError("reset".to_string()));
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let storage = Arc::clone(&storage);
+                tokio::spawn(async move {
+                    let mut counts = Vec::new();
+                    for _ in 0..200 {
+                        if let Ok(increment) = storage.increment("shared", 60).await {
+                            counts.push(increment.count);
+                        }
+                    }
+                    counts
+                })
+            })
+            .collect();
+        let mut counts = Vec::new();
+        for task in tasks {
+            counts.extend(task.await.unwrap());
+        }
+
+        counts.sort_unstable();
+        assert_eq!(counts.len(), 1600 - 1600 / 7);
+        assert_eq!(counts, (1..=counts.len() as u64).collect::<Vec<_>>());
+        assert_eq!(storage.inner.count("shared"), counts.len() as u64);
+    }
+}
@@ -0,0 +1,237 @@
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use crate::denylist::{self, Denylist, Network};
+
+/// Where reputation data comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeedSource {
+    /// A CrowdSec Local API, read as a bouncer through the decisions
+    /// stream, e.g. `http://127.0.0.1:8080`
+    CrowdSec { url: String, api_key: String },
+    /// A URL serving one address or CIDR per line (anything after a comma
+    /// or `#` is ignored), or a JSON array of them
+    List { url: String },
+}
+
+/// Schedule for `ThreatFeed`.
+#[derive(Debug, Clone)]
+pub struct ThreatFeedOptions {
+    pub source: FeedSource,
+    pub interval: Duration,
+    /// How long plain list entries are denied; refreshed on every pull, so
+    /// entries dropped from the list expire after this
+    pub ttl: Duration,
+    pub timeout: Duration,
+}
+
+impl ThreatFeedOptions {
+    pub fn new(source: FeedSource) -> Self {
+        Self {
+            source,
+            interval: Duration::from_secs(60),
+            ttl: Duration::from_secs(3600),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DecisionStream {
+    #[serde(default)]
+    new: Option<Vec<Decision>>,
+    #[serde(default)]
+    deleted: Option<Vec<Decision>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Decision {
+    scope: String,
+    value: String,
+    #[serde(default)]
+    duration: String,
+    #[serde(rename = "type", default)]
+    kind: String,
+}
+
+impl Decision {
+    fn network(&self) -> Option<Network> {
+        match self.scope.to_ascii_lowercase().as_str() {
+            "ip" | "range" => Network::parse(&self.value),
+            _ => None,
+        }
+    }
+}
+
+/// Pulls an IP reputation feed into a `Denylist`, so known-bad clients are
+/// rejected before any counter is read.
+///
+/// CrowdSec decisions keep their own durations and removals; only `ban`
+/// decisions on `Ip` and `Range` scopes are used. Plain lists are denied
+/// for `ttl` from each pull.
+pub struct ThreatFeed {
+    client: reqwest::Client,
+    options: ThreatFeedOptions,
+    denylist: Arc<Denylist>,
+    /// Whether the CrowdSec stream was read from the start yet
+    started: AtomicBool,
+}
+
+impl ThreatFeed {
+    pub fn new(options: ThreatFeedOptions, denylist: Arc<Denylist>) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .timeout(options.timeout)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            client,
+            options,
+            denylist,
+            started: AtomicBool::new(false),
+        })
+    }
+
+    /// Pull the feed once; returns how many entries were added.
+    pub async fn pull(&self) -> Result<usize, String> {
+        let added = match &self.options.source {
+            FeedSource::CrowdSec { url, api_key } => {
+                let startup = !self.started.load(Ordering::Relaxed);
+                let body = self
+                    .fetch(
+                        self.client
+                            .get(format!("{}/v1/decisions/stream", url.trim_end_matches('/')))
+                            .query(&[("startup", startup)])
+                            .header("X-Api-Key", api_key),
+                    )
+                    .await?;
+                let stream: DecisionStream = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+                self.started.store(true, Ordering::Relaxed);
+                apply_decisions(&self.denylist, stream)
+            }
+            FeedSource::List { url } => {
+                let body = self.fetch(self.client.get(url)).await?;
+                let networks = parse_list(&body);
+                for network in &networks {
+                    self.denylist.insert(*network, self.options.ttl);
+                }
+                networks.len()
+            }
+        };
+        self.denylist.purge_expired();
+        metrics::gauge!("rate_limiter_denylist_entries").set(self.denylist.len() as f64);
+        Ok(added)
+    }
+
+    async fn fetch(&self, request: reqwest::RequestBuilder) -> Result<String, String> {
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("feed returned {}", status));
+        }
+        response.text().await.map_err(|e| e.to_string())
+    }
+
+    /// Pull every `interval` until the returned handle is aborted. Failed
+    /// pulls leave the denylist as it was.
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let feed = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(feed.options.interval);
+            loop {
+                interval.tick().await;
+                match feed.pull().await {
+                    Ok(added) => log::debug!("Threat feed added or refreshed {} entries", added),
+                    Err(e) => {
+                        metrics::counter!("rate_limiter_threat_feed_errors_total").increment(1);
+                        log::warn!("Pulling the threat feed failed: {}", e);
+                    }
+                }
+            }
+        })
+    }
+}
+
+fn apply_decisions(denylist: &Denylist, stream: DecisionStream) -> usize {
+    for decision in stream.deleted.unwrap_or_default() {
+        if let Some(network) = decision.network() {
+            denylist.remove(network);
+        }
+    }
+    let mut added = 0;
+    for decision in stream.new.unwrap_or_default() {
+        if !decision.kind.eq_ignore_ascii_case("ban") {
+            continue;
+        }
+        if let (Some(network), Some(ttl)) = (decision.network(), parse_go_duration(&decision.duration)) {
+            denylist.insert(network, ttl);
+            added += 1;
+        }
+    }
+    added
+}
+
+fn parse_list(body: &str) -> Vec<Network> {
+    if let Ok(entries) = serde_json::from_str::<Vec<String>>(body) {
+        return entries.iter().filter_map(|entry| Network::parse(entry)).collect();
+    }
+    denylist::parse_list(body).collect()
+}
+
+/// A Go duration such as `3h59m58.5s`, as CrowdSec reports them.
+fn parse_go_duration(s: &str) -> Option<Duration> {
+    let mut total = 0.0f64;
+    let mut rest = s.trim();
+    if rest.is_empty() || rest.starts_with('-') {
+        return None;
+    }
+    while !rest.is_empty() {
+        let number_len = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let value: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let seconds = match &rest[..unit_len] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            "us" | "µs" => 0.000_001,
+            "ns" => 0.000_000_001,
+            _ => return None,
+        };
+        total += value * seconds;
+        rest = &rest[unit_len..];
+    }
+    Some(Duration::from_secs_f64(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crowdsec_decisions_and_lists() {
+        let denylist = Denylist::new();
+        let stream: DecisionStream = serde_json::from_str(
+            r#"{"new": [
+                {"scope": "Ip", "value": "192.0.2.7", "duration": "3h59m58.5s", "type": "ban"},
+                {"scope": "Range", "value": "198.51.100.0/24", "duration": "1h", "type": "ban"},
+                {"scope": "Ip", "value": "192.0.2.8", "duration": "1h", "type": "captcha"},
+                {"scope": "Country", "value": "XX", "duration": "1h", "type": "ban"}
+            ], "deleted": null}"#,
+        )
+        .unwrap();
+        assert_eq!(apply_decisions(&denylist, stream), 2);
+        assert!(denylist.contains_key("198.51.100.9") && !denylist.contains_key("192.0.2.8"));
+
+        let stream: DecisionStream =
+            serde_json::from_str(r#"{"new": null, "deleted": [{"scope": "Ip", "value": "192.0.2.7"}]}"#).unwrap();
+        apply_decisions(&denylist, stream);
+        assert!(!denylist.contains_key("192.0.2.7"));
+
+        assert_eq!(parse_go_duration("1h30m"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_list("# bad hosts\n203.0.113.1, scanner\n203.0.113.0/28\n\n").len(), 2);
+        assert_eq!(parse_list(r#"["203.0.113.1", "junk"]"#).len(), 1);
+    }
+}
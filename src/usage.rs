@@ -0,0 +1,245 @@
+use dashmap::DashMap;
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use crate::clock::{self, Clock};
+use crate::storage::{CounterSnapshot, StorageBackend, StorageError};
+
+/// Prefix of the usage buckets in the backend.
+pub const USAGE_KEY_PREFIX: &str = "usage:";
+
+/// Size of a usage bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    pub fn seconds(self) -> u64 {
+        match self {
+            Granularity::Hour => 3600,
+            Granularity::Day => 86400,
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Granularity::Hour => "h",
+            Granularity::Day => "d",
+        }
+    }
+
+    fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "h" => Some(Granularity::Hour),
+            "d" => Some(Granularity::Day),
+            _ => None,
+        }
+    }
+}
+
+/// Buckets and schedule for `UsageRecorder`.
+#[derive(Debug, Clone)]
+pub struct UsageOptions {
+    pub granularities: Vec<Granularity>,
+    /// How long a bucket is kept after it ends
+    pub retention: Duration,
+    /// How often local counts are written to the backend
+    pub flush_interval: Duration,
+}
+
+impl Default for UsageOptions {
+    fn default() -> Self {
+        Self {
+            granularities: vec![Granularity::Hour, Granularity::Day],
+            retention: Duration::from_secs(35 * 86400),
+            flush_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// One key's allowed requests in one bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsageRow {
+    pub key: String,
+    pub granularity: Granularity,
+    /// Unix time the bucket starts
+    pub start: u64,
+    pub count: u64,
+}
+
+/// Counts allowed requests per key into hourly and daily buckets for
+/// usage-based billing.
+///
+/// Requests are counted in the worker and written to the backend every
+/// `flush_interval` with `import_all`, which adds them to what other
+/// workers and nodes wrote, so the request path makes no extra backend
+/// calls. Counts not yet flushed are lost if the worker dies. Backends
+/// without `import_all` and `export_all` cannot hold usage.
+pub struct UsageRecorder {
+    /// Bucket key to the requests counted since the last flush
+    pending: DashMap<String, u64>,
+    options: UsageOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for UsageRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UsageRecorder").field("pending", &self.pending.len()).finish()
+    }
+}
+
+impl UsageRecorder {
+    pub fn new(options: UsageOptions) -> Self {
+        Self {
+            pending: DashMap::new(),
+            options,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Count one allowed request for `key`.
+    pub fn record(&self, key: &str) {
+        let now = self.clock.unix_secs();
+        for granularity in &self.options.granularities {
+            let size = granularity.seconds();
+            let mut bucket = String::with_capacity(USAGE_KEY_PREFIX.len() + 14 + key.len());
+            let _ = write!(bucket, "{}{}:{}:{}", USAGE_KEY_PREFIX, granularity.tag(), now / size * size, key);
+            *self.pending.entry(bucket).or_insert(0) += 1;
+        }
+    }
+
+    /// Write the counts since the last flush to `storage`; returns how many
+    /// buckets were written. On failure the counts are kept for the next
+    /// flush.
+    pub async fn flush(&self, storage: &dyn StorageBackend) -> Result<u64, StorageError> {
+        let keys: Vec<String> = self.pending.iter().map(|entry| entry.key().clone()).collect();
+        let retention = self.options.retention.as_secs();
+        let mut counters = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some((key, count)) = self.pending.remove(&key) {
+                let (granularity, start, _) = parse_bucket(&key).expect("buckets are built by record");
+                let expire_at = start + granularity.seconds() + retention;
+                counters.push(CounterSnapshot { key, count, expire_at });
+            }
+        }
+        if counters.is_empty() {
+            return Ok(0);
+        }
+        match storage.import_all(&counters).await {
+            Ok(written) => Ok(written),
+            Err(e) => {
+                for counter in counters {
+                    *self.pending.entry(counter.key).or_insert(0) += counter.count;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Flush every `flush_interval` until the returned handle is aborted.
+    pub fn spawn(self: &Arc<Self>, storage: Arc<dyn StorageBackend>) -> JoinHandle<()> {
+        let recorder = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(recorder.options.flush_interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = recorder.flush(storage.as_ref()).await {
+                    metrics::counter!("rate_limiter_usage_flush_errors_total").increment(1);
+                    log::warn!("Flushing rate limit usage failed: {}", e);
+                }
+            }
+        })
+    }
+}
+
+/// Granularity, bucket start and rate limit key of a bucket key.
+fn parse_bucket(key: &str) -> Option<(Granularity, u64, &str)> {
+    let mut parts = key.strip_prefix(USAGE_KEY_PREFIX)?.splitn(3, ':');
+    let granularity = Granularity::from_tag(parts.next()?)?;
+    let start = parts.next()?.parse().ok()?;
+    Some((granularity, start, parts.next()?))
+}
+
+/// Usage in `granularity` buckets starting in `[from, to)` Unix seconds,
+/// sorted by key and time.
+pub async fn report(
+    storage: &dyn StorageBackend,
+    granularity: Granularity,
+    from: u64,
+    to: u64,
+) -> Result<Vec<UsageRow>, StorageError> {
+    let mut rows: Vec<UsageRow> = storage
+        .export_all()
+        .await?
+        .into_iter()
+        .filter_map(|counter| {
+            let (bucket, start, key) = parse_bucket(&counter.key)?;
+            (bucket == granularity && (from..to).contains(&start)).then(|| UsageRow {
+                key: key.to_string(),
+                granularity,
+                start,
+                count: counter.count,
+            })
+        })
+        .collect();
+    rows.sort_by(|a, b| (&a.key, a.start).cmp(&(&b.key, b.start)));
+    Ok(rows)
+}
+
+/// `key,granularity,start,count` lines with a header, keys quoted as CSV
+/// requires.
+pub fn to_csv(rows: &[UsageRow]) -> String {
+    let mut csv = String::from("key,granularity,start,count\n");
+    for row in rows {
+        let granularity = match row.granularity {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        };
+        if row.key.contains([',', '"', '\n', '\r']) {
+            let _ = write!(csv, "\"{}\"", row.key.replace('"', "\"\""));
+        } else {
+            csv.push_str(&row.key);
+        }
+        let _ = writeln!(csv, ",{},{},{}", granularity, row.start, row.count);
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_usage_is_bucketed_and_reported() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1_700_000_000)));
+        let storage = MemoryStorage::new().with_clock(clock.clone());
+        let recorder = UsageRecorder::new(UsageOptions::default()).with_clock(clock.clone());
+
+        recorder.record("2001:db8::1");
+        recorder.record("2001:db8::1");
+        assert_eq!(recorder.flush(&storage).await.unwrap(), 2);
+        clock.advance(Duration::from_secs(3600));
+        recorder.record("2001:db8::1");
+        recorder.record("a,b");
+        recorder.flush(&storage).await.unwrap();
+
+        let hours = report(&storage, Granularity::Hour, 0, u64::MAX).await.unwrap();
+        let counts: Vec<(u64, u64)> = hours.iter().map(|row| (row.start, row.count)).collect();
+        assert_eq!(counts, vec![(1_699_999_200, 2), (1_700_002_800, 1), (1_700_002_800, 1)]);
+        let days = report(&storage, Granularity::Day, 0, u64::MAX).await.unwrap();
+        assert_eq!(days.iter().find(|row| row.key == "2001:db8::1").unwrap().count, 3);
+        assert!(to_csv(&days).contains("\"a,b\",day,1699920000,1\n"));
+    }
+}
@@ -0,0 +1,283 @@
+//! The limiter as a Proxy-Wasm HTTP filter, for Envoy, Istio and
+//! nginx-wasm. Built with
+//!
+//! ```sh
+//! cargo build --lib --release --target wasm32-wasip1 --features proxy-wasm
+//! ```
+//!
+//! The plugin configuration is JSON: the limits in the shared
+//! `LimitsConfig` format, plus where counts are kept.
+//!
+//! ```json
+//! {
+//!   "requests": 100, "window": 60,
+//!   "rules": [{"path": {"prefix": "/api/"}, "requests": 10, "window": 60}],
+//!   "backend": {"type": "http", "cluster": "ratelimit", "path": "/decide"},
+//!   "failure_mode": "allow"
+//! }
+//! ```
+//!
+//! The `memory` backend counts in the VM running the filter. Envoy runs
+//! one VM per worker thread, so those limits apply per worker. The `http`
+//! backend asks the HTTP decision API, e.g. a `rate-limit-sidecar` shared
+//! with the nginx fleet, through an upstream cluster.
+
+use proxy_wasm::traits::{Context, HttpContext, RootContext};
+use proxy_wasm::types::{Action, ContextType, LogLevel};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use crate::clock::Clock;
+use crate::config::{Limits, LimitsConfig};
+use crate::request_key;
+use crate::storage::{DecisionRequest, DecisionResponse, FailureMode, MemoryOptions, MemoryStorage};
+
+/// Where the filter keeps counts.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FilterBackend {
+    /// In the filter's VM, bounded like `rate_limit_storage memory`
+    #[default]
+    Memory,
+    /// The HTTP decision API behind an upstream `cluster`
+    Http {
+        cluster: String,
+        #[serde(default = "default_decision_path")]
+        path: String,
+        /// `:authority` of the call, the cluster name if unset
+        #[serde(default)]
+        authority: Option<String>,
+        #[serde(default = "default_timeout_ms")]
+        timeout_ms: u64,
+    },
+}
+
+fn default_decision_path() -> String {
+    "/decide".to_string()
+}
+
+fn default_timeout_ms() -> u64 {
+    100
+}
+
+/// The plugin configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterConfig {
+    #[serde(flatten)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub backend: FilterBackend,
+    /// `MemoryOptions` string for the `memory` backend, e.g. `max_entries=100k`
+    #[serde(default)]
+    pub memory: String,
+    /// What happens when the decision service fails
+    #[serde(default = "default_failure_mode")]
+    pub failure_mode: FailureMode,
+}
+
+fn default_failure_mode() -> FailureMode {
+    FailureMode::Allow
+}
+
+/// Time from the proxy, which is all a sandboxed filter can read.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HostClock;
+
+impl Clock for HostClock {
+    fn now(&self) -> Duration {
+        proxy_wasm::hostcalls::get_current_time()
+            .ok()
+            .and_then(|now| now.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// State shared by the requests of one configuration.
+struct Shared {
+    limits: Limits,
+    backend: FilterBackend,
+    failure_mode: FailureMode,
+    storage: MemoryStorage,
+}
+
+impl Shared {
+    fn from_config(config: &[u8]) -> Result<Self, String> {
+        let config: FilterConfig = serde_json::from_slice(config).map_err(|e| e.to_string())?;
+        let options = MemoryOptions::parse(&config.memory).map_err(|e| e.to_string())?;
+        Ok(Self {
+            limits: config.limits.compile(1).map_err(|e| e.to_string())?,
+            backend: config.backend,
+            failure_mode: config.failure_mode,
+            storage: MemoryStorage::with_options(options).with_clock(Arc::new(HostClock)),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Root {
+    shared: Option<Rc<Shared>>,
+}
+
+impl Context for Root {}
+
+impl RootContext for Root {
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let config = self.get_plugin_configuration().unwrap_or_default();
+        match Shared::from_config(&config) {
+            Ok(shared) => {
+                self.shared = Some(Rc::new(shared));
+                true
+            }
+            Err(e) => {
+                log::error!("Invalid rate limiter configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
+        let shared = Rc::clone(self.shared.as_ref()?);
+        Some(Box::new(Filter { shared, window: 0 }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+/// One request.
+struct Filter {
+    shared: Rc<Shared>,
+    /// Window of the limit applied, for `Retry-After`
+    window: u32,
+}
+
+impl Filter {
+    /// The client address without its port.
+    fn client(&self) -> String {
+        let address = self
+            .get_property(vec!["source", "address"])
+            .and_then(|address| String::from_utf8(address).ok())
+            .unwrap_or_default();
+        match address.parse::<SocketAddr>() {
+            Ok(address) => address.ip().to_string(),
+            Err(_) => address,
+        }
+    }
+
+    fn reject(&self) {
+        metrics::counter!("rate_limiter_wasm_rejected_total").increment(1);
+        let retry_after = self.window.to_string();
+        self.send_http_response(429, vec![("retry-after", retry_after.as_str())], Some(b"Too Many Requests\n"));
+    }
+
+    /// Apply the failure mode when the decision service cannot answer.
+    fn fail(&self) -> Action {
+        match self.shared.failure_mode {
+            FailureMode::Allow => Action::Continue,
+            FailureMode::Deny => {
+                self.reject();
+                Action::Pause
+            }
+        }
+    }
+}
+
+impl HttpContext for Filter {
+    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        let path = self.get_http_request_header(":path").unwrap_or_default();
+        let path = path.split('?').next().unwrap_or_default();
+        let (limit, window) = self.shared.limits.for_path(path);
+        self.window = window;
+        let key = request_key(self.client());
+
+        match &self.shared.backend {
+            FilterBackend::Memory => {
+                let storage = &self.shared.storage;
+                if storage.count(&key) >= u64::from(limit) {
+                    self.reject();
+                    return Action::Pause;
+                }
+                storage.hit(&key, window);
+                Action::Continue
+            }
+            FilterBackend::Http { cluster, path, authority, timeout_ms } => {
+                let request = DecisionRequest { key: Cow::Borrowed(key.as_str()), limit, window, hits: 1 };
+                let Ok(body) = serde_json::to_vec(&request) else {
+                    return self.fail();
+                };
+                let headers = vec![
+                    (":method", "POST"),
+                    (":path", path.as_str()),
+                    (":authority", authority.as_deref().unwrap_or(cluster)),
+                    ("content-type", "application/json"),
+                ];
+                match self.dispatch_http_call(cluster, headers, Some(&body), vec![], Duration::from_millis(*timeout_ms)) {
+                    // Resumed or rejected in on_http_call_response
+                    Ok(_) => Action::Pause,
+                    Err(e) => {
+                        log::warn!("Rate limit decision call to {} failed: {:?}", cluster, e);
+                        self.fail()
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Context for Filter {
+    fn on_http_call_response(&mut self, _token_id: u32, _num_headers: usize, body_size: usize, _num_trailers: usize) {
+        let succeeded = self
+            .get_http_call_response_header(":status")
+            .is_some_and(|status| status.starts_with('2'));
+        let response = self
+            .get_http_call_response_body(0, body_size)
+            .filter(|_| succeeded)
+            .and_then(|body| serde_json::from_slice::<DecisionResponse>(&body).ok());
+
+        match response {
+            Some(response) if response.allowed => self.resume_http_request(),
+            Some(_) => self.reject(),
+            None => {
+                log::warn!("Rate limit decision service gave no usable answer");
+                if self.fail() == Action::Continue {
+                    self.resume_http_request();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Warn);
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> { Box::new(Root::default()) });
+}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config: FilterConfig = serde_json::from_str(
+            r#"{"requests": 100, "window": 60,
+                "rules": [{"path": {"prefix": "/api/"}, "requests": 10, "window": 1}],
+                "backend": {"type": "http", "cluster": "ratelimit"},
+                "failure_mode": "deny"}"#,
+        )
+        .unwrap();
+        assert!(matches!(&config.backend, FilterBackend::Http { path, timeout_ms: 100, .. } if path == "/decide"));
+        assert_eq!(config.failure_mode, FailureMode::Deny);
+
+        let limits = config.limits.compile(1).unwrap();
+        assert_eq!(limits.for_path("/api/users"), (10, 1));
+        assert_eq!(limits.for_path("/"), (100, 60));
+
+        let config: FilterConfig = serde_json::from_str(r#"{"requests": 5, "window": 1}"#).unwrap();
+        assert!(matches!(config.backend, FilterBackend::Memory));
+    }
+}
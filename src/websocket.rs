@@ -0,0 +1,146 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::{self, Clock};
+use crate::storage::KeyBuf;
+use crate::tenant::RequestInfo;
+
+/// WebSocket close code for a socket closed for breaking policy.
+pub const POLICY_VIOLATION: u16 = 1008;
+
+/// Upgrade limits and message budgets for WebSocket connections.
+#[derive(Debug, Clone)]
+pub struct WebSocketOptions {
+    /// Upgrades a key may make per `upgrade_window` seconds
+    pub upgrades: u32,
+    pub upgrade_window: u32,
+    /// Messages one socket may send per `message_window`
+    pub messages: u32,
+    /// Payload bytes one socket may send per `message_window`
+    pub bytes: u64,
+    pub message_window: Duration,
+    /// Close code sent to sockets over their budget, e.g. 1008 (policy
+    /// violation) or 1013 (try again later)
+    pub close_code: u16,
+}
+
+impl Default for WebSocketOptions {
+    fn default() -> Self {
+        Self {
+            upgrades: 10,
+            upgrade_window: 60,
+            messages: 100,
+            bytes: 1 << 20,
+            message_window: Duration::from_secs(1),
+            close_code: POLICY_VIOLATION,
+        }
+    }
+}
+
+/// Whether `request` asks to upgrade to a WebSocket.
+pub fn is_upgrade(request: &RequestInfo<'_>) -> bool {
+    request
+        .header("upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+}
+
+/// `ws:{key}`, the counter of a key's upgrades, apart from its requests.
+pub fn upgrade_key(key: &str) -> KeyBuf {
+    let mut buf = KeyBuf::new();
+    let _ = write!(buf, "ws:{}", key);
+    buf
+}
+
+/// The close frame to send a socket that went over its budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Close {
+    pub code: u16,
+    pub reason: &'static str,
+}
+
+/// The message budget of one proxied socket.
+///
+/// Per-request counting is blind to a socket that stays open for hours,
+/// so a proxy passes each message the client sends to `message`, and
+/// closes the socket with the returned frame once it is over budget. The
+/// budget is the socket's own and needs no backend calls.
+pub struct MessageBudget {
+    options: WebSocketOptions,
+    window: u64,
+    messages: u32,
+    bytes: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl std::fmt::Debug for MessageBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MessageBudget")
+            .field("messages", &self.messages)
+            .field("bytes", &self.bytes)
+            .finish()
+    }
+}
+
+impl MessageBudget {
+    pub fn new(options: WebSocketOptions) -> Self {
+        Self {
+            options,
+            window: 0,
+            messages: 0,
+            bytes: 0,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Count a message of `len` payload bytes. Returns the close frame to
+    /// send when it puts the socket over its budget.
+    pub fn message(&mut self, len: usize) -> Option<Close> {
+        let window = (self.clock.now().as_millis() / self.options.message_window.as_millis().max(1)) as u64;
+        if window != self.window {
+            self.window = window;
+            self.messages = 0;
+            self.bytes = 0;
+        }
+        self.messages = self.messages.saturating_add(1);
+        self.bytes = self.bytes.saturating_add(len as u64);
+
+        let reason = if self.messages > self.options.messages {
+            "too many messages"
+        } else if self.bytes > self.options.bytes {
+            "too much data"
+        } else {
+            return None;
+        };
+        metrics::counter!("rate_limiter_websocket_closes_total").increment(1);
+        Some(Close { code: self.options.close_code, reason })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_sockets_over_budget_are_closed() {
+        let clock = Arc::new(ManualClock::default());
+        let options = WebSocketOptions { messages: 3, bytes: 100, ..WebSocketOptions::default() };
+        let mut budget = MessageBudget::new(options).with_clock(clock.clone());
+
+        for _ in 0..3 {
+            assert_eq!(budget.message(10), None);
+        }
+        assert_eq!(budget.message(10), Some(Close { code: 1008, reason: "too many messages" }));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(budget.message(101).map(|close| close.reason), Some("too much data"));
+
+        let headers = [("Upgrade", "WebSocket")];
+        assert!(is_upgrade(&RequestInfo { headers: &headers, ..RequestInfo::default() }));
+        assert_eq!(upgrade_key("192.0.2.7").as_str(), "ws:192.0.2.7");
+    }
+}
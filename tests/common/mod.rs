@@ -0,0 +1,78 @@
+//! Scenarios shared by the integration test binaries.
+
+// Each binary uses its own subset
+#![allow(dead_code)]
+
+use ngx_http_rate_limiter::storage::StorageBackend;
+use std::future::Future;
+use std::time::Duration;
+
+pub async fn test_storage_backend<T: StorageBackend>(storage: T) {
+    // Basic increment and get
+    assert_eq!(storage.increment("test_key", 60).await.unwrap().count, 1);
+    assert_eq!(storage.get("test_key").await.unwrap(), 1);
+
+    assert_eq!(storage.increment("test_key", 60).await.unwrap().count, 2);
+    assert_eq!(storage.get("test_key").await.unwrap(), 2);
+
+    // Delete
+    storage.delete("test_key").await.unwrap();
+    assert_eq!(storage.get("test_key").await.unwrap(), 0);
+
+    // Batches; a repeated key is counted once per occurrence
+    let increments = storage
+        .increment_many(&[("batch_a", 60), ("batch_b", 60), ("batch_a", 60)])
+        .await
+        .unwrap();
+    let counts: Vec<u64> = increments.iter().map(|increment| increment.count).collect();
+    assert_eq!(counts, vec![1, 1, 2]);
+    assert_eq!(storage.get_many(&["batch_a", "missing", "batch_b"]).await.unwrap(), vec![2, 0, 1]);
+    storage.delete("batch_a").await.unwrap();
+    storage.delete("batch_b").await.unwrap();
+
+    // Cleanup expired
+    storage.cleanup_expired().await.unwrap();
+}
+
+/// A counter is gone once its TTL has passed, a new window starts at one
+/// and `cleanup_expired` leaves live counters alone. Backends only resolve
+/// expiry to the second, so this waits in real time.
+pub async fn test_expiry<T: StorageBackend>(storage: T) {
+    storage.increment("ttl_short", 1).await.unwrap();
+    storage.increment("ttl_short", 1).await.unwrap();
+    storage.increment("ttl_long", 60).await.unwrap();
+    assert_eq!(storage.get("ttl_short").await.unwrap(), 2);
+
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(storage.get("ttl_short").await.unwrap(), 0);
+    assert_eq!(storage.get("ttl_long").await.unwrap(), 1);
+
+    storage.cleanup_expired().await.unwrap();
+    assert_eq!(storage.get("ttl_long").await.unwrap(), 1);
+    assert_eq!(storage.increment("ttl_short", 1).await.unwrap().count, 1);
+
+    storage.delete("ttl_short").await.unwrap();
+    storage.delete("ttl_long").await.unwrap();
+}
+
+/// Retry `connect` until it succeeds and the backend answers a `get`, for
+/// servers that accept connections before they can serve queries.
+pub async fn wait_ready<T, F, Fut>(mut connect: F) -> T
+where
+    T: StorageBackend,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ngx_http_rate_limiter::storage::StorageError>>,
+{
+    let mut last_error = None;
+    for _ in 0..60 {
+        match connect().await {
+            Ok(storage) => match storage.get("readiness").await {
+                Ok(_) => return storage,
+                Err(e) => last_error = Some(e),
+            },
+            Err(e) => last_error = Some(e),
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    panic!("Backend not ready after 30s: {:?}", last_error);
+}
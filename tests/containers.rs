@@ -0,0 +1,90 @@
+//! Runs the backend suite against throwaway containers, so no services
+//! need to be running beforehand. Each test starts its backend with
+//! testcontainers, waits until it is ready and skips, printing why, when
+//! the container cannot be started, e.g. without Docker:
+//!
+//! ```sh
+//! cargo test --test containers
+//! ```
+
+use ngx_http_rate_limiter::storage::{MemcachedStorage, MySQLStorage, PostgresStorage, RedisStorage};
+use testcontainers::core::{IntoContainerPort, WaitFor};
+use testcontainers::runners::AsyncRunner;
+use testcontainers::{ContainerAsync, GenericImage, Image, ImageExt};
+use testcontainers_modules::{mysql::Mysql, postgres::Postgres, redis::Redis};
+
+mod common;
+use common::{test_expiry, test_storage_backend, wait_ready};
+
+/// Start `image` and wait for its ready message, or `None` when it cannot
+/// be started: no Docker daemon, the image cannot be pulled, and so on.
+async fn start<I: Image>(image: impl AsyncRunner<I>) -> Option<ContainerAsync<I>> {
+    match image.start().await {
+        Ok(container) => Some(container),
+        Err(e) => {
+            eprintln!("Skipping: the container could not be started ({})", e);
+            None
+        }
+    }
+}
+
+/// `host:port` of the container's `port`.
+async fn address<I: Image>(container: &ContainerAsync<I>, port: u16) -> String {
+    let service = container.image().name();
+    let host = container
+        .get_host()
+        .await
+        .unwrap_or_else(|e| panic!("no host for the {} container: {}", service, e));
+    let port = container
+        .get_host_port_ipv4(port)
+        .await
+        .unwrap_or_else(|e| panic!("{} container does not map port {}: {}", service, port, e));
+    format!("{}:{}", host, port)
+}
+
+#[tokio::test]
+async fn test_redis_container() {
+    let Some(container) = start(Redis::default().with_tag("7-alpine")).await else {
+        return;
+    };
+    let url = format!("redis://{}/", address(&container, 6379).await);
+
+    test_storage_backend(wait_ready(|| async { RedisStorage::new(&url) }).await).await;
+    test_expiry(wait_ready(|| async { RedisStorage::new(&url) }).await).await;
+}
+
+#[tokio::test]
+async fn test_memcached_container() {
+    let image = GenericImage::new("memcached", "1.6-alpine")
+        .with_exposed_port(11211.tcp())
+        .with_wait_for(WaitFor::seconds(1));
+    let Some(container) = start(image).await else {
+        return;
+    };
+    let url = format!("memcache://{}", address(&container, 11211).await);
+
+    test_storage_backend(wait_ready(|| async { MemcachedStorage::new(&url) }).await).await;
+    test_expiry(wait_ready(|| async { MemcachedStorage::new(&url) }).await).await;
+}
+
+#[tokio::test]
+async fn test_mysql_container() {
+    let Some(container) = start(Mysql::default()).await else {
+        return;
+    };
+    let url = format!("mysql://root@{}/test", address(&container, 3306).await);
+
+    test_storage_backend(wait_ready(|| MySQLStorage::new(&url)).await).await;
+    test_expiry(wait_ready(|| MySQLStorage::new(&url)).await).await;
+}
+
+#[tokio::test]
+async fn test_postgres_container() {
+    let Some(container) = start(Postgres::default()).await else {
+        return;
+    };
+    let url = format!("postgres://postgres:postgres@{}/postgres", address(&container, 5432).await);
+
+    test_storage_backend(wait_ready(|| PostgresStorage::new(&url)).await).await;
+    test_expiry(wait_ready(|| PostgresStorage::new(&url)).await).await;
+}